@@ -1,4 +1,4 @@
-use super::get;
+use super::{args, get};
 use clap::{App, Arg, ArgMatches, SubCommand};
 use elba::{
     cli::build,
@@ -18,6 +18,12 @@ pub fn cli() -> App<'static, 'static> {
                 .help("The package spec to add"),
         )
         .arg(Arg::with_name("dev").help("Whether to add the package as a dev_dependency"))
+        .arg(
+            Arg::with_name("force")
+                .long("force")
+                .help("Replace the package if it's already a dependency"),
+        )
+        .arg(args::registry())
 }
 
 pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
@@ -25,11 +31,20 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
         "couldn't get current dir; doesn't exist or no permissions..."
     ))?;
 
-    let spec = Spec::from_str(args.value_of("spec").unwrap())?;
+    let mut spec = Spec::from_str(args.value_of("spec").unwrap())?;
 
-    let bcx = get::build_ctx(c, args);
+    let bcx = get::build_ctx(c, args)?;
+    if args.is_present("registry") && spec.resolution.is_none() {
+        spec.resolution = bcx.indices.values().next().map(|ir| ir.clone().into());
+    }
 
-    let res = build::add(&bcx, &project, &spec, args.is_present("dev"))?;
+    let res = build::add(
+        &bcx,
+        &project,
+        &spec,
+        args.is_present("dev"),
+        args.is_present("force"),
+    )?;
 
     Ok(res)
 }
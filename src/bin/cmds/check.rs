@@ -13,8 +13,16 @@ pub fn cli() -> App<'static, 'static> {
         .arg(args::target_lib())
         .arg(args::target_bin())
         .arg(args::target_test())
+        .arg(args::target_example())
         .arg(args::build_threads())
+        .arg(args::download_jobs())
+        .args(&args::profiles())
         .arg(args::offline())
+        .arg(args::vendored())
+        .arg(args::deadline())
+        .arg(args::frozen())
+        .arg(args::registry())
+        .arg(args::features())
         .arg(args::debug_log())
         .arg(args::idris_opts())
         .args(&args::backends())
@@ -25,13 +33,14 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
         "couldn't get current dir; doesn't exist or no permissions..."
     ))?;
 
-    let ctx = get::build_ctx(c, args);
+    let ctx = get::build_ctx(c, args)?;
 
     let ts = (
         args.is_present("lib"),
         false,
         args.values_of("bin").map(|x| x.collect::<Vec<_>>()),
         args.values_of("test").map(|x| x.collect::<Vec<_>>()),
+        args.values_of("example").map(|x| x.collect::<Vec<_>>()),
     );
 
     // This is where our default codegen backend is set
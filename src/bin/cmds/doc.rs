@@ -11,8 +11,15 @@ pub fn cli() -> App<'static, 'static> {
     SubCommand::with_name("doc")
         .about("Builds the docs for the root package")
         .arg(args::build_threads())
+        .arg(args::download_jobs())
+        .args(&args::profiles())
         .arg(args::debug_log())
         .arg(args::offline())
+        .arg(args::vendored())
+        .arg(args::deadline())
+        .arg(args::frozen())
+        .arg(args::registry())
+        .arg(args::features())
         .arg(args::idris_opts())
 }
 
@@ -21,7 +28,7 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
         "couldn't get current dir; doesn't exist or no permissions..."
     ))?;
 
-    let ctx = get::build_ctx(c, args);
+    let ctx = get::build_ctx(c, args)?;
 
     build::doc(&ctx, &project)
 }
@@ -0,0 +1,40 @@
+use super::{args, get};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use elba::util::{config::Config, error::Result};
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("doctor")
+        .about("Checks the global cache for problems, and optionally fixes them")
+        .arg(
+            Arg::with_name("fix")
+                .long("fix")
+                .help("Fixes the problems which are found"),
+        )
+        .arg(args::offline())
+        .arg(args::deadline())
+        .arg(args::debug_log())
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let bcx = get::build_ctx(c, args)?;
+    let mut cache = bcx.cache()?;
+    cache.offline |= bcx.offline;
+
+    let report = cache.doctor(args.is_present("fix"))?;
+    for issue in &report.issues {
+        println!("{}", issue);
+    }
+
+    let unfixed = report.unfixed().count();
+    if report.issues.is_empty() {
+        Ok("no problems found".to_string())
+    } else if unfixed == 0 {
+        Ok(format!("fixed {} problems", report.issues.len()))
+    } else {
+        Ok(format!(
+            "found {} problems ({} left unfixed)",
+            report.issues.len(),
+            unfixed
+        ))
+    }
+}
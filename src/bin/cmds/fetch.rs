@@ -0,0 +1,36 @@
+use super::{args, get};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::build,
+    util::{config::Config, error::Result},
+};
+use failure::{format_err, ResultExt};
+use std::env::current_dir;
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("fetch")
+        .about("Downloads every package in elba.lock into the cache for offline builds")
+        .arg(args::download_jobs())
+        .arg(
+            Arg::with_name("vendor")
+                .long("vendor")
+                .help("Download into the project's vendored cache instead of the global cache"),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Only report which packages would be downloaded, and how much"),
+        )
+        .arg(args::deadline())
+        .arg(args::debug_log())
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let project = current_dir().context(format_err!(
+        "couldn't get current dir; doesn't exist or no permissions..."
+    ))?;
+
+    let ctx = get::build_ctx(c, args)?;
+
+    build::fetch(&ctx, &project, args.is_present("dry-run"))
+}
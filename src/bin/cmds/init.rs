@@ -12,6 +12,7 @@ pub fn cli() -> App<'static, 'static> {
     SubCommand::with_name("init")
         .about("Initializes an elba project in the current directory")
         .arg(args::vcs())
+        .arg(args::author())
         .arg(
             Arg::with_name("lib")
                 .long("lib")
@@ -29,12 +30,14 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
         .ok_or_else(|| format_err!("can't make a project in a root directory"))?
         .to_string_lossy()
         .into_owned();
-    let name = Name::new(name.clone(), name)?;
+    let name = Name::try_from_parts(&name, &name)?;
     let bin = !args.is_present("lib");
-    let author = if let Some(profile) = &c.profile {
-        Some((profile.name.clone(), profile.email.clone()))
-    } else {
-        None
+    let author = match args.value_of("author") {
+        Some(author) => Some(new::parse_author(author)?),
+        None => c
+            .profile
+            .as_ref()
+            .map(|profile| (profile.name.clone(), profile.email.clone())),
     };
 
     let new_ctx = new::NewCtx {
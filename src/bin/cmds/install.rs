@@ -14,9 +14,15 @@ pub fn cli() -> App<'static, 'static> {
         .about("Installs a package's artifacts")
         .arg(Arg::with_name("spec"))
         .arg(args::build_threads())
+        .arg(args::download_jobs())
+        .args(&args::profiles())
         .arg(args::target_bin())
         .arg(args::debug_log())
         .arg(args::offline())
+        .arg(args::deadline())
+        .arg(args::frozen())
+        .arg(args::registry())
+        .arg(args::features())
         .arg(args::idris_opts())
         .arg(
             Arg::with_name("force")
@@ -39,7 +45,16 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
         bail!("no package was specified to be installed and the current directory is inaccessible")
     };
 
-    let ctx = get::build_ctx(c, args);
+    let ctx = get::build_ctx(c, args)?;
+    let proj = match proj {
+        Left(mut spec) => {
+            if args.is_present("registry") && spec.resolution.is_none() {
+                spec.resolution = ctx.indices.values().next().map(|ir| ir.clone().into());
+            }
+            Left(spec)
+        }
+        Right(d) => Right(d),
+    };
 
     let targets = args
         .values_of("bin")
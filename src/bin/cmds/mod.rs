@@ -3,12 +3,15 @@ mod build;
 mod check;
 mod clean;
 mod doc;
+mod doctor;
+mod fetch;
 mod init;
 mod install;
 mod new;
 mod package;
 mod print_config;
 mod repl;
+mod run;
 mod script;
 mod search;
 mod test;
@@ -37,12 +40,15 @@ pub fn subcommands() -> Vec<App<'static, 'static>> {
         check::cli(),
         clean::cli(),
         doc::cli(),
+        doctor::cli(),
+        fetch::cli(),
         init::cli(),
         install::cli(),
         new::cli(),
         package::cli(),
         print_config::cli(),
         repl::cli(),
+        run::cli(),
         script::cli(),
         search::cli(),
         test::cli(),
@@ -58,12 +64,15 @@ pub fn execute_internal(cmd: &str) -> Option<Exec> {
         "check" => Some(check::exec),
         "clean" => Some(clean::exec),
         "doc" => Some(doc::exec),
+        "doctor" => Some(doctor::exec),
+        "fetch" => Some(fetch::exec),
         "init" => Some(init::exec),
         "install" => Some(install::exec),
         "new" => Some(new::exec),
         "package" => Some(package::exec),
         "print-config" => Some(print_config::exec),
         "repl" => Some(repl::exec),
+        "run" => Some(run::exec),
         "script" => Some(script::exec),
         "search" => Some(search::exec),
         "test" => Some(test::exec),
@@ -109,22 +118,106 @@ pub fn execute_external(cmd: &str, args: &ArgMatches) -> Result<String> {
 
 mod get {
     use super::*;
-    use elba::cli::build::BuildCtx;
+    use elba::{
+        cli::build::BuildCtx,
+        package::cfg::Platform,
+        remote::{
+            prioritize_index,
+            resolution::{DirectRes, IndexRes},
+            signature::PublicKey,
+        },
+        retrieve::cache::{frozen_env, offline_env},
+        util::config::{BuildProfile, BUILD_JOBS_ENV, DOWNLOAD_JOBS_ENV},
+    };
+    use indexmap::IndexMap;
     use slog::Drain;
+    use std::{
+        str::FromStr,
+        time::{Duration, Instant},
+    };
 
-    pub fn build_ctx(c: &mut Config, args: &ArgMatches) -> BuildCtx {
+    pub fn build_ctx(c: &mut Config, args: &ArgMatches) -> Result<BuildCtx> {
         let logger = get::logger(c, args);
 
-        BuildCtx {
+        let ctx = BuildCtx {
             compiler: c.compiler.clone(),
-            indices: c.indices.to_owned(),
+            indices: get::indices(c, args)?,
             global_cache: c.layout(),
+            base_cache: c.base_layout(),
             logger,
             threads: get::threads(c, args),
+            download_jobs: get::download_jobs(c, args),
             shell: c.shell(),
-            offline: args.is_present("offline"),
+            offline: args.is_present("offline") || args.is_present("vendored") || offline_env(),
             opts: get::idris_opts(c, args),
+            features: get::features(c, args),
+            profile: get::profile(c, args)?,
+            platform: Platform::new(&get::backends(c, args).name),
+            credentials: c.credentials.clone(),
+            compress_sources: c.cache.compress_sources,
+            slim_sources: c.cache.slim_sources,
+            source_naming: c.cache.source_naming,
+            mirrors: c.mirrors.clone(),
+            index_keys: get::index_keys(c)?,
+            tarball_dirs: c.cache.tarball_dirs.clone(),
+            frozen: args.is_present("frozen") || frozen_env(),
+            strict_indices: c.strict_indices,
+            local_builds: args.is_present("local-builds"),
+            connect_timeout: Duration::from_secs(c.net.connect_timeout),
+            request_timeout: Duration::from_secs(c.net.request_timeout),
+            deadline: get::deadline(args)?,
+        };
+
+        if args.is_present("vendor") || args.is_present("vendored") {
+            let project = env::current_dir().context(format_err!(
+                "couldn't get current dir; doesn't exist or no permissions..."
+            ))?;
+            ctx.vendored(&project)
+        } else {
+            Ok(ctx)
+        }
+    }
+
+    /// When resolving and retrieving has to be done by, counting from now.
+    pub fn deadline(args: &ArgMatches) -> Result<Option<Instant>> {
+        args.value_of("deadline")
+            .map(|secs| {
+                let secs = secs
+                    .trim()
+                    .parse::<u64>()
+                    .with_context(|e| format_err!("invalid deadline `{}`: {}", secs, e))?;
+                Ok(Instant::now() + Duration::from_secs(secs))
+            })
+            .transpose()
+    }
+
+    pub fn indices(c: &mut Config, args: &ArgMatches) -> Result<IndexMap<String, IndexRes>> {
+        let mut indices = c.indices.to_owned();
+        if let Some(name) = args.value_of("registry") {
+            prioritize_index(&mut indices, name)?;
         }
+
+        Ok(indices)
+    }
+
+    /// The keys from the configuration that signed indices have to be signed with, with the
+    /// aliases of indices resolved.
+    pub fn index_keys(c: &Config) -> Result<IndexMap<DirectRes, PublicKey>> {
+        c.index_keys
+            .iter()
+            .map(|(index, key)| {
+                let res = match c.indices.get(index) {
+                    Some(ir) => ir.res.clone(),
+                    None => IndexRes::from_str(index)
+                        .map(|ir| ir.res)
+                        .or_else(|_| DirectRes::from_str(index))
+                        .with_context(|e| {
+                            format_err!("`index_keys` names unknown index `{}`: {}", index, e)
+                        })?,
+                };
+                Ok((res, key.clone()))
+            })
+            .collect()
     }
 
     pub fn logger(c: &mut Config, args: &ArgMatches) -> Logger {
@@ -169,10 +262,48 @@ mod get {
         backend
     }
 
-    pub fn threads(_c: &mut Config, args: &ArgMatches) -> u32 {
-        args.value_of("threads")
-            .and_then(|s| s.parse().ok())
-            .unwrap_or(2)
+    /// How many packages to build at once: the `--threads` flag wins over `ELBA_BUILD_JOBS`, which
+    /// wins over the configuration.
+    pub fn threads(c: &mut Config, args: &ArgMatches) -> u32 {
+        jobs(c.build_jobs, args.value_of("threads"), BUILD_JOBS_ENV)
+    }
+
+    /// How many packages to download at once, in the same order of precedence as `threads`.
+    pub fn download_jobs(c: &mut Config, args: &ArgMatches) -> u32 {
+        jobs(
+            c.download_jobs,
+            args.value_of("download-jobs"),
+            DOWNLOAD_JOBS_ENV,
+        )
+    }
+
+    fn jobs(configured: u32, flag: Option<&str>, var: &str) -> u32 {
+        flag.map(str::to_owned)
+            .or_else(|| env::var(var).ok())
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(configured)
+    }
+
+    pub fn features(_c: &mut Config, args: &ArgMatches) -> Vec<String> {
+        args.values_of("features")
+            .map(|x| {
+                x.flat_map(|s| s.split(&[',', ' '][..]))
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.to_string())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn profile(c: &mut Config, args: &ArgMatches) -> Result<BuildProfile> {
+        let name = if args.is_present("release") {
+            "release"
+        } else {
+            args.value_of("profile").unwrap_or("debug")
+        };
+
+        c.get_build_profile(name)
+            .ok_or_else(|| format_err!("no build profile named `{}` is configured", name))
     }
 
     pub fn idris_opts(_c: &mut Config, args: &ArgMatches) -> Vec<String> {
@@ -217,13 +348,44 @@ mod args {
             .help("The names of the tests to which the command should apply (or all if no argument is provided)")
     }
 
+    pub fn target_example() -> Arg {
+        Arg::with_name("example")
+            .long("example")
+            .takes_value(true)
+            .min_values(0)
+            .help("The names of the examples to which the command should apply (or all if no argument is provided)")
+    }
+
     pub fn build_threads() -> Arg {
         Arg::with_name("threads")
             .long("threads")
+            .alias("build-jobs")
             .short("j")
             .takes_value(true)
             .number_of_values(1)
-            .help("The number of threads to use to build")
+            .help("The number of packages to build at once (0 for one per core)")
+    }
+
+    pub fn download_jobs() -> Arg {
+        Arg::with_name("download-jobs")
+            .long("download-jobs")
+            .takes_value(true)
+            .number_of_values(1)
+            .help("The number of packages to download at once (0 for one per core)")
+    }
+
+    pub fn profiles() -> Vec<Arg> {
+        vec![
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .number_of_values(1)
+                .help("The build profile to use (`debug` by default)"),
+            Arg::with_name("release")
+                .long("release")
+                .conflicts_with("profile")
+                .help("Build with the `release` profile"),
+        ]
     }
 
     pub fn backends() -> Vec<Arg> {
@@ -249,10 +411,53 @@ mod args {
         ]
     }
 
+    pub fn features() -> Arg {
+        Arg::with_name("features")
+            .long("features")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Features of the root package to turn on (separated by commas or spaces)")
+    }
+
+    pub fn registry() -> Arg {
+        Arg::with_name("registry")
+            .long("registry")
+            .takes_value(true)
+            .number_of_values(1)
+            .help("The name of the index to use as the default (and highest priority) index")
+    }
+
     pub fn offline() -> Arg {
         Arg::with_name("offline")
             .long("offline")
-            .help("Run in offline mode; nothing will be retrieved.")
+            .help("Run in offline mode; nothing will be retrieved. Same as setting ELBA_OFFLINE=1.")
+    }
+
+    pub fn frozen() -> Arg {
+        Arg::with_name("frozen")
+            .long("frozen")
+            .help("Like --offline, but changing elba.lock is an error too. Same as setting ELBA_FROZEN=1.")
+    }
+
+    pub fn deadline() -> Arg {
+        Arg::with_name("deadline")
+            .long("deadline")
+            .takes_value(true)
+            .number_of_values(1)
+            .help("Give up on resolving and retrieving packages after this many seconds")
+    }
+
+    pub fn local_builds() -> Arg {
+        Arg::with_name("local-builds")
+            .long("local-builds")
+            .help("Cache builds in the project's target directory instead of the global cache")
+    }
+
+    pub fn vendored() -> Arg {
+        Arg::with_name("vendored").long("vendored").help(
+            "Use only the project's vendored cache (see `elba fetch --vendor`); implies --offline",
+        )
     }
 
     pub fn vcs() -> Arg {
@@ -264,6 +469,14 @@ mod args {
             .help("The VCS template to use when initializing a new repo")
     }
 
+    pub fn author() -> Arg {
+        Arg::with_name("author")
+            .long("author")
+            .takes_value(true)
+            .number_of_values(1)
+            .help("The author of the new package, as `Name <email>` (defaults to the configured profile, then git's user)")
+    }
+
     pub fn debug_log() -> Arg {
         Arg::with_name("debug-log")
             .long("debug-log")
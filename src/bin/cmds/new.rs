@@ -10,9 +10,10 @@ use std::{env::current_dir, str::FromStr};
 
 pub fn cli() -> App<'static, 'static> {
     SubCommand::with_name("new")
-        .about("Creates a new empty project")
+        .about("Creates a new project in a new (or empty) directory")
         .arg(Arg::with_name("name").required(true))
         .arg(args::vcs())
+        .arg(args::author())
         .arg(
             Arg::with_name("lib")
                 .long("lib")
@@ -26,10 +27,12 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
     let name = Name::from_str(name)
         .with_context(|e| format_err!("the name `{}` is invalid: {}", name, e))?;
     let bin = !args.is_present("lib");
-    let author = if let Some(profile) = &c.profile {
-        Some((profile.name.clone(), profile.email.clone()))
-    } else {
-        None
+    let author = match args.value_of("author") {
+        Some(author) => Some(new::parse_author(author)?),
+        None => c
+            .profile
+            .as_ref()
+            .map(|profile| (profile.name.clone(), profile.email.clone())),
     };
     let cdir = current_dir().context(format_err!(
         "couldn't get current dir; doesn't exist or no permissions..."
@@ -15,6 +15,10 @@ use super::{args, get};
 pub fn cli() -> App<'static, 'static> {
     SubCommand::with_name("package")
         .arg(args::no_verify())
+        .arg(
+            args::registry()
+                .help("Check that the package can be published to this index as its version"),
+        )
         .about("Compiles the package and packages it into a nice tarball")
 }
 
@@ -23,23 +27,28 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
         "couldn't get current dir; doesn't exist or no permissions..."
     ))?;
 
-    let ctx = get::build_ctx(c, args);
+    let ctx = get::build_ctx(c, args)?;
     let (project, _) = build::find_manifest(&project, false, None).unwrap();
 
     if !args.is_present("no-verify") {
         build::build(
             &ctx,
             &project,
-            &(true, false, None, None),
+            &(true, false, None, None, None),
             true,
             &Backend::default(),
         )?;
     }
 
-    let (gz_name, _) = index::package(&project)?;
+    let (gz_name, manifest, cksum) = index::package(&project)?;
+
+    if args.is_present("registry") {
+        index::check_registry(&ctx, &manifest, &cksum)?;
+    }
 
     Ok(format!(
-        "created compressed tarball at `{}`",
-        gz_name.display()
+        "created compressed tarball at `{}` ({})",
+        gz_name.display(),
+        cksum
     ))
 }
@@ -11,9 +11,16 @@ pub fn cli() -> App<'static, 'static> {
     SubCommand::with_name("repl")
         .about("Launches a repl, loading the root package")
         .arg(args::build_threads())
+        .arg(args::download_jobs())
+        .args(&args::profiles())
         .arg(args::target_bin())
         .arg(args::target_lib())
         .arg(args::offline())
+        .arg(args::vendored())
+        .arg(args::deadline())
+        .arg(args::frozen())
+        .arg(args::registry())
+        .arg(args::features())
         .arg(args::debug_log())
         .arg(args::idris_opts())
         .arg(
@@ -51,7 +58,7 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
     };
 
     let backend = get::backends(c, args);
-    let ctx = get::build_ctx(c, args);
+    let ctx = get::build_ctx(c, args)?;
 
     build::repl(&ctx, &project, &ts, &backend, interactivity)
 }
@@ -0,0 +1,70 @@
+use super::{args, get};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use elba::{
+    cli::build,
+    util::{config::Config, error::Result},
+};
+use failure::{format_err, ResultExt};
+use std::env::current_dir;
+
+pub fn cli() -> App<'static, 'static> {
+    SubCommand::with_name("run")
+        .about("Builds and runs a binary or an example of the root package")
+        .arg(
+            Arg::with_name("bin")
+                .long("bin")
+                .takes_value(true)
+                .conflicts_with("example")
+                .help("The name of the binary to run (if the package has more than one)"),
+        )
+        .arg(
+            Arg::with_name("example")
+                .long("example")
+                .takes_value(true)
+                .help("The name of the example to run"),
+        )
+        .arg(args::build_threads())
+        .arg(args::download_jobs())
+        .args(&args::profiles())
+        .arg(args::offline())
+        .arg(args::vendored())
+        .arg(args::deadline())
+        .arg(args::frozen())
+        .arg(args::local_builds())
+        .arg(args::registry())
+        .arg(args::features())
+        .arg(args::debug_log())
+        // Everything after `--` goes to the program, so Idris options only come from IDRIS_OPTS
+        .args(&args::backends())
+        .arg(
+            Arg::with_name("args")
+                .multiple(true)
+                .last(true)
+                .help("The arguments to pass to the program"),
+        )
+}
+
+pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
+    let project = current_dir().context(format_err!(
+        "couldn't get current dir; doesn't exist or no permissions..."
+    ))?;
+
+    let ctx = get::build_ctx(c, args)?;
+
+    // This is where our default codegen backend is set
+    let backend = get::backends(c, args);
+
+    let run_args = args
+        .values_of("args")
+        .map(|x| x.collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    build::run(
+        &ctx,
+        &project,
+        args.value_of("bin"),
+        args.value_of("example"),
+        &run_args,
+        &backend,
+    )
+}
@@ -1,4 +1,4 @@
-use std::env::current_dir;
+use std::env::{self, current_dir};
 
 use clap::{App, Arg, ArgMatches, SubCommand};
 use console::style;
@@ -31,8 +31,12 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
         );
         c.shell().println_plain(
             fmt_multiple(
-                &run_script(&cdir, s)
-                    .with_context(|e| format_err!("error running script:\n{}", e))?,
+                &run_script(
+                    &cdir,
+                    s,
+                    &manifest.build.resolve_env(|var| env::var(var).ok())?,
+                )
+                .with_context(|e| format_err!("error running script:\n{}", e))?,
             ),
             Verbosity::Normal,
         );
@@ -1,4 +1,4 @@
-use super::get;
+use super::{args, get};
 use clap::{App, Arg, ArgMatches, SubCommand};
 use elba::{
     cli::index,
@@ -14,13 +14,21 @@ pub fn cli() -> App<'static, 'static> {
                 .required(true)
                 .help("The search query."),
         )
+        .arg(
+            Arg::with_name("channel")
+                .long("channel")
+                .takes_value(true)
+                .help("Only shows versions released on this channel, like stable or nightly."),
+        )
+        .arg(args::registry())
 }
 
 pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
     let query = args.value_of("query").unwrap();
-    let bcx = get::build_ctx(c, args);
+    let channel = args.value_of("channel");
+    let bcx = get::build_ctx(c, args)?;
 
-    println!("{}", index::search(&bcx, &query)?);
+    println!("{}", index::search(&bcx, query, channel)?);
 
     Ok("search complete".to_string())
 }
@@ -12,8 +12,21 @@ pub fn cli() -> App<'static, 'static> {
         .about("Runs the tests of the root package")
         .args(&args::backends())
         .arg(args::build_threads())
+        .arg(args::download_jobs())
+        .args(&args::profiles())
         .arg(args::offline())
+        .arg(args::vendored())
+        .arg(args::deadline())
+        .arg(args::frozen())
+        .arg(args::local_builds())
+        .arg(args::registry())
+        .arg(args::features())
         .arg(args::debug_log())
+        .arg(
+            Arg::with_name("all")
+                .long("all")
+                .help("Test every member of the workspace, instead of just its default members"),
+        )
         .arg(
             Arg::with_name("test-threads")
                 .long("test-threads")
@@ -34,7 +47,7 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
         "couldn't get current dir; doesn't exist or no permissions..."
     ))?;
 
-    let ctx = get::build_ctx(c, args);
+    let ctx = get::build_ctx(c, args)?;
 
     // This is where our default codegen backend is set
     let backend = get::backends(c, args);
@@ -49,5 +62,7 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
         .and_then(|x| x.parse::<u32>().ok())
         .unwrap_or(1);
 
-    build::test(&ctx, &project, &targets, &backend, test_threads)
+    build::for_members(&project, args.is_present("all"), |project| {
+        build::test(&ctx, project, &targets, &backend, test_threads)
+    })
 }
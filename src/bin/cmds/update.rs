@@ -18,6 +18,7 @@ pub fn cli() -> App<'static, 'static> {
         )
         .about("Generates or updates elba.lock according to the manifest")
         .arg(args::idris_opts())
+        .arg(args::registry())
 }
 
 pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
@@ -25,7 +26,7 @@ pub fn exec(c: &mut Config, args: &ArgMatches) -> Result<String> {
         "couldn't get current dir; doesn't exist or no permissions..."
     ))?;
 
-    let ctx = get::build_ctx(c, args);
+    let ctx = get::build_ctx(c, args)?;
 
     let packages = args
         .values_of("update")
@@ -5,9 +5,12 @@ mod cmds;
 
 use clap::{App, AppSettings, Arg, ArgMatches};
 use console::style;
-use elba::util::{config::Config, shell::Verbosity};
+use elba::{
+    retrieve::cache::{FROZEN_ENV, OFFLINE_ENV},
+    util::{config::Config, shell::Verbosity},
+};
 use failure::{Error, ResultExt};
-use std::{process::exit, time::Instant};
+use std::{env, process::exit, time::Instant};
 
 // TODO: Tasks and scripts (i.e. hooks)
 // Tasks are binary dependencies which can be executed from within the project with `elba task`.
@@ -132,6 +135,15 @@ fn go() -> Result<String, Error> {
         }
     };
 
+    // The flags are passed on through the environment, so that every Cache (and any external
+    // subcommand) stays offline or frozen too.
+    if subcommand_args.is_present("offline") {
+        env::set_var(OFFLINE_ENV, "1");
+    }
+    if subcommand_args.is_present("frozen") {
+        env::set_var(FROZEN_ENV, "1");
+    }
+
     if let Some(exec) = cmds::execute_internal(cmd) {
         return exec(&mut config, subcommand_args);
     }
@@ -1,8 +1,14 @@
 use crate::{
+    package::Name,
     retrieve::cache::Cache,
-    util::{config::Backend, error::Result, fmt_output},
+    util::{
+        config::{Backend, BuildProfile},
+        error::Result,
+        fmt_output,
+    },
 };
 use failure::{format_err, ResultExt};
+use indexmap::IndexMap;
 use std::{
     path::{Path, PathBuf},
     process::Command,
@@ -16,8 +22,27 @@ pub struct BuildContext {
     pub compiler: Compiler,
     /// The global cache to use.
     pub cache: Cache,
+    /// How many jobs to build at once; 0 means one for every core.
     pub threads: u32,
     pub opts: Vec<String>,
+    /// The profile to build with, which adds its own flags to `opts`
+    pub profile: BuildProfile,
+    /// Extra flags to build particular packages with, as normalized by `BuildConfig::flags`
+    pub flags: IndexMap<Name, Vec<String>>,
+}
+
+impl BuildContext {
+    /// The extra flags to build the package `name` with.
+    pub fn flags_for(&self, name: &Name) -> &[String] {
+        self.flags.get(name).map(|x| x.as_slice()).unwrap_or(&[])
+    }
+
+    /// The extra flags to build the package `name` with, split up into compiler arguments.
+    pub fn flag_args(&self, name: &Name) -> impl Iterator<Item = String> + '_ {
+        self.flags_for(name)
+            .iter()
+            .flat_map(|x| x.split_whitespace().map(|x| x.to_string()))
+    }
 }
 
 /// Information on the compiler executable
@@ -10,6 +10,7 @@ use crate::{
     },
 };
 use failure::bail;
+use indexmap::IndexMap;
 use itertools::Itertools;
 use std::{
     path::{Path, PathBuf},
@@ -24,6 +25,7 @@ pub async fn invoke_compile<'a>(
     target: &'a Path,
     build: PathBuf,
     args: &'a [String],
+    env: &'a IndexMap<String, String>,
     bcx: &'a BuildContext,
     shell: Shell,
 ) -> Result<Output> {
@@ -31,6 +33,7 @@ pub async fn invoke_compile<'a>(
     process
         .kill_on_drop(true)
         .current_dir(&build)
+        .envs(env)
         .arg("--check");
 
     let flavor = bcx.compiler.flavor();
@@ -38,13 +41,16 @@ pub async fn invoke_compile<'a>(
     if flavor.is_idris1() {
         for binary in deps {
             // We assume that the binary has already been compiled
-            process.arg("-i").arg(binary.target.path());
+            for dir in binary.include_dirs() {
+                process.arg("-i").arg(dir);
+            }
         }
     } else {
         process.env(
             "BLODWEN_PATH",
             deps.iter()
-                .map(|x| x.target.path().to_string_lossy())
+                .flat_map(|x| x.include_dirs())
+                .map(|x| x.to_string_lossy().into_owned())
                 .join(":"),
         );
     }
@@ -84,10 +90,12 @@ pub async fn invoke_codegen<'a>(
     // Whether the output should be treated as a binary (false) or artifact files (true)
     is_artifact: bool,
     args: &'a [String],
+    env: &'a IndexMap<String, String>,
     bcx: &'a BuildContext,
     shell: Shell,
 ) -> Result<Output> {
     let mut process: Command = bcx.compiler.process().into();
+    process.envs(env);
 
     let flavor = bcx.compiler.flavor();
     if is_artifact {
@@ -1,9 +1,18 @@
-use super::{compile_bin, compile_doc, compile_lib, context::BuildContext, Target, Targets};
+use super::{
+    compile_bin, compile_doc, compile_lib,
+    context::BuildContext,
+    report::{BuildReport, PackageReport},
+    run_build_script, Target, Targets, POSTBUILD_SCRIPT,
+};
 use crate::{
-    retrieve::cache::{Binary, BuildHash, OutputLayout, Source},
+    retrieve::{
+        cache::{Binary, BuildHash, OutputLayout, Source},
+        Timings,
+    },
     util::{
         clear_dir,
-        error::Result,
+        config::jobs,
+        error::{Error as ErrorKind, Result},
         fmt_multiple,
         graph::Graph,
         lock::DirLock,
@@ -11,12 +20,17 @@ use crate::{
     },
 };
 use console::style;
-use failure::{bail, format_err, ResultExt};
-use futures::future;
+use failure::{bail, format_err, Error, Fail, ResultExt};
+use futures::future::{self, Either};
 use petgraph::graph::NodeIndex;
 use slog::{debug, o, Logger};
-use std::{collections::HashSet, future::Future, path::PathBuf};
-use tokio::runtime::Runtime;
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use tokio::{runtime::Runtime, time::delay_for};
 
 /// Work refers to either a Source and its BuildHash which needs to be built,
 /// a built library which is still being used by other code, or a built target
@@ -59,6 +73,9 @@ impl Work {
 pub struct Job {
     pub work: Work,
     pub targets: Targets,
+    /// Whether the build is kept out of the build cache, because the package or one of its
+    /// dependencies is excluded from it (see `Source::is_excluded_from_cache`).
+    pub uncached: bool,
 }
 
 impl Default for Job {
@@ -66,10 +83,39 @@ impl Default for Job {
         Job {
             work: Work::None,
             targets: Targets::new(vec![Target::Lib(false)]),
+            uncached: false,
         }
     }
 }
 
+/// How long to wait before checking again whether another process is done with the build lock
+/// of a job, when there's nothing else to do in the meantime.
+const BUILD_LOCK_RETRY: Duration = Duration::from_millis(200);
+
+/// The outcome of building a single job: the library it produced (if any) and the binaries it
+/// built, along with the names of the packages they belong to.
+type JobResult = Result<(Option<Binary>, Vec<(PathBuf, String)>)>;
+
+/// What a JobQueue produces: the libraries of the root's direct dependencies, all the binaries
+/// which were built, and a report of how long everything took.
+pub type QueueResult = Result<(Vec<PathBuf>, Vec<(PathBuf, String)>, BuildReport)>;
+
+/// A JobQueue schedules the builds of every package in a dependency graph.
+///
+/// A package is only built once all of its dependencies have been built, but packages which don't
+/// depend on each other are built in parallel, with at most `bcx.threads` builds running at once.
+/// Each build gets a temporary output directory from the cache (`Cache::checkout_tmp`), and its
+/// library is stored back into the cache (`Cache::store_build`) once it's done. Packages which are
+/// excluded from the cache, and the packages which depend on them, are built every time instead,
+/// and their libraries are used straight from where they were built.
+///
+/// If a package fails to build, every package which depends on it is cancelled, but the rest of
+/// the graph keeps building; the whole build still fails at the end. A package which another
+/// process is building at the same time is put off until that process is done with it, while the
+/// rest of the graph keeps building.
+///
+/// How long each package took to compile, and whether it was cached, is kept in a `BuildReport`,
+/// which is written to the root output layout (if there is one) once the build succeeds.
 pub struct JobQueue {
     /// The graph of jobs which need to be done.
     pub graph: Graph<Job>,
@@ -77,6 +123,7 @@ pub struct JobQueue {
     pub logger: Logger,
     pub shell: Shell,
     pub bcx: BuildContext,
+    pub report: BuildReport,
 }
 
 // The current implementation of the JobQueue combines target generation and dependency preparation
@@ -90,8 +137,20 @@ impl JobQueue {
         plog: &Logger,
         shell: Shell,
     ) -> Result<Self> {
+        // A cycle would leave every job in it waiting on the others forever
+        solve.check_acyclic(|source| source.meta().name().to_string())?;
+
         let mut graph = Graph::new(solve.inner.map(|_, _| Job::default(), |_, _| ()));
 
+        // Virtual packages are never built, so whatever depends on one is built against its
+        // dependencies instead. Build hashes still come from `solve`, which is left as it is.
+        for node in solve.inner.node_indices().skip(1) {
+            if solve[node].meta().is_virtual() {
+                graph.bypass(node);
+            }
+        }
+
+        let mut report = BuildReport::default();
         let mut curr_layer = HashSet::new();
         let mut next_layer = HashSet::new();
 
@@ -120,33 +179,45 @@ impl JobQueue {
                 let source = &solve[node];
 
                 let targets = if node == NodeIndex::new(0) {
-                    root.clone()
+                    if source.meta().is_virtual() {
+                        Targets::new(vec![])
+                    } else {
+                        root.clone()
+                    }
                 } else {
                     Targets::new(vec![Target::Lib(false)])
                 };
 
-                let build_hash = BuildHash::new(
-                    source,
-                    &solve,
-                    &targets,
-                    &bcx,
-                    (node != NodeIndex::new(0) || bcx.codegen) && targets.is_codegen(),
-                );
+                let build_hash = BuildHash::for_job(source, &solve, &targets, &bcx)?;
+                // The hash of a package which is excluded from the cache doesn't change with its
+                // contents, so neither it nor anything built on top of it is ever up to date
+                let uncached = solve
+                    .sub_tree(node)
+                    .any(|(_, src)| src.is_excluded_from_cache());
 
+                let hash = build_hash.0.clone();
                 let root_ol = root_ol.as_ref();
                 let job = if node == NodeIndex::new(0)
+                    && !uncached
                     && root_ol.is_some()
                     && root_ol.unwrap().is_built(&build_hash)
                 {
                     Job {
                         work: Work::None,
                         targets,
+                        uncached,
                     }
                 } else {
-                    match bcx.cache.checkout_build(&build_hash)? {
+                    let cached = if uncached {
+                        None
+                    } else {
+                        bcx.cache.checkout_build(&build_hash, &bcx.profile)?
+                    };
+                    match cached {
                         Some(binary) => Job {
                             work: Work::Fresh(binary),
                             targets,
+                            uncached,
                         },
                         None => {
                             next_layer.extend(
@@ -160,10 +231,19 @@ impl JobQueue {
                             Job {
                                 work: Work::Dirty(source.clone(), build_hash),
                                 targets,
+                                uncached,
                             }
                         }
                     }
                 };
+                report.packages.insert(
+                    source.summary(),
+                    PackageReport {
+                        hash,
+                        cached: !job.work.is_dirty(),
+                        ..PackageReport::default()
+                    },
+                );
                 graph[node] = job;
             }
         }
@@ -177,16 +257,25 @@ impl JobQueue {
             bcx,
             logger,
             shell,
+            report,
         })
     }
 
-    pub fn exec<'a>(self) -> Result<(Vec<PathBuf>, Vec<(PathBuf, String)>)> {
+    /// Adds how long resolving and retrieving the packages in the queue took to its report.
+    pub fn with_timings(mut self, timings: &Timings) -> Self {
+        self.report.add_timings(timings);
+        self
+    }
+
+    /// Builds every dirty job in the queue, returning the libraries of the root's direct
+    /// dependencies, all the binaries which were built, and the report of the build.
+    pub fn exec(self) -> QueueResult {
         let mut rt =
             Runtime::new().with_context(|_| format_err!("Couldn't start parallel runtime"))?;
         rt.block_on(self.exec_async())
     }
 
-    async fn exec_async<'a>(mut self) -> Result<(Vec<PathBuf>, Vec<(PathBuf, String)>)> {
+    async fn exec_async(mut self) -> QueueResult {
         let root_ol = &self.root_ol;
         let root_hash = self.graph.root().and_then(|x| {
             if let Work::Dirty(_, h) = &x.work {
@@ -196,44 +285,103 @@ impl JobQueue {
             }
         });
 
+        let limit = jobs(self.bcx.threads) as usize;
+        let total = self
+            .graph
+            .inner
+            .node_indices()
+            .filter(|&index| self.graph[index].work.is_dirty())
+            .count();
+        let mut started = 0;
+
         let mut ongoing_jobs: HashSet<NodeIndex> = HashSet::new();
+        // Jobs whose build lock another process is holding, and when we first found it held.
+        let mut waiting_jobs: HashMap<NodeIndex, Instant> = HashMap::new();
+        // Jobs which failed, along with every job which depends on one that failed.
+        let mut failed_jobs: HashSet<NodeIndex> = HashSet::new();
         let mut parallal_jobs_future = Vec::new();
         let mut bins_vec = Vec::new();
 
         loop {
             // Bottom jobs are Dirty jobs whose dependencies are all satisfied.
-            let bottom_jobs = self.graph.inner.node_indices().filter(|&index| {
-                self.graph[index].work.is_dirty()
-                    && self
-                        .graph
-                        .children(index)
-                        .all(|(child, _)| self.graph[child].work.is_fresh())
-            });
-
-            // Spwan new jobs
+            let bottom_jobs = self
+                .graph
+                .inner
+                .node_indices()
+                .filter(|&index| {
+                    self.graph[index].work.is_dirty()
+                        && !ongoing_jobs.contains(&index)
+                        && !failed_jobs.contains(&index)
+                        && self
+                            .graph
+                            .children(index)
+                            .all(|(child, _)| self.graph[child].work.is_fresh())
+                })
+                .collect::<Vec<_>>();
+
+            // Spawn new jobs, up to the worker limit
             for job in bottom_jobs {
-                if !ongoing_jobs.contains(&job) {
-                    parallal_jobs_future.push(Box::pin(self.complete_job(job)?));
-                    ongoing_jobs.insert(job);
+                if ongoing_jobs.len() >= limit {
+                    break;
+                }
+                match self.complete_job(job, (started + 1, total)) {
+                    Ok(Some(fut)) => {
+                        started += 1;
+                        waiting_jobs.remove(&job);
+                        parallal_jobs_future.push(Box::pin(fut));
+                        ongoing_jobs.insert(job);
+                    }
+                    // Another process is building the same thing, so the job is tried again
+                    // later instead of blocking the jobs we're already running
+                    Ok(None) => {
+                        let since = *waiting_jobs.entry(job).or_insert_with(Instant::now);
+                        if since.elapsed() >= self.bcx.cache.lock_timeout {
+                            waiting_jobs.remove(&job);
+                            let source = match &self.graph[job].work {
+                                Work::Dirty(source, _) => source.pretty_summary(),
+                                _ => unreachable!(),
+                            };
+                            let err = ErrorKind::LockTimeout
+                                .context(format!(
+                                    "timed out after {}s waiting for another process to build {}",
+                                    self.bcx.cache.lock_timeout.as_secs(),
+                                    source
+                                ))
+                                .into();
+                            self.fail_job(job, err, &mut failed_jobs);
+                        }
+                    }
+                    Err(err) => {
+                        started += 1;
+                        self.fail_job(job, err, &mut failed_jobs)
+                    }
                 }
             }
 
             // Check if build is complete
             if ongoing_jobs.is_empty() {
-                break;
+                if waiting_jobs.is_empty() {
+                    break;
+                }
+                delay_for(BUILD_LOCK_RETRY).await;
+                continue;
             }
 
             // Await one of the jobs to complete
-            let (job_res, _, remaining) = future::select_all(parallal_jobs_future).await;
+            let ((job_index, job_res, time), _, remaining) =
+                future::select_all(parallal_jobs_future).await;
             parallal_jobs_future = remaining;
+            ongoing_jobs.remove(&job_index);
+
+            if let Work::Dirty(source, _) = &self.graph[job_index].work {
+                if let Some(pkg) = self.report.packages.get_mut(&source.summary()) {
+                    pkg.compile = time;
+                }
+            }
 
             // Handle the job result
             match job_res {
-                Ok((job_index, binary, mut bins)) => {
-                    ongoing_jobs.remove(&job_index);
-
-                    // prg += 1;
-                    // pb.set_position(prg);
+                Ok((binary, mut bins)) => {
                     if let Some(b) = binary {
                         // If we got a compiled library out of it, set the binary
                         self.graph[job_index].work = Work::Fresh(b)
@@ -264,15 +412,14 @@ impl JobQueue {
 
                     bins_vec.append(&mut bins);
                 }
-                Err(err) => {
-                    // pb.finish_and_clear();
-                    self.shell
-                        .println(style("[error]").red().bold(), err, Verbosity::Quiet);
-                    bail!("one or more packages couldn't be built");
-                }
+                Err(err) => self.fail_job(job_index, err, &mut failed_jobs),
             }
         }
 
+        if !failed_jobs.is_empty() {
+            bail!("one or more packages couldn't be built");
+        }
+
         // Clean up the build environment
         if let Some(ol) = root_ol.as_ref() {
             let res = clear_dir(&ol.build);
@@ -301,43 +448,124 @@ impl JobQueue {
                     );
                 }
             }
+
+            if let Err(e) = self.report.write(ol) {
+                self.shell
+                    .println(style("[warn]").yellow().bold(), e, Verbosity::Normal);
+            }
         }
 
         let root_children = self
             .graph
             .children(NodeIndex::new(0))
-            .filter_map(|(_, j)| {
+            .flat_map(|(_, j)| {
                 if let Work::Fresh(b) = &j.work {
-                    Some(b.target.path().to_owned())
+                    b.include_dirs()
                 } else {
-                    None
+                    vec![]
                 }
             })
             .collect::<Vec<_>>();
 
-        Ok((root_children, bins_vec))
+        Ok((root_children, bins_vec, self.report))
+    }
+
+    /// Reports that a job failed, and cancels every job which (transitively) depends on it.
+    /// Jobs which don't depend on the failed one are left alone so they can still finish.
+    fn fail_job(&self, job_index: NodeIndex, err: Error, failed: &mut HashSet<NodeIndex>) {
+        self.shell
+            .println(style("[error]").red().bold(), err, Verbosity::Quiet);
+        failed.insert(job_index);
+
+        let mut stack = vec![job_index];
+        while let Some(node) = stack.pop() {
+            for (parent, job) in self.graph.parents(node) {
+                if !failed.insert(parent) {
+                    continue;
+                }
+                if let Work::Dirty(source, _) = &job.work {
+                    self.shell.println(
+                        style("Cancelled").yellow(),
+                        format!("{} (a dependency failed to build)", source.pretty_summary()),
+                        Verbosity::Normal,
+                    );
+                }
+                stack.push(parent);
+            }
+        }
     }
 
-    // Drive a job from dirty to done
+    // Drive a job from dirty to done, timing how long that takes. `progress` is the position of
+    // this job among all the jobs which need to be built. Returns None without starting the job
+    // if another process is holding its build lock.
     fn complete_job(
         &self,
         job_index: NodeIndex,
-    ) -> Result<impl Future<Output = Result<(NodeIndex, Option<Binary>, Vec<(PathBuf, String)>)>>>
-    {
+        progress: (usize, usize),
+    ) -> Result<Option<impl Future<Output = (NodeIndex, JobResult, Duration)>>> {
         if let Work::Dirty(source, build_hash) = &self.graph[job_index].work {
+            let start = Instant::now();
+
+            // Anything which ends up in the cache is built while holding its build lock, so that
+            // another elba building the same thing waits for us (or we for it). If the build was
+            // stored while we were waiting, there's nothing left for us to do.
+            let uncached = self.graph[job_index].uncached;
+            let stored = (job_index != NodeIndex::new(0) || self.root_ol.is_none()) && !uncached;
+            let build_lock = if stored {
+                let lock = match self
+                    .bcx
+                    .cache
+                    .try_acquire_build_lock(build_hash, &self.bcx.profile)?
+                {
+                    Some(lock) => lock,
+                    None => return Ok(None),
+                };
+                if let Some(binary) = self
+                    .bcx
+                    .cache
+                    .checkout_build(build_hash, &self.bcx.profile)?
+                {
+                    self.shell.println(
+                        style("Fresh").green(),
+                        format!(
+                            "{} [{}..] (built by another process)",
+                            source.pretty_summary(),
+                            &build_hash.0[0..8]
+                        ),
+                        Verbosity::Normal,
+                    );
+                    let res: JobResult = Ok((Some(binary), vec![]));
+                    return Ok(Some(Either::Left(async move {
+                        (job_index, res, start.elapsed())
+                    })));
+                }
+                Some(lock)
+            } else {
+                None
+            };
+
+            // A source which was slimmed after it was last built has to be whole to be built again
+            self.bcx.cache.restore_source(source)?;
+
             self.shell.println(
                 style("Building").cyan(),
-                format!("{} [{}..]", source.pretty_summary(), &build_hash.0[0..8]),
+                format!(
+                    "{} [{}..] ({}/{})",
+                    source.pretty_summary(),
+                    &build_hash.0[0..8],
+                    progress.0,
+                    progress.1
+                ),
                 Verbosity::Normal,
             );
             let layout: OutputLayout = if job_index == NodeIndex::new(0) {
                 if let Some(x) = &self.root_ol {
                     x.clone()
                 } else {
-                    self.bcx.cache.checkout_tmp(&build_hash)?
+                    self.bcx.cache.checkout_tmp(build_hash, &self.bcx.profile)?
                 }
             } else {
-                self.bcx.cache.checkout_tmp(&build_hash)?
+                self.bcx.cache.checkout_tmp(build_hash, &self.bcx.profile)?
             };
 
             let deps = self
@@ -359,13 +587,18 @@ impl JobQueue {
                 targets,
                 deps,
                 layout,
+                build_lock,
                 self.root_ol.is_some(),
+                uncached,
                 self.logger.clone(),
                 self.bcx.clone(),
                 self.shell,
             );
 
-            Ok(res)
+            Ok(Some(Either::Right(async move {
+                let res = res.await;
+                (job_index, res, start.elapsed())
+            })))
         } else {
             unreachable!()
         }
@@ -378,14 +611,18 @@ impl JobQueue {
         targets: Targets,
         deps: Vec<Binary>,
         layout: OutputLayout,
+        build_lock: Option<DirLock>,
         is_root: bool,
+        uncached: bool,
         logger: Logger,
         bcx: BuildContext,
         shell: Shell,
-    ) -> Result<(NodeIndex, Option<Binary>, Vec<(PathBuf, String)>)> {
+    ) -> JobResult {
         let mut res: Option<Binary> = None;
         let mut bins: Vec<(PathBuf, String)> = Vec::new();
         let has_lib = targets.has_lib();
+        let builds_anything = !targets.0.is_empty();
+        let mut built_lib = false;
 
         for target in targets.0 {
             match target {
@@ -406,15 +643,11 @@ impl JobQueue {
                             )
                         })?;
 
-                    res = if job_index == NodeIndex::new(0) && is_root {
+                    if job_index == NodeIndex::new(0) && is_root {
                         let out = fmt_multiple(&out);
                         shell.println_plain(out, Verbosity::Normal);
-
-                        let target = DirLock::acquire(&layout.lib)?;
-                        Some(Binary::new(target))
-                    } else {
-                        Some(bcx.cache.store_build(&layout.lib, &build_hash)?)
                     }
+                    built_lib = true;
                 }
                 Target::Bin(ix) => {
                     debug!(
@@ -438,7 +671,7 @@ impl JobQueue {
                             .with_context(|e| {
                                 format!(
                                     "Couldn't build binary {} for {}\n{}",
-                                    ix,
+                                    source.meta().targets.bin[ix].name,
                                     source.pretty_summary(),
                                     e
                                 )
@@ -489,6 +722,42 @@ impl JobQueue {
                     // For now, only the root package can do tests, so we
                     // don't worry about storing the binary anywhere.
                 }
+                Target::Example(ix) => {
+                    debug!(
+                        logger, "building target";
+                        "target_type" => "example",
+                        "target" => ix,
+                        "summary" => source.summary()
+                    );
+                    let mut deps = deps.clone();
+                    let root_lib;
+                    if has_lib {
+                        root_lib = {
+                            let target = DirLock::acquire(&layout.lib)?;
+                            Binary::new(target)
+                        };
+                        deps.push(root_lib);
+                    }
+                    let (out, _) =
+                        compile_bin(&source, Target::Example(ix), &deps, &layout, &bcx, shell)
+                            .await
+                            .with_context(|e| {
+                                format!(
+                                    "Couldn't build example {} for {}\n{}",
+                                    source.meta().targets.example[ix].name,
+                                    source.pretty_summary(),
+                                    e
+                                )
+                            })?;
+
+                    if job_index == NodeIndex::new(0) && is_root {
+                        let out = fmt_multiple(&out);
+                        shell.println_plain(out, Verbosity::Normal);
+                    }
+
+                    // Like tests, examples are only ever built for the root package, and aren't
+                    // installed along with its binaries.
+                }
                 Target::Doc => {
                     debug!(
                         logger, "building target";
@@ -509,6 +778,41 @@ impl JobQueue {
             }
         }
 
-        Ok((job_index, res, bins))
+        if builds_anything {
+            run_build_script(&source, POSTBUILD_SCRIPT, &layout.root, &layout, shell)?;
+        }
+
+        // The library is only stored once the postbuild script is done with it. Libraries which
+        // are kept out of the cache are used right where they were built.
+        if built_lib {
+            res = if (job_index == NodeIndex::new(0) && is_root) || uncached {
+                let target = DirLock::acquire(&layout.lib)?;
+                Some(Binary::new(target))
+            } else {
+                Some(
+                    bcx.cache
+                        .store_build(&layout.lib, &build_hash, &bcx.profile)?,
+                )
+            }
+        }
+        // Only now that the library is stored can anyone waiting to build it use it instead
+        drop(build_lock);
+
+        // Once the build is stored, most of the source isn't needed anymore
+        if res.is_some() && bcx.cache.slim_sources && !(job_index == NodeIndex::new(0) && is_root) {
+            if let Err(e) = bcx.cache.slim_source(&source) {
+                shell.println(
+                    style("[warn]").yellow().bold(),
+                    format!(
+                        "Couldn't slim the source of {}: {}",
+                        source.pretty_summary(),
+                        e
+                    ),
+                    Verbosity::Normal,
+                );
+            }
+        }
+
+        Ok((res, bins))
     }
 }
@@ -3,6 +3,7 @@
 pub mod context;
 pub mod invoke;
 pub mod job;
+pub mod report;
 
 use std::{
     env,
@@ -15,6 +16,7 @@ use std::{
 use console::style;
 use failure::{bail, format_err, ResultExt};
 use futures::future;
+use indexmap::IndexMap;
 use itertools::Itertools;
 use rand::{distributions::Alphanumeric, seq::SliceRandom, thread_rng, Rng};
 use walkdir::WalkDir;
@@ -24,9 +26,12 @@ use self::{
     invoke::{invoke_codegen, invoke_compile},
 };
 use crate::{
-    retrieve::cache::{Binary, OutputLayout, Source},
+    package::manifest::LibTarget,
+    retrieve::cache::{Binary, OutputLayout, Source, LIBS_DIR},
     util::{
-        clear_dir, copy_dir, copy_dir_iter,
+        clear_dir,
+        config::jobs,
+        copy_dir, copy_dir_iter,
         error::Result,
         fmt_multiple, fmt_output, generate_ipkg,
         shell::{OutputGroup, Shell, Verbosity},
@@ -47,6 +52,12 @@ pub enum Target {
     Bin(usize),
     // Test is like Bin, except that it requires the lib to be built already.
     Test(usize),
+    /// Compile an example, which is like a test in that it needs the lib to be built already,
+    /// but is only ever built when it's asked for
+    ///
+    /// The usize field is the index of the ExampleTarget in the manifest's list of
+    /// ExampleTargets which should be built
+    Example(usize),
     // I would assume creating documentation requires the lib to be built too
     /// Create documentation
     Doc,
@@ -74,12 +85,20 @@ impl Target {
                 let b4: u8 = (x & 0xff) as u8;
                 [3, b1, b2, b3, b4]
             }
+            Target::Example(x) => {
+                let x = *x as u32;
+                let b1: u8 = ((x >> 24) & 0xff) as u8;
+                let b2: u8 = ((x >> 16) & 0xff) as u8;
+                let b3: u8 = ((x >> 8) & 0xff) as u8;
+                let b4: u8 = (x & 0xff) as u8;
+                [4, b1, b2, b3, b4]
+            }
         }
     }
 
     pub fn is_codegen(&self) -> bool {
         match self {
-            Target::Bin(_) | Target::Test(_) => true,
+            Target::Bin(_) | Target::Test(_) | Target::Example(_) => true,
             _ => false,
         }
     }
@@ -110,6 +129,9 @@ impl Targets {
                 Target::Test(_) => {
                     res.push(i);
                 }
+                Target::Example(_) => {
+                    res.push(i);
+                }
                 Target::Doc => {
                     if !seen_lib {
                         seen_lib = true;
@@ -144,13 +166,67 @@ pub async fn compile_lib<'a>(
     bcx: &'a BuildContext,
     shell: Shell,
 ) -> Result<OutputGroup> {
-    let lib_target = source.meta().targets.lib.clone().ok_or_else(|| {
-        format_err!(
+    if !source.meta().targets.has_lib() {
+        bail!(
             "package {} doesn't contain a lib target",
             source.meta().package.name
         )
-    })?;
+    }
+
+    let mut res = OutputGroup::new();
+
+    clear_dir(&layout.lib)?;
+    if codegen {
+        clear_dir(&layout.artifacts.join(&bcx.backend.name))?;
+    }
 
+    // Each lib target is built in a directory of its own, so that the files of one can't get in
+    // the way of another's
+    for (name, lib_target) in source.meta().targets.lib_targets() {
+        let build_dir = match name {
+            Some(name) => layout.build.join("libs").join(name),
+            None => layout.build.join("lib"),
+        };
+        let output_name = match name {
+            Some(name) => format!("{}-{}", source.meta().name().name(), name),
+            None => source.meta().name().name().to_string(),
+        };
+        let outputs = compile_lib_target(
+            source,
+            lib_target,
+            &build_dir,
+            &layout.lib_dir(name),
+            if codegen {
+                Some(output_name.as_str())
+            } else {
+                None
+            },
+            deps,
+            layout,
+            bcx,
+            shell,
+        )
+        .await?;
+        res.0.extend(outputs.0);
+    }
+
+    Ok(res)
+}
+
+/// Builds one lib target of `source` in `build_dir`, and puts its outputs in `lib_dir`. If
+/// `codegen` is given, the target is codegenned into an artifact by that name too.
+#[allow(clippy::too_many_arguments)]
+async fn compile_lib_target<'a>(
+    source: &'a Source,
+    lib_target: &'a LibTarget,
+    build_dir: &'a Path,
+    lib_dir: &'a Path,
+    codegen: Option<&'a str>,
+    deps: &'a [Binary],
+    layout: &'a OutputLayout,
+    bcx: &'a BuildContext,
+    shell: Shell,
+) -> Result<OutputGroup> {
     // We know that lib_target.path will be relative to the package root
     let src_path = source.path().join(&lib_target.path.0);
     let mut targets = lib_target
@@ -174,16 +250,18 @@ pub async fn compile_lib<'a>(
 
     let mut args = vec![];
     args.extend(lib_target.idris_opts.iter().map(|x| x.to_owned()));
+    args.extend(bcx.flag_args(source.meta().name()));
+    args.extend(bcx.profile.opts.iter().cloned());
     args.extend(bcx.opts.iter().cloned());
 
     let src_walker = source
         .meta()
         .list_files(source.path(), &src_path, |x| x.path() != layout.build)?;
 
-    clear_dir(&layout.build.join("lib"))?;
-    copy_dir_iter(src_walker, &src_path, &layout.build.join("lib"))?;
+    clear_dir(build_dir)?;
+    copy_dir_iter(src_walker, &src_path, build_dir)?;
 
-    run_prebuild_script(source, &layout.build.join("lib"), shell)?;
+    run_build_script(source, PREBUILD_SCRIPT, build_dir, layout, shell)?;
 
     // shuffle the targets to decreases the prosiblity that complier
     // overloads because of paralleling
@@ -205,7 +283,15 @@ pub async fn compile_lib<'a>(
             Verbosity::Normal,
         );
 
-        invoke_compile(deps, &target, layout.build.join("lib"), &args, bcx, shell)
+        invoke_compile(
+            deps,
+            target,
+            build_dir.to_path_buf(),
+            &args,
+            source.env(),
+            bcx,
+            shell,
+        )
     });
 
     let mut outputs = Vec::new();
@@ -215,7 +301,7 @@ pub async fn compile_lib<'a>(
     loop {
         while let Some(compile) = compilations.next() {
             ongoing_compilation.push(Box::pin(compile));
-            if ongoing_compilation.len() >= bcx.threads as usize {
+            if ongoing_compilation.len() >= jobs(bcx.threads) as usize {
                 break;
             }
         }
@@ -236,12 +322,10 @@ pub async fn compile_lib<'a>(
 
     let mut res = OutputGroup(outputs);
 
-    clear_dir(&layout.lib)?;
-
     let from = if bcx.compiler.flavor().is_idris2() {
-        layout.build.join("lib/build")
+        build_dir.join("build")
     } else {
-        layout.build.join("lib")
+        build_dir.to_path_buf()
     };
 
     let build_walker = WalkDir::new(&from).into_iter().filter_map(|x| {
@@ -259,12 +343,10 @@ pub async fn compile_lib<'a>(
 
     let lib_files = build_walker.collect::<Vec<_>>();
 
-    clear_dir(&layout.lib)?;
-    copy_dir_iter(lib_files.clone().into_iter(), &from, &layout.lib)?;
-
-    if codegen {
-        clear_dir(&layout.artifacts.join(&bcx.backend.name))?;
+    clear_dir(lib_dir)?;
+    copy_dir_iter(lib_files.clone().into_iter(), &from, lib_dir)?;
 
+    if let Some(output_name) = codegen {
         // TODO: Idris 2 now doesn't support lib interface,
         // so it's temporarily safe to assume all the codegen
         // targets are ibc files.
@@ -276,11 +358,12 @@ pub async fn compile_lib<'a>(
 
         let output = invoke_codegen(
             &lib_bins,
-            source.meta().name().name(),
-            layout.build.join("lib"),
+            output_name,
+            build_dir.to_path_buf(),
             layout.artifacts.join(&bcx.backend.name),
             true,
             &args,
+            source.env(),
             &bcx,
             shell,
         )
@@ -307,6 +390,7 @@ pub async fn compile_bin<'a>(
     let bin_target = match target {
         Target::Bin(ix) => source.meta().targets.bin[ix].clone(),
         Target::Test(ix) => source.meta().targets.test[ix].clone().into(),
+        Target::Example(ix) => source.meta().targets.example[ix].clone().into(),
         _ => bail!("compile_bin called with non-binary target"),
     };
 
@@ -321,15 +405,26 @@ pub async fn compile_bin<'a>(
     clear_dir(&layout.build.join("bin"))?;
     copy_dir(&src_path, &layout.build.join("bin"), false)?;
 
-    run_prebuild_script(source, &layout.build.join("bin"), shell)?;
+    run_build_script(
+        source,
+        PREBUILD_SCRIPT,
+        &layout.build.join("bin"),
+        layout,
+        shell,
+    )?;
 
     // The Idris compiler ignores and rebuilds the imported
     // ibc modules if there are idrs match the modules name in
     // the source directory. So we copy the ibcs into the build
     // directory in advance to avoid that.
-    if let Some(lib_target) = &source.meta().targets.lib {
+    for (name, lib_target) in source.meta().targets.lib_targets() {
         if lib_target.path == bin_target.path {
-            copy_dir(&layout.lib, &layout.build.join("bin"), false)?;
+            let from = layout.lib_dir(name);
+            let walker = WalkDir::new(&from)
+                .into_iter()
+                .filter_entry(|x| x.file_name() != LIBS_DIR)
+                .filter_map(|x| x.ok());
+            copy_dir_iter(walker, &from, &layout.build.join("bin"))?;
         }
     }
 
@@ -351,6 +446,8 @@ pub async fn compile_bin<'a>(
 
     let mut args = vec![];
     args.extend(bin_target.idris_opts.iter().map(|x| x.to_owned()));
+    args.extend(bcx.flag_args(source.meta().name()));
+    args.extend(bcx.profile.opts.iter().cloned());
     args.extend(bcx.opts.iter().cloned());
 
     let module = if target_path.is_absolute() {
@@ -377,6 +474,7 @@ pub async fn compile_bin<'a>(
         &target_path,
         layout.build.join("bin"),
         &args,
+        source.env(),
         bcx,
         shell,
     )
@@ -413,6 +511,7 @@ pub async fn compile_bin<'a>(
         layout.bin.clone(),
         false,
         &args,
+        source.env(),
         &bcx,
         shell,
     )
@@ -465,7 +564,9 @@ pub async fn compile_doc<'a>(
     // Include dependencies
     for binary in deps {
         // We assume that the binary has already been compiled
-        opts.push_str(format!("-i {}", &*binary.target.path().to_string_lossy()).as_ref());
+        for dir in binary.include_dirs() {
+            opts.push_str(format!("-i {}", &*dir.to_string_lossy()).as_ref());
+        }
     }
 
     opts.push_str(bcx.opts.iter().join(" ").as_str());
@@ -507,7 +608,84 @@ pub async fn compile_doc<'a>(
     Ok(res.into())
 }
 
-pub fn run_script(root: &Path, cmd: &str) -> Result<OutputGroup> {
+/// Runs a script in `root` with the environment variables in `env` set on top of `PATH`.
+pub fn run_script(root: &Path, cmd: &str, env: &IndexMap<String, String>) -> Result<OutputGroup> {
+    run_command(script_command(root, cmd), env)
+}
+
+/// The script of a package's manifest which is run before each of its targets is built, in the
+/// directory the target's sources were copied to.
+pub const PREBUILD_SCRIPT: &str = "prebuild";
+
+/// The script of a package's manifest which is run once all of its targets have been built, in
+/// its output directory. The package's library isn't cached until this succeeds.
+pub const POSTBUILD_SCRIPT: &str = "postbuild";
+
+/// The variables which build scripts get from the environment elba runs in; anything else they
+/// see is set by elba itself or by the package's `[build.env]`.
+const SCRIPT_ENV_PASSTHROUGH: &[&str] = &["PATH", "HOME", "SYSTEMROOT"];
+
+/// Runs the build script `name` (`PREBUILD_SCRIPT` or `POSTBUILD_SCRIPT`) of a package in `root`,
+/// if the package has one.
+///
+/// Build scripts only get a few variables from the environment elba runs in (see
+/// `SCRIPT_ENV_PASSTHROUGH`), so that builds don't depend on whatever happens to be set. On top
+/// of the package's own environment, `ELBA_PKG_NAME` and `ELBA_PKG_VERSION` are set to the name
+/// and version of the package, `ELBA_SRC_DIR` to its source directory and `ELBA_OUT_DIR` to its
+/// output directory.
+pub fn run_build_script(
+    source: &Source,
+    name: &str,
+    root: &Path,
+    layout: &OutputLayout,
+    shell: Shell,
+) -> Result<()> {
+    let cmd = match source.meta().scripts.get(name) {
+        Some(cmd) => cmd,
+        None => return Ok(()),
+    };
+    shell.println(
+        style("Running").dim(),
+        format!("{} script > {}", name, cmd),
+        Verbosity::Verbose,
+    );
+
+    let mut env = source.env().clone();
+    env.insert("ELBA_PKG_NAME".to_owned(), source.meta().name().to_string());
+    env.insert(
+        "ELBA_PKG_VERSION".to_owned(),
+        source.meta().version().to_string(),
+    );
+    env.insert(
+        "ELBA_SRC_DIR".to_owned(),
+        source.path().to_string_lossy().into_owned(),
+    );
+    env.insert(
+        "ELBA_OUT_DIR".to_owned(),
+        layout.root.to_string_lossy().into_owned(),
+    );
+
+    let mut process = script_command(root, cmd);
+    process.env_clear();
+    for var in SCRIPT_ENV_PASSTHROUGH {
+        if let Some(v) = env::var_os(var) {
+            process.env(var, v);
+        }
+    }
+    let out = run_command(process, &env).with_context(|e| {
+        format_err!(
+            "{} script of {} failed:\n{}",
+            name,
+            source.pretty_summary(),
+            e
+        )
+    })?;
+    shell.println_plain(fmt_multiple(&out), Verbosity::Normal);
+
+    Ok(())
+}
+
+fn script_command(root: &Path, cmd: &str) -> Command {
     let mut process = if cfg!(target_os = "windows") {
         let mut p = Command::new("cmd");
         p.args(&["/C", cmd]);
@@ -525,6 +703,12 @@ pub fn run_script(root: &Path, cmd: &str) -> Result<OutputGroup> {
         process.env("PATH", v);
     }
 
+    process
+}
+
+fn run_command(mut process: Command, env: &IndexMap<String, String>) -> Result<OutputGroup> {
+    process.envs(env);
+
     let res = process.output()?;
     if !res.status.success() {
         bail!("> {:#?}\n{}", process, fmt_output(&res))
@@ -533,19 +717,6 @@ pub fn run_script(root: &Path, cmd: &str) -> Result<OutputGroup> {
     Ok(res.into())
 }
 
-pub fn run_prebuild_script(source: &Source, root: &Path, shell: Shell) -> Result<()> {
-    if let Some(s) = source.meta().scripts.get("prebuild") {
-        shell.println(
-            style("Running").dim(),
-            format!("prebuild script > {}", s),
-            Verbosity::Verbose,
-        );
-        shell.println_plain(fmt_multiple(&run_script(root, s)?), Verbosity::Normal);
-    }
-
-    Ok(())
-}
-
 fn make_main_file(module: &str, fun: &str, parent: &Path) -> Result<PathBuf> {
     let rstr: String = thread_rng().sample_iter(&Alphanumeric).take(8).collect();
     let fname = format!("elba-{}.idr", rstr);
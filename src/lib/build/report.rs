@@ -0,0 +1,83 @@
+//! Reports of where the time in a build went.
+
+use std::{fs, time::Duration};
+
+use failure::{format_err, ResultExt};
+use indexmap::IndexMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    retrieve::{cache::OutputLayout, Timings},
+    util::error::Result,
+};
+
+/// The name of the file in a local output directory which the report of its last build goes in.
+pub const BUILD_REPORT_FILE: &str = "build-report.json";
+
+/// How long each step of a build took, and which packages could be reused from the cache instead
+/// of being compiled. All durations are written out in seconds.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BuildReport {
+    /// How long resolving dependencies took; zero if an earlier resolve was reused.
+    #[serde(with = "secs")]
+    pub resolve: Duration,
+    /// Every package which is part of the build, keyed by its summary.
+    pub packages: IndexMap<String, PackageReport>,
+}
+
+/// The part of a build which belongs to a single package.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PackageReport {
+    /// The build hash of the package.
+    pub hash: String,
+    /// Whether an earlier build of the package was reused. Cached packages are never compiled, so
+    /// their compile time is always zero.
+    pub cached: bool,
+    /// How long retrieving the package's source took; zero if it was already loaded.
+    #[serde(with = "secs")]
+    pub download: Duration,
+    /// How long compiling all of the package's targets (and storing its library) took.
+    #[serde(with = "secs")]
+    pub compile: Duration,
+}
+
+impl BuildReport {
+    /// Fills in how long the Retriever took to resolve the build and retrieve each of its packages.
+    pub fn add_timings(&mut self, timings: &Timings) {
+        self.resolve = timings.resolve;
+        for (sum, time) in &timings.downloads {
+            if let Some(pkg) = self.packages.get_mut(sum) {
+                pkg.download = *time;
+            }
+        }
+    }
+
+    /// Writes the report out to the root of an output directory.
+    pub fn write(&self, layout: &OutputLayout) -> Result<()> {
+        let path = layout.root.join(BUILD_REPORT_FILE);
+        fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|_| format_err!("couldn't write build report {}", path.display()))?;
+
+        Ok(())
+    }
+}
+
+mod secs {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(d: &Duration, s: S) -> std::result::Result<S::Ok, S::Error> {
+        d.as_secs_f64().serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Duration, D::Error> {
+        let secs = f64::deserialize(d)?;
+        if secs.is_finite() && secs >= 0.0 {
+            Ok(Duration::from_secs_f64(secs))
+        } else {
+            Err(serde::de::Error::custom(format!(
+                "invalid duration {}",
+                secs
+            )))
+        }
+    }
+}
@@ -1,22 +1,23 @@
 use std::{
     convert::TryInto,
     env, fs,
-    io::{prelude::*, Seek, SeekFrom},
+    io::prelude::*,
     path::{Path, PathBuf},
     process::Command,
     str::FromStr,
+    time::{Duration, Instant},
 };
 
 use console::style;
 use crossbeam::queue::MsQueue;
 use failure::{bail, format_err, ResultExt};
-use indexmap::IndexMap;
+use indexmap::{IndexMap, IndexSet};
 use itertools::Either::{self, Left, Right};
 use petgraph::{graph::NodeIndex, visit::Dfs};
 use scoped_threadpool::Pool;
+use semver_constraints::Constraint;
 use slog::Logger;
 use toml;
-use toml_edit;
 
 use crate::{
     build::{
@@ -25,22 +26,28 @@ use crate::{
         Target, Targets,
     },
     package::{
+        cfg::Platform,
         ipkg::Ipkg,
-        lockfile::LockfileToml,
-        manifest::{BinTarget, Manifest},
-        PackageId, Spec, Summary,
+        lockfile::{DriftReport, LockfileToml, SolveCache, SOLVE_CACHE_FILE},
+        manifest::{member_manifest, BinTarget, DepReq, Manifest, MANIFEST_FILE, VENDOR_DIR},
+        Name, PackageId, Spec, Summary,
+    },
+    remote::{
+        auth::Credential,
+        resolution::{DirectRes, IndexRes, Resolution},
+        signature::PublicKey,
     },
-    remote::resolution::{DirectRes, IndexRes, Resolution},
     resolve::Resolver,
     retrieve::{
-        cache::{Cache, Layout, OutputLayout},
+        cache::{frozen_env, Cache, Layout, OutputLayout, Source, SourceNaming},
         Retriever,
     },
     util::{
-        config::Backend,
+        config::{Backend, BuildProfile},
         error::Result,
         fmt_output,
         graph::Graph,
+        hash::Hasher,
         lock::DirLock,
         shell::{Shell, Verbosity},
     },
@@ -50,11 +57,97 @@ pub struct BuildCtx {
     pub compiler: String,
     pub indices: IndexMap<String, IndexRes>,
     pub global_cache: Layout,
+    /// The read-only cache which is looked in before the global cache, if any
+    pub base_cache: Option<Layout>,
     pub logger: Logger,
+    /// How many packages to build at once; 0 means one for every core
     pub threads: u32,
+    /// How many packages to download at once; 0 means one for every core
+    pub download_jobs: u32,
     pub shell: Shell,
     pub offline: bool,
     pub opts: Vec<String>,
+    /// The features of the root package to turn on
+    pub features: Vec<String>,
+    /// The profile to build with; its outputs go in `target/<profile>`
+    pub profile: BuildProfile,
+    /// The platform to pull in platform-specific dependencies for
+    pub platform: Platform,
+    /// Credentials for private hosts from the configuration
+    pub credentials: IndexMap<String, Credential>,
+    /// Whether the global cache should keep downloaded tarballs compressed
+    pub compress_sources: bool,
+    /// Whether the global cache should slim tarball sources down once they've been built
+    pub slim_sources: bool,
+    /// How the global cache names the directories of sources
+    pub source_naming: SourceNaming,
+    /// Source replacement rules from the configuration
+    pub mirrors: IndexMap<String, String>,
+    /// The keys signed indices have to be signed with, by index
+    pub index_keys: IndexMap<DirectRes, PublicKey>,
+    /// Local directories the global cache looks for tarballs in before downloading them
+    pub tarball_dirs: Vec<PathBuf>,
+    /// Whether nothing may be downloaded and the lockfile may not change (see `Cache::frozen`)
+    pub frozen: bool,
+    /// Whether a package which two indices define differently is an error
+    pub strict_indices: bool,
+    /// Whether builds should be cached in the project's target directory instead of the global
+    /// cache
+    pub local_builds: bool,
+    /// How long to wait for a connection to a server
+    pub connect_timeout: Duration,
+    /// How long a single request to a server may take
+    pub request_timeout: Duration,
+    /// When resolving and retrieving packages has to be done by (see `Cache::deadline`)
+    pub deadline: Option<Instant>,
+}
+
+impl BuildCtx {
+    /// Opens the global cache with the settings of this context.
+    pub fn cache(&self) -> Result<Cache> {
+        Ok(Cache::from_disk(
+            &self.logger,
+            self.global_cache.clone(),
+            self.base_cache.clone(),
+            self.shell,
+        )?
+        .with_timeouts(self.connect_timeout, self.request_timeout)?
+        .with_deadline(self.deadline)
+        .with_credentials(&self.credentials)
+        .with_compressed_sources(self.compress_sources)
+        .with_slim_sources(self.slim_sources)
+        .with_source_naming(self.source_naming)
+        .with_mirrors(&self.mirrors)
+        .with_index_keys(&self.index_keys)
+        .with_tarball_dirs(&self.tarball_dirs)
+        .with_frozen(self.frozen)
+        .with_strict_indices(self.strict_indices))
+    }
+
+    /// Switches this context over to the vendored cache of the project at `project`. The cache in
+    /// the project's `vendor` directory takes the place of the global cache, and neither the
+    /// base cache nor the tarball directories from the configuration are looked in, so a project
+    /// which checks its vendored cache in can be built offline with nothing from outside of it.
+    pub fn vendored(self, project: &Path) -> Result<Self> {
+        let (root, _) = find_manifest(project, true, None)?;
+        let vendor = root.join(VENDOR_DIR);
+
+        Ok(BuildCtx {
+            global_cache: self.global_cache.relocated(&vendor),
+            base_cache: None,
+            tarball_dirs: vec![],
+            ..self
+        })
+    }
+
+    /// The cache to build `layout` with: builds go in the layout itself if they're cached locally.
+    fn build_cache(&self, cache: &Cache, layout: &OutputLayout) -> Cache {
+        if self.local_builds {
+            cache.clone().with_local_builds(&layout.deps)
+        } else {
+            cache.clone()
+        }
+    }
 }
 
 pub fn test(
@@ -78,15 +171,23 @@ pub fn test(
         // We drop the Retriever because we want to release our lock on the Indices as soon as we
         // can to avoid stopping other instances of elba from downloading and resolving (even
         // though we don't even need the Retriever anymore).
+        let timings = retriever.timings.clone();
         drop(retriever);
 
+        // We want to store the outputs of our labor in a local target directory.
+        let lock = DirLock::acquire(&manifest.target_dir(&project).join(&ctx.profile.name))?;
+        lock.warn_reclaimed(ctx.shell);
+        let layout = OutputLayout::new(lock).context("could not create local target directory")?;
+
         let bctx = BuildContext {
             backend: backend.clone(),
             codegen: true,
             compiler: Compiler::new(&ctx.compiler)?,
             opts: ctx.opts.clone(),
-            cache: cache.clone(),
+            cache: ctx.build_cache(cache, &layout),
             threads: ctx.threads,
+            profile: ctx.profile.clone(),
+            flags: root_flags(&sources),
         };
 
         ctx.shell.println(
@@ -95,14 +196,10 @@ pub fn test(
             Verbosity::Quiet,
         );
 
-        // We want to store the outputs of our labor in a local target directory.
-        let lock = DirLock::acquire(&project.join("target"))?;
-        let layout = OutputLayout::new(lock).context("could not create local target directory")?;
-
         let bin_dir = layout.bin.clone();
 
         let mut root = vec![];
-        if manifest.targets.lib.is_some() {
+        if manifest.targets.has_lib() {
             root.push(Target::Lib(false));
         } else {
             ctx.shell.println(
@@ -120,7 +217,8 @@ pub fn test(
         }
 
         let root = Targets::new(root);
-        let q = JobQueue::new(sources, &root, Some(layout), bctx, &ctx.logger, ctx.shell)?;
+        let q = JobQueue::new(sources, &root, Some(layout), bctx, &ctx.logger, ctx.shell)?
+            .with_timings(&timings);
         q.exec()?;
 
         ctx.shell.println(
@@ -241,13 +339,11 @@ pub fn install(
         let manifest = Manifest::from_str(&contents)?;
 
         // By default, we build all bin targets.
-        let mut root = vec![];
-        let emp = targets.is_empty();
-        for (ix, bt) in manifest.targets.bin.iter().enumerate() {
-            if emp || targets.contains(&bt.name.as_str()) {
-                root.push(Target::Bin(ix));
-            }
-        }
+        let root = manifest
+            .bins(targets)?
+            .into_iter()
+            .map(|(ix, _)| Target::Bin(ix))
+            .collect();
         let root = Targets::new(root);
 
         let bctx = BuildContext {
@@ -257,6 +353,8 @@ pub fn install(
             opts: ctx.opts.clone(),
             cache: cache.clone(),
             threads: ctx.threads,
+            profile: ctx.profile.clone(),
+            flags: root_flags(&sources),
         };
 
         ctx.shell.println(
@@ -315,8 +413,8 @@ pub fn repl(
     let mut parents = vec![];
     let mut paths = vec![];
 
-    if let Some(lib) = manifest.targets.lib {
-        if targets.1.is_none() || targets.0 {
+    if targets.1.is_none() || targets.0 {
+        for (_, lib) in manifest.targets.lib_targets() {
             let src_path = lib.path.0.clone();
             let new_paths = lib
                 .mods
@@ -388,6 +486,8 @@ pub fn repl(
             opts: ctx.opts.clone(),
             cache: cache.clone(),
             threads: ctx.threads,
+            profile: ctx.profile.clone(),
+            flags: root_flags(&sources),
         };
 
         ctx.shell.println(
@@ -493,6 +593,7 @@ pub fn doc(ctx: &BuildCtx, project: &Path) -> Result<String> {
         // We drop the Retriever because we want to release our lock on the Indices as soon as we
         // can to avoid stopping other instances of elba from downloading and resolving (even
         // though we don't even need the Retriever anymore).
+        let timings = retriever.timings.clone();
         drop(retriever);
 
         let backend = Backend::default();
@@ -505,6 +606,8 @@ pub fn doc(ctx: &BuildCtx, project: &Path) -> Result<String> {
             opts: ctx.opts.clone(),
             cache: cache.clone(),
             threads: ctx.threads,
+            profile: ctx.profile.clone(),
+            flags: root_flags(&sources),
         };
 
         ctx.shell.println(
@@ -514,30 +617,54 @@ pub fn doc(ctx: &BuildCtx, project: &Path) -> Result<String> {
         );
 
         // We want to store the outputs of our labor in a local target directory.
-        let lock = DirLock::acquire(&project.join("target"))?;
+        let lock = DirLock::acquire(&manifest.target_dir(&project).join(&ctx.profile.name))?;
+        lock.warn_reclaimed(ctx.shell);
         let layout = OutputLayout::new(lock).context("could not create local target directory")?;
+        let docs_dir = layout.docs.clone();
 
-        let q = JobQueue::new(sources, &root, Some(layout), bctx, &ctx.logger, ctx.shell)?;
+        let q = JobQueue::new(sources, &root, Some(layout), bctx, &ctx.logger, ctx.shell)?
+            .with_timings(&timings);
         // Because we're just building, we don't need to do anything after executing the build
         // process. Yay abstraction!
         q.exec()?;
 
-        Ok("docs output available at `./target/docs`".to_string())
+        Ok(format!("docs output available at `{}`", docs_dir.display()))
     })
 }
 
+/// The targets to build, as given on the command line: whether the lib target was asked for,
+/// whether it should be codegenned, and the names of the binaries, tests and examples which were
+/// asked for (None if they weren't at all, or an empty list for all of them).
+pub type BuildTargets<'a> = (
+    bool,
+    bool,
+    Option<Vec<&'a str>>,
+    Option<Vec<&'a str>>,
+    Option<Vec<&'a str>>,
+);
+
 pub fn build(
     ctx: &BuildCtx,
     project: &Path,
-    targets: &(bool, bool, Option<Vec<&str>>, Option<Vec<&str>>),
+    targets: &BuildTargets,
     codegen: bool,
     backend: &Backend,
 ) -> Result<String> {
     let (project, manifest) = find_manifest(project, true, None)?;
+    let root = root_targets(&manifest, targets)?;
+    build_root(ctx, &project, &manifest, root, codegen, backend)?;
 
-    // By default, we build all lib and bin targets.
+    Ok(format!(
+        "build output available at `{}`",
+        manifest.target_dir(&project).display()
+    ))
+}
+
+/// Works out which targets of the root package to build. By default, that's the lib target and
+/// all of the binaries: tests and examples are only built if they're asked for.
+pub fn root_targets(manifest: &Manifest, targets: &BuildTargets) -> Result<Targets> {
     let mut root = vec![];
-    if (targets.2.is_none() || targets.0 || targets.1) && manifest.targets.lib.is_some() {
+    if (targets.2.is_none() || targets.0 || targets.1) && manifest.targets.has_lib() {
         root.push(Target::Lib(targets.1));
     } else if targets.0 || targets.1 {
         // The user specifically asked for a lib target but there wasn't any. Error.
@@ -549,20 +676,15 @@ pub fn build(
         bail!("the package doesn't have any binary targets. add one before proceeding")
     }
 
-    for (ix, bt) in manifest.targets.bin.iter().enumerate() {
+    let bins = match &targets.2 {
         // Case 1: If the --bin flag is passed by itself, we assume the user wants all binaries.
-        //         Or, the --bin flag might come with the name of a binary which we should build.
-        let target_specified = targets
-            .2
-            .as_ref()
-            .map(|v| v.is_empty() || v.contains(&bt.name.as_str()))
-            .unwrap_or(false);
+        //         Or, the --bin flag might come with the names of the binaries to build.
+        Some(names) => manifest.bins(names)?,
         // Case 2: Neither --bin nor --lib are specified. We're fine with --lib-cg.
-        let neither_specified = !targets.0 && targets.2.is_none();
-        if target_specified || neither_specified {
-            root.push(Target::Bin(ix));
-        }
-    }
+        None if !targets.0 => manifest.bins(&[])?,
+        None => vec![],
+    };
+    root.extend(bins.into_iter().map(|(ix, _)| Target::Bin(ix)));
 
     // We only build test targets if the user asks for them.
     if let Some(ts) = &targets.3 {
@@ -575,8 +697,29 @@ pub fn build(
         }
     }
 
-    let root = Targets::new(root);
-    solve_local(ctx, &project, 2, None, |cache, mut retriever, solve| {
+    // Same goes for examples.
+    if let Some(names) = &targets.4 {
+        if manifest.targets.example.is_empty() {
+            bail!("the package doesn't have any examples. add one before proceeding")
+        }
+        let examples = manifest.examples(names)?;
+        root.extend(examples.into_iter().map(|(ix, _)| Target::Example(ix)));
+    }
+
+    Ok(Targets::new(root))
+}
+
+/// Builds the targets `root` of the package at `project` into its target directory, returning
+/// the directory that its binaries end up in.
+fn build_root(
+    ctx: &BuildCtx,
+    project: &Path,
+    manifest: &Manifest,
+    root: Targets,
+    codegen: bool,
+    backend: &Backend,
+) -> Result<PathBuf> {
+    solve_local(ctx, project, 2, None, |cache, mut retriever, solve| {
         let sources = retriever
             .retrieve_packages(&solve)
             .context(format_err!("package retrieval failed"))?;
@@ -584,15 +727,25 @@ pub fn build(
         // We drop the Retriever because we want to release our lock on the Indices as soon as we
         // can to avoid stopping other instances of elba from downloading and resolving (even
         // though we don't even need the Retriever anymore).
+        let timings = retriever.timings.clone();
         drop(retriever);
 
+        // We want to store the outputs of our labor in a local target directory.
+        let target_dir = manifest.target_dir(project);
+        let lock = DirLock::acquire(&target_dir.join(&ctx.profile.name))?;
+        lock.warn_reclaimed(ctx.shell);
+        let layout = OutputLayout::new(lock).context("could not create local target directory")?;
+        let bin_dir = layout.bin.clone();
+
         let bctx = BuildContext {
             backend: backend.clone(),
             codegen,
             compiler: Compiler::new(&ctx.compiler)?,
             opts: ctx.opts.clone(),
-            cache: cache.clone(),
+            cache: ctx.build_cache(cache, &layout),
             threads: ctx.threads,
+            profile: ctx.profile.clone(),
+            flags: root_flags(&sources),
         };
 
         ctx.shell.println(
@@ -601,21 +754,80 @@ pub fn build(
             Verbosity::Quiet,
         );
 
-        // We want to store the outputs of our labor in a local target directory.
-        let lock = DirLock::acquire(&project.join("target"))?;
-        let layout = OutputLayout::new(lock).context("could not create local target directory")?;
-
-        let q = JobQueue::new(sources, &root, Some(layout), bctx, &ctx.logger, ctx.shell)?;
+        let q = JobQueue::new(sources, &root, Some(layout), bctx, &ctx.logger, ctx.shell)?
+            .with_timings(&timings);
         // Because we're just building, we don't need to do anything after executing the build
         // process. Yay abstraction!
         q.exec()?;
 
-        Ok("build output available at `./target`".to_string())
+        Ok(bin_dir)
     })
 }
 
+/// Builds a binary or an example of the root package and runs it with `args`. Without either, the
+/// package's only binary is run.
+pub fn run(
+    ctx: &BuildCtx,
+    project: &Path,
+    bin: Option<&str>,
+    example: Option<&str>,
+    args: &[&str],
+    backend: &Backend,
+) -> Result<String> {
+    let (project, manifest) = find_manifest(project, true, None)?;
+
+    let mut root = vec![];
+    if manifest.targets.has_lib() {
+        root.push(Target::Lib(false));
+    }
+    let name = if let Some(example) = example {
+        let (ix, ex) = manifest.examples(&[example])?.remove(0);
+        root.push(Target::Example(ix));
+        ex.name.clone()
+    } else {
+        let mut bins = manifest.bins(&bin.map(|b| vec![b]).unwrap_or_default())?;
+        if bins.is_empty() {
+            bail!("the package doesn't have any binary targets to run")
+        } else if bins.len() > 1 {
+            bail!(
+                "the package has more than one binary target, so pick one with --bin (it has {})",
+                bins.iter()
+                    .map(|(_, bin)| format!("`{}`", bin.name))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        }
+        let (ix, bin) = bins.remove(0);
+        root.push(Target::Bin(ix));
+        bin.name.clone()
+    };
+
+    let bin_dir = build_root(ctx, &project, &manifest, Targets::new(root), true, backend)?;
+    let exe = bin_dir.join(&name);
+
+    ctx.shell.println(
+        style("Running").cyan(),
+        format!("{} {}", name, args.join(" ")).trim_end(),
+        Verbosity::Normal,
+    );
+    let status = match &backend.runner {
+        Some(runner) => Command::new(runner).arg(&exe).args(args).status(),
+        None => Command::new(&exe).args(args).status(),
+    }
+    .with_context(|e| format_err!("couldn't run {}: {}", exe.display(), e))?;
+
+    if status.success() {
+        Ok(format!("{} exited successfully", name))
+    } else {
+        bail!("{} exited with {}", name, status)
+    }
+}
+
 pub fn update(ctx: &BuildCtx, project: &Path, ignore: Option<&[Spec]>) -> Result<String> {
-    let (project, _) = find_manifest(project, true, None)?;
+    if ctx.frozen || frozen_env() {
+        bail!("updating rewrites elba.lock, which --frozen forbids")
+    }
+    let (project, manifest) = find_manifest(project, true, None)?;
 
     let op = || -> Result<Graph<Summary>> {
         let mut f = fs::File::open(&project.join("elba.lock"))?;
@@ -628,6 +840,17 @@ pub fn update(ctx: &BuildCtx, project: &Path, ignore: Option<&[Spec]>) -> Result
 
     let prev = op().ok();
 
+    // We refresh the indices up front so that the newest versions of packages can be picked.
+    let cache = ctx.cache()?;
+    if !ctx.offline && !cache.offline {
+        let reses = index_reses(manifest.indices_for(&project, &ctx.indices).values());
+        ctx.shell
+            .println(style("Refreshing").cyan(), "indices", Verbosity::Normal);
+        cache
+            .get_indices(&reses, false, ctx.offline)
+            .refresh(cache.client(), &cache.credentials)?;
+    }
+
     solve_local(ctx, &project, 1, ignore, |_, _, solve| {
         if let Some(prev) = prev.as_ref() {
             for (_, old) in prev.sub_tree(NodeIndex::new(0)) {
@@ -676,67 +899,128 @@ pub fn update(ctx: &BuildCtx, project: &Path, ignore: Option<&[Spec]>) -> Result
     })
 }
 
-pub fn add(ctx: &BuildCtx, project: &Path, spec: &Spec, dev: bool) -> Result<String> {
-    let mut contents = String::new();
+/// Retrieves every package the lockfile of a project pins into the cache, so that the project can
+/// be built offline afterwards.
+pub fn fetch(ctx: &BuildCtx, project: &Path, dry_run: bool) -> Result<String> {
+    if dry_run {
+        return solve_local(ctx, project, 1, None, |_, mut retriever, solve| {
+            let plan = retriever.plan_packages(&solve)?;
+            for planned in plan.downloads() {
+                let size = planned
+                    .size
+                    .map(|x| format!(" ({} bytes)", x))
+                    .unwrap_or_default();
+                ctx.shell.println(
+                    style("Would fetch").cyan(),
+                    format!("{}{}", planned.summary, size),
+                    Verbosity::Quiet,
+                );
+            }
+
+            Ok(format!(
+                "{} of {} packages would be downloaded ({} bytes that the indices know of)",
+                plan.downloads().count(),
+                plan.packages.len(),
+                plan.download_size()
+            ))
+        });
+    }
+
     let (project, _) = find_manifest(project, true, None)?;
-    let mut mf = fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(project.join("elba.toml"))
-        .context(format_err!("failed to read manifest file (elba.toml)"))?;
-    mf.read_to_string(&mut contents)?;
-    let mut manifest = contents
-        .parse::<toml_edit::Document>()
-        .with_context(|e| format!("invalid manifest toml format: {}", e))?;
+    let contents = fs::read_to_string(project.join("elba.lock")).context(format_err!(
+        "couldn't read elba.lock; run `elba update` to create it first"
+    ))?;
+    let lock = LockfileToml::from_str(&contents)?;
+
+    let cache = ctx.cache()?;
+    cache.warm_from_lock(&lock, ctx.download_jobs)?;
+
+    Ok(format!(
+        "packages cached in `{}`",
+        cache.layout.src.display()
+    ))
+}
 
-    let cache = Cache::from_disk(&ctx.logger, ctx.global_cache.clone(), ctx.shell)?;
-    let indices = ctx
-        .indices
-        .values()
-        .cloned()
-        .map(|x| x.res)
-        .collect::<Vec<_>>();
-    let indices = cache.get_indices(&indices, false, false);
-    let target = indices.select_by_spec(&spec)?;
-    let target_s = target.to_string();
+pub fn add(ctx: &BuildCtx, project: &Path, spec: &Spec, dev: bool, force: bool) -> Result<String> {
+    let (project, manifest) = find_manifest(project, true, None)?;
+    let path = project.join("elba.toml");
+    let contents = fs::read_to_string(&path)
+        .context(format_err!("failed to read manifest file (elba.toml)"))?;
 
-    let res = match target.id.resolution() {
-        Resolution::Index(IndexRes { res }) => res.clone(),
-        _ => unreachable!(),
+    let (req, added) = match &spec.resolution {
+        Some(Resolution::Direct(DirectRes::Git {
+            repo, tag, subdir, ..
+        })) => (
+            DepReq::Git {
+                git: repo.clone(),
+                tag: tag.clone(),
+                subdir: subdir.clone(),
+                optional: false,
+                features: vec![],
+                package: None,
+            },
+            spec.to_string(),
+        ),
+        Some(Resolution::Direct(DirectRes::Dir { path })) => (
+            DepReq::Local {
+                path: path.clone(),
+                optional: false,
+                features: vec![],
+                package: None,
+            },
+            spec.to_string(),
+        ),
+        Some(Resolution::Direct(tar @ DirectRes::Tar { .. })) => (
+            DepReq::Tar {
+                tar: tar.clone(),
+                optional: false,
+                features: vec![],
+                package: None,
+            },
+            spec.to_string(),
+        ),
+        Some(Resolution::Direct(_)) => bail!(
+            "only index, git, path, and tarball dependencies can be added to a manifest, not {}",
+            spec
+        ),
+        _ => {
+            let cache = ctx.cache()?;
+            let ixmap = manifest.indices_for(&project, &ctx.indices);
+            let mut indices = cache.get_indices(&index_reses(ixmap.values()), false, false);
+            indices.alias(&ixmap);
+            let target = indices.select_by_spec(spec)?;
+
+            let res = match target.id.resolution() {
+                Resolution::Index(IndexRes { res }) => res.clone(),
+                _ => unreachable!(),
+            };
+            let req = DepReq::RegLong {
+                version: Constraint::from_str(&target.version.to_string())?,
+                index: Some(res.to_string()),
+                optional: false,
+                features: vec![],
+                package: None,
+            };
+
+            (req, target.to_string())
+        }
     };
 
-    if !dev {
-        manifest["dependencies"][target.id.name().to_string()]["version"] =
-            toml_edit::value(target.version.to_string());
-        manifest["dependencies"][target.id.name().to_string()]["index"] =
-            toml_edit::value(res.to_string());
-        manifest["dependencies"][target.id.name().to_string()]
-            .as_inline_table_mut()
-            .map(|t| t.fmt());
-    } else {
-        manifest["dev_dependencies"][target.id.name().to_string()]["version"] =
-            toml_edit::value(target.version.to_string());
-        manifest["dev_dependencies"][target.id.name().to_string()]["index"] =
-            toml_edit::value(res.to_string());
-        manifest["dev_dependencies"][target.id.name().to_string()]
-            .as_inline_table_mut()
-            .map(|t| t.fmt());
-    }
-
-    mf.seek(SeekFrom::Start(0))?;
-    mf.write_all(manifest.to_string().as_bytes())?;
+    let contents = Manifest::add_dependency(&contents, spec.name.as_str(), &req, dev, force)?;
+    fs::write(&path, contents).context(format_err!("failed to write manifest file (elba.toml)"))?;
 
-    Ok(format!("added package {} to manifest", target_s))
+    Ok(format!("added package {} to manifest", added))
 }
 
-pub fn solve_local<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String>>(
+pub fn solve_local<T, F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<T>>(
     ctx: &BuildCtx,
     project: &Path,
     total: u8,
     ignore: Option<&[Spec]>,
     mut f: F,
-) -> Result<String> {
+) -> Result<T> {
     let (project, manifest) = find_manifest(project, true, Some(ctx.shell))?;
+    let cache = ctx.cache()?;
 
     let op = || -> Result<Graph<Summary>> {
         let mut f = fs::File::open(&project.join("elba.lock"))?;
@@ -748,13 +1032,14 @@ pub fn solve_local<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String>
     };
 
     let lock = match ignore {
-        None => {
-            if let Ok(solve) = op() {
-                solve
-            } else {
-                Graph::default()
-            }
-        }
+        None => match op() {
+            Ok(solve) => solve,
+            Err(e) if cache.frozen => bail!(
+                "couldn't read elba.lock ({}), and --frozen forbids creating it",
+                e
+            ),
+            Err(_) => Graph::default(),
+        },
         Some(i) => {
             if i.is_empty() {
                 Graph::default()
@@ -793,23 +1078,51 @@ pub fn solve_local<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String>
         Summary::new(pid, manifest.version().clone())
     };
 
+    let ixmap = manifest.indices_for(&project, &ctx.indices);
     let deps = manifest
-        .deps(&ctx.indices, &root.id, true)?
+        .deps(&ixmap, &root.id, true, &ctx.features, &ctx.platform)?
         .into_iter()
         .collect::<Vec<_>>();
 
-    let dreses = deps
-        .iter()
-        .filter_map(|(p, _)| {
-            if let Resolution::Index(IndexRes { res }) = p.resolution() {
-                Some(res.clone())
-            } else {
-                None
-            }
-        })
-        .collect::<Vec<_>>();
+    // The lockfile is only a starting point for the solve, so anything the manifest changed gets
+    // resolved again; that's worth pointing out, since it's easy to miss
+    if ignore.is_none() {
+        let drift = DriftReport::new(&lock, &deps);
+        if !drift.is_empty() && cache.frozen {
+            bail!(
+                "elba.lock is out of date with elba.toml, and --frozen forbids updating it:\n{}",
+                drift
+            )
+        } else if !drift.is_empty() {
+            ctx.shell.println(
+                style("[warn]").yellow().bold(),
+                format!(
+                    "elba.lock is out of date with elba.toml; re-resolving:\n{}",
+                    drift
+                ),
+                Verbosity::Normal,
+            );
+        }
+    }
 
-    let cache = Cache::from_disk(&ctx.logger, ctx.global_cache.clone(), ctx.shell)?;
+    let dreses = index_reses(deps.iter().filter_map(|(p, _)| {
+        if let Resolution::Index(ir) = p.resolution() {
+            Some(ir)
+        } else {
+            None
+        }
+    }));
+
+    // Updating always resolves from scratch, but otherwise the last solve is as good as a new one
+    // if nothing it was resolved from has changed since
+    let solve_cache = manifest.target_dir(&project).join(SOLVE_CACHE_FILE);
+    let reused = if ignore.is_none() {
+        SolveCache::load(&solve_cache, |solve| {
+            solve_key(ctx, &cache, &ixmap, &project, solve)
+        })
+    } else {
+        None
+    };
 
     ctx.shell.println(
         style(format!("[1/{}]", total)).dim().bold(),
@@ -817,6 +1130,13 @@ pub fn solve_local<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String>
         Verbosity::Quiet,
     );
 
+    // A frozen solve has to come out exactly as it was locked
+    let locked: Option<LockfileToml> = if cache.frozen {
+        Some(lock.clone().into())
+    } else {
+        None
+    };
+
     let mut retriever = Retriever::new(
         &cache.logger,
         &cache,
@@ -824,41 +1144,149 @@ pub fn solve_local<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String>
         deps,
         Left(dreses),
         lock,
-        &ctx.indices,
+        &ixmap,
         ctx.shell,
         ctx.offline,
     );
+    retriever.download_jobs = ctx.download_jobs;
+    retriever.request_features(&manifest);
+    retriever.overrides = manifest.overrides(&project);
+    retriever.platform = ctx.platform.clone();
+
+    if let Some(solve) = reused {
+        ctx.shell.println(
+            style("Reusing").dim(),
+            format!("last resolve from {}", solve_cache.display()),
+            Verbosity::Verbose,
+        );
+        return f(&cache, retriever, solve);
+    }
+
+    let start = Instant::now();
     let solver = Resolver::new(&retriever.logger.clone(), &mut retriever);
     let solve = solver.solve()?;
-    ctx.shell.println(
-        style("Writing").dim(),
-        "lockfile at elba.lock",
-        Verbosity::Verbose,
-    );
+    retriever.timings.resolve = start.elapsed();
+    let lf_contents: LockfileToml = solve.clone().into();
 
-    let mut lockfile = fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(project.join("elba.lock"))
-        .context(format_err!("could not open elba.lock for writing"))?;
+    if let Some(locked) = locked {
+        let changes = locked.diff(&lf_contents);
+        if !changes.is_empty() {
+            bail!(
+                "resolving would change elba.lock, and --frozen forbids updating it:\n{}",
+                changes.join("\n")
+            )
+        }
+    } else {
+        ctx.shell.println(
+            style("Writing").dim(),
+            "lockfile at elba.lock",
+            Verbosity::Verbose,
+        );
 
-    let lf_contents: LockfileToml = solve.clone().into();
-    let lf_contents = toml::to_string_pretty(&lf_contents)?;
+        let mut lockfile = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(project.join("elba.lock"))
+            .context(format_err!("could not open elba.lock for writing"))?;
 
-    lockfile
-        .write_all(lf_contents.as_bytes())
-        .context(format_err!("could not write to elba.lock"))?;
+        let lf_contents = toml::to_string_pretty(&lf_contents)?;
+
+        lockfile
+            .write_all(lf_contents.as_bytes())
+            .context(format_err!("could not write to elba.lock"))?;
+    }
+
+    // The saved solve is only there to speed things up, so it's fine if it can't be written
+    if let Some(key) = solve_key(ctx, &cache, &ixmap, &project, &solve) {
+        let _ = SolveCache::save(&solve_cache, key, solve.clone());
+    }
 
     f(&cache, retriever, solve)
 }
 
+/// Fingerprints everything that a local solve is resolved from: the project's manifest and
+/// lockfile, the options and indices it's resolved with, the state of the indices, and the
+/// manifests of the local packages in `solve`. Gives None if the project doesn't have an
+/// `elba.toml`.
+fn solve_key(
+    ctx: &BuildCtx,
+    cache: &Cache,
+    indices: &IndexMap<String, IndexRes>,
+    project: &Path,
+    solve: &Graph<Summary>,
+) -> Option<String> {
+    fn input(hash: &mut Hasher, x: &[u8]) {
+        hash.input((x.len() as u64).to_le_bytes());
+        hash.input(x);
+    }
+
+    let mut hash = Hasher::new();
+    input(&mut hash, env!("CARGO_PKG_VERSION").as_bytes());
+    input(&mut hash, project.to_string_lossy().as_bytes());
+    input(&mut hash, &fs::read(project.join("elba.toml")).ok()?);
+    input(
+        &mut hash,
+        &fs::read(project.join("elba.lock")).unwrap_or_default(),
+    );
+    input(&mut hash, ctx.features.join(",").as_bytes());
+    input(&mut hash, format!("{:?}", ctx.platform).as_bytes());
+    input(&mut hash, &[(ctx.offline || cache.offline) as u8]);
+    for (name, res) in indices {
+        input(
+            &mut hash,
+            format!("{}={}", name, res.res.cache_key()).as_bytes(),
+        );
+    }
+    let index_reses = indices.values().map(|x| x.res.clone()).collect::<Vec<_>>();
+    input(&mut hash, cache.index_fingerprint(&index_reses).as_bytes());
+
+    for (_, sum) in solve.sub_tree(NodeIndex::new(0)) {
+        if let Resolution::Direct(DirectRes::Dir { path }) = sum.resolution() {
+            input(&mut hash, path.to_string_lossy().as_bytes());
+            input(
+                &mut hash,
+                &fs::read(path.join("elba.toml")).unwrap_or_default(),
+            );
+        }
+    }
+
+    Some(hash.finish())
+}
+
+/// The extra flags to build each package with, taken from the `[build.flags]` of the root of
+/// `sources`. The root can refer to its renamed dependencies by their aliases there.
+fn root_flags(sources: &Graph<Source>) -> IndexMap<Name, Vec<String>> {
+    sources
+        .root()
+        .map(|root| {
+            let aliases = root.aliases();
+            root.meta()
+                .build
+                .flags()
+                .into_iter()
+                .map(|(name, flags)| (aliases.get(&name).cloned().unwrap_or(name), flags))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The resolutions of some indices, with each index only showing up once.
+fn index_reses<'a>(indices: impl Iterator<Item = &'a IndexRes>) -> Vec<DirectRes> {
+    indices
+        .map(|x| x.res.clone())
+        .collect::<IndexSet<_>>()
+        .into_iter()
+        .collect()
+}
+
 pub fn solve_remote<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String>>(
     ctx: &BuildCtx,
     name: &Spec,
     total: u8,
     mut f: F,
 ) -> Result<String> {
-    let cache = Cache::from_disk(&ctx.logger, ctx.global_cache.clone(), ctx.shell)?;
+    let cache = ctx.cache()?;
     ctx.shell.println(
         style(format!("[1/{}]", total)).dim().bold(),
         "Resolving dependencies...",
@@ -872,6 +1300,7 @@ pub fn solve_remote<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String
         .map(|x| x.res)
         .collect::<Vec<_>>();
     let mut indices = cache.get_indices(&indices, true, ctx.offline);
+    indices.alias(&ctx.indices);
     ctx.shell.println(
         style("Cached").dim(),
         format!("indices at {}", cache.layout.indices.display()),
@@ -882,11 +1311,26 @@ pub fn solve_remote<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String
     let deps = indices
         .select(&root)
         .unwrap()
-        .dependencies
-        .iter()
+        .active_deps(&ctx.features)?
+        .into_iter()
         .cloned()
         .map(|d| (PackageId::new(d.name, d.index.into()), d.req))
         .collect::<Vec<_>>();
+    let root_name = root.name().clone();
+
+    // The package's own `[override]` table applies just like it does when it's built locally,
+    // which means looking at its manifest before anything else gets resolved
+    let location = indices.select(&root)?.location.clone();
+    let overrides = {
+        let (_, source) = cache.checkout_summary(&root, &location, false, ctx.offline, || {
+            ctx.shell.println(
+                style("Retrieving").cyan(),
+                root.to_string(),
+                Verbosity::Normal,
+            );
+        })?;
+        source.meta().overrides(source.path())
+    };
 
     let lock = Graph::default();
 
@@ -901,6 +1345,11 @@ pub fn solve_remote<F: FnMut(&Cache, Retriever, Graph<Summary>) -> Result<String
         ctx.shell,
         ctx.offline,
     );
+    retriever.download_jobs = ctx.download_jobs;
+    retriever
+        .features
+        .insert(root_name, ctx.features.iter().cloned().collect());
+    retriever.overrides = overrides;
     let solve = Resolver::new(&retriever.logger.clone(), &mut retriever).solve()?;
 
     f(&cache, retriever, solve)
@@ -967,3 +1416,78 @@ pub fn find_manifest(
         )),
     }
 }
+
+/// The packages that a workspace-wide command (like `build` or `test`) run from `project` should
+/// work on, or `None` if it should just work on the package at `project` as usual.
+///
+/// In a workspace root, these are the workspace's default members, or every member if `all` is
+/// set or it doesn't have any default members. A workspace root with a package of its own and no
+/// default members is the exception: unless `all` is set, it works on its own package.
+pub fn workspace_members(project: &Path, all: bool) -> Result<Option<Vec<PathBuf>>> {
+    let root = match project.ancestors().find(|p| p.join(MANIFEST_FILE).exists()) {
+        Some(root) => root,
+        None => return Ok(None),
+    };
+    let contents = fs::read_to_string(root.join(MANIFEST_FILE)).with_context(|e| {
+        format_err!(
+            "failed to read manifest file ({}): {}",
+            root.join(MANIFEST_FILE).display(),
+            e
+        )
+    })?;
+    let ws = match Manifest::workspace(&contents) {
+        Some(ws) if !ws.is_empty() => ws,
+        _ => return Ok(None),
+    };
+
+    let has_package = match contents.parse::<toml::Value>() {
+        Ok(x) => x.get("package").is_some(),
+        Err(_) => false,
+    };
+    if has_package && !all && ws.default_members.is_empty() {
+        return Ok(None);
+    }
+
+    ws.selected(all)?
+        .into_iter()
+        .map(|(name, path)| {
+            let (dir, file) = member_manifest(path);
+            let manifest = root.join(&dir).join(&file);
+            if file != Path::new(MANIFEST_FILE) {
+                bail!(
+                    "workspace member `{}` can't be built from the workspace root, since its \
+                     manifest ({}) isn't named {}",
+                    name,
+                    manifest.display(),
+                    MANIFEST_FILE
+                )
+            }
+            if !manifest.exists() {
+                bail!(
+                    "workspace member `{}` has no manifest at {}",
+                    name,
+                    manifest.display()
+                )
+            }
+
+            Ok(root.join(dir))
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(Some)
+}
+
+/// Runs `f` on every package that a workspace-wide command run from `project` should work on
+/// (see `workspace_members`), one after another, joining up what each run reports.
+pub fn for_members<F>(project: &Path, all: bool, mut f: F) -> Result<String>
+where
+    F: FnMut(&Path) -> Result<String>,
+{
+    match workspace_members(project, all)? {
+        Some(members) => members
+            .iter()
+            .map(|member| f(member))
+            .collect::<Result<Vec<_>>>()
+            .map(|outputs| outputs.join("\n")),
+        None => f(project),
+    }
+}
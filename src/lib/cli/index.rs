@@ -1,65 +1,162 @@
 //! Registry-related commands: publishing, yanking, etc.
 
 use std::{
-    fs::{create_dir_all, File},
+    fs::{create_dir_all, File, Metadata},
+    io::{self, Write},
     path::{Path, PathBuf},
     str::{self},
 };
 
+use failure::{format_err, Fail};
 use flate2::{write::GzEncoder, Compression};
+use sha2::{Digest, Sha256};
 use tar;
 
 use super::build;
 use crate::{
     cli::build::find_manifest,
-    package::manifest::Manifest,
-    retrieve::Cache,
-    util::{error::Result, valid_file},
+    package::{manifest::Manifest, Checksum, ChecksumFmt},
+    remote::Index,
+    util::error::{Error, Result},
 };
 
-pub fn package(project: &Path) -> Result<(PathBuf, Manifest)> {
+/// The gzip level package tarballs are compressed with. This is pinned rather than left to
+/// flate2's default, since the checksum of a package depends on it.
+pub const PACKAGE_COMPRESSION: u32 = 6;
+
+/// Packages a project into a tarball for publishing, returning the path to the tarball, the
+/// project's manifest, and the checksum of the tarball (which is what gets recorded in an index).
+pub fn package(project: &Path) -> Result<(PathBuf, Manifest, Checksum)> {
     let (project, manifest) = find_manifest(project, false, None)?;
 
-    let gz_name = format!(
-        "target/{}_{}-{}.tar.gz",
+    let target_dir = manifest.target_dir(&project);
+    let gz_name = target_dir.join(format!(
+        "{}_{}-{}.tar.gz",
         manifest.name().group(),
         manifest.name().name(),
         manifest.version()
-    );
+    ));
 
-    create_dir_all(project.join("target"))?;
-    let tar_gz = File::create(project.join(&gz_name))?;
-    let enc = GzEncoder::new(tar_gz, Compression::default());
+    create_dir_all(&target_dir)?;
+    let tar_gz = File::create(&gz_name)?;
+    let enc = GzEncoder::new(tar_gz, Compression::new(PACKAGE_COMPRESSION));
     let mut tar = tar::Builder::new(enc);
 
-    let walker = manifest
-        .list_files(&project, &project, |x| {
-            x.file_name() != ".git" && x.file_name() != "target"
-        })?
-        .filter(valid_file);
-
-    for item in walker {
-        let suffix = item.path().strip_prefix(&project).unwrap();
-        tar.append_path_with_name(item.path(), suffix)?;
+    // `package_files` is sorted, and every header is normalized, so packaging the same files
+    // gives the same tarball (and checksum) no matter where or when it's done
+    for item in manifest.package_files(&project)? {
+        append_normalized(&mut tar, &project.join(&item), &item)?;
     }
 
     // Finish writing to the tarball
-    drop(tar);
+    tar.into_inner()?.finish()?;
+
+    let mut hash = Sha256::new();
+    io::copy(&mut File::open(&gz_name)?, &mut hash)?;
+    let cksum = Checksum {
+        fmt: ChecksumFmt::Sha256,
+        hash: hex::encode(hash.result()),
+    };
+
+    Ok((gz_name, manifest, cksum))
+}
+
+/// Appends a file to a tarball with a header which only records its name, size, and whether it's
+/// executable. Modification times and owners are left out, since they differ from machine to
+/// machine even when the contents of the file don't.
+fn append_normalized<W: Write>(tar: &mut tar::Builder<W>, path: &Path, name: &Path) -> Result<()> {
+    let file = File::open(path)?;
+    let meta = file.metadata()?;
+
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(meta.len());
+    header.set_mode(if is_executable(&meta) { 0o755 } else { 0o644 });
+    header.set_mtime(0);
+    header.set_uid(0);
+    header.set_gid(0);
+    // Names which are too long for the header are handled by `append_data`
+    tar.append_data(&mut header, name, file)?;
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_executable(meta: &Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_: &Metadata) -> bool {
+    false
+}
+
+/// Checks that a package tarball with the checksum `cksum` can be published to `index` as the
+/// version in its manifest. That's fine if the index doesn't have that version yet, or if it has
+/// exactly the same tarball (so publishing it again doesn't change anything); otherwise, the
+/// version has to be bumped first.
+pub fn check_republish(index: &Index, manifest: &Manifest, cksum: &Checksum) -> Result<()> {
+    let checksums = index.checksums(manifest.name())?;
+    let existing = match checksums.get(manifest.version()) {
+        Some(existing) => existing,
+        None => return Ok(()),
+    };
+
+    if existing.as_ref() == Some(cksum) {
+        return Ok(());
+    }
+
+    let msg = match existing {
+        Some(existing) => format!(
+            "{} {} is already published to {} with checksum {}, but the package now has checksum {}",
+            manifest.name(),
+            manifest.version(),
+            index.id,
+            existing,
+            cksum
+        ),
+        None => format!(
+            "{} {} is already published to {}, and its contents can't be compared without a checksum",
+            manifest.name(),
+            manifest.version(),
+            index.id
+        ),
+    };
+
+    Err(Error::VersionAlreadyPublished.context(msg).into())
+}
+
+/// Checks that a packaged project can be published to the default index of `bcx`; see
+/// `check_republish`.
+pub fn check_registry(bcx: &build::BuildCtx, manifest: &Manifest, cksum: &Checksum) -> Result<()> {
+    let cache = bcx.cache()?;
+    let ir = bcx
+        .indices
+        .values()
+        .next()
+        .ok_or_else(|| format_err!("no indices are configured"))?;
+    let indices = cache.get_indices(std::slice::from_ref(&ir.res), false, bcx.offline);
+    let index = indices
+        .indices
+        .get(ir)
+        .ok_or_else(|| format_err!("couldn't load index {}", ir))?;
 
-    Ok((project.join(&gz_name), manifest))
+    check_republish(index, manifest, cksum)
 }
 
-pub fn search(bcx: &build::BuildCtx, query: &str) -> Result<String> {
-    let cache = Cache::from_disk(&bcx.logger, bcx.global_cache.clone(), bcx.shell)?;
+pub fn search(bcx: &build::BuildCtx, query: &str, channel: Option<&str>) -> Result<String> {
+    let cache = bcx.cache()?;
     let ixs = bcx
         .indices
         .values()
         .cloned()
         .map(|x| x.res)
         .collect::<Vec<_>>();
-    let indices = cache.get_indices(&ixs, false, false);
+    let mut indices = cache.get_indices(&ixs, false, false);
+    indices.alias(&bcx.indices);
 
-    let pkgs = indices.search(query)?;
+    let pkgs = indices.search(query, channel)?;
     let mut res = String::new();
 
     for (name, ver, ir) in &pkgs {
@@ -1,38 +1,97 @@
 use crate::{
     package::Name,
-    util::{error::Result, git},
+    util::{config::Profile, error::Result, git},
 };
 use failure::{bail, format_err, ResultExt};
-use inflector::Inflector;
-use std::{fs, path::PathBuf};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
 pub struct NewCtx {
     pub path: PathBuf,
     pub name: Name,
-    // Tuple of name and email.
+    // Tuple of name and email. If this is None, we look in the global config and then in git's
+    // config for a default.
     pub author: Option<(String, String)>,
     pub bin: bool,
     pub git: bool,
 }
 
+/// Creates a new package in the directory `ctx.path`, creating the directory too.
+///
+/// The directory is allowed to exist already as long as it's empty (or only holds a `.git`
+/// folder), in which case the package is made inside it just like `init` would. Making a package
+/// out of a directory which already has other files in it is what `init` is for.
 pub fn new(ctx: NewCtx) -> Result<String> {
     let path = &ctx.path;
-    if fs::metadata(path).is_ok() {
-        bail!(
-            "destination `{}` already exists\n\n\
-             create a new `elba.toml` manifest file in the directory instead",
-            path.display()
-        )
+    if let Ok(meta) = fs::metadata(path) {
+        if path.join("elba.toml").exists() {
+            bail!("elba project already exists in `{}`", path.display())
+        }
+        if !meta.is_dir() || !is_empty_dir(path)? {
+            bail!(
+                "destination `{}` already exists\n\n\
+                 create a new `elba.toml` manifest file in the directory instead with `elba init`",
+                path.display()
+            )
+        }
+    } else {
+        fs::create_dir_all(path).context(format_err!("could not create dir {}", path.display()))?;
     }
 
-    fs::create_dir_all(path).context(format_err!("could not create dir {}", path.display()))?;
-
     init(ctx)
 }
 
+/// Whether a directory has nothing in it, other than maybe a git repository.
+fn is_empty_dir(path: &Path) -> Result<bool> {
+    for entry in fs::read_dir(path)? {
+        if entry?.file_name() != ".git" {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Picks the author of a new package. An explicitly given author takes precedence over the
+/// `[profile]` of the global configuration, which in turn takes precedence over git's
+/// `user.name` and `user.email`.
+pub fn find_author(
+    explicit: Option<(String, String)>,
+    profile: Option<Profile>,
+    git_config: Option<&git2::Config>,
+) -> Option<(String, String)> {
+    explicit
+        .or_else(|| profile.map(|p| (p.name, p.email)))
+        .or_else(|| git_config.and_then(git::author))
+}
+
+/// Parses an author of the form `Name <email>`.
+pub fn parse_author(s: &str) -> Result<(String, String)> {
+    let mut split = s.trim().splitn(2, '<');
+    let name = split.next().unwrap().trim();
+    let email = split
+        .next()
+        .map(|x| x.trim())
+        .filter(|x| x.ends_with('>'))
+        .map(|x| &x[..x.len() - 1])
+        .ok_or_else(|| format_err!("author `{}` must be of the form `Name <email>`", s))?;
+
+    if name.is_empty() || email.is_empty() {
+        bail!("author `{}` must be of the form `Name <email>`", s)
+    }
+
+    Ok((name.to_string(), email.trim().to_string()))
+}
+
+/// Creates a new package in the existing directory `ctx.path`, whatever else is in it, unless it
+/// already has a manifest.
 pub fn init(ctx: NewCtx) -> Result<String> {
     let name = &ctx.name;
-    let author = if let Some((author, email)) = ctx.author {
+    let git_config = git2::Config::open_default().ok();
+    let author = find_author(ctx.author, Profile::global(), git_config.as_ref());
+    let author = if let Some((author, email)) = author {
         format!("{} <{}>", author, email)
     } else {
         "".to_string()
@@ -58,7 +117,7 @@ mods = [
 ]
 
 "#,
-            name.name().to_pascal_case(),
+            name.name_dir(),
         )
     };
 
@@ -82,10 +141,10 @@ authors = [{}]
         bail!("elba project already exists in this directory")
     }
 
-    fs::create_dir_all(path.join(format!("src/{}", name.name().to_pascal_case())))
+    fs::create_dir_all(path.join("src").join(name.name_dir()))
         .context(format_err!("could not create dir {}", path.display()))?;
 
-    let lib_path = path.join(format!("src/{}.idr", name.name().to_pascal_case()));
+    let lib_path = path.join(format!("src/{}.idr", name.name_dir()));
 
     if !ctx.bin && !lib_path.exists() {
         fs::write(
@@ -97,7 +156,7 @@ hello : IO ()
 hello = do
   print "Hello, world!"
 "#,
-                name.name().to_pascal_case()
+                name.name_dir()
             )
             .as_bytes(),
         )?;
@@ -134,3 +193,132 @@ main = do
         path.display()
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate tempdir;
+
+    use super::*;
+    use std::str::FromStr;
+
+    fn git_config(dir: &tempdir::TempDir) -> git2::Config {
+        let path = dir.path().join("gitconfig");
+        fs::write(&path, "[user]\n\tname = Git User\n\temail = git@user.me\n").unwrap();
+        git2::Config::open(&path).unwrap()
+    }
+
+    fn new_ctx(path: PathBuf) -> NewCtx {
+        NewCtx {
+            path,
+            name: Name::from_str("new/pkg").unwrap(),
+            author: Some(("Me".to_string(), "me@me.me".to_string())),
+            bin: false,
+            git: false,
+        }
+    }
+
+    #[test]
+    fn new_empty_dir() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        fs::create_dir(tmp.path().join(".git")).unwrap();
+
+        new(new_ctx(tmp.path().to_owned())).unwrap();
+        assert!(tmp.path().join("elba.toml").exists());
+        assert!(tmp.path().join("src/Pkg.idr").exists());
+    }
+
+    #[test]
+    fn new_module_casing() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let mut ctx = new_ctx(tmp.path().to_owned());
+        ctx.name = Name::from_str("new/my_fancy-pkg").unwrap();
+
+        new(ctx).unwrap();
+        assert!(tmp.path().join("src/MyFancyPkg.idr").exists());
+        let manifest = fs::read_to_string(tmp.path().join("elba.toml")).unwrap();
+        assert!(manifest.contains("\"MyFancyPkg\""), "{}", manifest);
+    }
+
+    #[test]
+    fn new_nonempty_dir() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        fs::write(tmp.path().join("README.md"), "").unwrap();
+
+        let err = new(new_ctx(tmp.path().to_owned())).unwrap_err();
+        assert!(err.to_string().contains("already exists"), "{}", err);
+        assert!(!tmp.path().join("elba.toml").exists());
+    }
+
+    #[test]
+    fn new_existing_manifest() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        fs::write(tmp.path().join("elba.toml"), "").unwrap();
+
+        let err = new(new_ctx(tmp.path().to_owned())).unwrap_err();
+        assert!(
+            err.to_string().contains("project already exists"),
+            "{}",
+            err
+        );
+        assert!(init(new_ctx(tmp.path().to_owned())).is_err());
+        assert_eq!(
+            fs::read_to_string(tmp.path().join("elba.toml")).unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn author_explicit() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let profile = Profile {
+            name: "Config User".to_string(),
+            email: "config@user.me".to_string(),
+        };
+        let author = find_author(
+            Some(("Me".to_string(), "me@me.me".to_string())),
+            Some(profile),
+            Some(&git_config(&tmp)),
+        );
+
+        assert_eq!(author, Some(("Me".to_string(), "me@me.me".to_string())));
+    }
+
+    #[test]
+    fn author_config() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let path = tmp.path().join("config");
+        fs::write(
+            &path,
+            "[profile]\nname = 'Config User'\nemail = 'config@user.me'\n",
+        )
+        .unwrap();
+        let profile = Profile::from_file(&path).unwrap();
+        let author = find_author(None, profile, Some(&git_config(&tmp)));
+
+        assert_eq!(
+            author,
+            Some(("Config User".to_string(), "config@user.me".to_string()))
+        );
+    }
+
+    #[test]
+    fn author_git() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let profile = Profile::from_file(&tmp.path().join("nonexistent")).unwrap();
+        let author = find_author(None, profile, Some(&git_config(&tmp)));
+
+        assert_eq!(
+            author,
+            Some(("Git User".to_string(), "git@user.me".to_string()))
+        );
+    }
+
+    #[test]
+    fn author_parse() {
+        assert_eq!(
+            parse_author("Me <y@boi.me>").unwrap(),
+            ("Me".to_string(), "y@boi.me".to_string())
+        );
+        assert!(parse_author("Me").is_err());
+    }
+}
@@ -0,0 +1,264 @@
+//! Predicates for platform-specific dependencies.
+//!
+//! A manifest can declare dependencies which only apply on some platforms with tables like
+//! `[target.'cfg(os = "windows")'.dependencies]`. The predicate language is kept small: `os` and
+//! `backend` can be compared against a string, and predicates can be combined with `all(...)`,
+//! `any(...)`, and `not(...)`.
+
+use std::{env, fmt, str::FromStr};
+
+use failure::format_err;
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_while, take_while1},
+    character::complete::{char, multispace0},
+    combinator::{all_consuming, map, map_res, opt},
+    multi::separated_list,
+    sequence::{delimited, preceded, separated_pair, terminated},
+    IResult,
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::util::error::Result;
+
+/// The platform that packages are being resolved and built for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Platform {
+    /// The operating system, as reported by `std::env::consts::OS` (e.g. `linux` or `windows`).
+    pub os: String,
+    /// The name of the codegen backend.
+    pub backend: String,
+}
+
+impl Platform {
+    /// The platform elba is running on, generating code with the given backend.
+    pub fn new(backend: &str) -> Self {
+        Platform {
+            os: env::consts::OS.to_string(),
+            backend: backend.to_string(),
+        }
+    }
+}
+
+impl Default for Platform {
+    fn default() -> Self {
+        Platform::new("c")
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CfgKey {
+    Os,
+    Backend,
+}
+
+impl FromStr for CfgKey {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "os" => Ok(CfgKey::Os),
+            "backend" => Ok(CfgKey::Backend),
+            _ => Err(format_err!("unknown cfg key `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for CfgKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CfgKey::Os => write!(f, "os"),
+            CfgKey::Backend => write!(f, "backend"),
+        }
+    }
+}
+
+/// A predicate on the platform, written as `cfg(<predicate>)`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Cfg {
+    Is(CfgKey, String),
+    All(Vec<Cfg>),
+    Any(Vec<Cfg>),
+    Not(Box<Cfg>),
+}
+
+impl Cfg {
+    pub fn matches(&self, platform: &Platform) -> bool {
+        match self {
+            Cfg::Is(CfgKey::Os, os) => &platform.os == os,
+            Cfg::Is(CfgKey::Backend, backend) => &platform.backend == backend,
+            Cfg::All(cfgs) => cfgs.iter().all(|c| c.matches(platform)),
+            Cfg::Any(cfgs) => cfgs.iter().any(|c| c.matches(platform)),
+            Cfg::Not(cfg) => !cfg.matches(platform),
+        }
+    }
+
+    fn fmt_inner(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let list = |f: &mut fmt::Formatter, name: &str, cfgs: &[Cfg]| {
+            write!(f, "{}(", name)?;
+            for (ix, cfg) in cfgs.iter().enumerate() {
+                if ix != 0 {
+                    write!(f, ", ")?;
+                }
+                cfg.fmt_inner(f)?;
+            }
+            write!(f, ")")
+        };
+
+        match self {
+            Cfg::Is(key, val) => write!(f, "{} = \"{}\"", key, val),
+            Cfg::All(cfgs) => list(f, "all", cfgs),
+            Cfg::Any(cfgs) => list(f, "any", cfgs),
+            Cfg::Not(cfg) => {
+                write!(f, "not(")?;
+                cfg.fmt_inner(f)?;
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Cfg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cfg(")?;
+        self.fmt_inner(f)?;
+        write!(f, ")")
+    }
+}
+
+impl FromStr for Cfg {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        all_consuming(delimited(ws(tag("cfg")), parens(parse_pred), multispace0))(s)
+            .map(|(_, cfg)| cfg)
+            .map_err(|_| {
+                format_err!(
+                    "invalid platform predicate `{}` (expected e.g. `cfg(os = \"windows\")`)",
+                    s
+                )
+            })
+    }
+}
+
+impl Serialize for Cfg {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Cfg {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
+fn ws<'a, O, F>(f: F) -> impl Fn(&'a str) -> IResult<&'a str, O>
+where
+    F: Fn(&'a str) -> IResult<&'a str, O>,
+{
+    delimited(multispace0, f, multispace0)
+}
+
+fn parens<'a, O, F>(f: F) -> impl Fn(&'a str) -> IResult<&'a str, O>
+where
+    F: Fn(&'a str) -> IResult<&'a str, O>,
+{
+    delimited(ws(char('(')), f, ws(char(')')))
+}
+
+fn parse_list(i: &str) -> IResult<&str, Vec<Cfg>> {
+    parens(terminated(
+        separated_list(ws(char(',')), parse_pred),
+        opt(ws(char(','))),
+    ))(i)
+}
+
+fn parse_pred(i: &str) -> IResult<&str, Cfg> {
+    alt((
+        map(preceded(ws(tag("all")), parse_list), Cfg::All),
+        map(preceded(ws(tag("any")), parse_list), Cfg::Any),
+        map(preceded(ws(tag("not")), parens(parse_pred)), |c| {
+            Cfg::Not(Box::new(c))
+        }),
+        parse_is,
+    ))(i)
+}
+
+fn parse_is(i: &str) -> IResult<&str, Cfg> {
+    let key = map_res(
+        ws(take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_')),
+        CfgKey::from_str,
+    );
+    let val = ws(delimited(char('"'), take_while(|c| c != '"'), char('"')));
+
+    map(separated_pair(key, char('='), val), |(k, v): (_, &str)| {
+        Cfg::Is(k, v.to_string())
+    })(i)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn windows_chez() -> Platform {
+        Platform {
+            os: "windows".to_string(),
+            backend: "chez".to_string(),
+        }
+    }
+
+    #[test]
+    fn cfg_parse() {
+        let cfg = Cfg::from_str(r#"cfg(all(os = "windows", not(backend="c"),))"#).unwrap();
+
+        assert_eq!(
+            cfg,
+            Cfg::All(vec![
+                Cfg::Is(CfgKey::Os, "windows".to_string()),
+                Cfg::Not(Box::new(Cfg::Is(CfgKey::Backend, "c".to_string()))),
+            ])
+        );
+        assert_eq!(Cfg::from_str(&cfg.to_string()).unwrap(), cfg);
+    }
+
+    #[test]
+    fn cfg_parse_invalid() {
+        assert!(Cfg::from_str(r#"os = "windows""#).is_err());
+        assert!(Cfg::from_str(r#"cfg(arch = "x86")"#).is_err());
+        assert!(Cfg::from_str(r#"cfg(os = "windows""#).is_err());
+    }
+
+    #[test]
+    fn cfg_matches() {
+        let platform = windows_chez();
+
+        assert!(Cfg::from_str(r#"cfg(os = "windows")"#)
+            .unwrap()
+            .matches(&platform));
+        assert!(
+            Cfg::from_str(r#"cfg(any(backend = "c", backend = "chez"))"#)
+                .unwrap()
+                .matches(&platform)
+        );
+    }
+
+    #[test]
+    fn cfg_not_matches() {
+        let platform = windows_chez();
+
+        assert!(!Cfg::from_str(r#"cfg(os = "linux")"#)
+            .unwrap()
+            .matches(&platform));
+        assert!(!Cfg::from_str(r#"cfg(all(os = "windows", backend = "c"))"#)
+            .unwrap()
+            .matches(&platform));
+        assert!(!Cfg::from_str(r#"cfg(not(backend = "chez"))"#)
+            .unwrap()
+            .matches(&platform));
+    }
+}
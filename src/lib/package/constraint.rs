@@ -0,0 +1,382 @@
+//! Parsing the version constraints written in manifests and indices.
+//!
+//! On top of elba's own syntax (caret and tilde constraints, inequalities like `>= 1.0 < 2`,
+//! `any`, and unions separated by commas), this accepts the syntax people are used to from other
+//! package managers:
+//!
+//! - exact versions: `=1.2.3`, which only matches 1.2.3 (or `=1.2`, which matches any 1.2.x)
+//! - wildcards: `*`, `1.*`, and `1.2.*` (`x` works as well as `*`)
+//! - Cargo-style intersections: `>= 1.2, < 2.0`, which is the same as `>= 1.2 < 2.0`
+//! - pre-releases in any bound: `>= 1.0.0-beta.1`, `=1.0.0-rc.1`
+//!
+//! A lower bound followed by an upper bound is the only case where a comma means an intersection
+//! instead of a union: as a union, `>= 1.2, < 2.0` would match every version. Bounds which don't
+//! overlap, like `>= 1.2, < 1.0`, are an error rather than a union of the two.
+//!
+//! Everything is parsed into a `semver_constraints::Constraint`, so constraints written either
+//! way resolve exactly the same.
+
+use std::str::FromStr;
+
+use failure::{bail, format_err};
+use semver::Version;
+use semver_constraints::{Constraint, Interval, Range};
+use serde::{de, Deserialize, Deserializer};
+
+use crate::util::error::Result;
+
+/// Parses a version constraint.
+pub fn parse(s: &str) -> Result<Constraint> {
+    let parts = s
+        .split(',')
+        .map(|part| parse_range(part.trim()))
+        .collect::<Result<Vec<_>>>()
+        .map_err(|e| format_err!("invalid version constraint `{}`: {}", s.trim(), e))?;
+
+    let mut ranges = vec![];
+    let mut parts = parts.into_iter().peekable();
+    while let Some((range, bound)) = parts.next() {
+        let range = match (bound, parts.peek()) {
+            (Bound::Lower, Some((upper, Bound::Upper))) => {
+                let both = Range::new(range.lower().clone(), upper.upper().clone());
+                parts.next();
+                both.ok_or_else(|| {
+                    format_err!(
+                        "invalid version constraint `{}`: its bounds don't overlap, so it \
+                         doesn't match any versions",
+                        s.trim()
+                    )
+                })?
+            }
+            _ => range,
+        };
+        ranges.push(range);
+    }
+
+    Ok(Constraint::new(ranges.into_iter().collect()))
+}
+
+/// Deserializes a constraint with `parse`, for use with `#[serde(deserialize_with)]`.
+pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Constraint, D::Error> {
+    let s = String::deserialize(d)?;
+    parse(&s).map_err(de::Error::custom)
+}
+
+/// Like `deserialize`, for constraints which can be left out.
+pub fn deserialize_opt<'de, D: Deserializer<'de>>(
+    d: D,
+) -> std::result::Result<Option<Constraint>, D::Error> {
+    let s = Option::<String>::deserialize(d)?;
+    s.map(|s| parse(&s)).transpose().map_err(de::Error::custom)
+}
+
+/// Which sides of a range were written out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Bound {
+    Lower,
+    Upper,
+    Both,
+}
+
+/// A version as it's written in a constraint, which can leave out its minor and patch versions
+/// or replace them with a wildcard.
+struct Written {
+    version: Version,
+    minor: bool,
+    patch: bool,
+    wildcard: bool,
+}
+
+impl Written {
+    /// The range of versions a partial or wildcard version stands for: `1` and `1.*` are 1.x.y,
+    /// `1.2` and `1.2.*` are 1.2.x.
+    fn partial_range(&self) -> Result<Range> {
+        let mut upper = self.version.clone();
+        if self.minor {
+            upper.increment_minor();
+        } else {
+            upper.increment_major();
+        }
+
+        Range::new(
+            Interval::Closed(self.version.clone(), false),
+            Interval::Open(upper, false),
+        )
+        .ok_or_else(|| format_err!("it doesn't match any versions"))
+    }
+}
+
+fn parse_version(s: &str) -> Result<Written> {
+    let s = s.trim();
+    if s.is_empty() {
+        bail!("a version is missing")
+    }
+
+    // Pre-releases (and build metadata) only make sense on a complete version
+    if s.contains('-') || s.contains('+') {
+        let version =
+            Version::parse(s).map_err(|e| format_err!("`{}` isn't a valid version: {}", s, e))?;
+        return Ok(Written {
+            version,
+            minor: true,
+            patch: true,
+            wildcard: false,
+        });
+    }
+
+    let mut nums = vec![];
+    let mut wildcard = false;
+    for comp in s.split('.') {
+        match comp {
+            "*" | "x" | "X" => wildcard = true,
+            _ if wildcard => bail!("`{}` has a number after a wildcard", s),
+            _ => nums.push(
+                u64::from_str(comp).map_err(|_| format_err!("`{}` isn't a valid version", s))?,
+            ),
+        }
+    }
+
+    if nums.is_empty() || nums.len() + (wildcard as usize) > 3 {
+        bail!("`{}` isn't a valid version", s)
+    }
+
+    Ok(Written {
+        version: Version::new(
+            nums[0],
+            nums.get(1).cloned().unwrap_or(0),
+            nums.get(2).cloned().unwrap_or(0),
+        ),
+        minor: nums.len() > 1,
+        patch: nums.len() > 2,
+        wildcard,
+    })
+}
+
+fn parse_range(s: &str) -> Result<(Range, Bound)> {
+    match s {
+        "" => bail!("a constraint is missing"),
+        "any" | "*" | "x" | "X" => return Ok((Range::any(), Bound::Both)),
+        _ => {}
+    }
+
+    if s.starts_with('>') || s.starts_with('<') {
+        return parse_inequality(s);
+    }
+
+    let (sigil, rest) = match s.chars().next() {
+        Some(c @ '=') | Some(c @ '^') | Some(c @ '~') => (Some(c), &s[1..]),
+        _ => (None, s),
+    };
+    let written = parse_version(rest)?;
+
+    let range = if written.wildcard || (sigil == Some('=') && !written.patch) {
+        written.partial_range()?
+    } else {
+        let lower = written.version.clone();
+        let upper = match sigil {
+            Some('=') => Interval::Closed(lower.clone(), false),
+            Some('~') => Interval::Open(increment_tilde(&written), false),
+            _ => Interval::Open(increment_caret(&written), false),
+        };
+        Range::new(Interval::Closed(lower, false), upper)
+            .ok_or_else(|| format_err!("it doesn't match any versions"))?
+    };
+
+    Ok((range, Bound::Both))
+}
+
+/// Parses `>`, `>=`, `<`, and `<=` bounds (with or without a bang), either on their own or with
+/// a lower bound followed by an upper bound.
+fn parse_inequality(s: &str) -> Result<(Range, Bound)> {
+    let (lower, upper) = match (s.starts_with('>'), s.find('<')) {
+        (true, Some(i)) => (Some(&s[..i]), Some(&s[i..])),
+        (true, None) => (Some(s), None),
+        (false, _) => (None, Some(s)),
+    };
+
+    let lower = lower.map(|x| parse_bound(&x[1..])).transpose()?;
+    let upper = upper.map(|x| parse_bound(&x[1..])).transpose()?;
+
+    let bound = match (&lower, &upper) {
+        (Some(_), Some(_)) => Bound::Both,
+        (Some(_), None) => Bound::Lower,
+        _ => Bound::Upper,
+    };
+    let lower = lower.map_or(Interval::Unbounded, |(closed, v, pre_ok)| {
+        interval(closed, v, pre_ok)
+    });
+    let upper = upper.map_or(Interval::Unbounded, |(closed, v, pre_ok)| {
+        interval(closed, v, pre_ok)
+    });
+
+    let range = Range::new(lower, upper).ok_or_else(|| {
+        format_err!("it doesn't match any versions (the lower bound has to come first)")
+    })?;
+
+    Ok((range, bound))
+}
+
+/// Parses what comes after the `>` or `<` of a bound: whether it's closed, the version, and
+/// whether it takes pre-releases.
+fn parse_bound(s: &str) -> Result<(bool, Version, bool)> {
+    let mut chars = s.chars();
+    let (closed, s) = match chars.next() {
+        Some('=') => (true, chars.as_str()),
+        _ => (false, s),
+    };
+    let mut chars = s.trim_start().chars();
+    let (bang, s) = match chars.next() {
+        Some('!') => (true, chars.as_str()),
+        _ => (false, s),
+    };
+
+    let written = parse_version(s)?;
+    if written.wildcard {
+        bail!("wildcards can't be used in inequalities")
+    }
+    // Like everywhere else, a bang doesn't mean anything for a bound which is a pre-release
+    let pre_ok = bang && !written.version.is_prerelease();
+
+    Ok((closed, written.version, pre_ok))
+}
+
+fn interval(closed: bool, v: Version, pre_ok: bool) -> Interval {
+    if closed {
+        Interval::Closed(v, pre_ok)
+    } else {
+        Interval::Open(v, pre_ok)
+    }
+}
+
+// These work the same way as in `semver_constraints`, so that a constraint means the same thing
+// no matter which parser reads it.
+fn increment_caret(w: &Written) -> Version {
+    let mut v = w.version.clone();
+    if v.major > 0 || (!w.minor && !w.patch) {
+        v.increment_major();
+    } else if v.minor > 0 {
+        v.increment_minor();
+    } else {
+        v.increment_patch();
+    }
+    v
+}
+
+fn increment_tilde(w: &Written) -> Version {
+    let mut v = w.version.clone();
+    if !w.minor {
+        v.increment_major();
+    } else {
+        v.increment_minor();
+    }
+    v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(c: &str, v: &str) -> bool {
+        parse(c).unwrap().satisfies(&Version::parse(v).unwrap())
+    }
+
+    #[test]
+    fn constraint_caret() {
+        assert_eq!(parse("^1.2").unwrap(), parse(">= 1.2.0 < 2.0.0").unwrap());
+        assert_eq!(parse("1.2").unwrap(), parse("^1.2").unwrap());
+        assert_eq!(parse("^0.2.3").unwrap(), parse(">= 0.2.3 < 0.3.0").unwrap());
+        assert_eq!(parse("^0.0.3").unwrap(), parse(">= 0.0.3 < 0.0.4").unwrap());
+        assert!(!matches("^1.2", "2.0.0"));
+    }
+
+    #[test]
+    fn constraint_tilde() {
+        assert_eq!(parse("~1.2").unwrap(), parse(">= 1.2.0 < 1.3.0").unwrap());
+        assert_eq!(parse("~1").unwrap(), parse(">= 1.0.0 < 2.0.0").unwrap());
+        assert!(matches("~1.2.3", "1.2.9"));
+        assert!(!matches("~1.2.3", "1.3.0"));
+    }
+
+    #[test]
+    fn constraint_exact() {
+        assert!(matches("=1.2.3", "1.2.3"));
+        assert!(matches("= 1.2.3", "1.2.3"));
+        assert!(!matches("=1.2.3", "1.2.4"));
+        // A partial exact version matches everything it doesn't leave out
+        assert_eq!(parse("=1.2").unwrap(), parse("1.2.*").unwrap());
+    }
+
+    #[test]
+    fn constraint_wildcard() {
+        assert_eq!(parse("*").unwrap(), Constraint::any());
+        assert_eq!(parse("1.*").unwrap(), parse(">= 1.0.0 < 2.0.0").unwrap());
+        assert_eq!(parse("1.2.x").unwrap(), parse(">= 1.2.0 < 1.3.0").unwrap());
+        assert_eq!(parse("0.*").unwrap(), parse(">= 0.0.0 < 1.0.0").unwrap());
+        assert!(parse("1.*.3").is_err());
+        assert!(parse(">= 1.*").is_err());
+    }
+
+    #[test]
+    fn constraint_compound() {
+        let both = parse(">= 1.2 < 2.0").unwrap();
+        assert_eq!(parse(">=1.2, <2.0").unwrap(), both);
+        assert_eq!(parse(">= 1.2, < 2.0").unwrap(), both);
+
+        // Everything else separated by commas is still a union
+        let union = parse("1.0.0, 3.0.0").unwrap();
+        assert!(matches("1.0.0, 3.0.0", "1.5.0"));
+        assert!(matches("1.0.0, 3.0.0", "3.1.0"));
+        assert!(!union.satisfies(&Version::parse("2.0.0").unwrap()));
+        assert!(matches("< 1, > 2", "0.5.0"));
+        assert!(!matches("< 1, > 2", "1.5.0"));
+    }
+
+    #[test]
+    fn constraint_prerelease() {
+        assert!(matches("=1.0.0-beta.1", "1.0.0-beta.1"));
+        assert!(!matches("=1.0.0-beta.1", "1.0.0-beta.2"));
+        assert!(!matches("=1.0.0-beta.1", "1.0.0"));
+        assert!(matches(">= 1.0.0-beta.1", "1.0.0-beta.2"));
+        assert!(!matches(">= 1.0.0-beta.2", "1.0.0-beta.1"));
+
+        // Pre-releases still have to be opted into
+        assert!(!matches("^1.0", "2.0.0-alpha.1"));
+        assert!(!matches("1.*", "1.1.0-beta.1"));
+        assert!(matches("> 1.0.0 <! 1.1.0", "1.1.0-beta.1"));
+
+        // Partial versions can't have pre-releases
+        assert!(parse("1.0-beta").is_err());
+    }
+
+    #[test]
+    fn constraint_roundtrip() {
+        // Whatever a constraint is written out as can be read back in
+        for c in &[
+            "=1.0.0-rc.1",
+            ">= 1.0.0-beta.1 < 2.0.0",
+            "1.*, >=! 3.0.0",
+            "~0.2",
+        ] {
+            let c = parse(c).unwrap();
+            assert_eq!(parse(&c.to_string()).unwrap(), c);
+        }
+    }
+
+    #[test]
+    fn constraint_invalid() {
+        for c in &[
+            "",
+            "1.2,",
+            ">",
+            "abc",
+            "< 1 > 0",
+            "> 1 < 0",
+            "^1.2.3.4",
+            "=>1",
+            ">=1.2, <1.0",
+            "> 2, < 1",
+        ] {
+            assert!(parse(c).is_err(), "{}", c);
+        }
+    }
+}
@@ -9,7 +9,9 @@ use semver::Version;
 use serde::Deserialize;
 
 use super::*;
-use crate::package::manifest::{BinTarget, LibTarget, Manifest, PackageInfo, Targets, TestTarget};
+use crate::package::manifest::{
+    BinTarget, BuildConfig, LibTarget, Manifest, PackageInfo, Targets, TestTarget, Workspace,
+};
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct Ipkg {
@@ -69,11 +71,16 @@ impl TryFrom<Ipkg> for Manifest {
                 .collect(),
             description: ipkg.brief,
             keywords: Vec::new(),
+            categories: Vec::new(),
             homepage: ipkg.homepage,
             repository: ipkg.sourceloc,
             readme: ipkg.readme.map(|readme| readme.parse()).transpose()?,
             license: ipkg.license,
+            include: None,
             exclude: None,
+            elba_version: None,
+            build: true,
+            exclude_from_cache: false,
         };
 
         let mut idris_opts = Vec::new();
@@ -125,13 +132,21 @@ impl TryFrom<Ipkg> for Manifest {
             package,
             dependencies: IndexMap::new(),
             dev_dependencies: IndexMap::new(),
+            platform_deps: IndexMap::new(),
             targets: Targets {
                 lib: lib_target,
+                libs: IndexMap::new(),
                 bin: bin_target,
                 test: test_targets,
+                example: vec![],
             },
-            workspace: IndexMap::new(),
+            workspace: Workspace::default(),
+            indices: IndexMap::new(),
             scripts,
+            features: IndexMap::new(),
+            badges: IndexMap::new(),
+            overrides: IndexMap::new(),
+            build: BuildConfig::default(),
         })
     }
 }
@@ -6,8 +6,9 @@ use crate::util::graph::Graph;
 use failure::{Error, ResultExt};
 use indexmap::{IndexMap, IndexSet};
 use petgraph::{self, graph::NodeIndex};
+use semver_constraints::Constraint;
 use serde::{Deserialize, Serialize};
-use std::iter::FromIterator;
+use std::{fmt, fs, iter::FromIterator, path::Path};
 use toml;
 
 use super::*;
@@ -35,20 +36,35 @@ impl FromStr for LockfileToml {
     }
 }
 
+impl LockfileToml {
+    /// Describes how `new` differs from this lockfile: which packages it adds or removes, and
+    /// which packages it locks to different dependencies. Gives nothing if both lock the same
+    /// packages in the same way.
+    pub fn diff(&self, new: &LockfileToml) -> Vec<String> {
+        let mut changes = vec![];
+        for pkg in new.packages.difference(&self.packages) {
+            if self.packages.iter().any(|old| old.sum == pkg.sum) {
+                changes.push(format!("changed the dependencies of {}", pkg.sum));
+            } else {
+                changes.push(format!("added {}", pkg.sum));
+            }
+        }
+        for pkg in self.packages.difference(&new.packages) {
+            if !new.packages.iter().any(|x| x.sum == pkg.sum) {
+                changes.push(format!("removed {}", pkg.sum));
+            }
+        }
+
+        changes
+    }
+}
+
 impl Into<LockfileToml> for Graph<Summary> {
     fn into(self) -> LockfileToml {
-        let root = &self[NodeIndex::new(0)];
-
-        let pkg_iter = self
-            .sub_tree(self.find_id(root).unwrap())
-            .map(|(_, pkg)| LockedPkg {
-                sum: pkg.clone(),
-                dependencies: self
-                    .children(self.find_id(pkg).unwrap())
-                    .map(|x| x.1)
-                    .cloned()
-                    .collect(),
-            });
+        let pkg_iter = self.sub_tree(NodeIndex::new(0)).map(|(ix, pkg)| LockedPkg {
+            sum: pkg.clone(),
+            dependencies: self.children(ix).map(|x| x.1).cloned().collect(),
+        });
 
         let packages = IndexSet::from_iter(pkg_iter);
         LockfileToml { packages }
@@ -88,6 +104,119 @@ impl From<LockfileToml> for Graph<Summary> {
     }
 }
 
+/// The name of the file in a project's target directory which its last solve is saved in.
+pub const SOLVE_CACHE_FILE: &str = ".elba-solve.json";
+
+/// A solve which is saved along with a fingerprint of everything it was resolved from, so that it
+/// can be reused as is until any of that changes.
+#[derive(Clone, Deserialize, Debug, Serialize)]
+pub struct SolveCache {
+    pub key: String,
+    pub lock: LockfileToml,
+}
+
+impl SolveCache {
+    /// Loads the solve saved at `path` if it's still fresh. The current fingerprint is worked out
+    /// by `key`, which is given the saved solve (since the fingerprint can depend on what's in
+    /// it); if it doesn't match the saved one, or the saved solve can't be read, this gives None.
+    pub fn load<F>(path: &Path, key: F) -> Option<Graph<Summary>>
+    where
+        F: FnOnce(&Graph<Summary>) -> Option<String>,
+    {
+        let cache: SolveCache = serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+        let solve: Graph<Summary> = cache.lock.into();
+        if solve.inner.node_count() > 0 && key(&solve)? == cache.key {
+            Some(solve)
+        } else {
+            None
+        }
+    }
+
+    pub fn save(path: &Path, key: String, solve: Graph<Summary>) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let cache = SolveCache {
+            key,
+            lock: solve.into(),
+        };
+        fs::write(path, serde_json::to_vec(&cache)?)?;
+
+        Ok(())
+    }
+}
+
+/// The ways in which the dependencies declared by a manifest have moved on from what the
+/// lockfile pinned for the root package.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DriftReport {
+    /// Dependencies which the manifest declares but the lockfile doesn't have.
+    pub added: Vec<(PackageId, Constraint)>,
+    /// Dependencies which the lockfile has but the manifest doesn't declare anymore.
+    pub removed: Vec<Summary>,
+    /// Dependencies whose pinned version doesn't satisfy the manifest's requirement anymore.
+    pub changed: Vec<(Summary, Constraint)>,
+}
+
+impl DriftReport {
+    /// Compares the dependencies of a manifest against the direct dependencies of the root of a
+    /// lockfile's solve. An empty lock can't drift from anything.
+    pub fn new(lock: &Graph<Summary>, deps: &[(PackageId, Constraint)]) -> Self {
+        let mut report = DriftReport::default();
+        if lock.inner.node_count() == 0 {
+            return report;
+        }
+
+        let locked = lock
+            .children(NodeIndex::new(0))
+            .map(|(_, sum)| sum)
+            .collect::<Vec<_>>();
+
+        for (pid, c) in deps {
+            match locked.iter().find(|sum| sum.id().lowkey_eq(pid)) {
+                Some(sum) if !c.satisfies(sum.version()) => {
+                    report.changed.push(((*sum).clone(), c.clone()))
+                }
+                Some(_) => {}
+                None => report.added.push((pid.clone(), c.clone())),
+            }
+        }
+        report.removed = locked
+            .into_iter()
+            .filter(|sum| !deps.iter().any(|(pid, _)| sum.id().lowkey_eq(pid)))
+            .cloned()
+            .collect();
+
+        report
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl fmt::Display for DriftReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut lines = vec![];
+        lines.extend(
+            self.added
+                .iter()
+                .map(|(pid, c)| format!("added {} ({})", pid, c)),
+        );
+        lines.extend(self.removed.iter().map(|sum| format!("removed {}", sum)));
+        lines.extend(self.changed.iter().map(|(sum, c)| {
+            format!(
+                "changed {} (locked at {}, now {})",
+                sum.id(),
+                sum.version(),
+                c
+            )
+        }));
+
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -109,4 +238,72 @@ dependencies = [
 
         assert!(LockfileToml::from_str(lockfile).is_ok());
     }
+
+    fn drift(deps: &[(&str, &str)]) -> DriftReport {
+        let lockfile = r#"
+[[packages]]
+id = "good/package@dir+/here/there"
+version = "1.0.5"
+dependencies = [
+    { id = "terminator/one@index+tar+https://elba.io/pkg", version = "0.1.4" },
+    { id = "terminator/two@index+tar+https://elba.io/pkg", version = "2.0.0" },
+]
+
+[[packages]]
+id = "terminator/one@index+tar+https://elba.io/pkg"
+version = "0.1.4"
+
+[[packages]]
+id = "terminator/two@index+tar+https://elba.io/pkg"
+version = "2.0.0"
+        "#;
+        let lock: Graph<Summary> = LockfileToml::from_str(lockfile).unwrap().into();
+        let deps = deps
+            .iter()
+            .map(|(pid, c)| {
+                (
+                    PackageId::from_str(pid).unwrap(),
+                    Constraint::from_str(c).unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        DriftReport::new(&lock, &deps)
+    }
+
+    #[test]
+    fn lockfile_no_drift() {
+        let report = drift(&[
+            ("terminator/one@index+tar+https://elba.io/pkg", ">= 0.1.0"),
+            ("terminator/two@index+tar+https://elba.io/pkg", "2.0.0"),
+        ]);
+        assert!(report.is_empty(), "{}", report);
+        assert!(DriftReport::new(&Graph::default(), &[]).is_empty());
+    }
+
+    #[test]
+    fn lockfile_drift_added_removed() {
+        let report = drift(&[
+            ("terminator/one@index+tar+https://elba.io/pkg", ">= 0.1.0"),
+            ("terminator/three@index+tar+https://elba.io/pkg", ">= 1.0.0"),
+        ]);
+
+        assert_eq!(report.added.len(), 1);
+        assert_eq!(report.added[0].0.name().as_str(), "terminator/three");
+        assert_eq!(report.removed.len(), 1);
+        assert_eq!(report.removed[0].name().as_str(), "terminator/two");
+        assert!(report.changed.is_empty());
+    }
+
+    #[test]
+    fn lockfile_drift_changed() {
+        let report = drift(&[
+            ("terminator/one@index+tar+https://elba.io/pkg", ">= 0.2.0"),
+            ("terminator/two@index+tar+https://elba.io/pkg", "2.0.0"),
+        ]);
+
+        assert!(report.added.is_empty() && report.removed.is_empty());
+        assert_eq!(report.changed.len(), 1);
+        assert_eq!(report.changed[0].0.version().to_string(), "0.1.4");
+    }
 }
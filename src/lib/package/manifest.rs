@@ -1,24 +1,31 @@
 //! Package manifest files.
 
 use std::{
+    env,
+    ffi::{OsStr, OsString},
+    fmt, fs,
     path::{Path, PathBuf},
     str::FromStr,
 };
 
-use failure::{format_err, Error, ResultExt};
+use failure::{format_err, Error, Fail, ResultExt};
 use ignore::gitignore::GitignoreBuilder;
-use indexmap::IndexMap;
+use indexmap::{IndexMap, IndexSet};
 use semver::Version;
 use semver_constraints::Constraint;
-use serde::Deserialize;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use toml;
+use toml_edit;
 use url::Url;
 use walkdir::{DirEntry, WalkDir};
 
-use super::*;
+use super::{
+    cfg::{Cfg, Platform},
+    *,
+};
 use crate::{
     remote::resolution::{DirectRes, IndexRes},
-    util::{valid_file, SubPath},
+    util::{error::Error as ErrorKind, valid_file, SubPath},
 };
 
 // TODO: Package aliasing. Have dummy alias files in the root target folder.
@@ -39,6 +46,34 @@ use crate::{
 //
 // With this in place, we can safely avoid module namespace conflicts.
 
+/// Version control directories, which are never packaged.
+const VCS_DIRS: &[&str] = &[".git", ".hg", ".svn", ".pijul", "_darcs"];
+
+/// The environment variable which overrides the target directory of every project.
+pub const TARGET_DIR_ENV: &str = "ELBA_TARGET_DIR";
+
+/// The name of the manifest file a package's directory has, unless told otherwise.
+pub const MANIFEST_FILE: &str = "elba.toml";
+
+/// The directory in a project which holds its vendored cache (see `BuildCtx::vendored`). It's
+/// never part of the package itself, so it's neither packaged nor hashed.
+pub const VENDOR_DIR: &str = "vendor";
+
+/// Splits the path of a workspace member into the directory the member is in and the name of its
+/// manifest there. Members usually point at a directory with an `elba.toml`, but they can point
+/// straight at a manifest (any file ending in `.toml`) with another name instead.
+pub fn member_manifest(member: &SubPath) -> (PathBuf, PathBuf) {
+    if member.0.extension() == Some(OsStr::new("toml")) {
+        let dir = member.0.parent().unwrap_or_else(|| Path::new(""));
+        (
+            dir.to_path_buf(),
+            PathBuf::from(member.0.file_name().unwrap()),
+        )
+    } else {
+        (member.0.clone(), PathBuf::from(MANIFEST_FILE))
+    }
+}
+
 #[serde(deny_unknown_fields)]
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Manifest {
@@ -47,18 +82,37 @@ pub struct Manifest {
     pub dependencies: IndexMap<Name, DepReq>,
     #[serde(default = "IndexMap::new")]
     pub dev_dependencies: IndexMap<Name, DepReq>,
+    /// Dependencies which only apply on platforms matching a predicate, declared in tables like
+    /// `[target.'cfg(os = "windows")'.dependencies]`.
+    #[serde(default, rename = "target")]
+    pub platform_deps: IndexMap<Cfg, PlatformDeps>,
     #[serde(default)]
     pub targets: Targets,
     #[serde(default)]
-    pub workspace: IndexMap<Name, SubPath>,
+    pub workspace: Workspace,
+    /// Index aliases to use on top of the configured ones when this package is the root. Members
+    /// of a workspace inherit the indices of the workspace root (see `Manifest::indices_for`).
+    #[serde(default)]
+    pub indices: IndexMap<String, IndexRes>,
     #[serde(default)]
     pub scripts: IndexMap<String, String>,
+    #[serde(default)]
+    pub features: IndexMap<String, Vec<String>>,
+    /// Status badges to show for the package, keyed by the badge's service (e.g. `travis-ci`).
+    #[serde(default)]
+    pub badges: IndexMap<String, IndexMap<String, String>>,
+    /// Local working copies to use in place of a dependency, wherever it appears in the
+    /// dependency graph. Only the root package's overrides are used, and they're never published.
+    #[serde(default, rename = "override")]
+    pub overrides: IndexMap<Name, Override>,
+    #[serde(default)]
+    pub build: BuildConfig,
 }
 
 impl Manifest {
     // Returns only the workspace portion of a manifest.
-    pub fn workspace(s: &str) -> Option<IndexMap<Name, SubPath>> {
-        toml::value::Value::try_from(&s)
+    pub fn workspace(s: &str) -> Option<Workspace> {
+        s.parse::<toml::Value>()
             .ok()?
             .get("workspace")?
             .clone()
@@ -66,6 +120,123 @@ impl Manifest {
             .ok()
     }
 
+    /// Returns only the indices portion of a manifest. Like `workspace`, this works for a manifest
+    /// which is just a workspace, without a package of its own.
+    pub fn workspace_indices(s: &str) -> Option<IndexMap<String, IndexRes>> {
+        // The order of the indices is their priority, and going through a `toml::Value` would
+        // sort them by name
+        #[derive(Deserialize)]
+        struct Indices {
+            #[serde(default)]
+            indices: IndexMap<String, IndexRes>,
+        }
+
+        toml::from_str::<Indices>(s).ok().map(|x| x.indices)
+    }
+
+    /// The indices to build the package at `root` with, starting from the configured `base`.
+    ///
+    /// If `root` is a member of a workspace (that is, the nearest manifest above it lists it in
+    /// its `[workspace]`), the workspace root's indices are layered on top of `base`, and then
+    /// this package's own indices on top of those. An index declared again under the same name
+    /// replaces the earlier one in place, so the default index only changes if `base` is empty;
+    /// new names go at the end.
+    pub fn indices_for(
+        &self,
+        root: &Path,
+        base: &IndexMap<String, IndexRes>,
+    ) -> IndexMap<String, IndexRes> {
+        let mut indices = base.clone();
+        if let Some(ws) = workspace_root(root) {
+            let contents = fs::read_to_string(ws.join("elba.toml")).unwrap_or_default();
+            indices.extend(Manifest::workspace_indices(&contents).unwrap_or_default());
+        }
+        indices.extend(self.indices.clone());
+
+        indices
+    }
+
+    /// Adds the dependency `name` to the manifest `raw` (under `[dev_dependencies]` if `dev` is
+    /// set), returning the edited manifest. Everything else in the manifest, comments and
+    /// formatting included, is left as it was.
+    ///
+    /// If the package is already a dependency, this fails unless `force` is set, in which case the
+    /// existing entry is replaced in place.
+    pub fn add_dependency(
+        raw: &str,
+        name: &str,
+        req: &DepReq,
+        dev: bool,
+        force: bool,
+    ) -> Result<String> {
+        let name = Name::from_str(name)?;
+        let section = if dev {
+            "dev_dependencies"
+        } else {
+            "dependencies"
+        };
+        let mut doc = raw
+            .parse::<toml_edit::Document>()
+            .with_context(|e| format_err!("invalid manifest file: {}", e))?;
+
+        let deps = doc.as_table_mut().entry(section);
+        if deps.is_none() {
+            *deps = toml_edit::table();
+        }
+        let deps = deps
+            .as_table_mut()
+            .ok_or_else(|| format_err!("`{}` in the manifest isn't a table", section))?;
+
+        // Names are compared normalized, so `a/b_c` and `A/b-c` are the same dependency
+        let existing = deps
+            .iter()
+            .map(|(k, _)| k)
+            .find(|k| Name::from_str(k).ok().as_ref() == Some(&name))
+            .map(|k| k.to_owned());
+        let key = match existing {
+            Some(key) if !force => bail!(
+                "`{}` is already in [{}] (as `{}`); use --force to replace it",
+                name,
+                section,
+                key
+            ),
+            Some(key) => key,
+            None => name.to_string(),
+        };
+        // The comments around an entry we're replacing stay where they were
+        let entry = deps.entry(&key);
+        *entry = match entry.as_value() {
+            Some(old) => toml_edit::Item::Value(toml_edit::decorated(
+                req.to_toml(),
+                old.decor().prefix(),
+                old.decor().suffix(),
+            )),
+            None => toml_edit::value(req.to_toml()),
+        };
+
+        let res = doc.to_string();
+        Manifest::from_str(&res)
+            .with_context(|e| format_err!("adding `{}` would break the manifest: {}", name, e))?;
+
+        Ok(res)
+    }
+
+    /// Checks that the running version of elba is one the package says it can be built with.
+    pub fn check_elba_version(&self) -> Result<()> {
+        let elba = Version::parse(env!("CARGO_PKG_VERSION")).unwrap();
+        match &self.package.elba_version {
+            Some(req) if !req.satisfies(&elba) => Err(ErrorKind::UnsupportedElbaVersion
+                .context(format!(
+                    "{} requires elba {}, but this is elba {}",
+                    self.name(),
+                    req,
+                    elba
+                ))
+                .into()),
+            _ => Ok(()),
+        }
+    }
+
     pub fn version(&self) -> &Version {
         &self.package.version
     }
@@ -74,21 +245,202 @@ impl Manifest {
         &self.package.name
     }
 
+    /// The authors of the package, split into their names and emails. An author which isn't
+    /// written like `Name <email>` (or just `Name`) is taken to be all name, with no email;
+    /// `validate` warns about those.
+    pub fn authors(&self) -> Vec<Author> {
+        self.package
+            .authors
+            .iter()
+            .map(|raw| {
+                Author::from_str(raw).unwrap_or_else(|_| Author {
+                    name: raw.trim().to_owned(),
+                    email: None,
+                })
+            })
+            .collect()
+    }
+
+    /// Whether this is a virtual package: one which doesn't get built at all, either because it
+    /// says so with `build = false` or because it doesn't have any targets. A virtual package
+    /// still gets resolved, and packages which depend on it get its dependencies.
+    pub fn is_virtual(&self) -> bool {
+        !self.package.build
+            || (!self.targets.has_lib()
+                && self.targets.bin.is_empty()
+                && self.targets.test.is_empty()
+                && self.targets.example.is_empty())
+    }
+
+    /// Returns the sources which should replace each overridden dependency, with relative paths
+    /// resolved against `root`.
+    pub fn overrides(&self, root: &Path) -> IndexMap<Name, DirectRes> {
+        self.overrides
+            .iter()
+            .map(|(n, o)| {
+                (
+                    n.clone(),
+                    DirectRes::Dir {
+                        path: root.join(&o.path),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// The directory that this project's build outputs go in. `ELBA_TARGET_DIR` wins over the
+    /// `target-dir` in the manifest, which wins over the default of `target`; relative paths are
+    /// resolved against `root`.
+    pub fn target_dir(&self, root: &Path) -> PathBuf {
+        self.target_dir_from(root, env::var_os(TARGET_DIR_ENV))
+    }
+
+    /// Like `target_dir`, with `from_env` standing in for the value of `ELBA_TARGET_DIR`.
+    fn target_dir_from(&self, root: &Path, from_env: Option<OsString>) -> PathBuf {
+        from_env
+            .filter(|x| !x.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| self.build.target_dir.clone())
+            .map(|x| root.join(x))
+            .unwrap_or_else(|| root.join("target"))
+    }
+
+    /// Returns the binary targets with the given names along with their index in `targets.bin`,
+    /// in the order they're declared. An empty list of names gives every binary target.
+    pub fn bins(&self, names: &[&str]) -> Result<Vec<(usize, &BinTarget)>> {
+        if let Some(missing) = names
+            .iter()
+            .find(|n| !self.targets.bin.iter().any(|bin| &bin.name == *n))
+        {
+            bail!(
+                "the package doesn't have a binary target named `{}` (it has {})",
+                missing,
+                if self.targets.bin.is_empty() {
+                    "none".to_owned()
+                } else {
+                    self.targets
+                        .bin
+                        .iter()
+                        .map(|bin| format!("`{}`", bin.name))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                }
+            )
+        }
+
+        Ok(self
+            .targets
+            .bin
+            .iter()
+            .enumerate()
+            .filter(|(_, bin)| names.is_empty() || names.contains(&bin.name.as_str()))
+            .collect())
+    }
+
+    /// Returns the example targets with the given names along with their index in
+    /// `targets.example`, in the order they're declared. An empty list of names gives every
+    /// example target.
+    pub fn examples(&self, names: &[&str]) -> Result<Vec<(usize, &ExampleTarget)>> {
+        if let Some(missing) = names
+            .iter()
+            .find(|n| !self.targets.example.iter().any(|ex| &ex.name == *n))
+        {
+            bail!(
+                "the package doesn't have an example named `{}` (it has {})",
+                missing,
+                if self.targets.example.is_empty() {
+                    "none".to_owned()
+                } else {
+                    self.targets
+                        .example
+                        .iter()
+                        .map(|ex| format!("`{}`", ex.name))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                }
+            )
+        }
+
+        Ok(self
+            .targets
+            .example
+            .iter()
+            .enumerate()
+            .filter(|(_, ex)| names.is_empty() || names.contains(&ex.name.as_str()))
+            .collect())
+    }
+
+    /// Returns the dependencies of this package which apply on the given platform: everything in
+    /// `[dependencies]`, along with the dependencies of every matching `[target]` table.
+    pub fn dependencies_for<'a>(
+        &'a self,
+        platform: &'a Platform,
+    ) -> impl Iterator<Item = (&'a Name, &'a DepReq)> {
+        self.dependencies.iter().chain(
+            self.platform_deps
+                .iter()
+                .filter(move |(cfg, _)| cfg.matches(platform))
+                .flat_map(|(_, deps)| deps.dependencies.iter()),
+        )
+    }
+
+    /// Like `dependencies_for`, but for dev-dependencies.
+    pub fn dev_dependencies_for<'a>(
+        &'a self,
+        platform: &'a Platform,
+    ) -> impl Iterator<Item = (&'a Name, &'a DepReq)> {
+        self.dev_dependencies.iter().chain(
+            self.platform_deps
+                .iter()
+                .filter(move |(cfg, _)| cfg.matches(platform))
+                .flat_map(|(_, deps)| deps.dev_dependencies.iter()),
+        )
+    }
+
+    /// Returns the renamed dependencies of this package, mapping the name each of them is declared
+    /// with to the real name of the package it refers to. Dependencies of every kind and for every
+    /// platform are included.
+    pub fn aliases(&self) -> IndexMap<Name, Name> {
+        self.dependencies
+            .iter()
+            .chain(self.dev_dependencies.iter())
+            .chain(
+                self.platform_deps
+                    .values()
+                    .flat_map(|deps| deps.dependencies.iter().chain(deps.dev_dependencies.iter())),
+            )
+            .filter_map(|(alias, dep)| dep.package().map(|real| (alias.clone(), real.clone())))
+            .collect()
+    }
+
+    /// Returns the dependencies of this package, including only the optional dependencies which
+    /// are turned on by the `features` given (and the `default` feature, if it exists) and the
+    /// platform-specific dependencies which apply to `platform`.
     pub fn deps(
         &self,
         ixmap: &IndexMap<String, IndexRes>,
         parent_pkg: &PackageId,
         dev_deps: bool,
+        features: &[String],
+        platform: &Platform,
     ) -> Result<IndexMap<PackageId, Constraint>> {
+        let enabled = self.enabled_deps(features)?;
+
         let mut deps = IndexMap::new();
-        for (n, dep) in &self.dependencies {
+        for (n, dep) in self.dependencies_for(platform) {
+            if dep.is_optional() && !enabled.contains(n) {
+                continue;
+            }
             let dep = dep.clone();
             let (pid, c) = dep.into_dep(ixmap, parent_pkg, n.clone())?;
             deps.insert(pid, c);
         }
 
         if dev_deps {
-            for (n, dep) in &self.dev_dependencies {
+            for (n, dep) in self.dev_dependencies_for(platform) {
+                if dep.is_optional() && !enabled.contains(n) {
+                    continue;
+                }
                 let dep = dep.clone();
                 let (pid, c) = dep.into_dep(ixmap, parent_pkg, n.clone())?;
                 deps.insert(pid, c);
@@ -98,6 +450,44 @@ impl Manifest {
         Ok(deps)
     }
 
+    /// Returns the names of all the optional dependencies which are turned on by the given
+    /// features. The `default` feature is always turned on if the package declares it.
+    ///
+    /// A feature can enable either an optional dependency (by its name) or other features.
+    pub fn enabled_deps(&self, features: &[String]) -> Result<IndexSet<Name>> {
+        enabled_items(self.name(), &self.features, features)?
+            .iter()
+            .map(|item| self.optional_dep(item))
+            .collect()
+    }
+
+    fn optional_dep(&self, name: &str) -> Result<Name> {
+        let name = Name::from_str(name).with_context(|e| {
+            format_err!("`{}` is neither a feature nor a dependency: {}", name, e)
+        })?;
+        let dep = self
+            .dependencies
+            .get(&name)
+            .or_else(|| self.dev_dependencies.get(&name))
+            .or_else(|| {
+                self.platform_deps.values().find_map(|deps| {
+                    deps.dependencies
+                        .get(&name)
+                        .or_else(|| deps.dev_dependencies.get(&name))
+                })
+            })
+            .ok_or_else(|| format_err!("`{}` is neither a feature nor a dependency", name))?;
+
+        if !dep.is_optional() {
+            bail!(
+                "dependency {} is enabled by a feature, but it isn't optional",
+                name
+            )
+        }
+
+        Ok(name)
+    }
+
     pub fn list_files<P>(
         &self,
         pkg_root: &Path,
@@ -139,7 +529,64 @@ impl Manifest {
         Ok(walker)
     }
 
-    pub fn validate(&self) -> Result<()> {
+    /// Lists the files (relative to `pkg_root`) which should be shipped when this package is
+    /// packaged for publishing, in a stable order.
+    ///
+    /// If `include` is given, only the files matching one of its globs are packaged. Files
+    /// matching `exclude` (or the `.gitignore`) are never packaged, even if they're included too.
+    /// The `target` directory, the vendored cache and VCS directories are always left out, and the
+    /// manifest itself is always packaged.
+    pub fn package_files(&self, pkg_root: &Path) -> Result<Vec<PathBuf>> {
+        let includes = if let Some(rs) = self.package.include.as_ref() {
+            let mut includes = GitignoreBuilder::new(pkg_root);
+            for r in rs {
+                includes.add_line(None, r)?;
+            }
+            Some(
+                includes
+                    .build()
+                    .with_context(|e| format_err!("invalid includes: {}", e))?,
+            )
+        } else {
+            None
+        };
+
+        let (target, vendor) = (self.target_dir(pkg_root), pkg_root.join(VENDOR_DIR));
+        let mut files = self
+            .list_files(pkg_root, pkg_root, |x| {
+                x.path() != target
+                    && x.path() != vendor
+                    && !VCS_DIRS.iter().any(|d| x.file_name() == *d)
+            })?
+            .filter_map(|x| {
+                let path = x.path().strip_prefix(pkg_root).ok()?.to_path_buf();
+                let included = match includes.as_ref() {
+                    _ if path == Path::new("elba.toml") => true,
+                    Some(i) => i.matched_path_or_any_parents(x.path(), false).is_ignore(),
+                    None => true,
+                };
+
+                if included {
+                    Some(path)
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        files.sort();
+        Ok(files)
+    }
+
+    /// Checks the manifest for problems which parsing it doesn't catch, returning every one that
+    /// was found. A manifest with any error-level problems is rejected by `from_str`, while the
+    /// warnings are only shown to the user.
+    ///
+    /// Invalid versions and binary targets without a `main` are caught before this, since the
+    /// manifest can't be parsed without them.
+    pub fn validate(&self) -> Vec<Lint> {
+        let mut lints = vec![];
+
         if self
             .package
             .description
@@ -147,7 +594,7 @@ impl Manifest {
             .filter(|description| description.len() > 244)
             .is_some()
         {
-            bail!(format_err!("descrption is over 244 characters"));
+            lints.push(Lint::error("descrption is over 244 characters"));
         }
         if self
             .package
@@ -156,10 +603,15 @@ impl Manifest {
             .filter(|license| license.len() > 20)
             .is_some()
         {
-            bail!(format_err!("license is over 20 characters"));
+            lints.push(Lint::error("license is over 20 characters"));
+        }
+        for author in &self.package.authors {
+            if let Err(e) = Author::from_str(author) {
+                lints.push(Lint::warning(format!("author `{}` {}", author, e)));
+            }
         }
         if self.package.keywords.len() > 5 {
-            bail!(format_err!("keywords should no more than 5"));
+            lints.push(Lint::error("keywords should no more than 5"));
         }
         if self
             .package
@@ -167,17 +619,338 @@ impl Manifest {
             .iter()
             .any(|keyword| keyword.trim().is_empty())
         {
-            bail!(format_err!("one of the keywords is empty"))
+            lints.push(Lint::error("one of the keywords is empty"));
         }
         if self
             .package
             .keywords
             .iter()
-            .any(|keyword| keyword.split_whitespace().skip(1).next().is_some())
+            .any(|keyword| keyword.split_whitespace().nth(1).is_some())
+        {
+            lints.push(Lint::error("one of the keywords contains whitespace"));
+        }
+        for (ix, bin) in self.targets.bin.iter().enumerate() {
+            if let Some(prev) = self.targets.bin[..ix].iter().find(|x| x.name == bin.name) {
+                lints.push(Lint::error(format!(
+                    "binary targets `{}` and `{}` are both named `{}`",
+                    prev.main, bin.main, bin.name
+                )));
+            }
+        }
+        // Examples end up in the same directory as binaries, so their names can't overlap either
+        for (ix, ex) in self.targets.example.iter().enumerate() {
+            let prev = self
+                .targets
+                .bin
+                .iter()
+                .map(|x| (&x.name, &x.main))
+                .chain(
+                    self.targets.example[..ix]
+                        .iter()
+                        .map(|x| (&x.name, &x.main)),
+                )
+                .find(|(name, _)| **name == ex.name);
+            if let Some((_, main)) = prev {
+                lints.push(Lint::error(format!(
+                    "targets `{}` and `{}` are both named `{}`",
+                    main, ex.main, ex.name
+                )));
+            }
+        }
+        for feature in self.features.keys() {
+            if let Err(e) = self.enabled_deps(std::slice::from_ref(feature)) {
+                lints.push(Lint::error(format!("invalid feature `{}`: {}", feature, e)));
+            }
+        }
+
+        let mut all_deps = self
+            .dependencies
+            .iter()
+            .chain(self.dev_dependencies.iter())
+            .chain(
+                self.platform_deps
+                    .values()
+                    .flat_map(|deps| deps.dependencies.iter().chain(deps.dev_dependencies.iter())),
+            );
+        if all_deps.any(|(n, dep)| dep.package().unwrap_or(n) == self.name()) {
+            lints.push(Lint::error(format!(
+                "package `{}` depends on itself",
+                self.name()
+            )));
+        }
+
+        // The same package can be depended on twice under different aliases, but only if the two
+        // come from different places
+        let mut real_names = IndexMap::new();
+        for (n, dep) in &self.dependencies {
+            let real = dep.package().unwrap_or(n);
+            let from = match dep {
+                DepReq::Registry(_) => None,
+                DepReq::RegLong { index, .. } => index.clone(),
+                DepReq::Local { path, .. } => Some(path.display().to_string()),
+                DepReq::Git { git, .. } => Some(git.to_string()),
+                DepReq::Tar { tar, .. } => Some(tar.to_string()),
+            };
+            if let Some(other) = real_names.insert((real, from), n) {
+                lints.push(Lint::error(format!(
+                    "dependencies `{}` and `{}` both refer to package `{}`",
+                    other, n, real
+                )));
+            }
+        }
+
+        let mut exported = IndexMap::new();
+        for (name, lib) in self.targets.lib_targets() {
+            let target = match name {
+                Some(name) => format!("lib target `{}`", name),
+                None => "the lib target".to_string(),
+            };
+            let valid_name = match name {
+                Some(name) => {
+                    !name.is_empty()
+                        && name
+                            .chars()
+                            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+                }
+                None => true,
+            };
+            if !valid_name {
+                lints.push(Lint::error(format!(
+                    "{} can only have letters, numbers, _, and - in its name",
+                    target
+                )));
+            }
+            if lib.mods.is_empty() {
+                lints.push(Lint::warning(format!(
+                    "{} (in `{}`) doesn't export any modules",
+                    target,
+                    lib.path.0.display()
+                )));
+            }
+            // Libraries are imported side by side, so a module can only come from one of them
+            for module in &lib.mods {
+                let module = module.trim_matches('.');
+                if let Some(other) = exported.insert(module, target.clone()) {
+                    if other != target {
+                        lints.push(Lint::error(format!(
+                            "module `{}` is exported by both {} and {}",
+                            module, other, target
+                        )));
+                    }
+                }
+            }
+        }
+        let bins = self
+            .targets
+            .bin
+            .iter()
+            .map(|bin| ("binary", bin.name.as_str(), bin.main.as_str()))
+            .chain(self.targets.test.iter().map(|test| {
+                (
+                    "test",
+                    test.name.as_deref().unwrap_or(""),
+                    test.main.as_str(),
+                )
+            }))
+            .chain(
+                self.targets
+                    .example
+                    .iter()
+                    .map(|ex| ("example", ex.name.as_str(), ex.main.as_str())),
+            );
+        for (kind, name, main) in bins {
+            if main.trim().is_empty() {
+                lints.push(Lint::warning(if name.is_empty() {
+                    format!("a {} target has an empty `main`", kind)
+                } else {
+                    format!("{} target `{}` has an empty `main`", kind, name)
+                }));
+            }
+        }
+
+        if !self.package.build
+            && (self.targets.has_lib()
+                || !self.targets.bin.is_empty()
+                || !self.targets.test.is_empty()
+                || !self.targets.example.is_empty())
         {
-            bail!(format_err!("one of the keywords contains whitespace"));
+            lints.push(Lint::warning(
+                "the package has `build = false`, so its targets are never built",
+            ));
+        }
+
+        if let Err(e) = self.workspace.selected(false) {
+            lints.push(Lint::error(e.to_string()));
+        }
+
+        for glob in self.excluded_includes() {
+            lints.push(Lint::warning(format!(
+                "`{}` is both included and excluded, so it's never packaged",
+                glob
+            )));
+        }
+
+        lints
+    }
+
+    /// The globs in `include` which are excluded as a whole by the globs in `exclude`.
+    fn excluded_includes(&self) -> Vec<&str> {
+        let (includes, excludes) = match (&self.package.include, &self.package.exclude) {
+            (Some(i), Some(e)) => (i, e),
+            _ => return vec![],
+        };
+
+        let mut builder = GitignoreBuilder::new("");
+        for glob in excludes {
+            if builder.add_line(None, glob).is_err() {
+                return vec![];
+            }
+        }
+        let excludes = match builder.build() {
+            Ok(e) => e,
+            Err(_) => return vec![],
+        };
+
+        includes
+            .iter()
+            .map(|glob| glob.as_str())
+            .filter(|glob| {
+                let path = glob
+                    .trim_end_matches("**")
+                    .trim_end_matches('*')
+                    .trim_end_matches('/');
+                let is_dir = path.len() != glob.len();
+                !path.is_empty()
+                    && excludes
+                        .matched_path_or_any_parents(path, is_dir)
+                        .is_ignore()
+            })
+            .collect()
+    }
+}
+
+/// Works out everything a package's `features` table turns on for the features requested of it:
+/// the names of the optional dependencies among the entries of every feature which ends up
+/// turned on. The `default` feature is always turned on if the package declares it.
+///
+/// A feature can enable either an optional dependency (by its name) or other features. Checking
+/// that the names which aren't features really are optional dependencies is up to the caller.
+pub fn enabled_items(
+    name: &Name,
+    table: &IndexMap<String, Vec<String>>,
+    features: &[String],
+) -> Result<IndexSet<String>> {
+    let mut queue = features.to_vec();
+    if table.contains_key("default") {
+        queue.push("default".to_string());
+    }
+
+    let mut seen = IndexSet::new();
+    let mut res = IndexSet::new();
+    while let Some(feature) = queue.pop() {
+        if !seen.insert(feature.clone()) {
+            continue;
+        }
+
+        let enables = table.get(&feature).ok_or_else(|| {
+            format_err!(
+                "package {} doesn't have a feature named `{}`",
+                name,
+                feature
+            )
+        })?;
+
+        for item in enables {
+            if table.contains_key(item) {
+                queue.push(item.clone());
+            } else {
+                res.insert(item.clone());
+            }
+        }
+    }
+
+    Ok(res)
+}
+
+/// The `[workspace]` section of a manifest: the packages in subdirectories of this one.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Workspace {
+    /// The members which workspace-wide commands work on unless they're told to use every
+    /// member. If this is empty, they use every member.
+    #[serde(
+        default,
+        rename = "default-members",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub default_members: Vec<Name>,
+    #[serde(flatten)]
+    pub members: IndexMap<Name, SubPath>,
+}
+
+impl Workspace {
+    pub fn get(&self, name: &Name) -> Option<&SubPath> {
+        self.members.get(name)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// The members that a workspace-wide command should work on: the default members (in the
+    /// order they're listed), or every member if `all` is set or there aren't any default ones.
+    ///
+    /// Every default member has to be a member of the workspace.
+    pub fn selected(&self, all: bool) -> Result<Vec<(&Name, &SubPath)>> {
+        if all || self.default_members.is_empty() {
+            return Ok(self.members.iter().collect());
+        }
+
+        self.default_members
+            .iter()
+            .map(|name| match self.members.get_full(name) {
+                Some((_, name, path)) => Ok((name, path)),
+                None => bail!("default member `{}` isn't a member of the workspace", name),
+            })
+            .collect()
+    }
+}
+
+/// How bad a problem found by `Manifest::validate` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem found with a manifest which otherwise parsed fine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Lint {
+    fn warning(message: impl Into<String>) -> Self {
+        Lint {
+            severity: Severity::Warning,
+            message: message.into(),
+        }
+    }
+
+    fn error(message: impl Into<String>) -> Self {
+        Lint {
+            severity: Severity::Error,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "warning: {}", self.message),
+            Severity::Error => write!(f, "error: {}", self.message),
         }
-        Ok(())
     }
 }
 
@@ -185,14 +958,52 @@ impl FromStr for Manifest {
     type Err = failure::Error;
 
     fn from_str(raw: &str) -> Result<Self> {
+        check_dependencies(raw)?;
         let toml: Manifest = toml::from_str(raw)
             .with_context(|e| format_err!("invalid manifest file: {}", e))
             .map_err(Error::from)?;
-        toml.validate()?;
+        if let Some(lint) = toml
+            .validate()
+            .into_iter()
+            .find(|lint| lint.severity == Severity::Error)
+        {
+            bail!("{}", lint.message)
+        }
         Ok(toml)
     }
 }
 
+/// Reads every dependency in a manifest with `DepReq::from_manifest_value`, which is also how
+/// they're read when the manifest is parsed for real. Doing it here first means that whatever's
+/// wrong with a dependency is reported along with its name.
+fn check_dependencies(raw: &str) -> Result<()> {
+    // Anything else which is wrong with the manifest is left for parsing it to report
+    let toml: toml::Value = match toml::from_str(raw) {
+        Ok(toml) => toml,
+        Err(_) => return Ok(()),
+    };
+
+    let platforms = toml
+        .get("target")
+        .and_then(|x| x.as_table())
+        .into_iter()
+        .flat_map(|x| x.values());
+    let deps = Some(&toml)
+        .into_iter()
+        .chain(platforms)
+        .flat_map(|x| vec![x.get("dependencies"), x.get("dev_dependencies")])
+        .flatten()
+        .filter_map(|x| x.as_table())
+        .flat_map(|x| x.iter());
+
+    for (name, req) in deps {
+        DepReq::from_manifest_value(req)
+            .with_context(|e| format_err!("invalid manifest file: dependency {}: {}", name, e))?;
+    }
+
+    Ok(())
+}
+
 #[serde(deny_unknown_fields)]
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct PackageInfo {
@@ -202,83 +1013,599 @@ pub struct PackageInfo {
     pub description: Option<String>,
     #[serde(default = "Vec::new")]
     pub keywords: Vec<String>,
+    #[serde(default = "Vec::new")]
+    pub categories: Vec<String>,
     pub homepage: Option<String>,
     pub repository: Option<String>,
     pub readme: Option<SubPath>,
     pub license: Option<String>,
+    pub include: Option<Vec<String>>,
     pub exclude: Option<Vec<String>>,
+    /// The versions of elba which can build this package.
+    #[serde(
+        default,
+        rename = "elba-version",
+        deserialize_with = "constraint::deserialize_opt"
+    )]
+    pub elba_version: Option<Constraint>,
+    /// Whether the package has anything to build; see `Manifest::is_virtual`.
+    #[serde(default = "default_build")]
+    pub build: bool,
+    /// Whether builds of the package are kept out of the build cache; see
+    /// `Source::is_excluded_from_cache`.
+    #[serde(default, rename = "exclude-from-cache")]
+    pub exclude_from_cache: bool,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[serde(untagged, deny_unknown_fields)]
-pub enum DepReq {
-    Registry(Constraint),
-    RegLong {
-        version: Constraint,
-        index: String,
-    },
-    Local {
-        path: PathBuf,
-    },
-    Git {
-        git: Url,
-        #[serde(default = "default_tag")]
-        tag: String,
-    },
+fn default_build() -> bool {
+    true
 }
 
-fn default_tag() -> String {
-    "master".to_owned()
+/// One of the authors of a package, as written in its manifest: `Name <email>`, or just `Name`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Author {
+    pub name: String,
+    pub email: Option<String>,
 }
 
-impl DepReq {
-    pub fn into_dep(
-        self,
-        ixmap: &IndexMap<String, IndexRes>,
-        parent_pkg: &PackageId,
-        n: Name,
-    ) -> Result<(PackageId, Constraint)> {
-        match self {
-            DepReq::Registry(c) => {
-                let def_index = ixmap
-                    .get_index(0)
-                    .ok_or_else(|| format_err!("no default index"))?;
-                let pi = PackageId::new(n, def_index.1.clone().into());
-                Ok((pi, c))
-            }
-            DepReq::RegLong { version, index } => {
-                if let Some(mapped) = ixmap.get(&index) {
-                    let pi = PackageId::new(n, mapped.clone().into());
-                    Ok((pi, version))
-                } else {
-                    let ix = IndexRes::from_str(&index)?;
-                    let pi = PackageId::new(n, ix.into());
-                    Ok((pi, version))
-                }
+impl FromStr for Author {
+    type Err = Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        let raw = raw.trim();
+        let (name, email) = match (raw.find('<'), raw.find('>')) {
+            (None, None) => (raw, None),
+            (Some(open), Some(close))
+                if open < close && close == raw.len() - 1 && !raw[open + 1..].contains('<') =>
+            {
+                (raw[..open].trim_end(), Some(raw[open + 1..close].trim()))
             }
-            DepReq::Local { path } => {
-                if let &Resolution::Direct(DirectRes::Dir { path: parent_root }) =
-                    &parent_pkg.resolution()
-                {
-                    let res = DirectRes::Dir {
-                        path: parent_root.join(path),
-                    };
-                    let pi = PackageId::new(n, res.into());
-                    Ok((pi, Constraint::any()))
-                } else {
-                    bail!(format_err!(
-                        "can't resolve local dependency {} because it's \
-                        parent package {} is not local.",
-                        path.display(),
-                        parent_pkg.name()
-                    ))
-                }
+            _ => bail!("should be a name, followed by an email in angle brackets if there is one"),
+        };
+
+        if name.is_empty() {
+            bail!("doesn't have a name")
+        }
+        if email.map(str::is_empty) == Some(true) {
+            bail!("has empty angle brackets where the email should be")
+        }
+
+        Ok(Author {
+            name: name.to_owned(),
+            email: email.map(str::to_owned),
+        })
+    }
+}
+
+impl fmt::Display for Author {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.email {
+            Some(email) => write!(f, "{} <{}>", self.name, email),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// Settings for how the project is built, declared in the `[build]` table.
+#[serde(deny_unknown_fields)]
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildConfig {
+    /// Where build outputs go instead of `target`.
+    #[serde(default, rename = "target-dir")]
+    pub target_dir: Option<PathBuf>,
+    /// Environment variables to set for the prebuild script and the compiler, declared in the
+    /// `[build.env]` table.
+    #[serde(default)]
+    pub env: IndexMap<String, EnvVar>,
+    /// Extra compiler flags to build packages with, by package name, declared in the
+    /// `[build.flags]` table. Only the flags of the root package's manifest are used.
+    #[serde(default)]
+    pub flags: IndexMap<Name, Vec<String>>,
+}
+
+impl BuildConfig {
+    /// The flags given for each package in `[build.flags]`, in the order they're written, with
+    /// the whitespace in each one collapsed and empty ones left out. Each flag is kept whole, so
+    /// a flag which takes a value (like `"-p contrib"`) has to be written as a single string.
+    pub fn flags(&self) -> IndexMap<Name, Vec<String>> {
+        self.flags
+            .iter()
+            .map(|(name, flags)| {
+                let flags = flags
+                    .iter()
+                    .map(|x| x.split_whitespace().collect::<Vec<_>>().join(" "))
+                    .filter(|x| !x.is_empty())
+                    .collect::<Vec<_>>();
+                (name.clone(), flags)
+            })
+            .collect()
+    }
+
+    /// Works out the value of every declared environment variable, expanding each `${VAR}` with
+    /// the value `parent` gives for `VAR` (or nothing, if it gives None). `$$` stands for a
+    /// literal `$`.
+    ///
+    /// A variable marked as required makes this fail if any variable it refers to isn't set.
+    pub fn resolve_env<F>(&self, parent: F) -> Result<IndexMap<String, String>>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        self.env
+            .iter()
+            .map(|(name, var)| {
+                let (value, required) = match var {
+                    EnvVar::Value(value) => (value.clone(), false),
+                    // Leaving out the value passes the variable through from the parent
+                    EnvVar::Detailed { value, required } => (
+                        value.clone().unwrap_or_else(|| format!("${{{}}}", name)),
+                        *required,
+                    ),
+                };
+                let value = expand_env(&value, |var| match parent(var) {
+                    Some(x) => Ok(x),
+                    None if required => bail!(
+                        "build env var `{}` is required, but `{}` isn't set",
+                        name,
+                        var
+                    ),
+                    None => Ok(String::new()),
+                })
+                .with_context(|e| format_err!("invalid build env var `{}`: {}", name, e))?;
+
+                Ok((name.clone(), value))
+            })
+            .collect()
+    }
+}
+
+/// An environment variable declared in `[build.env]`: either just its value, or a table which
+/// can also mark it as required.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum EnvVar {
+    Value(String),
+    Detailed {
+        value: Option<String>,
+        #[serde(default)]
+        required: bool,
+    },
+}
+
+/// Replaces every `${VAR}` in `s` with whatever `lookup` gives for `VAR`.
+fn expand_env<F>(s: &str, mut lookup: F) -> Result<String>
+where
+    F: FnMut(&str) -> Result<String>,
+{
+    let mut res = String::new();
+    let mut rest = s;
+
+    while let Some(ix) = rest.find('$') {
+        res.push_str(&rest[..ix]);
+        rest = &rest[ix + 1..];
+
+        let mut chars = rest.chars();
+        match chars.next() {
+            Some('$') => {
+                res.push('$');
+                rest = chars.as_str();
+            }
+            Some('{') => {
+                let after = chars.as_str();
+                let end = after
+                    .find('}')
+                    .ok_or_else(|| format_err!("unterminated `${{` in `{}`", s))?;
+                let var = &after[..end];
+                if var.is_empty() {
+                    bail!("empty `${{}}` in `{}`", s)
+                }
+                res.push_str(&lookup(var)?);
+                rest = &after[end + 1..];
             }
-            DepReq::Git { git, tag } => {
-                let res = DirectRes::Git { repo: git, tag };
+            _ => res.push('$'),
+        }
+    }
+    res.push_str(rest);
+
+    Ok(res)
+}
+
+/// The root of the workspace that the package at `root` is a member of, if it's in one.
+///
+/// Only the nearest manifest above `root` is considered, and it only counts if its `[workspace]`
+/// lists `root` as a member.
+fn workspace_root(root: &Path) -> Option<PathBuf> {
+    let root = root.canonicalize().ok()?;
+    let ws = root
+        .ancestors()
+        .skip(1)
+        .find(|p| p.join("elba.toml").exists())?;
+    let contents = fs::read_to_string(ws.join("elba.toml")).ok()?;
+
+    let member = Manifest::workspace(&contents)?
+        .members
+        .values()
+        .any(|member| {
+            let (dir, _) = member_manifest(member);
+            ws.join(dir).canonicalize().ok().as_ref() == Some(&root)
+        });
+
+    if member {
+        Some(ws.to_path_buf())
+    } else {
+        None
+    }
+}
+
+/// The dependencies declared in a `[target.'cfg(...)']` table.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct PlatformDeps {
+    #[serde(default)]
+    pub dependencies: IndexMap<Name, DepReq>,
+    #[serde(default)]
+    pub dev_dependencies: IndexMap<Name, DepReq>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Override {
+    pub path: PathBuf,
+}
+
+/// A dependency as it's declared in a manifest. Dependencies are read with
+/// `DepReq::from_manifest_value`.
+#[derive(Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum DepReq {
+    Registry(Constraint),
+    RegLong {
+        version: Constraint,
+        index: Option<String>,
+        optional: bool,
+        features: Vec<String>,
+        package: Option<Name>,
+    },
+    Local {
+        path: PathBuf,
+        optional: bool,
+        features: Vec<String>,
+        package: Option<Name>,
+    },
+    Git {
+        git: Url,
+        tag: String,
+        subdir: Option<SubPath>,
+        optional: bool,
+        features: Vec<String>,
+        package: Option<Name>,
+    },
+    Tar {
+        #[serde(serialize_with = "serialize_tar")]
+        tar: DirectRes,
+        optional: bool,
+        features: Vec<String>,
+        package: Option<Name>,
+    },
+}
+
+impl<'de> Deserialize<'de> for DepReq {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+        DepReq::from_manifest_value(&toml::Value::deserialize(d)?).map_err(de::Error::custom)
+    }
+}
+
+/// The long form of a dependency from an index.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegistryDep {
+    #[serde(deserialize_with = "constraint::deserialize")]
+    version: Constraint,
+    index: Option<String>,
+    #[serde(default)]
+    optional: bool,
+    #[serde(default)]
+    features: Vec<String>,
+    #[serde(default)]
+    package: Option<Name>,
+}
+
+/// What's left of a dependency which doesn't come from an index once its source is taken out.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DirectDep {
+    #[serde(default)]
+    optional: bool,
+    #[serde(default)]
+    features: Vec<String>,
+    #[serde(default)]
+    package: Option<Name>,
+}
+
+/// The fields of a dependency which say where a package comes from, if not from an index.
+const SOURCE_FIELDS: &[&str] = &["git", "tag", "subdir", "path", "tar"];
+
+fn default_tag() -> String {
+    "master".to_owned()
+}
+
+fn serialize_tar<S: Serializer>(tar: &DirectRes, s: S) -> std::result::Result<S::Ok, S::Error> {
+    tar_fields(tar)
+        .into_iter()
+        .collect::<IndexMap<_, _>>()
+        .serialize(s)
+}
+
+/// The fields of the `tar` table of a tarball dependency, leaving out the ones it doesn't have.
+fn tar_fields(tar: &DirectRes) -> Vec<(&'static str, String)> {
+    let mut fields = vec![];
+    if let DirectRes::Tar {
+        url,
+        cksum,
+        subpath,
+    } = tar
+    {
+        fields.push(("url", url.to_string()));
+        if let Some(cksum) = cksum {
+            fields.push(("cksum", cksum.to_string()));
+        }
+        if let Some(subpath) = subpath {
+            fields.push(("subpath", subpath.0.to_string_lossy().into_owned()));
+        }
+    }
+    fields
+}
+
+impl DepReq {
+    /// Reads a dependency the way it's written in a manifest: either a version constraint, or a
+    /// table. A table with a `version` is the long form of a dependency from an index; any other
+    /// table gets where the package comes from out of `DirectRes::from_manifest_value`.
+    pub fn from_manifest_value(value: &toml::Value) -> Result<Self> {
+        let table = match value {
+            toml::Value::String(s) => return Ok(DepReq::Registry(constraint::parse(s)?)),
+            toml::Value::Table(table) => table,
+            _ => bail!("should be a version constraint or a table"),
+        };
+
+        let direct = ["git", "path", "tar"]
+            .iter()
+            .any(|x| table.contains_key(*x));
+        match (direct, table.contains_key("version")) {
+            (true, true) => bail!("`version` only applies to dependencies from an index"),
+            (false, true) => {
+                let dep: RegistryDep = value.clone().try_into()?;
+                return Ok(DepReq::RegLong {
+                    version: dep.version,
+                    index: dep.index,
+                    optional: dep.optional,
+                    features: dep.features,
+                    package: dep.package,
+                });
+            }
+            // Anything else doesn't come from an index, so it has to say where it does come from
+            _ => (),
+        }
+
+        let mut rest = table.clone();
+        for field in SOURCE_FIELDS {
+            rest.remove(*field);
+        }
+        let DirectDep {
+            optional,
+            features,
+            package,
+        } = toml::Value::Table(rest).try_into()?;
+
+        Ok(match DirectRes::from_manifest_value(value)? {
+            DirectRes::Dir { path } => DepReq::Local {
+                path,
+                optional,
+                features,
+                package,
+            },
+            DirectRes::Git {
+                repo, tag, subdir, ..
+            } => DepReq::Git {
+                git: repo,
+                tag,
+                subdir,
+                optional,
+                features,
+                package,
+            },
+            tar => DepReq::Tar {
+                tar,
+                optional,
+                features,
+                package,
+            },
+        })
+    }
+
+    /// Whether this dependency is only pulled in when a feature enables it.
+    pub fn is_optional(&self) -> bool {
+        match self {
+            DepReq::Registry(_) => false,
+            DepReq::RegLong { optional, .. }
+            | DepReq::Local { optional, .. }
+            | DepReq::Git { optional, .. }
+            | DepReq::Tar { optional, .. } => *optional,
+        }
+    }
+
+    /// The features of the dependency which the depending package asks to be turned on.
+    pub fn features(&self) -> &[String] {
+        match self {
+            DepReq::Registry(_) => &[],
+            DepReq::RegLong { features, .. }
+            | DepReq::Local { features, .. }
+            | DepReq::Git { features, .. }
+            | DepReq::Tar { features, .. } => features,
+        }
+    }
+
+    /// The real name of the package this dependency refers to, if it's depended on under another
+    /// name. The name a dependency is declared with is then just a local alias for it.
+    pub fn package(&self) -> Option<&Name> {
+        match self {
+            DepReq::Registry(_) => None,
+            DepReq::RegLong { package, .. }
+            | DepReq::Local { package, .. }
+            | DepReq::Git { package, .. }
+            | DepReq::Tar { package, .. } => package.as_ref(),
+        }
+    }
+
+    /// The way this dependency would be written in a manifest, leaving out anything which is
+    /// just the default.
+    pub fn to_toml(&self) -> toml_edit::Value {
+        let mut table = toml_edit::InlineTable::default();
+        let (optional, features, package) = match self {
+            DepReq::Registry(c) => return c.to_string().into(),
+            DepReq::RegLong {
+                version,
+                index,
+                optional,
+                features,
+                package,
+            } => {
+                table.get_or_insert("version", version.to_string());
+                if let Some(index) = index {
+                    table.get_or_insert("index", index.as_str());
+                }
+                (optional, features, package)
+            }
+            DepReq::Local {
+                path,
+                optional,
+                features,
+                package,
+            } => {
+                table.get_or_insert("path", path.to_string_lossy().as_ref());
+                (optional, features, package)
+            }
+            DepReq::Git {
+                git,
+                tag,
+                subdir,
+                optional,
+                features,
+                package,
+            } => {
+                table.get_or_insert("git", git.as_str());
+                if tag != &default_tag() {
+                    table.get_or_insert("tag", tag.as_str());
+                }
+                if let Some(subdir) = subdir {
+                    table.get_or_insert("subdir", subdir.0.to_string_lossy().as_ref());
+                }
+                (optional, features, package)
+            }
+            DepReq::Tar {
+                tar,
+                optional,
+                features,
+                package,
+            } => {
+                let mut fields = toml_edit::InlineTable::default();
+                for (key, value) in tar_fields(tar) {
+                    fields.get_or_insert(key, value);
+                }
+                fields.fmt();
+                table.get_or_insert("tar", fields);
+                (optional, features, package)
+            }
+        };
+
+        if let Some(package) = package {
+            table.get_or_insert("package", package.as_str());
+        }
+        if *optional {
+            table.get_or_insert("optional", true);
+        }
+        if !features.is_empty() {
+            table.get_or_insert(
+                "features",
+                features
+                    .iter()
+                    .map(|x| x.as_str())
+                    .collect::<toml_edit::Value>(),
+            );
+        }
+        table.fmt();
+
+        table.into()
+    }
+
+    /// Turns this dependency into the package and version range it requires. `n` is the name it
+    /// was declared with; a renamed dependency requires the package it's an alias for instead.
+    pub fn into_dep(
+        self,
+        ixmap: &IndexMap<String, IndexRes>,
+        parent_pkg: &PackageId,
+        n: Name,
+    ) -> Result<(PackageId, Constraint)> {
+        let n = self.package().cloned().unwrap_or(n);
+        match self {
+            DepReq::Registry(c)
+            | DepReq::RegLong {
+                version: c,
+                index: None,
+                ..
+            } => {
+                let def_index = ixmap
+                    .get_index(0)
+                    .ok_or_else(|| format_err!("no default index"))?;
+                let pi = PackageId::new(n, def_index.1.clone().into());
+                Ok((pi, c))
+            }
+            DepReq::RegLong {
+                version,
+                index: Some(index),
+                ..
+            } => {
+                if let Some(mapped) = ixmap.get(&index) {
+                    let pi = PackageId::new(n, mapped.clone().into());
+                    Ok((pi, version))
+                } else {
+                    let ix = IndexRes::from_str(&index)?;
+                    let pi = PackageId::new(n, ix.into());
+                    Ok((pi, version))
+                }
+            }
+            DepReq::Local { path, .. } => {
+                if let &Resolution::Direct(DirectRes::Dir { path: parent_root }) =
+                    &parent_pkg.resolution()
+                {
+                    let res = DirectRes::Dir {
+                        path: parent_root.join(path),
+                    };
+                    let pi = PackageId::new(n, res.into());
+                    Ok((pi, Constraint::any()))
+                } else {
+                    bail!(format_err!(
+                        "can't resolve local dependency {} because it's \
+                        parent package {} is not local.",
+                        path.display(),
+                        parent_pkg.name()
+                    ))
+                }
+            }
+            DepReq::Git {
+                git, tag, subdir, ..
+            } => {
+                let res = DirectRes::Git {
+                    repo: git,
+                    tag,
+                    rev: None,
+                    subdir,
+                };
                 let pi = PackageId::new(n, res.into());
                 Ok((pi, Constraint::any()))
             }
+            DepReq::Tar { tar, .. } => {
+                let pi = PackageId::new(n, tar.into());
+                Ok((pi, Constraint::any()))
+            }
         }
     }
 }
@@ -287,10 +1614,33 @@ impl DepReq {
 #[derive(Deserialize, Serialize, Default, Debug, Clone)]
 pub struct Targets {
     pub lib: Option<LibTarget>,
+    /// Extra libraries, each with its own source path and modules, by name. Their modules are
+    /// imported just like the lib target's, but each one is built on its own.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub libs: IndexMap<String, LibTarget>,
     #[serde(default = "Vec::new")]
     pub bin: Vec<BinTarget>,
     #[serde(default = "Vec::new")]
     pub test: Vec<TestTarget>,
+    /// Example programs, which are only built when they're asked for by name.
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
+    pub example: Vec<ExampleTarget>,
+}
+
+impl Targets {
+    /// Whether there's a library to build, either the lib target or a named one.
+    pub fn has_lib(&self) -> bool {
+        self.lib.is_some() || !self.libs.is_empty()
+    }
+
+    /// Every library target: the lib target first (with no name), then the named ones.
+    pub fn lib_targets(&self) -> impl Iterator<Item = (Option<&str>, &LibTarget)> {
+        self.lib.iter().map(|lib| (None, lib)).chain(
+            self.libs
+                .iter()
+                .map(|(name, lib)| (Some(name.as_str()), lib)),
+        )
+    }
 }
 
 #[serde(deny_unknown_fields)]
@@ -341,6 +1691,34 @@ fn default_test_subpath() -> SubPath {
     SubPath::from_path(Path::new("tests")).unwrap()
 }
 
+/// An ExampleTarget is a BinTarget which lives in `examples` by default, and which is only built
+/// when it's asked for (with `elba build --example` or `elba run --example`).
+#[serde(deny_unknown_fields)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExampleTarget {
+    pub name: String,
+    #[serde(default = "default_example_subpath")]
+    pub path: SubPath,
+    pub main: String,
+    #[serde(default)]
+    pub idris_opts: Vec<String>,
+}
+
+fn default_example_subpath() -> SubPath {
+    SubPath::from_path(Path::new("examples")).unwrap()
+}
+
+impl From<ExampleTarget> for BinTarget {
+    fn from(t: ExampleTarget) -> Self {
+        BinTarget {
+            name: t.name,
+            path: t.path,
+            main: t.main,
+            idris_opts: t.idris_opts,
+        }
+    }
+}
+
 impl From<TestTarget> for BinTarget {
     fn from(t: TestTarget) -> Self {
         let default_name = format!("test-{}", &t.main)
@@ -452,7 +1830,10 @@ impl BinTarget {
 
 #[cfg(test)]
 mod tests {
+    extern crate tempdir;
+
     use super::*;
+    use std::fs;
 
     #[test]
     fn manifest_valid() {
@@ -542,4 +1923,902 @@ mods = [
 
         assert!(Manifest::from_str(manifest).is_err());
     }
+
+    #[test]
+    fn manifest_overrides() {
+        let manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[dependencies]
+'awesome/a' = '>= 1.0.0 < 2.0.0'
+
+[override]
+'deep/dep' = { path = '../dep' }
+"#;
+
+        let manifest = Manifest::from_str(manifest).unwrap();
+        let overrides = manifest.overrides(Path::new("/root/pkg"));
+
+        assert_eq!(
+            overrides.get(&Name::from_str("deep/dep").unwrap()),
+            Some(&DirectRes::Dir {
+                path: PathBuf::from("/root/pkg/../dep")
+            })
+        );
+    }
+
+    #[test]
+    fn manifest_target_dir() {
+        let manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+"#;
+        let root = Path::new("/root/pkg");
+
+        let default = Manifest::from_str(manifest).unwrap();
+        let custom =
+            Manifest::from_str(&format!("{}\n[build]\ntarget-dir = 'out'\n", manifest)).unwrap();
+        assert_eq!(custom.build.target_dir.as_deref(), Some(Path::new("out")));
+
+        assert_eq!(default.target_dir_from(root, None), root.join("target"));
+        assert_eq!(custom.target_dir_from(root, None), root.join("out"));
+
+        let from_env = || Some(OsString::from("/tmp/elba-target"));
+        assert_eq!(
+            default.target_dir_from(root, from_env()),
+            PathBuf::from("/tmp/elba-target")
+        );
+        assert_eq!(
+            custom.target_dir_from(root, from_env()),
+            PathBuf::from("/tmp/elba-target")
+        );
+    }
+
+    fn bins_manifest(bins: &[(&str, &str)]) -> Result<Manifest> {
+        let mut manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+"#
+        .to_owned();
+        for (name, main) in bins {
+            manifest.push_str(&format!(
+                "\n[[targets.bin]]\nname = '{}'\nmain = '{}'\n",
+                name, main
+            ));
+        }
+
+        Manifest::from_str(&manifest)
+    }
+
+    #[test]
+    fn manifest_bins() {
+        let manifest =
+            bins_manifest(&[("one", "One"), ("two", "Two"), ("three", "Three")]).unwrap();
+        let bins = |names: &[&str]| {
+            manifest
+                .bins(names)
+                .unwrap()
+                .into_iter()
+                .map(|(ix, bin)| (ix, bin.main.as_str()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(bins(&[]), vec![(0, "One"), (1, "Two"), (2, "Three")]);
+        // Binaries always come out in the order they're declared in
+        assert_eq!(bins(&["three", "one"]), vec![(0, "One"), (2, "Three")]);
+        assert!(manifest.bins(&["one", "four"]).is_err());
+
+        let manifest = bins_manifest(&[]).unwrap();
+        assert!(manifest.bins(&[]).unwrap().is_empty());
+        assert!(manifest.bins(&["one"]).is_err());
+    }
+
+    #[test]
+    fn manifest_bins_duplicate() {
+        let err = bins_manifest(&[("one", "One"), ("two", "Two"), ("one", "Three")]).unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("`One`") && msg.contains("`Three`"), "{}", msg);
+
+        // Names have to match exactly to clash
+        assert!(bins_manifest(&[("one", "One"), ("One", "Two")]).is_ok());
+    }
+
+    const ADD_MANIFEST: &str = r#"# The manifest of a very important package
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[dependencies]
+# Everything depends on this
+'awesome/a' = '>= 1.0.0 < 2.0.0'
+'great/C_d' = { path = 'here/right/now' }   # still local
+
+[targets.lib]
+path = "src/"
+mods = ["Ring.Ding"]
+"#;
+
+    #[test]
+    fn manifest_add_dependency() {
+        let req = DepReq::Git {
+            git: Url::parse("https://github.com/super/cool").unwrap(),
+            tag: "v1.0.0".to_owned(),
+            subdir: None,
+            optional: false,
+            features: vec!["fast".to_owned()],
+            package: None,
+        };
+        let res = Manifest::add_dependency(ADD_MANIFEST, "cool/b", &req, false, false).unwrap();
+        assert_eq!(
+            res,
+            ADD_MANIFEST.replace(
+                "   # still local\n",
+                "   # still local\n\"cool/b\" = { git = \"https://github.com/super/cool\", \
+                 tag = \"v1.0.0\", features = [\"fast\"] }\n"
+            )
+        );
+
+        let manifest = Manifest::from_str(&res).unwrap();
+        let names = manifest
+            .dependencies
+            .keys()
+            .map(|x| x.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["awesome/a", "great/C_d", "cool/b"]);
+
+        // A missing section gets added at the end
+        let req = DepReq::Registry(Constraint::from_str("1.2.3").unwrap());
+        let res = Manifest::add_dependency(ADD_MANIFEST, "cool/b", &req, true, false).unwrap();
+        assert!(res.starts_with(ADD_MANIFEST));
+        let manifest = Manifest::from_str(&res).unwrap();
+        assert_eq!(manifest.dev_dependencies.len(), 1);
+        assert_eq!(manifest.dependencies.len(), 2);
+    }
+
+    #[test]
+    fn manifest_add_dependency_existing() {
+        let req = DepReq::Local {
+            path: PathBuf::from("over/there"),
+            optional: true,
+            features: vec![],
+            package: None,
+        };
+
+        // Names are compared normalized
+        assert!(Manifest::add_dependency(ADD_MANIFEST, "Great/c-d", &req, false, false).is_err());
+
+        let res = Manifest::add_dependency(ADD_MANIFEST, "Great/c-d", &req, false, true).unwrap();
+        assert_eq!(
+            res,
+            ADD_MANIFEST.replace(
+                "{ path = 'here/right/now' }",
+                "{ path = \"over/there\", optional = true }"
+            )
+        );
+    }
+
+    #[test]
+    fn manifest_add_dependency_invalid() {
+        let req = DepReq::Registry(Constraint::any());
+        assert!(Manifest::add_dependency(ADD_MANIFEST, "no_group", &req, false, false).is_err());
+        assert!(Manifest::add_dependency(ADD_MANIFEST, "bad/na me", &req, false, false).is_err());
+        assert!(Manifest::add_dependency("[package", "good/name", &req, false, false).is_err());
+    }
+
+    #[test]
+    fn manifest_platform_deps() {
+        let manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[dependencies]
+'awesome/a' = '>= 1.0.0 < 2.0.0'
+
+[target.'cfg(os = "windows")'.dependencies]
+'win/api' = '1.0.0'
+
+[target.'cfg(backend = "chez")'.dev_dependencies]
+'chez/test' = '1.0.0'
+"#;
+
+        let manifest = Manifest::from_str(manifest).unwrap();
+        let platform = Platform {
+            os: "windows".to_string(),
+            backend: "c".to_string(),
+        };
+
+        let deps = manifest
+            .dependencies_for(&platform)
+            .map(|(n, _)| n.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(deps, vec!["awesome/a", "win/api"]);
+        assert_eq!(manifest.dev_dependencies_for(&platform).count(), 0);
+
+        let linux = Platform {
+            os: "linux".to_string(),
+            backend: "chez".to_string(),
+        };
+        assert_eq!(manifest.dependencies_for(&linux).count(), 1);
+        assert_eq!(manifest.dev_dependencies_for(&linux).count(), 1);
+    }
+
+    const FEATURES_MANIFEST: &str = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[dependencies]
+'awesome/a' = '>= 1.0.0 < 2.0.0'
+'cool/b' = { version = '1.0.0', optional = true }
+'great/c' = { path = 'here/right/now', optional = true }
+
+[features]
+default = ['cool/b']
+all = ['default', 'great/c']
+"#;
+
+    #[test]
+    fn manifest_features_default() {
+        let manifest = Manifest::from_str(FEATURES_MANIFEST).unwrap();
+        let enabled = manifest.enabled_deps(&[]).unwrap();
+
+        assert_eq!(enabled.len(), 1);
+        assert!(enabled.contains(&Name::from_str("cool/b").unwrap()));
+    }
+
+    #[test]
+    fn manifest_features_explicit() {
+        let manifest = Manifest::from_str(FEATURES_MANIFEST).unwrap();
+        let enabled = manifest.enabled_deps(&["all".to_string()]).unwrap();
+
+        assert_eq!(enabled.len(), 2);
+        assert!(enabled.contains(&Name::from_str("great/c").unwrap()));
+        assert!(manifest.enabled_deps(&["nope".to_string()]).is_err());
+    }
+
+    #[test]
+    fn manifest_features_invalid() {
+        let manifest = r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+
+[dependencies]
+'awesome/a' = '>= 1.0.0 < 2.0.0'
+
+[features]
+default = ['awesome/a']
+"#;
+
+        assert!(Manifest::from_str(manifest).is_err());
+    }
+
+    /// Validates a minimal manifest with `extra` tacked onto its `[package]` table.
+    fn lints(extra: &str) -> Vec<Lint> {
+        let manifest = format!(
+            "[package]\nname = 'lint/me'\nversion = '1.0.0'\nauthors = ['me']\n{}",
+            extra
+        );
+        toml::from_str::<Manifest>(&manifest).unwrap().validate()
+    }
+
+    fn warnings(extra: &str) -> Vec<String> {
+        lints(extra)
+            .into_iter()
+            .map(|lint| {
+                assert_eq!(lint.severity, Severity::Warning, "{}", lint);
+                lint.message
+            })
+            .collect()
+    }
+
+    #[test]
+    fn manifest_lints_clean() {
+        assert_eq!(
+            lints(
+                r#"include = ['src/', 'README.md']
+exclude = ['src/Data/']
+
+[dependencies]
+'lint/other' = '1.0'
+
+[[targets.bin]]
+name = 'me'
+main = 'Main'
+
+[targets.lib]
+mods = ['Lint.Me']
+"#
+            ),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn manifest_authors() {
+        let manifest = Manifest::from_str(
+            r#"[package]
+name = 'authors/me'
+version = '1.0.0'
+authors = ['Just Me', 'Me Too <me@too.me>', ' Spaced<spaced@me.me> ', 'Broken <me@broken.me']
+"#,
+        )
+        .unwrap();
+
+        let authors = manifest.authors();
+        assert_eq!(
+            authors,
+            vec![
+                Author {
+                    name: "Just Me".to_owned(),
+                    email: None,
+                },
+                Author {
+                    name: "Me Too".to_owned(),
+                    email: Some("me@too.me".to_owned()),
+                },
+                Author {
+                    name: "Spaced".to_owned(),
+                    email: Some("spaced@me.me".to_owned()),
+                },
+                Author {
+                    name: "Broken <me@broken.me".to_owned(),
+                    email: None,
+                },
+            ]
+        );
+        assert_eq!(authors[1].to_string(), "Me Too <me@too.me>");
+
+        let lints = manifest.validate();
+        assert_eq!(lints.len(), 1);
+        assert_eq!(lints[0].severity, Severity::Warning);
+        assert!(lints[0].message.contains("Broken <me@broken.me"));
+    }
+
+    #[test]
+    fn author_malformed() {
+        for raw in &[
+            "Me <me@x.me",
+            "Me me@x.me>",
+            "Me >me@x.me<",
+            "Me <me@x.me> later",
+            "Me <<me@x.me>>",
+            "<me@x.me>",
+            "Me <>",
+            "",
+        ] {
+            assert!(Author::from_str(raw).is_err(), "{}", raw);
+        }
+    }
+
+    #[test]
+    fn manifest_lints_empty_mods() {
+        assert_eq!(
+            warnings("[targets.lib]\nmods = []\n"),
+            vec!["the lib target (in `src`) doesn't export any modules"]
+        );
+    }
+
+    #[test]
+    fn manifest_multiple_libs() {
+        let manifest = Manifest::from_str(
+            r#"[package]
+name = 'multi/libs'
+version = '1.0.0'
+authors = []
+
+[targets.lib]
+mods = ['Multi.Core']
+
+[targets.libs.parser]
+path = 'parser/src'
+mods = ['Multi.Parser', 'Multi.Parser.Lexer']
+
+[targets.libs.pretty]
+path = 'pretty/src'
+mods = ['Multi.Pretty']
+"#,
+        )
+        .unwrap();
+
+        assert!(manifest.targets.has_lib());
+        assert!(!manifest.is_virtual());
+        let libs = manifest
+            .targets
+            .lib_targets()
+            .map(|(name, lib)| (name, lib.path.0.clone(), lib.mods.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            libs,
+            vec![
+                (None, PathBuf::from("src"), 1),
+                (Some("parser"), PathBuf::from("parser/src"), 2),
+                (Some("pretty"), PathBuf::from("pretty/src"), 1),
+            ]
+        );
+        assert!(manifest.validate().is_empty());
+
+        // Named libs are enough for a package to be built
+        let manifest = Manifest::from_str(
+            "[package]\nname = 'multi/libs'\nversion = '1.0.0'\nauthors = []\n\n[targets.libs.only]\nmods = ['Only']\n",
+        )
+        .unwrap();
+        assert!(manifest.targets.lib.is_none());
+        assert!(!manifest.is_virtual());
+    }
+
+    #[test]
+    fn manifest_lints_libs() {
+        let found = lints(
+            "[targets.lib]\nmods = ['Lint.Me']\n\n[targets.libs.other]\npath = 'other'\nmods = ['Lint.Other', '.Lint.Me']\n",
+        );
+        assert_eq!(
+            found,
+            vec![Lint::error(
+                "module `Lint.Me` is exported by both the lib target and lib target `other`"
+            )]
+        );
+
+        assert_eq!(
+            warnings("[targets.libs.empty]\nmods = []\n"),
+            vec!["lib target `empty` (in `src`) doesn't export any modules"]
+        );
+        assert_eq!(
+            lints("[targets.libs.'bad name']\nmods = ['Lint.Me']\n"),
+            vec![Lint::error(
+                "lib target `bad name` can only have letters, numbers, _, and - in its name"
+            )]
+        );
+    }
+
+    #[test]
+    fn manifest_lints_empty_main() {
+        assert_eq!(
+            warnings("[[targets.bin]]\nname = 'me'\nmain = ''\n\n[[targets.test]]\nmain = ' '\n"),
+            vec![
+                "binary target `me` has an empty `main`",
+                "a test target has an empty `main`",
+            ]
+        );
+
+        // Without a `main` at all, the manifest doesn't even parse
+        assert!(toml::from_str::<Manifest>(
+            "[package]\nname = 'lint/me'\nversion = '1.0.0'\nauthors = []\n\n[[targets.bin]]\nname = 'me'\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn manifest_lints_examples() {
+        assert_eq!(
+            lints("[[targets.bin]]\nname = 'demo'\nmain = 'Main'\n\n[[targets.example]]\nname = 'demo'\nmain = 'Demo'\n"),
+            vec![Lint::error("targets `Main` and `Demo` are both named `demo`")]
+        );
+        assert_eq!(
+            warnings("[[targets.example]]\nname = 'demo'\nmain = ''\n"),
+            vec!["example target `demo` has an empty `main`"]
+        );
+    }
+
+    #[test]
+    fn manifest_lints_self_dependency() {
+        let found = lints("[target.'cfg(os = \"linux\")'.dev_dependencies]\n'lint/me' = '1.0'\n");
+        assert_eq!(
+            found,
+            vec![Lint::error("package `lint/me` depends on itself")]
+        );
+
+        let err = Manifest::from_str(
+            "[package]\nname = 'lint/me'\nversion = '1.0.0'\nauthors = []\n\n[dependencies]\n'lint/me' = '1.0'\n",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("depends on itself"));
+    }
+
+    #[test]
+    fn manifest_lints_aliases() {
+        let found = lints(
+            "[dependencies]\n'lint/other' = '1.0'\n'lint/alias' = { version = '1.0', package = 'lint/other' }\n",
+        );
+        assert_eq!(
+            found,
+            vec![Lint::error(
+                "dependencies `lint/other` and `lint/alias` both refer to package `lint/other`"
+            )]
+        );
+
+        // ...which is fine if they come from different indices
+        let found = lints(
+            "[dependencies]\n'lint/other' = '1.0'\n'lint/alias' = { version = '1.0', index = 'other', package = 'lint/other' }\n",
+        );
+        assert_eq!(found, vec![]);
+
+        // Depending on yourself under another name is still depending on yourself
+        let found = lints("[dependencies]\n'lint/alias' = { path = 'me', package = 'lint/me' }\n");
+        assert_eq!(
+            found,
+            vec![Lint::error("package `lint/me` depends on itself")]
+        );
+    }
+
+    #[test]
+    fn manifest_lints_default_members() {
+        let found =
+            lints("[workspace]\ndefault-members = ['lint/one', 'lint/two']\n'lint/one' = 'one'\n");
+        assert_eq!(
+            found,
+            vec![Lint::error(
+                "default member `lint/two` isn't a member of the workspace"
+            )]
+        );
+
+        let ws = Manifest::workspace(
+            "[workspace]\ndefault-members = ['lint/two']\n'lint/one' = 'one'\n'lint/two' = 'two'\n",
+        )
+        .unwrap();
+        let names = |all| {
+            ws.selected(all)
+                .unwrap()
+                .into_iter()
+                .map(|(name, _)| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(false), vec!["lint/two"]);
+        assert_eq!(names(true), vec!["lint/one", "lint/two"]);
+    }
+
+    #[test]
+    fn manifest_lints_invalid_version() {
+        // Versions are checked while parsing, before the manifest gets validated
+        let err =
+            Manifest::from_str("[package]\nname = 'lint/me'\nversion = '1.0'\nauthors = []\n")
+                .unwrap_err();
+        assert!(err.to_string().contains("invalid manifest file"));
+    }
+
+    #[test]
+    fn manifest_lints_build_false() {
+        assert_eq!(
+            warnings("build = false\n\n[targets.lib]\nmods = ['Lint.Me']\n"),
+            vec!["the package has `build = false`, so its targets are never built"]
+        );
+        assert_eq!(warnings("build = false\n"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn manifest_virtual() {
+        let parse = |extra: &str| {
+            Manifest::from_str(&format!(
+                "[package]\nname = 'virt/me'\nversion = '1.0.0'\nauthors = []\n{}",
+                extra
+            ))
+            .unwrap()
+        };
+
+        assert!(parse("").is_virtual());
+        assert!(
+            parse("build = false\n\n[[targets.bin]]\nname = 'me'\nmain = 'Main'\n").is_virtual()
+        );
+        assert!(!parse("\n[[targets.bin]]\nname = 'me'\nmain = 'Main'\n").is_virtual());
+        assert!(!parse("\n[targets.lib]\nmods = ['Virt.Me']\n").is_virtual());
+    }
+
+    #[test]
+    fn manifest_lints_excluded_includes() {
+        assert_eq!(
+            warnings("include = ['src/', 'tests/**', 'docs/*', 'README.md', '*.idr']\nexclude = ['tests/', 'docs', 'README.md', 'Thing.idr']\n"),
+            vec![
+                "`tests/**` is both included and excluded, so it's never packaged",
+                "`docs/*` is both included and excluded, so it's never packaged",
+                "`README.md` is both included and excluded, so it's never packaged",
+            ]
+        );
+    }
+
+    fn build_env(env: &str) -> BuildConfig {
+        let manifest = format!(
+            "[package]\nname = 'env/me'\nversion = '1.0.0'\nauthors = []\n\n[build.env]\n{}",
+            env
+        );
+        Manifest::from_str(&manifest).unwrap().build
+    }
+
+    fn parent(var: &str) -> Option<String> {
+        match var {
+            "HOME" => Some("/home/me".to_string()),
+            "TOKEN" => Some("s3cr3t".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn manifest_build_env_expansion() {
+        let build = build_env(
+            r#"IDRIS_PATH = "${HOME}/idris:${HOME}/lib"
+PRICE = "$$5 ${MISSING}"
+TOKEN = { required = true }
+PLAIN = "no vars here $ at all"
+"#,
+        );
+        let env = build.resolve_env(parent).unwrap();
+        assert_eq!(
+            env.into_iter().collect::<Vec<_>>(),
+            vec![
+                (
+                    "IDRIS_PATH".to_string(),
+                    "/home/me/idris:/home/me/lib".to_string()
+                ),
+                ("PRICE".to_string(), "$5 ".to_string()),
+                ("TOKEN".to_string(), "s3cr3t".to_string()),
+                ("PLAIN".to_string(), "no vars here $ at all".to_string()),
+            ]
+        );
+
+        assert!(build_env("BAD = \"${HOME\"\n").resolve_env(parent).is_err());
+        assert!(build_env("BAD = \"${}\"\n").resolve_env(parent).is_err());
+    }
+
+    #[test]
+    fn manifest_build_env_required() {
+        let err = build_env("KEY = { value = \"${HOME}/${API_KEY}\", required = true }\n")
+            .resolve_env(parent)
+            .unwrap_err();
+        assert!(format!("{}", err).contains("KEY"));
+        assert!(err
+            .iter_chain()
+            .any(|c| c.to_string().contains("`API_KEY` isn't set")));
+
+        let err = build_env("API_KEY = { required = true }\n")
+            .resolve_env(parent)
+            .unwrap_err();
+        assert!(err
+            .iter_chain()
+            .any(|c| c.to_string().contains("`API_KEY` isn't set")));
+
+        // Without being required, a missing variable is just empty
+        let env = build_env("KEY = { value = \"${API_KEY}\" }\n")
+            .resolve_env(parent)
+            .unwrap();
+        assert_eq!(env["KEY"], "");
+    }
+
+    #[test]
+    fn manifest_build_flags() {
+        let manifest = r#"
+[package]
+name = 'flags/me'
+version = '1.0.0'
+authors = []
+
+[build.flags]
+"flags/me" = ["--warnreach"]
+"idris/dep" = ["-p  contrib", "--total", "-p contrib", " "]
+"#;
+        let flags = Manifest::from_str(manifest).unwrap().build.flags();
+        assert_eq!(
+            flags[&Name::from_str("idris/dep").unwrap()],
+            vec![
+                "-p contrib".to_string(),
+                "--total".to_string(),
+                "-p contrib".to_string()
+            ]
+        );
+        assert_eq!(
+            flags[&Name::from_str("flags/me").unwrap()],
+            vec!["--warnreach".to_string()]
+        );
+    }
+
+    #[test]
+    fn manifest_elba_version() {
+        let manifest = |req: &str| {
+            Manifest::from_str(&format!(
+                r#"
+[package]
+name = 'versioned/pkg'
+version = '1.0.0'
+authors = []
+elba-version = '{}'
+"#,
+                req
+            ))
+            .unwrap()
+        };
+
+        manifest(">= 0.1").check_elba_version().unwrap();
+
+        let err = manifest(">= 1000").check_elba_version().unwrap_err();
+        assert!(err
+            .iter_chain()
+            .any(|c| c.downcast_ref::<ErrorKind>() == Some(&ErrorKind::UnsupportedElbaVersion)));
+        let msg = err.to_string();
+        assert!(
+            msg.contains("versioned/pkg requires elba >=1000.0.0"),
+            "{}",
+            msg
+        );
+    }
+
+    #[test]
+    fn manifest_constraint_syntax() {
+        let manifest = |deps: &str| {
+            Manifest::from_str(&format!(
+                r#"
+[package]
+name = 'versioned/pkg'
+version = '1.0.0'
+authors = []
+
+[dependencies]
+{}
+"#,
+                deps
+            ))
+        };
+
+        let m = manifest(
+            r#""a/exact" = "=1.2.3"
+"a/wildcard" = { version = "1.*" }
+"a/compound" = ">= 1.2, < 2.0""#,
+        )
+        .unwrap();
+        let version = |name: &str| match &m.dependencies[&Name::from_str(name).unwrap()] {
+            DepReq::Registry(c) | DepReq::RegLong { version: c, .. } => c.to_string(),
+            _ => panic!("not a registry dependency"),
+        };
+        assert_eq!(version("a/exact"), ">=1.2.3 <=1.2.3");
+        assert_eq!(version("a/wildcard"), ">=1.0.0 <2.0.0");
+        assert_eq!(version("a/compound"), ">=1.2.0 <2.0.0");
+
+        // Bad constraints are blamed on the dependency they belong to
+        let err = manifest(r#""a/broken" = "1.*.3""#).unwrap_err().to_string();
+        assert!(err.contains("dependency a/broken"), "{}", err);
+        assert!(err.contains("`1.*.3`"), "{}", err);
+    }
+
+    #[test]
+    fn manifest_dependency_sources() {
+        let manifest = |deps: &str| {
+            Manifest::from_str(&format!(
+                "[package]\nname = 'sourced/pkg'\nversion = '1.0.0'\nauthors = []\n\n[dependencies]\n{}\n",
+                deps
+            ))
+        };
+
+        let m = manifest(
+            r#""a/plain" = { tar = "https://example.com/plain.tar.gz" }
+"a/checked" = { tar = { url = "https://example.com/checked.tar.gz", cksum = "sha256=abcd" }, optional = true }"#,
+        )
+        .unwrap();
+        let plain = &m.dependencies[&Name::from_str("a/plain").unwrap()];
+        let checked = &m.dependencies[&Name::from_str("a/checked").unwrap()];
+        match checked {
+            DepReq::Tar { tar, optional, .. } => {
+                assert_eq!(
+                    tar.to_string(),
+                    "tar+https://example.com/checked.tar.gz#sha256=abcd"
+                );
+                assert!(optional);
+            }
+            _ => panic!("not a tarball dependency"),
+        }
+        match plain {
+            DepReq::Tar { tar, .. } => {
+                assert_eq!(tar.to_string(), "tar+https://example.com/plain.tar.gz")
+            }
+            _ => panic!("not a tarball dependency"),
+        }
+
+        // Bad sources are blamed on the dependency and the field they're in
+        let err = manifest(r#""a/broken" = { git = "https://a.com/b", tag = 1 }"#)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("dependency a/broken: `tag` should be a string"),
+            "{}",
+            err
+        );
+        let err = manifest(
+            r#""a/broken" = { tar = { url = "https://a.com/b.tar.gz", cksum = "nope" } }"#,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("dependency a/broken: `tar.cksum`"), "{}", err);
+        let err = manifest(r#""a/broken" = { path = "../b", version = "1.0" }"#)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("`version` only applies"), "{}", err);
+    }
+
+    fn package_dir(include: &str, exclude: &str) -> (tempdir::TempDir, Manifest) {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let manifest = format!(
+            r#"
+[package]
+name = 'ring_ding/test'
+version = '1.0.0'
+authors = ['me']
+include = {}
+exclude = {}
+"#,
+            include, exclude
+        );
+        fs::write(tmp.path().join("elba.toml"), &manifest).unwrap();
+
+        for f in &[
+            "src/Main.idr",
+            "src/Main.idr~",
+            "src/Data/Thing.idr",
+            "tests/Test.idr",
+            "target/lib/Main.ibc",
+            "vendor/src/dep/elba.toml",
+            ".git/HEAD",
+            "README.md",
+        ] {
+            let path = tmp.path().join(f);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+
+        (tmp, Manifest::from_str(&manifest).unwrap())
+    }
+
+    fn paths(files: &[&str]) -> Vec<PathBuf> {
+        files.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn manifest_package_files_default() {
+        let (tmp, manifest) = package_dir("[]", "[]");
+        // An empty include list still packages the manifest
+        assert_eq!(
+            manifest.package_files(tmp.path()).unwrap(),
+            paths(&["elba.toml"])
+        );
+
+        let (tmp, mut manifest) = package_dir("[]", "[]");
+        manifest.package.include = None;
+        assert_eq!(
+            manifest.package_files(tmp.path()).unwrap(),
+            paths(&[
+                "README.md",
+                "elba.toml",
+                "src/Data/Thing.idr",
+                "src/Main.idr",
+                "src/Main.idr~",
+                "tests/Test.idr",
+            ])
+        );
+    }
+
+    #[test]
+    fn manifest_package_files_overlapping() {
+        // Exclusions always win over inclusions, even when they overlap
+        let (tmp, manifest) = package_dir(
+            "['src/', 'tests/', 'target/', 'README.md']",
+            "['*~', 'tests/', 'src/Data/']",
+        );
+        assert_eq!(
+            manifest.package_files(tmp.path()).unwrap(),
+            paths(&["README.md", "elba.toml", "src/Main.idr"])
+        );
+
+        let (tmp, manifest) = package_dir("['*.idr']", "['Thing.idr']");
+        assert_eq!(
+            manifest.package_files(tmp.path()).unwrap(),
+            paths(&["elba.toml", "src/Main.idr", "tests/Test.idr"])
+        );
+    }
 }
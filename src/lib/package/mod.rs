@@ -1,5 +1,7 @@
 //! Data structures related to packages.
 
+pub mod cfg;
+pub mod constraint;
 pub mod ipkg;
 pub mod lockfile;
 pub mod manifest;
@@ -9,6 +11,7 @@ use crate::{
     util::error::{Error, Result},
 };
 use failure::{bail, format_err};
+use inflector::Inflector;
 use semver::Version;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::{
@@ -46,46 +49,24 @@ struct NameInner {
 
 impl Name {
     pub fn new(group: String, name: String) -> Result<Self> {
-        let group_valid = group
-            .chars()
-            .all(|x| x.is_alphanumeric() || x == '_' || x == '-');
-        if !group_valid {
-            bail!("group can only contain letters, numbers, _, and -")
-        }
-        let name_valid = name
-            .chars()
-            .all(|x| x.is_alphanumeric() || x == '_' || x == '-');
-        if !name_valid {
-            bail!("name can only contain letters, numbers, _, and -")
-        }
+        validate_part("group", &group)?;
+        validate_part("name", &name)?;
 
         let mut s = String::with_capacity(group.len() + 2 + name.len());
         s.push_str(&group);
         s.push('/');
         s.push_str(&name);
 
+        let normalize = |x: &str| {
+            x.to_ascii_lowercase()
+                .drain(..)
+                .map(|c| if c == '_' { '-' } else { c })
+                .collect::<String>()
+        };
         let mut n = String::with_capacity(group.len() + 2 + name.len());
-        let pre = &group
-            .to_ascii_lowercase()
-            .drain(..)
-            .map(|c| if c == '_' { '-' } else { c })
-            .collect::<String>();
-        if pre.is_empty() {
-            bail!("group cannot be empty")
-        }
-        n.push_str(pre);
-
+        n.push_str(&normalize(&group));
         n.push('/');
-
-        let post = &name
-            .to_ascii_lowercase()
-            .drain(..)
-            .map(|c| if c == '_' { '-' } else { c })
-            .collect::<String>();
-        if post.is_empty() {
-            bail!("name cannot be empty")
-        }
-        n.push_str(post);
+        n.push_str(&normalize(&name));
 
         Ok(Name {
             inner: Arc::new(NameInner {
@@ -97,6 +78,12 @@ impl Name {
         })
     }
 
+    /// Creates a name from a group and a name which have already been split apart, checking that
+    /// both of them are valid.
+    pub fn try_from_parts(group: &str, name: &str) -> Result<Self> {
+        Name::new(group.to_owned(), name.to_owned())
+    }
+
     pub fn group(&self) -> &str {
         &self.inner.group
     }
@@ -120,6 +107,25 @@ impl Name {
     pub fn as_normalized(&self) -> &str {
         &self.inner.normalization
     }
+
+    /// The Idris module a package with this name is rooted at, as in `Group.Name` for
+    /// `group/name`.
+    pub fn module_path(&self) -> String {
+        format!("{}.{}", self.group_dir(), self.name_dir())
+    }
+
+    /// The directory that modules under the group of this name go in. Each part of the group
+    /// (separated by `-`, `_` or a change in case) becomes a capitalized word, so `my-group` and
+    /// `MY_GROUP` both become `MyGroup`.
+    pub fn group_dir(&self) -> String {
+        self.group().to_pascal_case()
+    }
+
+    /// The directory that modules under the name part of this name go in, cased the same way as
+    /// `group_dir`.
+    pub fn name_dir(&self) -> String {
+        self.name().to_pascal_case()
+    }
 }
 
 impl PartialEq for NameInner {
@@ -136,21 +142,50 @@ impl Hash for NameInner {
     }
 }
 
+/// Checks that one part of a name (its group or its name) is non-empty and contains no illegal
+/// characters, pointing at the first illegal character if there is one.
+fn validate_part(kind: &str, part: &str) -> Result<()> {
+    if part.is_empty() {
+        bail!("{} cannot be empty", kind)
+    }
+
+    let illegal = part
+        .chars()
+        .enumerate()
+        .find(|(_, c)| !(c.is_alphanumeric() || *c == '_' || *c == '-'));
+    if let Some((ix, c)) = illegal {
+        bail!(
+            "{} `{}` contains illegal character {:?} at position {}; it can only contain letters, numbers, _, and -",
+            kind,
+            part,
+            c,
+            ix + 1
+        )
+    }
+
+    Ok(())
+}
+
 impl FromStr for Name {
     type Err = failure::Error;
 
     fn from_str(s: &str) -> Result<Self> {
         let v: Vec<&str> = s.split('/').collect();
 
-        if v.len() != 2 {
-            return Err(format_err!(
-                "name must have group and name separated by '/'"
-            ))?;
+        match v.len() {
+            1 => bail!(
+                "`{}` is missing a '/' between the group and the name (e.g. `group/{}`)",
+                s,
+                s
+            ),
+            2 => {}
+            _ => bail!(
+                "`{}` has more than one '/'; names are of the form `group/name`",
+                s
+            ),
         }
 
-        let (group, name) = (v[0].to_owned(), v[1].to_owned());
-
-        Name::new(group, name)
+        Name::try_from_parts(v[0], v[1])
     }
 }
 
@@ -464,3 +499,68 @@ impl FromStr for Spec {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn err(s: &str) -> String {
+        Name::from_str(s).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn name_from_parts() {
+        let name = Name::try_from_parts("Awesome_Group", "pkg").unwrap();
+        assert_eq!(name, Name::from_str("awesome-group/pkg").unwrap());
+        assert_eq!(name.as_str(), "Awesome_Group/pkg");
+        assert_eq!(name.group(), "Awesome_Group");
+        assert_eq!(name.name(), "pkg");
+    }
+
+    #[test]
+    fn name_missing_slash() {
+        assert!(err("pkg").contains("missing a '/'"));
+    }
+
+    #[test]
+    fn name_extra_slash() {
+        assert!(err("a/b/c").contains("more than one '/'"));
+    }
+
+    #[test]
+    fn name_empty_parts() {
+        assert_eq!(err("/pkg"), "group cannot be empty");
+        assert_eq!(err("group/"), "name cannot be empty");
+        assert!(Name::try_from_parts("", "pkg").is_err());
+    }
+
+    #[test]
+    fn name_illegal_chars() {
+        let e = err("gr.oup/pkg");
+        assert!(e.starts_with("group `gr.oup`"), "{}", e);
+        assert!(e.contains("'.' at position 3"), "{}", e);
+
+        let e = Name::try_from_parts("group", "pk g")
+            .unwrap_err()
+            .to_string();
+        assert!(e.starts_with("name `pk g`"), "{}", e);
+        assert!(e.contains("' ' at position 3"), "{}", e);
+    }
+
+    #[test]
+    fn name_module_path() {
+        let name = Name::from_str("my-awesome_group/pkg").unwrap();
+        assert_eq!(name.group_dir(), "MyAwesomeGroup");
+        assert_eq!(name.name_dir(), "Pkg");
+        assert_eq!(name.module_path(), "MyAwesomeGroup.Pkg");
+
+        // Names which only differ in their casing and separators get the same module
+        let name = Name::from_str("MY_GROUP/fooBar").unwrap();
+        assert_eq!(name.group_dir(), "MyGroup");
+        assert_eq!(name.name_dir(), "FooBar");
+        assert_eq!(
+            name.module_path(),
+            Name::from_str("my-group/foo-bar").unwrap().module_path()
+        );
+    }
+}
@@ -0,0 +1,290 @@
+//! The archive formats which tarball sources can come in.
+//!
+//! Which format an archive is in is worked out from the bytes it starts with, rather than from
+//! its url or file name: each `ArchiveExtractor` in a set of `ArchiveFormats` is asked in turn
+//! whether it recognizes those bytes, and the first one which does unpacks the archive. elba
+//! unpacks tarballs which are gzipped or not compressed at all on its own. Tarballs which are
+//! compressed with xz or zstandard are decompressed by running `xz` or `zstd`, and zip archives
+//! are unpacked by running `unzip`, so those programs have to be installed for these formats to
+//! be unpacked.
+//!
+//! Embedders can add their own formats with `ArchiveFormats::with_format` (or
+//! `Cache::with_archive_format`). Formats which are added are asked before the built-in ones, so
+//! they can take over from those as well.
+
+use std::{
+    fmt, fs,
+    io::{self, BufRead},
+    path::Path,
+    process::{Command, Output, Stdio},
+    sync::Arc,
+    thread,
+};
+
+use failure::{bail, format_err, Fail, ResultExt};
+use flate2::read::GzDecoder;
+use walkdir::WalkDir;
+
+use crate::{
+    remote::resolution::{contained_path, resolves_inside, unpack_archive},
+    util::error::{Error, Result},
+};
+
+/// Something which knows how to unpack archives of a certain format.
+pub trait ArchiveExtractor: Send + Sync {
+    /// The name of the format, for messages about it.
+    fn name(&self) -> &str;
+
+    /// Whether an archive which starts with `magic` is in this format. `magic` holds at least the
+    /// first few bytes of the archive, unless the archive is shorter than that.
+    fn detect(&self, magic: &[u8]) -> bool;
+
+    /// Unpacks the whole archive into `target`, which already exists. Entries which would end up
+    /// outside of `target` have to be refused (`unpack_archive` does that for tarballs).
+    fn extract(&self, archive: &mut dyn BufRead, target: &Path) -> Result<()>;
+}
+
+/// A set of archive formats, which archives are unpacked with according to the bytes they start
+/// with.
+#[derive(Clone)]
+pub struct ArchiveFormats {
+    /// The formats to try, in order.
+    formats: Vec<Arc<dyn ArchiveExtractor>>,
+}
+
+impl ArchiveFormats {
+    /// Adds a format, which is tried before all of the formats that are already known.
+    pub fn with_format(mut self, format: impl ArchiveExtractor + 'static) -> Self {
+        self.formats.insert(0, Arc::new(format));
+        self
+    }
+
+    /// Returns the format of an archive which starts with `magic`, if any is recognized.
+    pub fn detect(&self, magic: &[u8]) -> Option<&dyn ArchiveExtractor> {
+        self.formats
+            .iter()
+            .find(|x| x.detect(magic))
+            .map(|x| x.as_ref())
+    }
+}
+
+impl Default for ArchiveFormats {
+    /// The formats which elba supports on its own.
+    fn default() -> Self {
+        ArchiveFormats {
+            formats: vec![
+                Arc::new(Gzip),
+                Arc::new(Piped {
+                    name: "xz",
+                    magic: &[0xfd, b'7', b'z', b'X', b'Z', 0x00],
+                    program: "xz",
+                }),
+                Arc::new(Piped {
+                    name: "zstandard",
+                    magic: &[0x28, 0xb5, 0x2f, 0xfd],
+                    program: "zstd",
+                }),
+                Arc::new(Zip),
+                Arc::new(Tar),
+            ],
+        }
+    }
+}
+
+impl fmt::Debug for ArchiveFormats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.formats.iter().map(|x| x.name()))
+            .finish()
+    }
+}
+
+/// Uncompressed tarballs. Tarballs don't have any magic bytes at their start, so anything which
+/// no other format recognizes is taken to be one.
+struct Tar;
+
+impl ArchiveExtractor for Tar {
+    fn name(&self) -> &str {
+        "tar"
+    }
+
+    fn detect(&self, _: &[u8]) -> bool {
+        true
+    }
+
+    fn extract(&self, archive: &mut dyn BufRead, target: &Path) -> Result<()> {
+        unpack_archive(archive, target)
+    }
+}
+
+/// Gzipped tarballs.
+struct Gzip;
+
+impl ArchiveExtractor for Gzip {
+    fn name(&self) -> &str {
+        "gzip"
+    }
+
+    fn detect(&self, magic: &[u8]) -> bool {
+        magic.starts_with(&[0x1f, 0x8b])
+    }
+
+    fn extract(&self, archive: &mut dyn BufRead, target: &Path) -> Result<()> {
+        unpack_archive(GzDecoder::new(archive), target)
+    }
+}
+
+/// Tarballs which are compressed with a format elba has no decoder for, and which are
+/// decompressed by piping them through `program -dc` instead.
+struct Piped {
+    name: &'static str,
+    magic: &'static [u8],
+    program: &'static str,
+}
+
+impl ArchiveExtractor for Piped {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn detect(&self, magic: &[u8]) -> bool {
+        magic.starts_with(self.magic)
+    }
+
+    fn extract(&self, archive: &mut dyn BufRead, target: &Path) -> Result<()> {
+        let mut child = Command::new(self.program)
+            .arg("-dc")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|e| {
+                format!(
+                    "couldn't run `{}` to decompress the {} archive (is it installed?): {}",
+                    self.program, self.name, e
+                )
+            })?;
+
+        // The archive can't be moved to another thread, so it's written here while the tarball
+        // which comes out is unpacked on another thread
+        let stdout = child.stdout.take().unwrap();
+        let into = target.to_owned();
+        let unpacking = thread::spawn(move || unpack_archive(stdout, &into));
+        let written = io::copy(archive, child.stdin.as_mut().unwrap());
+        drop(child.stdin.take());
+
+        // If unpacking stops early, the program can't write anything more and stops reading,
+        // which makes the error from unpacking the one worth reporting
+        let unpacked = unpacking
+            .join()
+            .map_err(|_| format_err!("unpacking the {} archive panicked", self.name))?;
+        let output = child.wait_with_output()?;
+        unpacked?;
+        check_status(self.program, &output)?;
+        written?;
+
+        Ok(())
+    }
+}
+
+/// Zip archives, which are unpacked with `unzip`. Zip archives can't be read front to back like
+/// tarballs, so the archive is saved to a file in `target` first.
+struct Zip;
+
+impl ArchiveExtractor for Zip {
+    fn name(&self) -> &str {
+        "zip"
+    }
+
+    fn detect(&self, magic: &[u8]) -> bool {
+        magic.starts_with(b"PK\x03\x04")
+    }
+
+    fn extract(&self, archive: &mut dyn BufRead, target: &Path) -> Result<()> {
+        let file = target.join(".elba-archive.zip");
+        io::copy(archive, &mut fs::File::create(&file)?)?;
+        let res = unzip(&file, target);
+        fs::remove_file(&file)?;
+
+        res
+    }
+}
+
+/// Unpacks the zip archive `file` into `target`. The entries are listed before anything is
+/// unpacked so that paths outside of `target` are refused up front; links can only be checked
+/// once they're unpacked (and followed through each other), so a link pointing outside is
+/// removed again before it's refused.
+fn unzip(file: &Path, target: &Path) -> Result<()> {
+    let refuse = |path: &Path, why: String| -> failure::Error {
+        Error::UnsafeArchivePath
+            .context(format!("archive entry {} {}", path.display(), why))
+            .into()
+    };
+
+    let listing = run_unzip(Command::new("unzip").arg("-Z1").arg(file))?;
+    for entry in String::from_utf8_lossy(&listing.stdout).lines() {
+        if contained_path(Path::new(entry)).is_none() {
+            return Err(refuse(
+                Path::new(entry),
+                "is outside of the directory it's unpacked into".to_owned(),
+            ));
+        }
+    }
+
+    run_unzip(
+        Command::new("unzip")
+            .arg("-qo")
+            .arg(file)
+            .arg("-d")
+            .arg(target),
+    )?;
+
+    let root = fs::canonicalize(target)?;
+    for entry in WalkDir::new(target).into_iter().filter_map(|x| x.ok()) {
+        if !entry.path_is_symlink() {
+            continue;
+        }
+        let inner = entry.path().strip_prefix(target)?;
+        let link = fs::read_link(entry.path())?;
+        let linked = inner.parent().unwrap_or_else(|| Path::new("")).join(&link);
+        if contained_path(&linked).is_none() || !resolves_inside(entry.path(), &root) {
+            fs::remove_file(entry.path())?;
+            return Err(refuse(
+                inner,
+                format!(
+                    "links to {}, which is outside of the directory it's unpacked into",
+                    link.display()
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs `unzip` with the arguments in `cmd`, failing if it doesn't succeed.
+fn run_unzip(cmd: &mut Command) -> Result<Output> {
+    let output = cmd.output().with_context(|e| {
+        format!(
+            "couldn't run `unzip` to unpack the zip archive (is it installed?): {}",
+            e
+        )
+    })?;
+    check_status("unzip", &output)?;
+
+    Ok(output)
+}
+
+/// Fails with what `program` printed if it didn't exit successfully.
+fn check_status(program: &str, output: &Output) -> Result<()> {
+    if !output.status.success() {
+        bail!(
+            "`{}` failed ({}): {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+    }
+
+    Ok(())
+}
@@ -0,0 +1,154 @@
+//! Credentials for private indices and tarballs.
+//!
+//! Credentials are given per host, either in the `[credentials]` section of the configuration or
+//! through `ELBA_TOKEN_<host>` environment variables (with the host uppercased and everything but
+//! letters and numbers replaced with underscores, so `registry.example.com` becomes
+//! `ELBA_TOKEN_REGISTRY_EXAMPLE_COM`). Environment variables take precedence over the
+//! configuration.
+//!
+//! Credentials never show up in logs or error messages: their `Debug` output is redacted.
+
+use std::{env, fmt};
+
+use failure::Fail;
+use indexmap::IndexMap;
+use reqwest::{
+    blocking::{RequestBuilder, Response},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::util::error::{Error, Result};
+
+/// The prefix of the environment variables which hold bearer tokens.
+pub const TOKEN_ENV_PREFIX: &str = "ELBA_TOKEN_";
+
+#[derive(Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Credential {
+    /// A token sent as `Authorization: Bearer <token>`.
+    Token { token: String },
+    /// A username and (optional) password sent with HTTP basic authentication.
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+}
+
+impl fmt::Debug for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Credential::Token { .. } => write!(f, "Token(<redacted>)"),
+            Credential::Basic { username, .. } => write!(f, "Basic({}, <redacted>)", username),
+        }
+    }
+}
+
+/// The credentials to use for each host.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Credentials {
+    /// Credentials keyed by their (lowercase) host.
+    hosts: IndexMap<String, Credential>,
+    /// Tokens from the environment, keyed by the normalized host in the variable's name.
+    env: IndexMap<String, Credential>,
+}
+
+impl Credentials {
+    /// Collects every token given in an `ELBA_TOKEN_<host>` environment variable.
+    pub fn from_env() -> Self {
+        Credentials::from_vars(env::vars())
+    }
+
+    /// Collects every token given in an `ELBA_TOKEN_<host>` variable out of `vars`, which are
+    /// taken the same way as the environment in `from_env`.
+    pub fn from_vars(vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        let env = vars
+            .into_iter()
+            .filter(|(k, v)| {
+                k.starts_with(TOKEN_ENV_PREFIX) && k.len() > TOKEN_ENV_PREFIX.len() && !v.is_empty()
+            })
+            .map(|(k, v)| {
+                let host = k[TOKEN_ENV_PREFIX.len()..].to_string();
+                (host, Credential::Token { token: v })
+            })
+            .collect();
+
+        Credentials {
+            hosts: IndexMap::new(),
+            env,
+        }
+    }
+
+    /// Adds credentials from the configuration. Credentials from the environment still win.
+    pub fn with_hosts(mut self, hosts: &IndexMap<String, Credential>) -> Self {
+        for (host, cred) in hosts {
+            self.hosts.insert(host.to_ascii_lowercase(), cred.clone());
+        }
+        self
+    }
+
+    /// The credential to use for requests to `url`, if there is one.
+    pub fn get(&self, url: &Url) -> Option<Credential> {
+        let host = url.host_str()?;
+        self.env
+            .get(&env_key(host))
+            .or_else(|| self.hosts.get(&host.to_ascii_lowercase()))
+            .cloned()
+    }
+
+    /// Attaches credentials for `url` to a request, if there are any.
+    pub fn authorize(&self, req: RequestBuilder, url: &Url) -> RequestBuilder {
+        match self.get(url) {
+            Some(Credential::Token { token }) => req.bearer_auth(token),
+            Some(Credential::Basic { username, password }) => req.basic_auth(username, password),
+            None => req,
+        }
+    }
+
+    /// Turns a response which says our credentials weren't good enough into an
+    /// `Error::Unauthorized` naming the host.
+    pub fn check(&self, resp: Response, url: &Url) -> Result<Response> {
+        match resp.status() {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                let host = url.host_str().unwrap_or_else(|| url.as_str());
+                let msg = if self.get(url).is_some() {
+                    format!(
+                        "{} rejected the configured credentials ({})",
+                        host,
+                        resp.status()
+                    )
+                } else {
+                    format!(
+                        "{} requires credentials ({}); set {}{} or configure them in [credentials]",
+                        host,
+                        resp.status(),
+                        TOKEN_ENV_PREFIX,
+                        env_key(host)
+                    )
+                };
+                Err(Error::Unauthorized.context(msg).into())
+            }
+            _ => Ok(resp),
+        }
+    }
+}
+
+/// Whether an error came from a server turning down our credentials.
+pub fn is_unauthorized(e: &failure::Error) -> bool {
+    e.iter_chain()
+        .any(|c| c.downcast_ref::<Error>() == Some(&Error::Unauthorized))
+}
+
+/// The part of an `ELBA_TOKEN_<host>` variable's name which corresponds to `host`.
+pub fn env_key(host: &str) -> String {
+    host.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
@@ -23,22 +23,33 @@
 //! [unofficial registries](https://github.com/rust-lang/rfcs/blob/master/text/2141-alternative-registries.md).
 
 use crate::{
-    package::*,
-    remote::resolution::{DirectRes, IndexRes, Resolution},
+    package::{
+        manifest::{enabled_items, DepReq, Manifest},
+        *,
+    },
+    remote::{
+        auth::Credentials,
+        resolution::{DirectRes, IndexRes, Resolution},
+        signature::PublicKey,
+        sparse::Remote,
+    },
     util::{
         error::{Error, Result},
         lock::DirLock,
     },
 };
-use failure::{bail, format_err, ResultExt};
+use failure::{bail, format_err, Context, Fail, ResultExt};
 use indexmap::IndexMap;
+use itertools::Itertools;
+use reqwest::blocking::Client;
 use semver::Version;
 use semver_constraints::Constraint;
 use serde::{Deserialize, Serialize};
 use serde_json;
+use sha2::{Digest, Sha256};
 use simsearch::{SearchOptions, SimSearch};
 use std::{
-    fs,
+    fmt, fs,
     io::{self, prelude::*, BufReader},
     str::FromStr,
 };
@@ -60,38 +71,109 @@ impl FromStr for IndexConfig {
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct IndexConfInner {
+    /// The human-readable name that the index gives itself.
+    #[serde(default)]
+    pub name: Option<String>,
     pub secure: bool,
     pub dependencies: IndexMap<String, IndexRes>,
+    /// The key which the index's contents and entries are signed with, if they're signed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<PublicKey>,
 }
 
 impl Default for IndexConfInner {
     fn default() -> Self {
         IndexConfInner {
+            name: None,
             secure: false,
             dependencies: IndexMap::new(),
+            public_key: None,
         }
     }
 }
 
+/// Moves the index with the alias `name` to the front of an index mapping, making it the default
+/// index and the index with the highest priority.
+pub fn prioritize_index(ixmap: &mut IndexMap<String, IndexRes>, name: &str) -> Result<()> {
+    let res = ixmap.shift_remove(name).ok_or_else(|| {
+        format_err!(
+            "no index named `{}` is configured (available indices: {})",
+            name,
+            ixmap.keys().join(", ")
+        )
+    })?;
+
+    let mut new = IndexMap::with_capacity(ixmap.len() + 1);
+    new.insert(name.to_string(), res);
+    new.extend(ixmap.drain(..));
+    *ixmap = new;
+
+    Ok(())
+}
+
 /// A dependency.
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Dep<T> {
     pub name: Name,
     pub index: T,
+    #[serde(deserialize_with = "constraint::deserialize")]
     pub req: Constraint,
+    /// Whether this dependency is only pulled in when one of the package's features asks for it.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub optional: bool,
+    /// The features of the dependency which the package asks to be turned on.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
 }
 
 pub type ResolvedDep = Dep<IndexRes>;
 pub type RawDep = Dep<Option<String>>;
 
+/// A version of a package which two indices both define, but differently: they each say it's
+/// retrieved from a different place, or with a different checksum. This can mean that one index
+/// is shadowing a package from the other, like a private index which happens to contain a
+/// package with the same name as a public one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexConflict {
+    pub name: Name,
+    pub version: Version,
+    /// The name of the index the package was actually taken from, along with its location there.
+    pub used: (String, DirectRes),
+    /// The name of the other index, along with its location there.
+    pub other: (String, DirectRes),
+}
+
+impl fmt::Display for IndexConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} is defined differently by index `{}` ({}) and index `{}` ({})",
+            self.name, self.version, self.used.0, self.used.1, self.other.0, self.other.1
+        )
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct Indices {
-    /// The indices being used.
+    /// The indices being used, in order of priority.
     ///
     /// It is assumed that all dependent indices have been resolved, and that this mapping contains
     /// every index mentioned or depended on.
     pub indices: IndexMap<IndexRes, Index>,
     pub cache: IndexMap<PackageId, IndexMap<Version, ResolvedEntry>>,
+    /// Whether a package which is defined differently by two indices is an error, rather than
+    /// something to warn about.
+    pub strict: bool,
+    /// The conflicts between indices which were found while loading packages, and which nobody
+    /// has warned about yet.
+    pub conflicts: Vec<IndexConflict>,
+    /// Warnings about the parts of entries which were ignored while loading packages, which
+    /// nobody has passed on yet.
+    pub warnings: Vec<String>,
 }
 
 impl Indices {
@@ -99,7 +181,91 @@ impl Indices {
         let indices = indices.into_iter().map(|i| (i.id.clone(), i)).collect();
         let cache = IndexMap::new();
 
-        Indices { indices, cache }
+        Indices {
+            indices,
+            cache,
+            strict: false,
+            conflicts: vec![],
+            warnings: vec![],
+        }
+    }
+
+    /// Finds every version of the package `name` in the index `ir` which another index defines
+    /// differently. Indices which don't have the package at all (or can't be read) are skipped.
+    pub fn find_conflicts(
+        &self,
+        ir: &IndexRes,
+        name: &Name,
+        entries: &IndexMap<Version, ResolvedEntry>,
+    ) -> Vec<IndexConflict> {
+        let label = |ir: &IndexRes, ix: &Index| {
+            ix.name()
+                .map(str::to_owned)
+                .unwrap_or_else(|| ir.to_string())
+        };
+        let used = &self.indices[ir];
+
+        let mut conflicts = vec![];
+        for (other_ir, other) in self.indices.iter().filter(|(x, _)| *x != ir) {
+            let others = match other.entries(name) {
+                Ok(others) => others,
+                Err(_) => continue,
+            };
+            for (version, entry) in entries {
+                if let Some(theirs) = others.get(version) {
+                    if theirs.location != entry.location {
+                        conflicts.push(IndexConflict {
+                            name: name.clone(),
+                            version: version.clone(),
+                            used: (label(ir, used), entry.location.clone()),
+                            other: (label(other_ir, other), theirs.location.clone()),
+                        });
+                    }
+                }
+            }
+        }
+
+        conflicts
+    }
+
+    /// Names the indices according to the aliases given to them in the configuration, which take
+    /// precedence over the names the indices give themselves. The priority of the indices is
+    /// also reordered to follow the order of the configuration; indices which aren't mentioned
+    /// keep their relative order after the configured ones.
+    pub fn alias(&mut self, ixmap: &IndexMap<String, IndexRes>) {
+        let mut ordered = IndexMap::with_capacity(self.indices.len());
+        for (alias, ir) in ixmap {
+            if let Some(mut ix) = self.indices.shift_remove(ir) {
+                ix.name = Some(alias.clone());
+                ordered.insert(ir.clone(), ix);
+            }
+        }
+        ordered.extend(self.indices.drain(..));
+        self.indices = ordered;
+    }
+
+    /// Refreshes every index in place, throwing away any entries which were already loaded.
+    pub fn refresh(&mut self, client: &Client, auth: &Credentials) -> Result<()> {
+        self.cache.clear();
+        for ix in self.indices.values_mut() {
+            ix.refresh(client, auth)?;
+        }
+
+        Ok(())
+    }
+
+    /// Finds an index by its human-readable name.
+    pub fn by_name(&self, name: &str) -> Result<&Index> {
+        self.indices
+            .values()
+            .find(|ix| ix.name() == Some(name))
+            .ok_or_else(|| {
+                format_err!(
+                    "no index named `{}` (available indices: {})",
+                    name,
+                    self.indices.values().filter_map(|ix| ix.name()).join(", ")
+                )
+            })
     }
 
     pub fn select_by_spec(&self, spec: &Spec) -> Result<Summary> {
@@ -165,8 +331,20 @@ impl Indices {
             let ix = self.indices.get(ir);
 
             if let Some(ix) = ix {
-                let mut v = ix.entries(pkg.name())?;
+                let (mut v, warnings) = ix.entries_with_warnings(pkg.name())?;
                 v.sort_keys();
+                self.warnings.extend(warnings);
+
+                let conflicts = self.find_conflicts(ir, pkg.name(), &v);
+                if self.strict && !conflicts.is_empty() {
+                    bail!(
+                        "indices disagree about {}:\n{}",
+                        pkg.name(),
+                        conflicts.iter().map(|c| format!("  {}", c)).join("\n")
+                    );
+                }
+                self.conflicts.extend(conflicts);
+
                 self.cache.insert(pkg.clone(), v);
                 Ok(&self.cache[pkg])
             } else {
@@ -177,7 +355,37 @@ impl Indices {
         }
     }
 
-    pub fn search(&self, query: &str) -> Result<Vec<(Name, Version, &IndexRes)>> {
+    /// Returns every version of the package `name` which any of the indices has, newest first.
+    /// Versions which more than one index has are only listed once, and yanked versions aren't
+    /// listed at all. A package which no index has has no versions.
+    pub fn versions(&self, name: &Name) -> Result<Vec<Version>> {
+        let mut versions = vec![];
+        for ix in self.indices.values() {
+            match ix.entries(name) {
+                Ok(entries) => versions.extend(
+                    entries
+                        .into_iter()
+                        .filter(|(_, entry)| !entry.yanked)
+                        .map(|(version, _)| version),
+                ),
+                Err(e) if is_not_found(&e) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        versions.sort_by(|a, b| b.cmp(a));
+        versions.dedup();
+        Ok(versions)
+    }
+
+    /// Searches the names of the packages in the indices for `query`, returning the newest
+    /// version of each package which matches. If a channel is given, the newest version on that
+    /// channel is returned instead, and packages with nothing on it are left out.
+    pub fn search(
+        &self,
+        query: &str,
+        channel: Option<&str>,
+    ) -> Result<Vec<(Name, Version, &IndexRes)>> {
         let mut engine: SimSearch<(&IndexRes, &str)> =
             SimSearch::new_with(SearchOptions::new().stop_words(&["/", "\\"]));
         let x = self
@@ -192,18 +400,29 @@ impl Indices {
         }
         let pkgs = engine.search(query);
 
-        pkgs.iter()
-            .map(|(ir, pkg)| {
-                let name = Name::from_str(pkg).unwrap();
-                let ix = &self.indices[*ir];
-                let ver: Version = ix.entries(&name)?.into_iter().map(|x| x.0).last().unwrap();
+        let mut res = vec![];
+        for (ir, pkg) in pkgs {
+            let name = Name::from_str(pkg).unwrap();
+            let ix = &self.indices[ir];
+            if let Some(newest) = ix.summaries(&name, channel)?.pop() {
+                res.push((name, newest.version().clone(), ir));
+            }
+        }
 
-                Ok((name, ver, *ir))
-            })
-            .collect::<Result<Vec<_>>>()
+        Ok(res)
     }
 }
 
+/// Whether an error from `Index::entries` just means that the index doesn't have the package.
+fn is_not_found(e: &failure::Error) -> bool {
+    // The error is either PackageNotFound itself, or a context of it over the io error
+    e.iter_chain().any(|c| {
+        c.downcast_ref::<Error>() == Some(&Error::PackageNotFound)
+            || c.downcast_ref::<Context<Error>>().map(|x| *x.get_context())
+                == Some(Error::PackageNotFound)
+    })
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct IndexEntry<D, L> {
     pub name: Name,
@@ -211,11 +430,304 @@ pub struct IndexEntry<D, L> {
     pub dependencies: Vec<Dep<D>>,
     pub yanked: bool,
     pub location: L,
+    /// The release channel this version was published to, like `stable` or `nightly`. Entries
+    /// which don't give one are on the channel their version implies: see `IndexEntry::channel`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    /// The package's `features` table, with optional dependencies under their real names.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub features: IndexMap<String, Vec<String>>,
+    #[serde(flatten)]
+    pub meta: EntryMeta,
+    /// The signature of this entry, for indices which sign their entries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+/// Every field an index entry can have, including the flattened fields of `EntryMeta`.
+const ENTRY_FIELDS: &[&str] = &[
+    "name",
+    "version",
+    "dependencies",
+    "yanked",
+    "location",
+    "channel",
+    "features",
+    "signature",
+    "description",
+    "homepage",
+    "repository",
+    "license",
+    "keywords",
+    "categories",
+    "badges",
+    "size",
+];
+
+/// Parses the entry on line `lix` of the entries of the package `name`, which starts `offset`
+/// bytes into the file. If the entry is broken, the error says which package and version it's
+/// for (as far as they can be made out) and where exactly the problem is.
+///
+/// Fields which this version of elba doesn't know about (like ones added by a newer version) are
+/// ignored, with a warning for each pushed onto `warnings`.
+fn parse_entry(
+    line: &str,
+    name: &Name,
+    lix: usize,
+    offset: usize,
+    warnings: &mut Vec<String>,
+) -> Result<RawEntry> {
+    let fail = |kind: Error, which: String, byte: usize, detail: String| -> failure::Error {
+        kind.context(format!(
+            "invalid index entry for {} at line {} (byte {}): {}",
+            which, lix, byte, detail
+        ))
+        .into()
+    };
+
+    let mut value = serde_json::from_str::<serde_json::Value>(line).map_err(|e| {
+        let byte = offset + e.column().saturating_sub(1);
+        // serde_json adds its own position (within the line) to the end, which we replace
+        let detail = e.to_string();
+        let detail = detail.rsplitn(2, " at line ").last().unwrap_or(&detail);
+        fail(
+            Error::MalformedIndexEntry,
+            name.to_string(),
+            byte,
+            detail.to_owned(),
+        )
+    })?;
+    let fields = value.as_object().ok_or_else(|| {
+        fail(
+            Error::MalformedIndexEntry,
+            name.to_string(),
+            offset,
+            "expected a json object".to_owned(),
+        )
+    })?;
+
+    let which = match (
+        fields.get("name").and_then(|x| x.as_str()),
+        fields.get("version").and_then(|x| x.as_str()),
+    ) {
+        (Some(n), Some(v)) => format!("{} {}", n, v),
+        (Some(n), None) => n.to_owned(),
+        (None, Some(v)) => format!("{} {}", name, v),
+        (None, None) => name.to_string(),
+    };
+
+    let unknown = fields
+        .keys()
+        .filter(|k| !ENTRY_FIELDS.contains(&k.as_str()))
+        .cloned()
+        .collect::<Vec<_>>();
+    for field in unknown {
+        let byte = line.find(&format!("\"{}\"", field)).unwrap_or(0) + offset;
+        warnings.push(format!(
+            "ignoring unknown field `{}` in index entry for {} at line {} (byte {})",
+            field, which, lix, byte
+        ));
+        if let Some(fields) = value.as_object_mut() {
+            fields.remove(&field);
+        }
+    }
+
+    let entry: RawEntry = serde_json::from_value(value).map_err(|e| {
+        let detail = e.to_string();
+        let kind = if detail.starts_with("missing field") {
+            Error::MissingIndexField
+        } else {
+            Error::MalformedIndexEntry
+        };
+        fail(kind, which.clone(), offset, detail)
+    })?;
+
+    // Entries are only ever looked up by the path they're filed under, so an entry which claims to
+    // be some other package would pass itself off as the package at that path
+    if entry.name != *name {
+        return Err(fail(
+            Error::InvalidIndex,
+            which,
+            offset,
+            format!(
+                "the entry is for {}, but it's filed under `{}`",
+                entry.name,
+                name.as_normalized()
+            ),
+        ));
+    }
+
+    Ok(entry)
+}
+
+impl<D, L> IndexEntry<D, L> {
+    /// The release channel of this version. If the entry doesn't name one, releases are on the
+    /// `stable` channel and prereleases are on the channel named by the first part of their
+    /// prerelease tag, so `1.0.0-nightly.3` is on `nightly`.
+    pub fn channel(&self) -> String {
+        match (&self.channel, self.version.pre.first()) {
+            (Some(channel), _) => channel.clone(),
+            (None, Some(tag)) => tag.to_string(),
+            (None, None) => "stable".to_owned(),
+        }
+    }
+
+    /// The dependencies of this version which are pulled in when the given features are
+    /// requested of it: every required dependency, and the optional dependencies which those
+    /// features (and the `default` feature, if there is one) turn on.
+    ///
+    /// Features can also turn on the package's dev-dependencies, which aren't in the index; those
+    /// entries don't matter here, so they're skipped.
+    pub fn active_deps(&self, features: &[String]) -> Result<Vec<&Dep<D>>> {
+        let enabled = enabled_items(&self.name, &self.features, features)?
+            .iter()
+            .filter_map(|item| Name::from_str(item).ok())
+            .collect::<Vec<_>>();
+
+        Ok(self
+            .dependencies
+            .iter()
+            .filter(|dep| !dep.optional || enabled.contains(&dep.name))
+            .collect())
+    }
 }
 
 pub type ResolvedEntry = IndexEntry<IndexRes, DirectRes>;
 pub type RawEntry = IndexEntry<Option<String>, Option<DirectRes>>;
 
+impl RawEntry {
+    /// Creates the index entry which would be published for a package.
+    ///
+    /// Only packages whose dependencies all come from indices can be published. Index entries
+    /// can't express platform-specific dependencies, so packages with them can't be published yet.
+    pub fn from_manifest(manifest: &Manifest, location: Option<DirectRes>) -> Result<RawEntry> {
+        if let Some(cfg) = manifest.platform_deps.keys().next() {
+            bail!(
+                "can't publish {}: platform-specific dependencies (like those for {}) can't be recorded in an index",
+                manifest.name(),
+                cfg
+            )
+        }
+
+        let dependencies = manifest
+            .dependencies
+            .iter()
+            .map(|(name, req)| match req {
+                DepReq::Registry(req) => Ok(Dep {
+                    name: name.clone(),
+                    index: None,
+                    req: req.clone(),
+                    optional: false,
+                    features: vec![],
+                }),
+                // Aliases only matter to the package itself, so the index gets the real name
+                DepReq::RegLong {
+                    version,
+                    index,
+                    package,
+                    optional,
+                    features,
+                } => Ok(Dep {
+                    name: package.as_ref().unwrap_or(name).clone(),
+                    index: index.clone(),
+                    req: version.clone(),
+                    optional: *optional,
+                    features: features.clone(),
+                }),
+                _ => bail!(
+                    "can't publish {}: the dependency {} doesn't come from an index",
+                    manifest.name(),
+                    name
+                ),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Features turn on optional dependencies by the name they're declared with, which has to
+        // be swapped for the real name too
+        let aliases = manifest.aliases();
+        let features = manifest
+            .features
+            .iter()
+            .map(|(feature, enables)| {
+                let enables = enables
+                    .iter()
+                    .map(|item| {
+                        Name::from_str(item)
+                            .ok()
+                            .and_then(|n| aliases.get(&n))
+                            .map(|real| real.to_string())
+                            .unwrap_or_else(|| item.clone())
+                    })
+                    .collect();
+                (feature.clone(), enables)
+            })
+            .collect();
+
+        Ok(IndexEntry {
+            name: manifest.name().clone(),
+            version: manifest.version().clone(),
+            dependencies,
+            yanked: false,
+            location,
+            channel: None,
+            features,
+            meta: EntryMeta::from_manifest(manifest),
+            signature: None,
+        })
+    }
+
+    /// The message which is signed to vouch for this entry: the entry itself, serialized without
+    /// its signature. Signed indices only use tarball locations with checksums, so this pins down
+    /// the contents of the package too.
+    pub fn signing_message(&self) -> Result<String> {
+        let unsigned = RawEntry {
+            signature: None,
+            ..self.clone()
+        };
+
+        Ok(serde_json::to_string(&unsigned)?)
+    }
+}
+
+/// Extra information about a package which indices can show to users, but which doesn't affect
+/// resolution. Missing fields are left out of the index entry entirely.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct EntryMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub homepage: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repository: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keywords: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub categories: Vec<String>,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub badges: IndexMap<String, IndexMap<String, String>>,
+    /// The size of the package's download in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+impl EntryMeta {
+    pub fn from_manifest(manifest: &Manifest) -> EntryMeta {
+        let info = &manifest.package;
+        EntryMeta {
+            description: info.description.clone(),
+            homepage: info.homepage.clone(),
+            repository: info.repository.clone(),
+            license: info.license.clone(),
+            keywords: info.keywords.clone(),
+            categories: info.categories.clone(),
+            badges: manifest.badges.clone(),
+            size: None,
+        }
+    }
+}
+
 /// Struct `Index` defines a single index.
 ///
 /// Indices must be sharded by group name.
@@ -223,16 +735,47 @@ pub type RawEntry = IndexEntry<Option<String>, Option<DirectRes>>;
 pub struct Index {
     /// Indicates identifying information about the index
     pub id: IndexRes,
+    /// The human-readable name of the index. This is either the alias given to the index in the
+    /// configuration or the name the index gives itself.
+    pub name: Option<String>,
     /// Indicates where this index is stored on-disk.
     pub path: DirLock,
     /// The configuration of this index.
     pub config: IndexConfig,
+    /// Where to fetch entries which haven't been cached yet from, if this is a sparse index.
+    /// Without it, a sparse index only knows about the entries it already has on disk.
+    pub remote: Option<Remote>,
 }
 
 impl Index {
     /// Creates a new empty package index directly from a Url and a local path.
     pub fn from_disk(res: DirectRes, path: DirLock) -> Result<Self> {
         let id = IndexRes { res };
+        let config = Self::read_config(&path)?;
+        let name = config.index.name.clone();
+
+        Ok(Index {
+            id,
+            name,
+            path,
+            config,
+            remote: None,
+        })
+    }
+
+    /// Lets a sparse index fetch the entries it doesn't have yet. This does nothing for any
+    /// other kind of index, since those are always retrieved in full.
+    pub fn connect(&mut self, client: &Client, auth: &Credentials) {
+        if let DirectRes::Sparse { url } = &self.id.res {
+            self.remote = Some(Remote {
+                url: url.clone(),
+                client: client.clone(),
+                auth: auth.clone(),
+            });
+        }
+    }
+
+    fn read_config(path: &DirLock) -> Result<IndexConfig> {
         let pn = path.path().join("index.toml");
         let file = fs::File::open(&pn)
             .with_context(|e| format_err!("couldn't open index config {}: {}", pn.display(), e))?;
@@ -240,23 +783,73 @@ impl Index {
         let mut contents = String::new();
         file.read_to_string(&mut contents)
             .with_context(|e| format_err!("couldn't read index config {}: {}", pn.display(), e))?;
-        let config = IndexConfig::from_str(&contents)?;
 
-        Ok(Index { id, path, config })
+        IndexConfig::from_str(&contents)
+    }
+
+    /// Re-retrieves the contents of this index into the directory it's already stored in, then
+    /// reloads its configuration. An index in a local directory is just reloaded from disk, and
+    /// a tarball index is only downloaded again if the server says it's changed.
+    pub fn refresh(&mut self, client: &Client, auth: &Credentials) -> Result<()> {
+        if !self.id.res.is_dir() {
+            self.id
+                .res
+                .retrieve_validated(client, auth, &self.path, true, |_| Ok(()))
+                .with_context(|e| format_err!("couldn't refresh index {}: {}", self.id, e))?;
+        }
+
+        let config = Self::read_config(&self.path)?;
+        // Aliases from the configuration take precedence over the name the index gives itself,
+        // so we only pick up a new name if the old one came from the index.
+        if self.name == self.config.index.name {
+            self.name = config.index.name.clone();
+        }
+        self.config = config;
+
+        Ok(())
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
     }
 
     pub fn entries(&self, name: &Name) -> Result<IndexMap<Version, ResolvedEntry>> {
+        self.entries_with_warnings(name).map(|(entries, _)| entries)
+    }
+
+    /// Like `entries`, but also returns warnings about the parts of the entries which were
+    /// ignored (see `parse_entry`).
+    pub fn entries_with_warnings(
+        &self,
+        name: &Name,
+    ) -> Result<(IndexMap<Version, ResolvedEntry>, Vec<String>)> {
         let mut res = IndexMap::new();
+        let mut warnings = vec![];
         let path = self.path.path().join(name.as_normalized());
-        let file = fs::File::open(path).context(Error::PackageNotFound)?;
-        let r = io::BufReader::new(&file);
-
-        for (lix, line) in r.lines().enumerate() {
-            let entry: RawEntry = serde_json::from_str(&line?).context(format_err!(
-                "index entry {} for package {} is invalid",
-                lix + 1,
-                name
-            ))?;
+        if let (Some(remote), false) = (&self.remote, path.exists()) {
+            if !remote
+                .fetch_to(name.as_normalized(), &path)
+                .with_context(|e| format_err!("couldn't fetch entries for {}: {}", name, e))?
+            {
+                bail!(Error::PackageNotFound)
+            }
+        }
+        let contents = fs::read_to_string(&path).context(Error::PackageNotFound)?;
+
+        for (lix, line) in contents.lines().enumerate() {
+            let offset = line.as_ptr() as usize - contents.as_ptr() as usize;
+            let entry = parse_entry(line, name, lix + 1, offset, &mut warnings)?;
+
+            if let Some(key) = &self.config.index.public_key {
+                self.verify_entry(&entry, key).with_context(|e| {
+                    format_err!(
+                        "signature verification failed for {} {}: {}",
+                        entry.name,
+                        entry.version,
+                        e
+                    )
+                })?;
+            }
 
             let dependencies = entry
                 .dependencies
@@ -271,6 +864,8 @@ impl Index {
                         index,
                         name: x.name,
                         req: x.req,
+                        optional: x.optional,
+                        features: x.features,
                     }
                 })
                 .collect::<Vec<_>>();
@@ -289,14 +884,108 @@ impl Index {
                 dependencies,
                 yanked: entry.yanked,
                 location,
+                channel: entry.channel,
+                features: entry.features,
+                meta: entry.meta,
+                signature: entry.signature,
             };
 
             res.insert(entry.version.clone(), entry);
         }
 
-        Ok(res)
+        Ok((res, warnings))
+    }
+
+    /// Returns a summary of every version of a package in this index, oldest first. If a channel
+    /// is given, only the versions on that channel are included.
+    pub fn summaries(&self, name: &Name, channel: Option<&str>) -> Result<Vec<Summary>> {
+        let pkg = PackageId::new(name.clone(), self.id.clone().into());
+
+        Ok(self
+            .entries(name)?
+            .into_iter()
+            .filter(|(_, entry)| match channel {
+                Some(c) => entry.channel() == c,
+                None => true,
+            })
+            .map(|(version, _)| Summary::new(pkg.clone(), version))
+            .collect())
+    }
+
+    /// Returns the checksum recorded for every version of a package in this index, which is None
+    /// for versions which aren't tarballs with a checksum. A package which isn't in the index at
+    /// all has no versions.
+    pub fn checksums(&self, name: &Name) -> Result<IndexMap<Version, Option<Checksum>>> {
+        let entries = match self.entries(name) {
+            Ok(entries) => entries,
+            Err(e) if is_not_found(&e) => return Ok(IndexMap::new()),
+            Err(e) => return Err(e),
+        };
+
+        Ok(entries
+            .into_iter()
+            .map(|(version, entry)| match entry.location {
+                DirectRes::Tar { cksum, .. } => (version, cksum),
+                _ => (version, None),
+            })
+            .collect())
+    }
+
+    fn verify_entry(&self, entry: &RawEntry, key: &PublicKey) -> Result<()> {
+        match &entry.location {
+            Some(DirectRes::Tar { cksum: Some(_), .. }) => {}
+            _ => bail!("entries of a signed index must be tarballs with a checksum"),
+        }
+        let signature = entry
+            .signature
+            .as_ref()
+            .ok_or_else(|| format_err!("the entry isn't signed"))?;
+
+        key.verify(entry.signing_message()?.as_bytes(), signature)
+    }
+
+    /// Returns a digest of the contents of this index: every file in it (except for its
+    /// signature and any VCS or lock files), along with their paths.
+    pub fn digest(&self) -> Result<String> {
+        let root = self.path.path();
+        let mut files = WalkDir::new(root)
+            .into_iter()
+            .filter_entry(|x| x.file_name() != ".git" && x.file_name() != ".dirlock")
+            .filter_map(|x| x.ok())
+            .filter(|x| x.file_type().is_file() && x.path() != root.join("index.sig"))
+            .map(|x| x.path().strip_prefix(root).unwrap().to_path_buf())
+            .collect::<Vec<_>>();
+        files.sort();
+
+        let mut hasher = Sha256::default();
+        for file in files {
+            hasher.input(file.to_string_lossy().replace("\\", "/").as_bytes());
+            hasher.input([0]);
+            let mut f = fs::File::open(root.join(&file))?;
+            io::copy(&mut f, &mut hasher)?;
+        }
+
+        Ok(hex::encode(hasher.result()))
+    }
+
+    /// Checks that the signature in the `index.sig` file at the root of this index is a valid
+    /// signature of its digest.
+    pub fn verify_signature(&self, key: &PublicKey) -> Result<()> {
+        let pn = self.path.path().join("index.sig");
+        let signature = fs::read_to_string(&pn).with_context(|e| {
+            format_err!("couldn't read index signature {}: {}", pn.display(), e)
+        })?;
+
+        key.verify(self.digest()?.as_bytes(), &signature)
+            .with_context(|e| {
+                format_err!("signature verification failed for index {}: {}", self.id, e)
+            })?;
+
+        Ok(())
     }
 
+    /// Lists the packages in this index. For a sparse index, that's only the packages whose
+    /// entries have been fetched already.
     pub fn packages(&self) -> impl Iterator<Item = String> {
         let root_path = self.path.path().to_path_buf();
         let git_path = root_path.join(".git");
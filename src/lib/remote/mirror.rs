@@ -0,0 +1,109 @@
+//! Source replacement, for pointing elba at mirrors of the hosts packages and indices come from.
+//!
+//! Mirrors are given in the `[mirrors]` section of the configuration, as url prefixes mapped to
+//! what they should be replaced with:
+//!
+//! ```toml
+//! [mirrors]
+//! "https://github.com/" = "https://mirror.internal/github/"
+//! ```
+//!
+//! Replacement only changes where things are downloaded from. Everything elba records about a
+//! package (its id, the cache directory it goes in, its build hash, and its entry in the
+//! lockfile) keeps using the original url, so turning a mirror on or off never invalidates the
+//! cache, and lockfiles stay the same whether they're written behind a mirror or not.
+
+use failure::format_err;
+use indexmap::IndexMap;
+use url::Url;
+
+use crate::{remote::resolution::DirectRes, util::error::Result};
+
+/// The source replacement rules to apply to every url before something is retrieved from it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Mirrors {
+    /// Pairs of a url prefix and its replacement, with the longest prefixes first.
+    rules: Vec<(String, String)>,
+}
+
+impl Mirrors {
+    /// Creates a set of rules from the `[mirrors]` section of the configuration. If more than one
+    /// prefix matches a url, the longest one wins.
+    pub fn new(rules: &IndexMap<String, String>) -> Self {
+        let mut rules = rules
+            .iter()
+            .map(|(from, to)| (from.clone(), to.clone()))
+            .collect::<Vec<_>>();
+        rules.sort_by_key(|rule| std::cmp::Reverse(rule.0.len()));
+
+        Mirrors { rules }
+    }
+
+    /// Returns where a resolution should actually be retrieved from. Directories can't be
+    /// mirrored, and resolutions which don't match any rule are passed through unchanged.
+    pub fn apply(&self, res: &DirectRes) -> Result<DirectRes> {
+        Ok(match res {
+            DirectRes::Tar {
+                url,
+                cksum,
+                subpath,
+            } => DirectRes::Tar {
+                url: self.replace(url)?,
+                cksum: cksum.clone(),
+                subpath: subpath.clone(),
+            },
+            DirectRes::Git {
+                repo,
+                tag,
+                rev,
+                subdir,
+            } => DirectRes::Git {
+                repo: self.replace(repo)?,
+                tag: tag.clone(),
+                rev: rev.clone(),
+                subdir: subdir.clone(),
+            },
+            DirectRes::Sparse { url } => DirectRes::Sparse {
+                url: self.replace(url)?,
+            },
+            DirectRes::Dir { .. } => res.clone(),
+        })
+    }
+
+    /// Undoes the replacement of a resolution which was returned from retrieving its mirrored
+    /// counterpart `fetched`, so that it can be recorded under the original url.
+    pub fn restore(&self, orig: &DirectRes, fetched: DirectRes) -> DirectRes {
+        match (orig, fetched) {
+            (
+                DirectRes::Git { repo, .. },
+                DirectRes::Git {
+                    tag, rev, subdir, ..
+                },
+            ) => DirectRes::Git {
+                repo: repo.clone(),
+                tag,
+                rev,
+                subdir,
+            },
+            (_, fetched) => fetched,
+        }
+    }
+
+    fn replace(&self, url: &Url) -> Result<Url> {
+        let rule = self
+            .rules
+            .iter()
+            .find(|(from, _)| url.as_str().starts_with(from.as_str()))
+            .map(|(from, to)| (to, &url.as_str()[from.len()..]));
+
+        match rule {
+            Some((to, rest)) => {
+                let new = format!("{}{}", to, rest);
+                Url::parse(&new).map_err(|e| {
+                    format_err!("the mirror of {} has an invalid url {}: {}", url, new, e)
+                })
+            }
+            None => Ok(url.clone()),
+        }
+    }
+}
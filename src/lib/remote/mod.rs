@@ -1,4 +1,9 @@
+pub mod archive;
+pub mod auth;
 mod index;
+pub mod mirror;
 pub mod resolution;
+pub mod signature;
+pub mod sparse;
 
 pub use self::index::*;
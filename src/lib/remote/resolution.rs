@@ -1,11 +1,21 @@
 use std::io;
 
-use std::{fmt, fs, io::BufReader, path::PathBuf, str::FromStr};
+use std::{
+    fmt, fs,
+    io::{prelude::*, BufReader, SeekFrom},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
 
-use failure::{bail, format_err, ResultExt};
-use flate2::read::GzDecoder;
+use failure::{bail, format_err, Fail, ResultExt};
 use git2::{BranchType, Repository, Sort};
-use reqwest::blocking::Client;
+use reqwest::{
+    blocking::{Client, RequestBuilder},
+    header::{
+        HeaderMap, CONTENT_RANGE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RANGE,
+    },
+    StatusCode,
+};
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use sha2::{Digest, Sha256};
 use tar::Archive;
@@ -13,11 +23,17 @@ use url::Url;
 
 use crate::{
     package::Checksum,
+    remote::{
+        archive::ArchiveFormats,
+        auth::{is_unauthorized, Credentials},
+        sparse,
+    },
     util::{
-        clear_dir,
+        clear_dir, clear_dir_except,
         error::{Error, Result},
         git::{clone, fetch, reset, update_submodules},
         lock::DirLock,
+        SubPath,
     },
 };
 
@@ -127,7 +143,20 @@ impl Resolution {
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum DirectRes {
     /// Git: the package originated from a git repository.
-    Git { repo: Url, tag: String },
+    ///
+    /// The `tag` is whatever the user asked for (a branch, tag, or commit), while the `rev` is the
+    /// exact commit that the tag was resolved to the last time the repository was retrieved. If a
+    /// `rev` is present, that commit is checked out instead of the tag, which makes builds of
+    /// packages pinned to a branch reproducible.
+    ///
+    /// If the package isn't at the root of the repository, `subdir` says which directory of it
+    /// the package is in. Only that directory is part of the package (and its hash).
+    Git {
+        repo: Url,
+        tag: String,
+        rev: Option<String>,
+        subdir: Option<SubPath>,
+    },
     /// Dir: the package is on disk in a folder directory.
     Dir { path: PathBuf },
     /// Tar: the package is an archive stored somewhere.
@@ -136,64 +165,671 @@ pub enum DirectRes {
     /// really make sense for DirectRes::Local, and we leave validation of repositories to Git
     /// itself. Checksums are stored in the fragment of the resolution url, with they key being the
     /// checksum format.
-    Tar { url: Url, cksum: Option<Checksum> },
+    ///
+    /// If the package isn't at the root of the archive, `subpath` says which directory of it is
+    /// the package root; it goes in the fragment too, after the checksum: `#sha256=...&subpath=x`.
+    Tar {
+        url: Url,
+        cksum: Option<Checksum>,
+        subpath: Option<SubPath>,
+    },
+    /// Sparse: an index whose files are fetched one at a time over HTTP (see `remote::sparse`).
+    ///
+    /// This only makes sense as the resolution of an index, so it can only be parsed as part of
+    /// an `index+sparse+...` url.
+    Sparse { url: Url },
 }
 
 impl DirectRes {
     pub fn lowkey_eq(&self, other: &DirectRes) -> bool {
         match (self, other) {
-            (DirectRes::Git { repo: r1, .. }, DirectRes::Git { repo: r2, .. }) => r1 == r2,
+            (
+                DirectRes::Git {
+                    repo: r1,
+                    subdir: s1,
+                    ..
+                },
+                DirectRes::Git {
+                    repo: r2,
+                    subdir: s2,
+                    ..
+                },
+            ) => r1 == r2 && s1 == s2,
             _ => self == other,
         }
     }
+
+    /// The string which identifies this resolution in the cache.
+    ///
+    /// Cache directories are named after the hash of this key, so unlike `Display`, its format
+    /// must never change without bumping the hash version (see `util::hash`). Keys look like:
+    ///
+    /// - `git+<repo>#<tag>`, followed by `:<rev>` if there is a rev and `&subdir=<path>` if there
+    ///   is a subdir
+    /// - `dir+<path>`, with `.` components and trailing slashes left out
+    /// - `tar+<url>`, followed by `#<fmt>=<hash>` if there is a checksum and `&subpath=<path>` (or
+    ///   `#subpath=<path>`, without a checksum) if there is a subpath
+    /// - `sparse+<url>`
+    ///
+    /// Fragments never make it to the server, so they're dropped from urls, as is an empty query
+    /// string. Non-empty query strings are kept as they are, since they might pick out entirely
+    /// different files.
+    pub fn cache_key(&self) -> String {
+        match self {
+            DirectRes::Git {
+                repo,
+                tag,
+                rev,
+                subdir,
+            } => {
+                let mut key = format!("git+{}#{}", url_key(repo), tag);
+                if let Some(rev) = rev {
+                    key.push(':');
+                    key.push_str(rev);
+                }
+                if let Some(subdir) = subdir {
+                    key.push_str(&format!("&subdir={}", subdir.0.display()));
+                }
+                key
+            }
+            DirectRes::Dir { path } => {
+                let path = path
+                    .components()
+                    .filter(|x| *x != Component::CurDir)
+                    .collect::<PathBuf>();
+                format!("dir+{}", path.display())
+            }
+            DirectRes::Tar {
+                url,
+                cksum,
+                subpath,
+            } => {
+                let mut key = format!("tar+{}", url_key(url));
+                if let Some(cksum) = cksum {
+                    key.push_str(&format!("#{}={}", cksum.fmt, cksum.hash));
+                }
+                if let Some(subpath) = subpath {
+                    key.push(if cksum.is_some() { '&' } else { '#' });
+                    key.push_str(&format!("subpath={}", subpath.0.display()));
+                }
+                key
+            }
+            DirectRes::Sparse { url } => format!("sparse+{}", url_key(url)),
+        }
+    }
+
+    /// Like `cache_key`, but git repositories are only identified by their url (and subdir, if
+    /// they have one), the same way `lowkey_eq` compares them.
+    pub fn lowkey_cache_key(&self) -> String {
+        match self {
+            DirectRes::Git {
+                repo, subdir: None, ..
+            } => format!("git+{}", url_key(repo)),
+            DirectRes::Git {
+                repo,
+                subdir: Some(subdir),
+                ..
+            } => format!("git+{}#&subdir={}", url_key(repo), subdir.0.display()),
+            _ => self.cache_key(),
+        }
+    }
+
+    /// Parses where a dependency comes from, as it's written in a manifest. `value` is either a
+    /// resolution string like `git+<url>#<tag>` (see `FromStr`), or a dependency table using one
+    /// of the shorthands:
+    ///
+    /// - `{ git = "<url>" }`, with an optional `tag` (`master` by default) and `subdir`
+    /// - `{ path = "<path>" }`
+    /// - `{ tar = "<url>" }`, or `{ tar = { url = "<url>", cksum = "<fmt>=<hash>" } }` with an
+    ///   optional `subpath`
+    ///
+    /// The other keys of a dependency table (like `features`) don't say where it comes from, so
+    /// they're left alone. Errors name the field which is wrong.
+    pub fn from_manifest_value(value: &toml::Value) -> Result<Self> {
+        let table = match value {
+            toml::Value::String(s) => {
+                return Ok(DirectRes::from_str(s)
+                    .with_context(|e| format_err!("`{}` isn't a valid source: {}", s, e))?);
+            }
+            toml::Value::Table(table) => table,
+            _ => bail!("should be a source string or a table"),
+        };
+
+        let sources = ["git", "path", "tar"]
+            .iter()
+            .filter(|x| table.contains_key(**x))
+            .collect::<Vec<_>>();
+        let source = match sources.as_slice() {
+            [source] => **source,
+            [] => bail!("doesn't say where the package comes from (with `git`, `path` or `tar`)"),
+            [a, b, ..] => bail!("`{}` and `{}` can't be used together", a, b),
+        };
+        if let Some(key) = ["tag", "subdir"]
+            .iter()
+            .find(|x| source != "git" && table.contains_key(**x))
+        {
+            bail!("`{}` only applies to `git` dependencies", key)
+        }
+
+        match source {
+            "git" => {
+                let repo = manifest_str(table, "", "git")?.unwrap();
+                let repo = Url::parse(repo)
+                    .with_context(|e| format_err!("`git` isn't a valid url: {}", e))?;
+                let tag = manifest_str(table, "", "tag")?.unwrap_or("master");
+                let subdir = manifest_str(table, "", "subdir")?
+                    .map(|x| SubPath::from_str(x).with_context(|e| format_err!("`subdir`: {}", e)))
+                    .transpose()?;
+
+                Ok(DirectRes::Git {
+                    repo,
+                    tag: tag.to_owned(),
+                    rev: None,
+                    subdir,
+                })
+            }
+            "path" => Ok(DirectRes::Dir {
+                path: PathBuf::from(manifest_str(table, "", "path")?.unwrap()),
+            }),
+            _ => {
+                let (field, url, cksum, subpath) = match &table["tar"] {
+                    toml::Value::String(url) => ("tar", url.as_str(), None, None),
+                    toml::Value::Table(tar) => {
+                        if let Some(key) = tar
+                            .keys()
+                            .find(|x| !["url", "cksum", "subpath"].contains(&x.as_str()))
+                        {
+                            bail!(
+                                "`tar.{}` isn't a field of a tarball (expected `url`, `cksum` or `subpath`)",
+                                key
+                            )
+                        }
+                        let url = manifest_str(tar, "tar.", "url")?
+                            .ok_or_else(|| format_err!("`tar.url` is missing"))?;
+                        let cksum = manifest_str(tar, "tar.", "cksum")?
+                            .map(|x| {
+                                Checksum::from_str(x)
+                                    .with_context(|e| format_err!("`tar.cksum`: {}", e))
+                            })
+                            .transpose()?;
+                        let subpath = manifest_str(tar, "tar.", "subpath")?
+                            .map(|x| {
+                                SubPath::from_str(x)
+                                    .with_context(|e| format_err!("`tar.subpath`: {}", e))
+                            })
+                            .transpose()?;
+                        ("tar.url", url, cksum, subpath)
+                    }
+                    _ => bail!("`tar` should be a url or a table"),
+                };
+
+                let url = Url::parse(url)
+                    .with_context(|e| format_err!("`{}` isn't a valid url: {}", field, e))?;
+                if url.scheme() != "http" && url.scheme() != "https" && url.scheme() != "file" {
+                    bail!("`{}` should be an http, https or file url", field)
+                }
+
+                Ok(DirectRes::Tar {
+                    url,
+                    cksum,
+                    subpath,
+                })
+            }
+        }
+    }
+}
+
+/// The string field `key` of a table in a manifest, if it's there. `prefix` is where the table
+/// is, for naming the field in errors.
+fn manifest_str<'a>(
+    table: &'a toml::value::Table,
+    prefix: &str,
+    key: &str,
+) -> Result<Option<&'a str>> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(toml::Value::String(s)) => Ok(Some(s)),
+        Some(_) => bail!("`{}{}` should be a string", prefix, key),
+    }
+}
+
+/// A url as it's written in cache keys: without a fragment or an empty query string.
+fn url_key(url: &Url) -> String {
+    let mut url = url.clone();
+    url.set_fragment(None);
+    if url.query() == Some("") {
+        url.set_query(None);
+    }
+    url.as_str().to_owned()
+}
+
+/// What a server said identifies the copy of a file it last sent us, so that the file can be
+/// asked for again only if it's changed since. A server which answers `304 Not Modified` to a
+/// request with these is saying that the copy we have is still good.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Validators {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    /// Reads the validators saved at `path`. Validators which can't be read are as good as none.
+    pub fn load(path: &Path) -> Option<Self> {
+        serde_json::from_slice(&fs::read(path).ok()?)
+            .ok()
+            .filter(|x: &Validators| !x.is_empty())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_vec(self)?)?;
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |name| {
+            headers
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .map(str::to_owned)
+        };
+
+        Validators {
+            etag: get(ETAG),
+            last_modified: get(LAST_MODIFIED),
+        }
+    }
+
+    fn apply(&self, mut req: RequestBuilder) -> RequestBuilder {
+        if let Some(etag) = &self.etag {
+            req = req.header(IF_NONE_MATCH, etag.as_str());
+        }
+        if let Some(last_modified) = &self.last_modified {
+            req = req.header(IF_MODIFIED_SINCE, last_modified.as_str());
+        }
+        req
+    }
 }
 
 /// Retrieves a package in the form of a tarball.
+///
+/// The tarball is downloaded to a `.part` file next to the target directory, which is only
+/// renamed into place once it's been downloaded completely and its checksum has been verified. If
+/// a download is cut off, the next retrieval picks up where it left off using a range request (or
+/// starts over, if the server doesn't support those).
+///
+/// If `validators` is given, the validators of the tarball are saved there once it's unpacked,
+/// and sent along the next time it's retrieved into the same (non-empty) target. If the server
+/// says the tarball hasn't changed since, the target is left as it is and this returns false.
 fn retrieve_tar(
     url: Url,
     client: &Client,
+    auth: &Credentials,
     target: &DirLock,
     cksum: Option<&Checksum>,
-) -> Result<()> {
-    let mut resp = client
-        .get(url.as_str())
-        .send()
-        .and_then(|resp| resp.error_for_status())?;
-
-    let mut buf: Vec<u8> = vec![];
-    resp.copy_to(&mut buf)?;
+    subpath: Option<&SubPath>,
+    formats: &ArchiveFormats,
+    validators: Option<&Path>,
+) -> Result<bool> {
+    let part = sibling(target.path(), "part");
+    let cached = validators
+        .filter(|_| has_contents(target.path()))
+        .and_then(Validators::load);
+    let fresh = match download(&url, client, auth, &part, cached.as_ref())? {
+        Some(fresh) => fresh,
+        None => return Ok(false),
+    };
+    // The old validators don't say anything about a target which is about to be overwritten
+    if let Some(path) = validators {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
 
-    let hash = hex::encode(Sha256::digest(&buf[..]).as_slice());
+    let mut hash = Sha256::new();
+    io::copy(&mut fs::File::open(&part)?, &mut hash)?;
+    let hash = hex::encode(hash.result());
     if let Some(cksum) = cksum {
         if cksum.hash != hash {
+            // There's no use resuming a download which turned out to be wrong
+            fs::remove_file(&part)?;
             bail!(format_err!("tarball checksum doesn't match real checksum"));
         }
     }
 
-    let archive = GzDecoder::new(&buf[..]);
-    let mut archive = Archive::new(archive);
+    let tarball = sibling(target.path(), "tar.gz");
+    fs::rename(&part, &tarball)?;
+
+    unpack_tar(fs::File::open(&tarball)?, target.path(), subpath, formats)?;
+    fs::remove_file(&tarball)?;
+
+    if let (Some(path), false) = (validators, fresh.is_empty()) {
+        fresh.save(path)?;
+    }
+
+    Ok(true)
+}
+
+/// Unpacks a tarball into `target`, working out which of `formats` it's in from the bytes it
+/// starts with (see `remote::archive`).
+///
+/// Once it's unpacked, the package root is moved up to `target` if it isn't there already: that's
+/// `subpath` if one is given, and otherwise the single top-level directory of the archive, if
+/// the archive has no manifest of its own but that directory does (as with tarballs of a
+/// `<name>-<version>/` directory, which is how most hosts and tools make them).
+pub fn unpack_tar(
+    archive: impl Read,
+    target: &Path,
+    subpath: Option<&SubPath>,
+    formats: &ArchiveFormats,
+) -> Result<()> {
+    let mut archive = BufReader::new(archive);
+    let format = match formats.detect(archive.fill_buf()?) {
+        Some(format) => format,
+        None => bail!("the tarball isn't in any archive format that's known"),
+    };
+
+    clear_dir_except(target, &[Path::new(".dirlock")])?;
+    format.extract(&mut archive, target)?;
+
+    let root = match subpath {
+        Some(subpath) => {
+            let root = target.join(&subpath.0);
+            if !root.is_dir() {
+                bail!(
+                    "the tarball doesn't have a directory {}",
+                    subpath.0.display()
+                )
+            }
+            Some(root)
+        }
+        None => wrapper_dir(target)?,
+    };
+
+    if let Some(root) = root {
+        if root != target {
+            // Clearing it first gets rid of whatever an unpack which was cut off left behind
+            let staged = sibling(target, "root");
+            clear_dir(&staged)?;
+            fs::remove_dir(&staged)?;
+            fs::rename(&root, &staged)?;
+            clear_dir_except(target, &[Path::new(".dirlock")])?;
+            for entry in fs::read_dir(&staged)? {
+                let entry = entry?;
+                fs::rename(entry.path(), target.join(entry.file_name()))?;
+            }
+            fs::remove_dir(&staged)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Unpacks an uncompressed tarball into `target` one entry at a time, refusing any entry which
+/// would end up outside of `target`: paths which are absolute or climb out of it with `..`, and
+/// links which point out of it, whether directly or through other links. Entries before the
+/// offending one are already unpacked by the time it's refused.
+pub fn unpack_archive(archive: impl Read, target: &Path) -> Result<()> {
+    fs::create_dir_all(target)?;
+    let root = fs::canonicalize(target)?;
+    let mut links = vec![];
+
+    for entry in Archive::new(archive).entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let refuse = |why: String| -> failure::Error {
+            Error::UnsafeArchivePath
+                .context(format!("archive entry {} {}", path.display(), why))
+                .into()
+        };
 
-    clear_dir(target.path())?;
+        let inner = contained_path(&path)
+            .ok_or_else(|| refuse("is outside of the directory it's unpacked into".to_owned()))?;
+        if let Some(link) = entry.link_name()? {
+            // Hard links are relative to the root of the archive, symlinks to their own directory
+            let linked = if entry.header().entry_type().is_hard_link() {
+                link.to_path_buf()
+            } else {
+                inner.parent().unwrap_or_else(|| Path::new("")).join(&link)
+            };
+            if contained_path(&linked).is_none() || !resolves_inside(&target.join(&linked), &root) {
+                return Err(refuse(format!(
+                    "links to {}, which is outside of the directory it's unpacked into",
+                    link.display()
+                )));
+            }
+        }
+
+        entry.unpack_in(target)?;
 
-    archive.unpack(target.path())?;
+        // A link can point through links which come before or after it, so every link is
+        // followed again whenever there's a new one
+        if entry.header().entry_type().is_symlink() {
+            links.push(inner);
+            if let Some(escaped) = links
+                .iter()
+                .find(|x| !resolves_inside(&target.join(x), &root))
+            {
+                fs::remove_file(target.join(escaped))?;
+                return Err(refuse(format!(
+                    "makes the link {} lead outside of the directory it's unpacked into",
+                    escaped.display()
+                )));
+            }
+        }
+    }
 
     Ok(())
 }
 
+/// Whether `path` leads somewhere inside of `root` (which has to be canonical) once every link
+/// on the way there is followed. A path which doesn't lead anywhere can't be written through, so
+/// it counts as inside.
+pub(crate) fn resolves_inside(path: &Path, root: &Path) -> bool {
+    match fs::canonicalize(path) {
+        Ok(path) => path.starts_with(root),
+        Err(_) => true,
+    }
+}
+
+/// Resolves the `.` and `..` in a relative path without touching the filesystem, returning None
+/// if the path is absolute or climbs out of the directory it's relative to.
+pub(crate) fn contained_path(path: &Path) -> Option<PathBuf> {
+    let mut inner = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => inner.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !inner.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(inner)
+}
+
+/// Finds the directory an unpacked tarball has wrapped its package in, if it has: a directory
+/// with a manifest which is the only thing at the top of the tarball, when there's no manifest
+/// next to it.
+fn wrapper_dir(target: &Path) -> Result<Option<PathBuf>> {
+    if target.join("elba.toml").exists() {
+        return Ok(None);
+    }
+
+    let mut entries = fs::read_dir(target)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .map(|path| !path.ends_with(".dirlock"))
+                .unwrap_or(true)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    match entries.pop() {
+        Some(dir) if entries.is_empty() && dir.join("elba.toml").is_file() => Ok(Some(dir)),
+        _ => Ok(None),
+    }
+}
+
+/// Downloads the file at `url` to `part`, resuming from the end of whatever's already in `part`
+/// if the server lets us. Returns the validators of the downloaded file.
+///
+/// If the `cached` validators of a copy we already have are given, the server is asked for the
+/// file only if it's changed since; None is returned if it hasn't. A download which is being
+/// resumed is always continued, though, since the server said it had changed before.
+fn download(
+    url: &Url,
+    client: &Client,
+    auth: &Credentials,
+    part: &Path,
+    cached: Option<&Validators>,
+) -> Result<Option<Validators>> {
+    let offset = fs::metadata(part).map(|m| m.len()).unwrap_or(0);
+
+    let mut req = auth.authorize(client.get(url.as_str()), url);
+    if offset > 0 {
+        req = req.header(RANGE, format!("bytes={}-", offset));
+    } else if let Some(cached) = cached {
+        req = cached.apply(req);
+    }
+    let resp = auth.check(req.send()?, url)?;
+
+    if offset == 0 && cached.is_some() && resp.status() == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+
+    if offset > 0 && resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        // Whatever we saved before doesn't fit the file on the server anymore
+        fs::remove_file(part)?;
+        return download(url, client, auth, part, cached);
+    }
+
+    let mut resp = resp.error_for_status()?;
+    let mut file = if resp.status() == StatusCode::PARTIAL_CONTENT {
+        let resumed = match resp.headers().get(CONTENT_RANGE).map(|x| x.to_str()) {
+            Some(Ok(range)) => range.starts_with(&format!("bytes {}-", offset)),
+            _ => false,
+        };
+        if !resumed {
+            fs::remove_file(part)?;
+            return download(url, client, auth, part, cached);
+        }
+
+        fs::OpenOptions::new().append(true).open(part)?
+    } else {
+        fs::File::create(part)?
+    };
+
+    let validators = Validators::from_headers(resp.headers());
+    resp.copy_to(&mut file)?;
+
+    Ok(Some(validators))
+}
+
+/// Whether a directory has anything in it besides its lockfile.
+fn has_contents(dir: &Path) -> bool {
+    fs::read_dir(dir)
+        .map(|mut x| x.any(|x| x.map(|x| x.file_name() != ".dirlock").unwrap_or(false)))
+        .unwrap_or(false)
+}
+
+/// The path of a file next to `path` with the extension `ext` tacked on.
+fn sibling(path: &Path, ext: &str) -> PathBuf {
+    let mut res = path.as_os_str().to_owned();
+    res.push(".");
+    res.push(ext);
+    PathBuf::from(res)
+}
+
 impl DirectRes {
+    /// Retrieves the resolution into `target`. Tarballs are unpacked with whichever of `formats`
+    /// they're in.
     pub fn retrieve(
         &self,
         client: &Client,
+        auth: &Credentials,
+        formats: &ArchiveFormats,
+        target: &DirLock,
+        eager: bool,
+        dl_f: impl Fn(bool) -> Result<()>,
+    ) -> Result<Option<DirectRes>> {
+        self.retrieve_with(client, auth, formats, target, eager, None, dl_f)
+    }
+
+    /// Like `retrieve`, but a tarball which is downloaded over HTTP has the validators the
+    /// server gave for it saved in a `.http` file next to `target`, so that retrieving it into
+    /// `target` again doesn't download it again unless it's changed (see `Validators`).
+    /// Everything else is retrieved as usual. This is for indices, which only ever come in the
+    /// built-in archive formats.
+    pub fn retrieve_validated(
+        &self,
+        client: &Client,
+        auth: &Credentials,
+        target: &DirLock,
+        eager: bool,
+        dl_f: impl Fn(bool) -> Result<()>,
+    ) -> Result<Option<DirectRes>> {
+        let validators = sibling(target.path(), "http");
+        let formats = ArchiveFormats::default();
+        self.retrieve_with(
+            client,
+            auth,
+            &formats,
+            target,
+            eager,
+            Some(&validators),
+            dl_f,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn retrieve_with(
+        &self,
+        client: &Client,
+        auth: &Credentials,
+        formats: &ArchiveFormats,
         target: &DirLock,
         eager: bool,
+        validators: Option<&Path>,
         dl_f: impl Fn(bool) -> Result<()>,
     ) -> Result<Option<DirectRes>> {
         match self {
-            DirectRes::Tar { url, cksum } => match url.scheme() {
+            DirectRes::Tar {
+                url,
+                cksum,
+                subpath,
+            } => match url.scheme() {
                 "http" | "https" => {
                     dl_f(true)?;
-                    retrieve_tar(url.clone(), &client, &target, cksum.as_ref())
-                        .context(Error::CannotDownload)?;
+                    retrieve_tar(
+                        url.clone(),
+                        client,
+                        auth,
+                        target,
+                        cksum.as_ref(),
+                        subpath.as_ref(),
+                        formats,
+                        validators,
+                    )
+                    .map_err(|e| {
+                        // Being turned away is worth telling the user about as it is
+                        if is_unauthorized(&e) {
+                            e
+                        } else {
+                            e.context(Error::CannotDownload).into()
+                        }
+                    })?;
 
                     Ok(None)
                 }
@@ -214,21 +850,37 @@ impl DirectRes {
                         }
                     }
 
-                    let archive = BufReader::new(archive);
-                    let archive = GzDecoder::new(archive);
-                    let mut archive = Archive::new(archive);
-
-                    clear_dir(target.path())?;
-
                     archive
-                        .unpack(target.path())
+                        .seek(SeekFrom::Start(0))
+                        .context(Error::CannotDownload)?;
+                    unpack_tar(archive, target.path(), subpath.as_ref(), formats)
                         .context(Error::CannotDownload)?;
 
                     Ok(None)
                 }
                 _ => unreachable!(),
             },
-            DirectRes::Git { repo: url, tag } => {
+            DirectRes::Git {
+                repo: url,
+                tag,
+                rev,
+                subdir,
+            } => {
+                // If we already know which commit the tag points to, we check out exactly that.
+                let target_ref = rev.as_ref().unwrap_or(tag);
+                let resolved = |id: String| {
+                    if rev.as_ref() == Some(&id) {
+                        None
+                    } else {
+                        Some(DirectRes::Git {
+                            repo: url.clone(),
+                            tag: tag.clone(),
+                            rev: Some(id),
+                            subdir: subdir.clone(),
+                        })
+                    }
+                };
+
                 // If we find a directory which already has a repo, we just check out the correct
                 // version of it. Whether or not a new dir is created isn't our job, that's for the
                 // Cache. If the Cache points to a directory that already exists, it means that the
@@ -241,32 +893,28 @@ impl DirectRes {
                         // We only want to NOT update an existing git repository if eager is false.
                         // We assume that the HEAD of the repo is at the current "locked" state.
                         //
-                        // If the tag is a branch:
+                        // If the tag is a branch (and we haven't already resolved it):
                         if !eager {
-                            if let Ok(b) = repo.find_branch(&tag, BranchType::Local) {
-                                let head = b.into_reference().resolve()?.peel_to_commit()?;
-                                let cur = repo.head()?.resolve()?.peel_to_commit()?;
+                            if rev.is_none() {
+                                if let Ok(b) = repo.find_branch(tag, BranchType::Local) {
+                                    let head = b.into_reference().resolve()?.peel_to_commit()?;
+                                    let cur = repo.head()?.resolve()?.peel_to_commit()?;
 
-                                let mut revwalk = repo.revwalk()?;
-                                revwalk.push(head.id())?;
-                                revwalk.set_sorting(Sort::TOPOLOGICAL)?;
+                                    let mut revwalk = repo.revwalk()?;
+                                    revwalk.push(head.id())?;
+                                    revwalk.set_sorting(Sort::TOPOLOGICAL)?;
 
-                                if revwalk.any(|x| x == Ok(cur.id())) {
-                                    if &cur.id().to_string() == tag {
-                                        return Ok(None);
-                                    } else {
-                                        return Ok(Some(DirectRes::Git {
-                                            repo: url.clone(),
-                                            tag: cur.id().to_string(),
-                                        }));
+                                    if revwalk.any(|x| x == Ok(cur.id())) {
+                                        return Ok(resolved(cur.id().to_string()));
                                     }
                                 }
                             }
 
                             // Otherwise, if the tag is an exact pointer to a commit, we try to check out to
                             // it locally without fetching anything
-                            let target =
-                                repo.revparse_single(&tag).and_then(|x| x.peel_to_commit());
+                            let target = repo
+                                .revparse_single(target_ref)
+                                .and_then(|x| x.peel_to_commit());
                             let cur = repo
                                 .head()
                                 .and_then(|x| x.resolve())
@@ -274,14 +922,7 @@ impl DirectRes {
                             if let Ok(t) = target {
                                 if let Ok(c) = cur {
                                     if t.id() == c.id() {
-                                        if tag == &c.id().to_string() {
-                                            return Ok(None);
-                                        } else {
-                                            return Ok(Some(DirectRes::Git {
-                                                repo: url.clone(),
-                                                tag: c.id().to_string(),
-                                            }));
-                                        }
+                                        return Ok(resolved(c.id().to_string()));
                                     } else {
                                         // Because we know the other tag exists in our local copy of the
                                         // repo, we can just check out into that and return
@@ -293,14 +934,7 @@ impl DirectRes {
                                                 e
                                             )
                                         })?;
-                                        if tag == &obj.id().to_string() {
-                                            return Ok(None);
-                                        } else {
-                                            return Ok(Some(DirectRes::Git {
-                                                repo: url.clone(),
-                                                tag: obj.id().to_string(),
-                                            }));
-                                        }
+                                        return Ok(resolved(obj.id().to_string()));
                                     }
                                 }
                             }
@@ -309,33 +943,69 @@ impl DirectRes {
                         // Get everything!!
                         dl_f(true)?;
                         let refspec = "refs/heads/*:refs/heads/*";
-                        fetch(&mut repo, &url, refspec).with_context(|e| {
+                        fetch(&mut repo, url, refspec, auth).with_context(|e| {
                             format_err!("couldn't fetch git repo {}: {}", url, e)
                         })?;
                         repo
                     }
                     Err(_) => {
-                        clear_dir(target.path())?;
+                        clear_dir_except(target.path(), &[Path::new(".dirlock")])?;
                         dl_f(true)?;
-                        clone(url, target.path()).with_context(|e| {
+                        // git only clones into empty directories, and the lockfile has to stay
+                        // where it is, so the clone is moved in afterwards
+                        let staged = sibling(target.path(), "clone");
+                        clear_dir(&staged)?;
+                        fs::remove_dir(&staged)?;
+                        clone(url, &staged, auth).with_context(|e| {
                             format_err!("couldn't fetch git repo {}:\n{}", url, e)
-                        })?
+                        })?;
+                        for entry in fs::read_dir(&staged)? {
+                            let entry = entry?;
+                            fs::rename(entry.path(), target.path().join(entry.file_name()))?;
+                        }
+                        fs::remove_dir(&staged)?;
+                        Repository::open(target.path())?
                     }
                 };
 
-                let obj = repo.revparse_single(&tag).context(Error::CannotDownload)?;
+                let obj = repo
+                    .revparse_single(target_ref)
+                    .context(Error::CannotDownload)?;
                 reset(&repo, &obj)
                     .with_context(|e| format_err!("couldn't fetch git repo {}:\n{}", url, e))?;
-                update_submodules(&repo).with_context(|e| {
+                update_submodules(&repo, auth).with_context(|e| {
                     format_err!("couldn't update submodules for git repo {}:\n{}", url, e)
                 })?;
 
                 let id = obj.peel_to_commit()?.id().to_string();
 
-                Ok(Some(DirectRes::Git {
-                    repo: url.clone(),
-                    tag: id,
-                }))
+                Ok(resolved(id))
+            }
+            DirectRes::Sparse { url } => {
+                // Only the config of a sparse index is retrieved up front; its entries are
+                // fetched as they're needed.
+                let config = target.path().join("index.toml");
+                if config.exists() && !eager {
+                    dl_f(false)?;
+                    return Ok(None);
+                }
+
+                dl_f(true)?;
+                let contents = sparse::fetch(client, auth, url, "index.toml")
+                    .map_err(|e| {
+                        if is_unauthorized(&e) {
+                            e
+                        } else {
+                            e.context(Error::CannotDownload).into()
+                        }
+                    })?
+                    .ok_or_else(|| format_err!("sparse index {} has no index.toml", url))?;
+
+                // Any entries we fetched before might be out of date now
+                clear_dir_except(target.path(), &[Path::new(".dirlock")])?;
+                fs::write(&config, contents)?;
+
+                Ok(None)
             }
             DirectRes::Dir { path } => {
                 // If this package is located on disk, we don't have to do anything...
@@ -378,6 +1048,10 @@ impl DirectRes {
             false
         }
     }
+
+    pub fn is_sparse(&self) -> bool {
+        matches!(self, DirectRes::Sparse { .. })
+    }
 }
 
 impl FromStr for DirectRes {
@@ -391,10 +1065,28 @@ impl FromStr for DirectRes {
         match utype {
             "git" => {
                 let mut url = Url::parse(rest).context(Error::InvalidSourceUrl)?;
-                let tag = url.fragment().unwrap_or_else(|| "master").to_owned();
+                // Git refs can't contain colons, so we use one to separate the tag from the
+                // commit it was resolved to. The subdir, if there is one, comes after both.
+                let mut fragment = url.fragment().unwrap_or("master").splitn(2, "&subdir=");
+                let mut refs = fragment.next().unwrap().splitn(2, ':');
+                let tag = match refs.next().unwrap() {
+                    "" => "master".to_owned(),
+                    tag => tag.to_owned(),
+                };
+                let rev = refs.next().map(|x| x.to_owned());
+                let subdir = fragment
+                    .next()
+                    .map(SubPath::from_str)
+                    .transpose()
+                    .context(Error::InvalidSourceUrl)?;
 
                 url.set_fragment(None);
-                Ok(DirectRes::Git { repo: url, tag })
+                Ok(DirectRes::Git {
+                    repo: url,
+                    tag,
+                    rev,
+                    subdir,
+                })
             }
             "dir" => {
                 let path = PathBuf::from(rest);
@@ -405,9 +1097,22 @@ impl FromStr for DirectRes {
                 if url.scheme() != "http" && url.scheme() != "https" && url.scheme() != "file" {
                     return Err(Error::InvalidSourceUrl)?;
                 }
-                let cksum = url.fragment().and_then(|x| Checksum::from_str(x).ok());
+                let mut cksum = None;
+                let mut subpath = None;
+                for part in url.fragment().unwrap_or("").split('&') {
+                    let mut kv = part.splitn(2, '=');
+                    if let (Some("subpath"), Some(path)) = (kv.next(), kv.next()) {
+                        subpath = Some(SubPath::from_str(path).context(Error::InvalidSourceUrl)?);
+                    } else if let Ok(c) = Checksum::from_str(part) {
+                        cksum = Some(c);
+                    }
+                }
                 url.set_fragment(None);
-                Ok(DirectRes::Tar { url, cksum })
+                Ok(DirectRes::Tar {
+                    url,
+                    cksum,
+                    subpath,
+                })
             }
             _ => Err(Error::InvalidSourceUrl)?,
         }
@@ -417,9 +1122,28 @@ impl FromStr for DirectRes {
 impl fmt::Display for DirectRes {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            DirectRes::Git { repo, tag } => write!(f, "git+{}#{}", repo, tag),
+            DirectRes::Git {
+                repo,
+                tag,
+                rev,
+                subdir,
+            } => {
+                write!(f, "git+{}#{}", repo, tag)?;
+                if let Some(rev) = rev {
+                    write!(f, ":{}", rev)?;
+                }
+                if let Some(subdir) = subdir {
+                    write!(f, "&subdir={}", subdir.0.display())?;
+                }
+                Ok(())
+            }
             DirectRes::Dir { path } => write!(f, "dir+{}", path.display()),
-            DirectRes::Tar { url, cksum } => {
+            DirectRes::Sparse { url } => write!(f, "sparse+{}", url),
+            DirectRes::Tar {
+                url,
+                cksum,
+                subpath,
+            } => {
                 let url = url.as_str();
                 write!(
                     f,
@@ -430,7 +1154,12 @@ impl fmt::Display for DirectRes {
                     } else {
                         "".to_string()
                     },
-                )
+                )?;
+                if let Some(subpath) = subpath {
+                    let sep = if cksum.is_some() { '&' } else { '#' };
+                    write!(f, "{}subpath={}", sep, subpath.0.display())?;
+                }
+                Ok(())
             }
         }
     }
@@ -463,6 +1192,16 @@ impl FromStr for IndexRes {
 
         match utype {
             "index" => {
+                let mut sparse = url.splitn(2, '+');
+                if let (Some("sparse"), Some(url)) = (sparse.next(), sparse.next()) {
+                    let url = Url::parse(url).context(Error::InvalidSourceUrl)?;
+                    if url.scheme() != "http" && url.scheme() != "https" {
+                        return Err(Error::InvalidSourceUrl)?;
+                    }
+                    return Ok(IndexRes {
+                        res: DirectRes::Sparse { url },
+                    });
+                }
                 let res = DirectRes::from_str(url).context(Error::InvalidSourceUrl)?;
                 Ok(IndexRes { res })
             }
@@ -0,0 +1,79 @@
+//! Ed25519 signatures for index contents and index entries.
+//!
+//! An index which declares a `public_key` in its `index.toml` promises that every entry it
+//! contains is signed with the matching private key, and that its contents as a whole are signed
+//! in an `index.sig` file at its root. Keys and signatures are written out in hex.
+
+use std::{fmt, str::FromStr};
+
+use failure::{bail, format_err, ResultExt};
+use openssl::{pkey::PKey, sign::Verifier};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::util::error::Result;
+
+/// The DER prefix of an Ed25519 SubjectPublicKeyInfo; the raw 32-byte key follows it.
+const ED25519_DER_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// A raw Ed25519 public key.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PublicKey(Vec<u8>);
+
+impl PublicKey {
+    /// Checks that `signature` (in hex) is a valid signature of `msg` made with this key.
+    pub fn verify(&self, msg: &[u8], signature: &str) -> Result<()> {
+        let signature = hex::decode(signature.trim())
+            .with_context(|e| format_err!("signature isn't valid hex: {}", e))?;
+
+        let mut der = ED25519_DER_PREFIX.to_vec();
+        der.extend_from_slice(&self.0);
+        let key = PKey::public_key_from_der(&der)?;
+
+        if Verifier::new_without_digest(&key)?.verify_oneshot(&signature, msg)? {
+            Ok(())
+        } else {
+            bail!("the signature doesn't match")
+        }
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = hex::decode(s.trim())
+            .with_context(|e| format_err!("public key isn't valid hex: {}", e))?;
+        if bytes.len() != 32 {
+            bail!(
+                "public key should be 32 bytes long, but it's {} bytes long",
+                bytes.len()
+            )
+        }
+
+        Ok(PublicKey(bytes))
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(&self.0))
+    }
+}
+
+impl Serialize for PublicKey {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for PublicKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(de::Error::custom)
+    }
+}
@@ -0,0 +1,87 @@
+//! Sparse indices, which are fetched file by file over HTTP instead of all at once.
+//!
+//! A sparse index is written as `index+sparse+https://example.com/index`, and lays its files out
+//! exactly like any other index: the config is at `<base>/index.toml`, and the entries of each
+//! package are at `<base>/<group>/<name>`. Retrieving a sparse index only fetches its config; the
+//! entries of a package are fetched the first time that package is looked up, and are cached in
+//! the index's directory from then on. Refreshing the index throws away every cached entry.
+//!
+//! Because a sparse index is never downloaded in full, the signature of its contents as a whole
+//! can't be checked. The signatures of its entries still are, though.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use failure::{format_err, ResultExt};
+use reqwest::{blocking::Client, StatusCode};
+use url::Url;
+
+use crate::{
+    remote::auth::Credentials,
+    util::error::{Error, Result},
+};
+
+/// Everything needed to fetch files from a sparse index.
+#[derive(Clone, Debug)]
+pub struct Remote {
+    pub url: Url,
+    pub client: Client,
+    pub auth: Credentials,
+}
+
+impl Remote {
+    /// Fetches the file at `path` (relative to the root of the index) and saves it to `dest`.
+    /// Returns false if the index doesn't have such a file.
+    ///
+    /// The file is written next to `dest` first and then moved into place, so a fetch which is
+    /// interrupted never leaves a truncated file behind to be read as a whole one later.
+    pub fn fetch_to(&self, path: &str, dest: &Path) -> Result<bool> {
+        match fetch(&self.client, &self.auth, &self.url, path)? {
+            Some(contents) => {
+                if let Some(parent) = dest.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let mut part = dest.as_os_str().to_owned();
+                part.push(".part");
+                let part = PathBuf::from(part);
+                fs::write(&part, contents)
+                    .and_then(|_| fs::rename(&part, dest))
+                    .with_context(|e| format_err!("couldn't save {}: {}", dest.display(), e))?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// The url of the file at `path` in the sparse index at `base`.
+pub fn file_url(base: &Url, path: &str) -> Result<Url> {
+    let url = format!("{}/{}", base.as_str().trim_end_matches('/'), path);
+    let url = Url::parse(&url).context(Error::InvalidSourceUrl)?;
+    Ok(url)
+}
+
+/// Fetches the file at `path` in the sparse index at `base`, or None if there isn't one.
+pub fn fetch(
+    client: &Client,
+    auth: &Credentials,
+    base: &Url,
+    path: &str,
+) -> Result<Option<Vec<u8>>> {
+    let url = file_url(base, path)?;
+    let resp = auth.check(auth.authorize(client.get(url.as_str()), &url).send()?, &url)?;
+
+    if resp.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    let mut resp = resp
+        .error_for_status()
+        .with_context(|e| format_err!("couldn't fetch {}: {}", url, e))?;
+    let mut contents = vec![];
+    resp.copy_to(&mut contents)?;
+
+    Ok(Some(contents))
+}
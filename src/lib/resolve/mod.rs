@@ -20,7 +20,7 @@ use petgraph::{
     Direction,
 };
 use semver::Version;
-use semver_constraints::{Constraint, Relation};
+use semver_constraints::{Constraint, Interval, Relation};
 use slog::{error, info, o, trace, Logger};
 use textwrap::fill;
 
@@ -81,17 +81,54 @@ impl<'ret, 'cache: 'ret> Resolver<'ret, 'cache> {
         let mut s = self;
 
         info!(s.logger, "beginning dependency resolution");
-        let r = s.solve_loop();
+        loop {
+            match s.solve_loop() {
+                // Running out of time isn't a conflict, so there's nothing to explain
+                Err(e)
+                    if e.iter_chain()
+                        .any(|c| c.downcast_ref::<Error>() == Some(&Error::DeadlineExceeded)) =>
+                {
+                    error!(s.logger, "solve ran out of time");
+                    return Err(e);
+                }
+                Err(_) => {
+                    error!(s.logger, "solve failed");
+                    let root_icix = s.incompats.len() - 1;
+                    let report = fill(&s.pp_error(root_icix), 80);
+                    match s.explain_conflict(root_icix) {
+                        Some(explanation) => bail!("{}\n{}", report, explanation),
+                        None => bail!("{}", report),
+                    }
+                }
+                Ok(solve) => {
+                    // The packages we picked can turn on features of each other, which can pull
+                    // in more dependencies; if they do, we start over with those features on
+                    if s.retriever.requested_features(&solve)? {
+                        info!(s.logger, "new features requested, solving again");
+                        s.reset();
+                        continue;
+                    }
 
-        if r.is_err() {
-            error!(s.logger, "solve failed");
-            bail!("{}", fill(&s.pp_error(s.incompats.len() - 1), 80))
-        } else {
-            info!(s.logger, "solve successful");
-            Ok(r.unwrap())
+                    // Nothing can be built if packages depend on each other in a circle
+                    solve.check_acyclic(|sum| sum.name().to_string())?;
+                    info!(s.logger, "solve successful");
+                    return Ok(solve);
+                }
+            }
         }
     }
 
+    /// Forgets everything about the last solve, so that we can start over.
+    fn reset(&mut self) {
+        self.step = 1;
+        self.level = 0;
+        self.assignments.clear();
+        self.decisions.clear();
+        self.derivations.clear();
+        self.incompats.clear();
+        self.incompat_ixs.clear();
+    }
+
     fn solve_loop(&mut self) -> Result<Graph<Summary>> {
         let c: Constraint = self.retriever.root().version().clone().into();
         let pkgs = indexmap!(self.retriever.root().id().clone() => c.complement());
@@ -402,6 +439,8 @@ impl<'ret, 'cache: 'ret> Resolver<'ret, 'cache> {
                     .cmp(&self.retriever.count_versions(b.0))
                     .reverse()
             });
+            self.retriever
+                .check_deadline(unsatisfied.iter().map(|(pkg, _)| pkg.name()))?;
             let package = unsatisfied.pop().unwrap();
             // TODO: What if we want to minimize our packages?
             let best = self.retriever.best(package.0, package.1, false);
@@ -653,6 +692,143 @@ impl<'ret, 'cache: 'ret> Resolver<'ret, 'cache> {
         out.push_str("\n");
     }
 
+    /// Boils a failed solve down to the requirements which actually clash: every pair of
+    /// dependencies on the same package which no version can satisfy at once, along with the
+    /// chain of dependencies which led from the root to each of them. If no such pair exists,
+    /// the dependencies which asked for versions that are unavailable are shown instead.
+    fn explain_conflict(&self, root_icix: usize) -> Option<String> {
+        let externals = self.externals(root_icix);
+        let deps = self.dependencies(externals.iter().cloned());
+        // The solver doesn't always need every dependency on the way to a conflict to derive it,
+        // so the chains leading up to it are filled in with whatever dependencies it knows about
+        let known = self.dependencies(0..self.incompats.len());
+
+        let needs = |ix: usize| {
+            let (_, _, dependee, req) = &deps[ix];
+            format!(
+                "{} needs {}",
+                self.dep_chain(&deps, &known, ix),
+                show_term(dependee, req)
+            )
+        };
+
+        let mut lines = vec![];
+        for (l, (_, _, l_pkg, l_req)) in deps.iter().enumerate() {
+            for (r, (_, _, r_pkg, r_req)) in deps.iter().enumerate().skip(l + 1) {
+                if l_pkg == r_pkg && l_req.intersection(r_req).is_empty() {
+                    lines.push(format!("    {}\n    {}", needs(l), needs(r)));
+                }
+            }
+        }
+
+        if !lines.is_empty() {
+            return Some(format!(
+                "these requirements conflict with each other:\n\n{}\n",
+                lines.join("\n\n")
+            ));
+        }
+
+        for ix in &externals {
+            let i = &self.incompats[*ix];
+            if i.cause() != IncompatibilityCause::Unavailable {
+                continue;
+            }
+            let (pkg, con) = i.deps().get_index(0).unwrap();
+            for (dix, (_, _, dependee, req)) in deps.iter().enumerate() {
+                let rel = req.relation(con);
+                if dependee == &pkg && (rel == Relation::Subset || rel == Relation::Equal) {
+                    lines.push(format!("    {}, which is unavailable", needs(dix)));
+                }
+            }
+        }
+
+        if lines.is_empty() {
+            None
+        } else {
+            Some(format!(
+                "these requirements can't be met:\n\n{}\n",
+                lines.join("\n")
+            ))
+        }
+    }
+
+    /// Collects the external incompatibilities (the ones which weren't derived from others) that
+    /// the incompatibility `icix` was derived from, in the order they were added.
+    fn externals(&self, icix: usize) -> Vec<usize> {
+        let mut seen = indexset!();
+        let mut stack = vec![icix];
+        let mut externals = vec![];
+        while let Some(ix) = stack.pop() {
+            if !seen.insert(ix) {
+                continue;
+            }
+            match self.incompats[ix].derived() {
+                Some((l, r)) => {
+                    stack.push(l);
+                    stack.push(r);
+                }
+                None => externals.push(ix),
+            }
+        }
+
+        externals.sort();
+        externals
+    }
+
+    /// The dependencies among the incompatibilities `ixs`, as the depender and the range of its
+    /// versions with the dependency, and the dependee and the versions of it they need.
+    fn dependencies(
+        &self,
+        ixs: impl Iterator<Item = usize>,
+    ) -> Vec<(&PackageId, &Constraint, &PackageId, Constraint)> {
+        ixs.map(|ix| &self.incompats[ix])
+            .filter(|i| i.cause() == IncompatibilityCause::Dependency)
+            .map(|i| {
+                let (depender, range) = i.deps().get_index(0).unwrap();
+                let (dependee, term) = i.deps().get_index(1).unwrap();
+                (depender, range, dependee, term.complement())
+            })
+            .collect()
+    }
+
+    /// Shows how the depender of the dependency `ix` in `deps` was reached, following the
+    /// dependencies in `deps` back up towards the root package (e.g. `root 1.0.0 -> a -> b 1.0.0`).
+    /// Where `deps` doesn't say how a package was reached, the dependencies in `known` are
+    /// followed instead.
+    fn dep_chain(
+        &self,
+        deps: &[(&PackageId, &Constraint, &PackageId, Constraint)],
+        known: &[(&PackageId, &Constraint, &PackageId, Constraint)],
+        ix: usize,
+    ) -> String {
+        let mut chain = VecDeque::new();
+        let mut seen = indexset!();
+        let mut cur = &deps[ix];
+        loop {
+            let (depender, range, _, _) = cur;
+            // Every version of a package with the same dependency is covered by one range, so
+            // there's no point showing that range when it covers every version there is
+            if **range == Constraint::any() {
+                chain.push_front(depender.name().to_string());
+            } else {
+                chain.push_front(show_term(depender, range));
+            }
+            if *depender == self.retriever.root().id() || !seen.insert(*depender) {
+                break;
+            }
+            match deps
+                .iter()
+                .chain(known.iter())
+                .find(|(_, _, dependee, _)| dependee == depender)
+            {
+                Some(parent) => cur = parent,
+                None => break,
+            }
+        }
+
+        chain.into_iter().collect::<Vec<_>>().join(" -> ")
+    }
+
     fn register(&mut self, a: &Assignment) {
         match a.ty() {
             AssignmentType::Decision { version } => {
@@ -756,3 +932,20 @@ fn get_two<T: NodeTrait, E>(graph: &DiGraphMap<T, E>, root: T) -> Option<(T, T)>
         None
     }
 }
+
+/// Shows a package's name along with the versions it's constrained to, writing a constraint which
+/// only allows one version as just that version.
+fn show_term(pkg: &PackageId, con: &Constraint) -> String {
+    let exact = match con.retrieve().iter().collect::<Vec<_>>().as_slice() {
+        [range] => match (range.lower(), range.upper()) {
+            (Interval::Closed(l, _), Interval::Closed(u, _)) if l == u => Some(l),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    match exact {
+        Some(v) => format!("{} {}", pkg.name(), v),
+        None => format!("{} {}", pkg.name(), con),
+    }
+}
@@ -37,54 +37,230 @@
 //!
 //! #### "Airplane mode"
 //! If a user does not want to access the Internet to resolve packages, `elba` can limit itself
-//! to only using the packages provided by the Cache.
+//! to only using the packages provided by the Cache. This can be asked for per-operation, or for
+//! the whole process by setting the `ELBA_OFFLINE` environment variable.
 //!
 //! #### Vendoring
 //! In order to vendor packages, `elba` can create a new Cache in the project directory and require
 //! that all packages originate from the vendor directory (basically airplane mode + custom cache
-//! directory). Directory dependencies should be copied into the Cache directory unconditionally.
-//! From there, the user should change their manifest so that it points to the vendored directory.
+//! directory). This is what `BuildCtx::vendored` does. Directory dependencies could also be copied
+//! into the Cache directory unconditionally, so that the user could change their manifest to point
+//! to the vendored directory; for now, they have to live in the project already.
 //!
 //! #### Build caching
 //! If we want to cache builds, we can just have a separate subfolder for ibcs.
 
 use std::{
-    collections::VecDeque,
+    cell::Cell,
+    collections::{HashSet, VecDeque},
+    env, fmt,
     fs::{self, File},
     io::{self, prelude::*, BufReader},
     path::{Path, PathBuf},
-    sync::Arc,
-    time::Duration,
+    process,
+    str::FromStr,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use console::style;
-use failure::{bail, format_err, ResultExt};
+use failure::{bail, format_err, Fail, ResultExt};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use git2::Repository;
 use indexmap::{IndexMap, IndexSet};
 use itertools::Itertools;
+use petgraph::{
+    graph::NodeIndex,
+    visit::{EdgeRef, IntoNodeReferences},
+    Direction,
+};
 use reqwest::blocking::Client;
+use semver::Version;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use slog::{debug, o, Logger};
+use slog::{debug, info, o, Logger};
 use toml;
 use walkdir::WalkDir;
 
 use crate::{
-    build::{context::BuildContext, Targets},
+    build::{context::BuildContext, Target, Targets, POSTBUILD_SCRIPT, PREBUILD_SCRIPT},
     cli::build::find_manifest,
-    package::{manifest::Manifest, PackageId, Spec},
+    package::{
+        cfg::Platform,
+        lockfile::LockfileToml,
+        manifest::{
+            member_manifest, BinTarget, DepReq, Lint, Manifest, PlatformDeps, MANIFEST_FILE,
+            VENDOR_DIR,
+        },
+        Name, PackageId, Spec, Summary,
+    },
     remote::{
-        resolution::{DirectRes, Resolution},
-        Index, Indices,
+        archive::{ArchiveExtractor, ArchiveFormats},
+        auth::{Credential, Credentials},
+        mirror::Mirrors,
+        resolution::{unpack_archive, unpack_tar, DirectRes, IndexRes, Resolution},
+        signature::PublicKey,
+        Index, IndexConfig, Indices,
+    },
+    retrieve::{
+        merkle,
+        storage::{LocalFs, Storage},
     },
     util::{
-        clear_dir, copy_dir,
-        error::Result,
+        clear_dir, clear_dir_except,
+        config::{jobs, BuildProfile},
+        copy_dir,
+        error::{Error, Result},
         graph::Graph,
-        lock::DirLock,
+        hash::{hexify_hash, parse_hash, HashAlgo, Hasher},
+        lock::{DirLock, LockState},
+        move_dir,
         shell::{Shell, Verbosity},
-        valid_file,
+        symlink, valid_file,
     },
 };
 
+/// The default amount of time to wait for another process to release a lock on part of the cache.
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The default amount of time to wait for a connection to a server to be made.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The default amount of time a single request to a server may take, from connecting to reading
+/// the last of the response.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The file in a slimmed source which records the hash of the full source (see
+/// `Cache::slim_source`).
+pub const SLIM_FILE: &str = ".elba-slim";
+
+/// The directory of a build's lib outputs which the outputs of its named lib targets go in, each
+/// in a directory of its own (see `OutputLayout::lib_dir`). Nothing can be imported from a
+/// directory whose name starts with a dot, so these never get mixed up with modules.
+pub const LIBS_DIR: &str = ".libs";
+
+/// The suffix of the archives which sources are kept in when the cache compresses them. A source
+/// stored as `src/<hash>` when extracted is stored as `src/<hash>.src.tar.gz` when compressed.
+pub const SOURCE_ARCHIVE_EXT: &str = ".src.tar.gz";
+
+/// The environment variable which turns on offline mode for every Cache in the process.
+pub const OFFLINE_ENV: &str = "ELBA_OFFLINE";
+
+/// The environment variable which turns on frozen mode for every Cache in the process.
+pub const FROZEN_ENV: &str = "ELBA_FROZEN";
+
+/// Whether offline mode has been turned on through the environment. Any value other than an empty
+/// string, `0`, or `false` counts.
+pub fn offline_env() -> bool {
+    env_flag(OFFLINE_ENV)
+}
+
+/// Whether frozen mode has been turned on through the environment, in the same way as
+/// `offline_env`.
+pub fn frozen_env() -> bool {
+    env_flag(FROZEN_ENV)
+}
+
+fn new_client(connect: Duration, request: Duration) -> Result<Client> {
+    Ok(Client::builder()
+        .connect_timeout(connect)
+        .timeout(request)
+        .build()?)
+}
+
+fn env_flag(var: &str) -> bool {
+    env::var(var)
+        .map(|v| !(v.is_empty() || v == "0" || v.eq_ignore_ascii_case("false")))
+        .unwrap_or(false)
+}
+
+/// How the directories which sources are kept in under `src` are named.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceNaming {
+    /// After the hash of where the source came from, as in `2-d2e4a311d3323b784ef...`.
+    Hashed,
+    /// After the package, as in `group_name-1.0.0`. Only packages from an index have a version
+    /// before they're retrieved, so git and tarball dependencies are just `group_name`. A source
+    /// whose name is already taken by another one gets the start of its hash added on, as in
+    /// `group_name-d2e4a311d3`, which is what tells apart the revisions of a git repository.
+    ///
+    /// The name a source was given is kept in a `<hash>.name` file next to where its hashed
+    /// directory would be, so it can still be found by where it came from.
+    Readable,
+}
+
+impl Default for SourceNaming {
+    fn default() -> Self {
+        SourceNaming::Hashed
+    }
+}
+
+impl SourceNaming {
+    /// The number of characters of the hash which are added on to a name that's already taken.
+    const SHORT_HASH: usize = 10;
+
+    /// The names a source of the package `name` can be given, in the order they're tried. `hash`
+    /// is the hashed name of the source (see `Cache::get_source_dir`).
+    fn candidates(name: &Name, version: Option<&Version>, hash: &str) -> [String; 2] {
+        let mut label = format!("{}_{}", name.group(), name.name());
+        if let Some(version) = version {
+            label.push_str(&format!("-{}", version));
+        }
+        let short = hash
+            .splitn(2, '-')
+            .last()
+            .unwrap_or(hash)
+            .chars()
+            .take(Self::SHORT_HASH)
+            .collect::<String>();
+        let disambiguated = format!("{}-{}", label, short);
+
+        [label, disambiguated]
+    }
+}
+
+/// The extension of the file which holds the readable name of a source (see
+/// `SourceNaming::Readable`).
+const SOURCE_NAME_EXT: &str = "name";
+
+/// The name of the directory under `src` that the source with the hashed name `hash` is kept
+/// in: its readable name if it was given one, or else the hash itself.
+pub(crate) fn source_name(src: &Path, hash: &str) -> String {
+    read_source_name(&sibling_file(&src.join(hash), SOURCE_NAME_EXT))
+        .unwrap_or_else(|| hash.to_owned())
+}
+
+/// Reads the readable name out of `path`, if it's the name file of a source.
+fn read_source_name(path: &Path) -> Option<String> {
+    if path.extension().and_then(|x| x.to_str()) != Some(SOURCE_NAME_EXT) {
+        return None;
+    }
+    // Anything which isn't a plain file name would point outside of `src`
+    fs::read_to_string(path)
+        .ok()
+        .filter(|x| !x.is_empty() && Path::new(x).file_name().and_then(|x| x.to_str()) == Some(x))
+}
+
+/// The readable names which have been given to sources in `src`, along with the hashed names of
+/// the sources they were given to.
+fn source_names(src: &Path) -> IndexMap<String, String> {
+    let entries = match fs::read_dir(src) {
+        Ok(entries) => entries.filter_map(|x| x.ok()),
+        Err(_) => return IndexMap::new(),
+    };
+
+    entries
+        .filter_map(|entry| {
+            let path = entry.path();
+            let name = read_source_name(&path)?;
+            let hash = path.file_stem()?.to_string_lossy().to_string();
+            Some((name, hash))
+        })
+        .collect()
+}
+
 /// The Cache encapsulates all of the global state required for `elba` to function.
 ///
 /// This global state includes stuff like temporary places to download and build packages, places
@@ -94,26 +270,517 @@ use crate::{
 #[derive(Debug, Clone)]
 pub struct Cache {
     pub layout: Layout,
+    /// A read-only cache which is looked in for tarball sources and builds before this one, like
+    /// one that's shared between all the users of a build server. Nothing is ever written to it:
+    /// whatever isn't there is retrieved or built into `layout` as usual.
+    pub base: Option<Layout>,
+    /// How long to wait for a contended lock on a directory in the cache before giving up.
+    pub lock_timeout: Duration,
+    /// How long to wait for a connection to a server before giving up.
+    pub connect_timeout: Duration,
+    /// How long a single request may take before it's given up on.
+    pub request_timeout: Duration,
+    /// When everything the cache has to resolve and retrieve has to be done by. Past it, nothing
+    /// new is started, and whatever's still pending fails with `Error::DeadlineExceeded`.
+    pub deadline: Option<Instant>,
     client: Client,
     pub logger: Logger,
     pub shell: Shell,
+    /// Whether nothing should be downloaded, regardless of what the caller asks for. This is set
+    /// from the `ELBA_OFFLINE` environment variable.
+    pub offline: bool,
+    /// Whether the build has to be reproducible from what's already cached and locked. A frozen
+    /// cache is always offline, and says so when something it needs isn't cached. This is set
+    /// from the `ELBA_FROZEN` environment variable.
+    pub frozen: bool,
+    /// The credentials to send along to private hosts.
+    pub credentials: Credentials,
+    /// The mirrors to retrieve sources and indices from instead of their original urls. Only
+    /// downloads go to the mirror: sources are cached, hashed and locked under their original
+    /// urls, so the same cache and lockfile work with and without mirrors (see `remote::mirror`).
+    pub mirrors: Mirrors,
+    /// The keys that signed indices have to be signed with, by index. A signed index whose key
+    /// isn't here isn't trusted, since the key it declares is only as trustworthy as the index.
+    pub index_keys: IndexMap<DirectRes, PublicKey>,
+    /// Local directories which are searched for a copy of a tarball before it's downloaded, like
+    /// the ones `elba package` makes. Only tarballs with a checksum can be found this way.
+    pub tarball_dirs: Vec<PathBuf>,
+    /// The archive formats tarball sources can be in (see `remote::archive`). By default, those
+    /// are the ones elba supports on its own.
+    pub archive_formats: ArchiveFormats,
+    /// Whether tarball sources should be kept as compressed archives once they've been retrieved.
+    /// Compressed sources are extracted into a working directory under `tmp` whenever they're
+    /// loaded. Sources which are already compressed are loaded either way.
+    pub compress_sources: bool,
+    /// Whether a package which two indices define differently is an error when it's loaded,
+    /// instead of a warning (see `Indices::strict`).
+    pub strict_indices: bool,
+    /// How the directories of newly retrieved sources are named. Sources are still found under
+    /// their hashes (in the base cache, say) whichever way this is set.
+    pub source_naming: SourceNaming,
+    /// Whether tarball sources should be slimmed down to the files of their targets once they've
+    /// been built (see `Cache::slim_source`).
+    pub slim_sources: bool,
+    /// Where builds and tarball sources are kept (see `retrieve::storage`). By default, that's
+    /// the cache's own directories, wherever its layout is at the time.
+    pub storage: Option<Arc<dyn Storage>>,
+    /// The directory builds are kept in instead of the global build directory, if any. Builds
+    /// kept here are private to one project: they're never handed to the Cache's storage, and
+    /// the global cache never sees them.
+    pub local_builds: Option<PathBuf>,
 }
 
 impl Cache {
-    pub fn from_disk(plog: &Logger, layout: Layout, shell: Shell) -> Result<Self> {
+    /// Opens the cache with the writable layout `layout`, on top of the read-only layout `base`
+    /// if there is one. Only the writable layout is created if it doesn't exist.
+    pub fn from_disk(
+        plog: &Logger,
+        layout: Layout,
+        base: Option<Layout>,
+        shell: Shell,
+    ) -> Result<Self> {
         layout.init()?;
 
-        let client = Client::builder().timeout(Duration::from_secs(10)).build()?;
+        let client = new_client(DEFAULT_CONNECT_TIMEOUT, DEFAULT_REQUEST_TIMEOUT)?;
         let logger = plog.new(o!("phase" => "cache"));
 
         Ok(Cache {
             layout,
+            base,
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            deadline: None,
             client,
             logger,
             shell,
+            offline: offline_env() || frozen_env(),
+            frozen: frozen_env(),
+            credentials: Credentials::from_env(),
+            compress_sources: false,
+            mirrors: Mirrors::default(),
+            index_keys: IndexMap::new(),
+            tarball_dirs: vec![],
+            archive_formats: ArchiveFormats::default(),
+            strict_indices: false,
+            source_naming: SourceNaming::default(),
+            slim_sources: false,
+            storage: None,
+            local_builds: None,
         })
     }
 
+    /// Adds credentials from the configuration to the ones picked up from the environment.
+    pub fn with_credentials(mut self, hosts: &IndexMap<String, Credential>) -> Self {
+        self.credentials = self.credentials.with_hosts(hosts);
+        self
+    }
+
+    /// Sets the source replacement rules from the configuration.
+    pub fn with_mirrors(mut self, mirrors: &IndexMap<String, String>) -> Self {
+        self.mirrors = Mirrors::new(mirrors);
+        self
+    }
+
+    /// Sets the keys signed indices are checked against from the configuration.
+    pub fn with_index_keys(mut self, keys: &IndexMap<DirectRes, PublicKey>) -> Self {
+        self.index_keys = keys.clone();
+        self
+    }
+
+    /// Sets the local directories to look for tarballs in before downloading them.
+    pub fn with_tarball_dirs(mut self, dirs: &[PathBuf]) -> Self {
+        self.tarball_dirs = dirs.to_vec();
+        self
+    }
+
+    /// Freezes the cache (see `Cache::frozen`). A cache which is already frozen stays frozen.
+    pub fn with_frozen(mut self, frozen: bool) -> Self {
+        self.frozen |= frozen;
+        self.offline |= frozen;
+        self
+    }
+
+    /// Sets whether newly retrieved tarball sources should be stored compressed.
+    pub fn with_compressed_sources(mut self, compress: bool) -> Self {
+        self.compress_sources = compress;
+        self
+    }
+
+    /// Sets whether the indices from this cache should treat conflicts between them as errors.
+    pub fn with_strict_indices(mut self, strict: bool) -> Self {
+        self.strict_indices = strict;
+        self
+    }
+
+    /// Sets how the directories of newly retrieved sources are named.
+    pub fn with_source_naming(mut self, naming: SourceNaming) -> Self {
+        self.source_naming = naming;
+        self
+    }
+
+    /// Sets whether tarball sources should be slimmed down once they've been built.
+    pub fn with_slim_sources(mut self, slim: bool) -> Self {
+        self.slim_sources = slim;
+        self
+    }
+
+    /// Adds an archive format for tarball sources to be unpacked with, which is tried before the
+    /// ones that are already known.
+    pub fn with_archive_format(mut self, format: impl ArchiveExtractor + 'static) -> Self {
+        self.archive_formats = self.archive_formats.with_format(format);
+        self
+    }
+
+    /// Sets where builds and tarball sources are kept.
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Keeps builds in `dir` instead of the global build directory, so that a project can build
+    /// incrementally without sharing its builds with (or waiting on) any other project.
+    pub fn with_local_builds(mut self, dir: &Path) -> Self {
+        self.local_builds = Some(dir.to_path_buf());
+        self
+    }
+
+    /// Where builds are kept: the local build directory if there is one, or else the global one.
+    pub fn builds_dir(&self) -> &Path {
+        self.local_builds.as_ref().unwrap_or(&self.layout.build)
+    }
+
+    /// Sets how long to wait for connections and requests to servers.
+    pub fn with_timeouts(mut self, connect: Duration, request: Duration) -> Result<Self> {
+        self.client = new_client(connect, request)?;
+        self.connect_timeout = connect;
+        self.request_timeout = request;
+        Ok(self)
+    }
+
+    /// Sets when everything has to be resolved and retrieved by (see `Cache::deadline`).
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// How much time is left until the deadline, if there is one.
+    pub fn time_left(&self) -> Option<Duration> {
+        self.deadline
+            .map(|d| d.saturating_duration_since(Instant::now()))
+    }
+
+    /// Fails with `Error::DeadlineExceeded` if the deadline has passed, listing the things in
+    /// `pending` which didn't get done in time.
+    pub fn check_deadline<I>(&self, pending: I) -> Result<()>
+    where
+        I: IntoIterator,
+        I::Item: fmt::Display,
+    {
+        if self.time_left() != Some(Duration::from_secs(0)) {
+            return Ok(());
+        }
+
+        let pending = pending
+            .into_iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        let msg = if pending.is_empty() {
+            "the deadline passed".to_string()
+        } else {
+            format!(
+                "the deadline passed with these still pending: {}",
+                pending.join(", ")
+            )
+        };
+        Err(Error::DeadlineExceeded.context(msg).into())
+    }
+
+    /// The HTTP client used to download packages and indices.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Moves the whole cache to a new root directory.
+    ///
+    /// Every directory of the current layout is moved to a directory with the same name under
+    /// `new_root` (so `src` ends up at `new_root/src`, `bin` at `new_root/bin`, etc.), and the
+    /// Cache's layout is updated to match. The following invariants are upheld:
+    ///
+    /// - Nothing in the cache may be locked while it's being moved. If a `DirLock` is held
+    ///   anywhere in the cache, this function fails without moving anything. Everything which
+    ///   has a lockfile (reclaiming stale ones) is locked for the duration of the move.
+    /// - Existing files are never clobbered: if any of the new directories already exists and
+    ///   isn't empty, this function fails before anything is moved.
+    /// - Symlinks inside the cache which point to an absolute location inside the cache are
+    ///   rewritten to point to the same location under `new_root`. Symlinks which point outside
+    ///   of the cache (e.g. to local dir dependencies) and relative symlinks are left alone.
+    pub fn relocate(&mut self, new_root: &Path) -> Result<()> {
+        let old = self.layout.clone();
+        let new = old.relocated(new_root);
+
+        for (from, to) in old.dirs().iter().zip(new.dirs().iter()) {
+            if to.starts_with(from) {
+                bail!(
+                    "can't relocate {} to {}, which is inside of it",
+                    from.display(),
+                    to.display()
+                )
+            }
+
+            if to.exists() && fs::read_dir(to)?.next().is_some() {
+                bail!(
+                    "can't relocate the cache to {}: {} already exists and isn't empty",
+                    new_root.display(),
+                    to.display()
+                )
+            }
+        }
+
+        // Everything which has been locked stays locked by us until the move is done, so that
+        // nobody can start using it halfway through
+        let mut locks = vec![];
+        for from in old.dirs().iter() {
+            self.lock_entries(from, &mut locks)?;
+        }
+
+        let mut moved = vec![];
+        for (from, to) in old.dirs().iter().zip(new.dirs().iter()) {
+            if !from.exists() {
+                continue;
+            }
+
+            // Something might've been locked since we last checked, so we check again right
+            // before moving it
+            let res = self.lock_entries(from, &mut locks).and_then(|()| {
+                move_dir(from, to)
+                    .with_context(|e| format_err!("couldn't move {}: {}", from.display(), e))?;
+                Ok(())
+            });
+
+            if let Err(e) = res {
+                for (from, to) in moved.into_iter().rev() {
+                    if move_dir(to, from).is_ok() {
+                        follow_locks(&mut locks, to, from);
+                    }
+                }
+                return Err(e);
+            }
+
+            follow_locks(&mut locks, from, to);
+            moved.push((from, to));
+        }
+
+        for dir in new.dirs().iter() {
+            for entry in WalkDir::new(dir).follow_links(false) {
+                let entry = entry?;
+                if !entry.path_is_symlink() {
+                    continue;
+                }
+
+                let target = fs::read_link(entry.path())?;
+                let fixed = old
+                    .dirs()
+                    .iter()
+                    .zip(new.dirs().iter())
+                    .find_map(|(from, to)| target.strip_prefix(from).ok().map(|x| to.join(x)));
+
+                if let Some(fixed) = fixed {
+                    debug!(
+                        self.logger, "repairing symlink";
+                        "link" => entry.path().display(),
+                        "target" => fixed.display()
+                    );
+                    fs::remove_file(entry.path())?;
+                    symlink(&fixed, entry.path()).with_context(|e| {
+                        format_err!("couldn't repair symlink {}: {}", entry.path().display(), e)
+                    })?;
+                }
+            }
+        }
+
+        new.init()?;
+        self.layout = new;
+
+        Ok(())
+    }
+
+    /// Locks every directory inside of `dir` which has a lockfile and isn't in `locks` already,
+    /// adding the locks to `locks`. Stale locks are reclaimed, but if anything is actually locked,
+    /// this fails.
+    fn lock_entries(&self, dir: &Path, locks: &mut Vec<DirLock>) -> Result<()> {
+        let locked = WalkDir::new(dir)
+            .follow_links(false)
+            .into_iter()
+            .filter_map(|x| x.ok())
+            .filter(|x| x.file_name() == ".dirlock")
+            .map(|x| x.path().parent().unwrap().to_owned())
+            .collect::<Vec<_>>();
+
+        for path in locked {
+            if locks.iter().any(|x| x.path() == path) {
+                continue;
+            }
+
+            match DirLock::try_acquire(&path)? {
+                Some(lock) => {
+                    lock.warn_reclaimed(self.shell);
+                    locks.push(lock);
+                }
+                None => bail!(
+                    "can't relocate the cache while {} is locked",
+                    path.display()
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Locks a directory, giving up if another process doesn't release it within the Cache's lock
+    /// timeout.
+    fn lock(&self, path: &Path) -> Result<DirLock> {
+        let lock = DirLock::acquire_timeout(path, self.lock_timeout)?;
+        lock.warn_reclaimed(self.shell);
+        Ok(lock)
+    }
+
+    /// Locks something the cache already has, unless it's in the base cache: nothing in there
+    /// ever changes, and it might not be writable anyways.
+    fn lock_cached(&self, path: &Path) -> Result<DirLock> {
+        match &self.base {
+            Some(base) if path.starts_with(&base.src) || path.starts_with(&base.build) => {
+                DirLock::unlocked(path)
+            }
+            _ => self.lock(path),
+        }
+    }
+
+    /// Looks through the local tarball directories for a tarball of `pkg` with the checksum `loc`
+    /// asks for, and unpacks it into `dir` if there is one. Tarballs are found by the name
+    /// `elba package` gives them (`<group>_<name>-<version>.tar.gz`), but only ever used if their
+    /// checksum matches. Returns whether the source was imported.
+    fn import_tarball(&self, pkg: &PackageId, loc: &DirectRes, dir: &DirLock) -> Result<bool> {
+        let (cksum, subpath) = match loc {
+            DirectRes::Tar {
+                cksum: Some(cksum),
+                subpath,
+                ..
+            } => (cksum, subpath.as_ref()),
+            _ => return Ok(false),
+        };
+        for tarball_dir in &self.tarball_dirs {
+            let entries = match fs::read_dir(tarball_dir) {
+                Ok(entries) => entries.filter_map(|x| x.ok()),
+                Err(_) => continue,
+            };
+
+            for entry in entries {
+                let path = entry.path();
+                if !is_tarball_of(&entry.file_name().to_string_lossy(), pkg.name())
+                    || !path.is_file()
+                {
+                    continue;
+                }
+
+                // A tarball which can't be read is no different from one which isn't there
+                let mut hash = Sha256::new();
+                match File::open(&path).and_then(|mut file| io::copy(&mut file, &mut hash)) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        debug!(
+                            self.logger, "skipped unreadable tarball";
+                            "tarball" => path.display(),
+                            "error" => e.to_string()
+                        );
+                        continue;
+                    }
+                }
+                if hex::encode(hash.result()) != cksum.hash {
+                    continue;
+                }
+                debug!(
+                    self.logger, "verified checksum";
+                    "url" => loc.to_string(),
+                    "cksum" => cksum.to_string(),
+                    "tarball" => path.display()
+                );
+
+                unpack_tar(
+                    File::open(&path)?,
+                    dir.path(),
+                    subpath,
+                    &self.archive_formats,
+                )
+                .with_context(|e| {
+                    format_err!("couldn't unpack tarball {}: {}", path.display(), e)
+                })?;
+                debug!(
+                    self.logger, "loaded source";
+                    "cause" => "tarball dir",
+                    "pkg" => pkg.to_string(),
+                    "tarball" => path.display()
+                );
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Retrieves a resolution into a directory from wherever its mirror says it should come from,
+    /// returning the resolution it was pinned to (under its original url) if that changed.
+    fn retrieve_mirrored(
+        &self,
+        loc: &DirectRes,
+        dir: &DirLock,
+        eager: bool,
+        dl_f: impl Fn(bool) -> Result<()>,
+    ) -> Result<Option<DirectRes>> {
+        let res = self.mirrors.apply(loc)?.retrieve(
+            &self.client,
+            &self.credentials,
+            &self.archive_formats,
+            dir,
+            eager,
+            dl_f,
+        )?;
+
+        // A tarball with a checksum is only ever unpacked once its checksum has been checked
+        if let DirectRes::Tar {
+            cksum: Some(cksum), ..
+        } = loc
+        {
+            debug!(
+                self.logger, "verified checksum";
+                "url" => loc.to_string(),
+                "cksum" => cksum.to_string()
+            );
+        }
+
+        Ok(res.map(|r| self.mirrors.restore(loc, r)))
+    }
+
+    /// Retrieves an index like `retrieve_mirrored`, keeping the validators the server gave for a
+    /// tarball index next to its directory, so that it's only downloaded again once it changes.
+    fn retrieve_index(
+        &self,
+        index: &DirectRes,
+        dir: &DirLock,
+        eager: bool,
+        dl_f: impl Fn(bool) -> Result<()>,
+    ) -> Result<Option<DirectRes>> {
+        let res = self.mirrors.apply(index)?.retrieve_validated(
+            &self.client,
+            &self.credentials,
+            dir,
+            eager,
+            dl_f,
+        )?;
+
+        Ok(res.map(|r| self.mirrors.restore(index, r)))
+    }
+
     /// Retrieve the metadata of a package, loading it into the cache if necessary.
     pub fn checkout_source(
         &self,
@@ -123,9 +790,76 @@ impl Cache {
         offline: bool,
         dl_f: impl Fn(),
     ) -> Result<(Option<DirectRes>, Source)> {
-        let p = self.load_source(pkg, loc, eager, offline, dl_f)?;
+        self.checkout_source_origin(pkg, loc, eager, offline, dl_f)
+            .map(|(res, source, _)| (res, source))
+    }
+
+    /// Like `checkout_source`, but also says whether the source came out of the cache or had to
+    /// be downloaded.
+    pub fn checkout_source_origin(
+        &self,
+        pkg: &PackageId,
+        loc: &DirectRes,
+        eager: bool,
+        offline: bool,
+        dl_f: impl Fn(),
+    ) -> Result<(Option<DirectRes>, Source, SourceOrigin)> {
+        self.checkout(pkg, None, loc, eager, offline, dl_f)
+    }
+
+    /// Like `checkout_source_origin`, but with the version of the package if it's already known,
+    /// which readable source directories are named after.
+    fn checkout(
+        &self,
+        pkg: &PackageId,
+        version: Option<&Version>,
+        loc: &DirectRes,
+        eager: bool,
+        offline: bool,
+        dl_f: impl Fn(),
+    ) -> Result<(Option<DirectRes>, Source, SourceOrigin)> {
+        let p = self.load_source(pkg, version, loc, eager, offline, dl_f)?;
+        let location = p.0.clone().unwrap_or_else(|| loc.clone());
+        let source = Source::from_folder(pkg, p.1, location)?;
+        source.verify_targets()?;
+
+        if !source.lints().is_empty() {
+            self.shell.println(
+                style("[warn]").yellow().bold(),
+                format!(
+                    "the manifest of {} has problems:\n{}",
+                    source.pretty_summary(),
+                    source.lints().iter().map(|x| &x.message).join("\n")
+                ),
+                Verbosity::Normal,
+            );
+        }
+
+        Ok((p.0, source, p.2))
+    }
+
+    /// Like `checkout_source`, but for the package `sum` in particular: the manifest of the source
+    /// has to declare the version that `sum` says it has, so that an index entry pointing at the
+    /// wrong tarball (or a tarball which was tampered with) is caught as soon as it's retrieved.
+    pub fn checkout_summary(
+        &self,
+        sum: &Summary,
+        loc: &DirectRes,
+        eager: bool,
+        offline: bool,
+        dl_f: impl Fn(),
+    ) -> Result<(Option<DirectRes>, Source)> {
+        let (res, source, _) =
+            self.checkout(sum.id(), Some(sum.version()), loc, eager, offline, dl_f)?;
+        source.verify_version(sum.version())?;
+        debug!(
+            self.logger, "checked out package";
+            "pkg" => sum.name().to_string(),
+            "version" => sum.version().to_string(),
+            "url" => loc.to_string()
+        );
 
-        Ok((p.0, Source::from_folder(pkg, p.1, loc.clone())?))
+        Ok((res, source))
     }
 
     // TODO: In the future (heh), return Box<Future<Item = PathBuf, Error = Error>> and use async
@@ -147,36 +881,55 @@ impl Cache {
     fn load_source(
         &self,
         pkg: &PackageId,
+        version: Option<&Version>,
         loc: &DirectRes,
         eager: bool,
         offline: bool,
         dl_f: impl Fn(),
-    ) -> Result<(Option<DirectRes>, DirLock)> {
+    ) -> Result<(Option<DirectRes>, DirLock, SourceOrigin)> {
         if let DirectRes::Dir { path } = loc {
             debug!(self.logger, "loaded source"; "cause" => "dir", "pkg" => pkg.to_string());
-            return Ok((None, DirLock::acquire(&path)?));
+            return Ok((None, self.lock(path)?, SourceOrigin::Cached));
         }
 
+        let offline = offline || self.offline;
         let eager = if offline { false } else { eager };
 
-        let new_dir = self.layout.src.join(Self::get_source_dir(loc, true));
-        // We record first if the directory existed before the retrieval process
+        self.discard_tampered(pkg, loc)?;
 
-        // If it does exist, we can stop immediately
-        if loc.is_tar() && new_dir.exists() {
+        // If the tarball has already been downloaded, we can stop immediately
+        if let Some(new_dir) = Some(loc)
+            .filter(|loc| loc.is_tar())
+            .and_then(|loc| self.check_source(loc))
+        {
             debug!(
                 self.logger, "loaded source";
                 "cause" => "exists",
                 "pkg" => pkg.to_string(),
                 "dir" => new_dir.display()
             );
-            return Ok((None, DirLock::acquire(&new_dir)?));
+            let dir = if new_dir.is_dir() {
+                self.lock_cached(&new_dir)?
+            } else {
+                self.extract_source(&new_dir)?
+            };
+            return Ok((None, dir, SourceOrigin::Cached));
         }
 
+        let start = Instant::now();
+        let downloaded = Cell::new(false);
         let new_f = |dl_online| {
             if offline && dl_online {
-                return Err(format_err!("Can't download package in offline mode"));
+                return Err(self.missing_source(loc));
             }
+            if dl_online && !downloaded.get() {
+                info!(
+                    self.logger, "download started";
+                    "pkg" => pkg.name().to_string(),
+                    "url" => loc.to_string()
+                );
+            }
+            downloaded.set(downloaded.get() || dl_online);
             dl_f();
             Ok(())
         };
@@ -185,8 +938,13 @@ impl Cache {
         // which don't exist yet.
         // If we're in "offline" mode, we immediately return an error from here because we
         // won't be able to download anything anyways.
-        let dir = DirLock::acquire(&self.layout.src.join(Self::get_source_dir(loc, true)))?;
-        let res = if let Resolution::Direct(g) = pkg.resolution() {
+        let dir = self.lock_source(pkg, version, loc)?;
+        let source_key = format!("src/{}", Self::get_source_dir(loc, true));
+        let res = if self.import_tarball(pkg, loc, &dir)?
+            || (loc.is_tar() && self.fetch_stored(&source_key, &dir)?)
+        {
+            Ok(None)
+        } else if let Resolution::Direct(g) = pkg.resolution() {
             // For a git repository, if the DirectRes and the PackageId don't match, we should try to
             // retrieve the locked variant (the DirectRes) and then update with the latest variant
             // (the PackageId).
@@ -196,32 +954,71 @@ impl Cache {
             // two fetch operations.
             if g.is_git() && g != loc {
                 debug_assert!(loc.is_git());
-                loc.retrieve(&self.client, &dir, eager, new_f)
+                let fetch = self.mirrors.apply(g)?;
+                self.mirrors
+                    .apply(loc)?
+                    .retrieve(
+                        &self.client,
+                        &self.credentials,
+                        &self.archive_formats,
+                        &dir,
+                        eager,
+                        new_f,
+                    )
                     .and_then(|_| {
-                        g.retrieve(&self.client, &dir, false, |dl_online| {
-                            if offline && dl_online {
-                                Err(format_err!("Can't download package in offline mode"))
-                            } else {
-                                Ok(())
-                            }
-                        })
+                        fetch.retrieve(
+                            &self.client,
+                            &self.credentials,
+                            &self.archive_formats,
+                            &dir,
+                            false,
+                            |dl_online| {
+                                if offline && dl_online {
+                                    Err(self.missing_source(g))
+                                } else {
+                                    Ok(())
+                                }
+                            },
+                        )
                     })
+                    .map(|res| res.map(|r| self.mirrors.restore(g, r)))
             } else {
-                loc.retrieve(&self.client, &dir, eager, new_f)
+                self.retrieve_mirrored(loc, &dir, eager, new_f)
             }
         } else {
-            loc.retrieve(&self.client, &dir, eager, new_f)
+            self.retrieve_mirrored(loc, &dir, eager, new_f)
         }?;
 
-        let new_dir = self.layout.src.join(&Self::get_source_dir(
-            if let Some(r) = res.as_ref() { r } else { &loc },
-            true,
-        ));
-        let dir = if new_dir != dir.path() {
-            if !new_dir.exists() {
-                copy_dir(dir.path(), &new_dir, true)?;
+        let (dir, moved) = match &res {
+            Some(res) if Self::get_source_dir(res, true) != Self::get_source_dir(loc, true) => {
+                let new_dir = self.lock_source(pkg, version, res)?;
+                if is_empty_entry(new_dir.path()) {
+                    copy_dir(dir.path(), new_dir.path(), true)?;
+                }
+                (new_dir, true)
             }
-            DirLock::acquire(&new_dir)?
+            _ => (dir, false),
+        };
+
+        // Sources are measured before they're compressed, so the size is the same either way
+        let origin = if downloaded.get() {
+            let (bytes, duration) = (dir_size(dir.path()), start.elapsed());
+            info!(
+                self.logger, "download finished";
+                "pkg" => pkg.name().to_string(),
+                "url" => loc.to_string(),
+                "bytes" => bytes,
+                "duration_ms" => duration.as_millis() as u64
+            );
+            SourceOrigin::Downloaded { bytes, duration }
+        } else {
+            SourceOrigin::Cached
+        };
+        if downloaded.get() && loc.is_tar() {
+            self.keep_stored(&source_key, dir.path())?;
+        }
+        let dir = if !moved && self.compress_sources && loc.is_tar() {
+            self.compress_source(dir)?
         } else {
             dir
         };
@@ -231,63 +1028,512 @@ impl Cache {
             "cause" => "retrieved_new",
             "pkg" => pkg.to_string(),
             "loc" => loc.to_string(),
-            "dir" => dir.path().display()
+            "dir" => dir.path().display(),
+            "origin" => format!("{:?}", origin)
         );
 
-        Ok((res, dir))
+        Ok((res, dir, origin))
     }
 
-    /// Gets the corresponding directory of a package.
-    pub fn get_source_dir(loc: &DirectRes, include_tag: bool) -> String {
-        let mut hasher = Sha256::default();
-        if !include_tag {
-            if let DirectRes::Git { repo, .. } = loc {
-                hasher.input(repo.to_string().as_bytes());
-            } else {
-                hasher.input(loc.to_string().as_bytes());
-            }
-        } else {
-            hasher.input(loc.to_string().as_bytes());
+    /// Throws away the cached source of `loc` if any of its files were changed since it was
+    /// retrieved, so that `load_source` retrieves it again as it was. Returns whether it was
+    /// thrown away.
+    ///
+    /// Only sources which can be retrieved again exactly as they were are checked: tarballs with
+    /// a checksum, and git repositories locked to a commit. The files are checked against the
+    /// merkle tree recorded next to the source the first time it was loaded, so files which were
+    /// added or removed count too. Slimmed sources are left to `restore_source`, which checks
+    /// them itself.
+    fn discard_tampered(&self, pkg: &PackageId, loc: &DirectRes) -> Result<bool> {
+        let path = self.layout.src.join(self.source_dir(loc, true));
+        let root = match loc {
+            DirectRes::Tar { cksum: Some(_), .. } => path.clone(),
+            DirectRes::Git {
+                rev: Some(_),
+                subdir,
+                ..
+            } => subdir.as_ref().map_or(path.clone(), |x| path.join(&x.0)),
+            _ => return Ok(false),
+        };
+        let sidecar = source_sidecar(&path, &root);
+        if !sidecar.is_file() || root.join(SLIM_FILE).exists() {
+            return Ok(false);
         }
-        hex::encode(hasher.result())
-    }
 
-    /// Return the build directory exists, else None.
-    pub fn checkout_build(&self, hash: &BuildHash) -> Result<Option<Binary>> {
-        if let Some(path) = self.check_build(&hash) {
-            Ok(Some(Binary::new(DirLock::acquire(&path)?)))
-        } else {
-            Ok(None)
+        let dir = self.lock(&path)?;
+        let changed = tampered_files(&root, &sidecar)?;
+        if changed.is_empty() {
+            return Ok(false);
         }
+
+        self.shell.println(
+            style("[warn]").yellow().bold(),
+            format!(
+                "the cached source of {} has been modified ({} changed); retrieving it again",
+                pkg,
+                changed.join(", ")
+            ),
+            Verbosity::Normal,
+        );
+        debug!(
+            self.logger, "discarded modified source";
+            "pkg" => pkg.to_string(),
+            "dir" => path.display(),
+            "changed" => changed.len()
+        );
+        // Like when a source is compressed, anyone waiting on the lock starts over once the
+        // directory is gone
+        remove_dir_all::remove_dir_all(dir.path())?;
+        let _ = fs::remove_file(&sidecar);
+
+        Ok(true)
     }
 
-    /// Returns a lock on a temporary build directory.
-    /// Note that the format of this directory should be an OutputLayout.
-    pub fn checkout_tmp(&self, hash: &BuildHash) -> Result<OutputLayout> {
-        let path = self.layout.tmp.join(&hash.0);
-        let lock = DirLock::acquire(&path)?;
-        if lock.path().exists() {
-            clear_dir(&lock.path()).context(format_err!(
-                "couldn't remove existing output path: {}",
-                lock.path().display()
-            ))?;
+    /// Slims a source down to its manifest and the files its targets are built from, once it's
+    /// been built. Most of a source is only needed to build it, so this saves a lot of space for
+    /// packages which are only ever used from the build cache. Returns whether the source was
+    /// slimmed.
+    ///
+    /// The hash of the full source is recorded in the `SLIM_FILE` of the slimmed source, so the
+    /// slimmed source keeps the hash (and so the build hashes) of the full one. If it ever has to be
+    /// built again, `restore_source` brings back the rest of it.
+    ///
+    /// Only tarball sources are slimmed, since they're the only ones which can be retrieved again
+    /// exactly as they were. Sources which are kept compressed are already small, so they're
+    /// left alone, as are sources with an ipkg file in place of their manifest.
+    pub fn slim_source(&self, source: &Source) -> Result<bool> {
+        let path = source.path();
+        if source.is_slim()
+            || !source.location().is_tar()
+            || path.parent() != Some(self.layout.src.as_path())
+            || !path.join(MANIFEST_FILE).is_file()
+        {
+            return Ok(false);
         }
-        OutputLayout::new(lock)
-    }
 
-    pub fn store_bins(&self, bins: &[(PathBuf, String)], force: bool) -> Result<()> {
-        // We use a file .bins in the bin directory to keep track of installed bins
-        let mut dot_f = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(true)
-            .open(self.layout.bin.join(".bins"))
-            .with_context(|e| format_err!("could not open .bins file:\n{}", e))?;
+        let mut keep = source.target_files();
+        keep.extend(
+            [MANIFEST_FILE, SLIM_FILE, ".dirlock"]
+                .iter()
+                .map(PathBuf::from),
+        );
+        fs::write(path.join(SLIM_FILE), source.hash())
+            .with_context(|e| format_err!("couldn't slim {}: {}", path.display(), e))?;
+
+        // Directories come after their contents, so the ones left empty can be removed too
+        for entry in WalkDir::new(path).min_depth(1).contents_first(true) {
+            let entry = entry?;
+            let rel = entry.path().strip_prefix(path).unwrap();
+            if entry.file_type().is_dir() {
+                let _ = fs::remove_dir(entry.path());
+            } else if !keep.iter().any(|x| x == rel) {
+                fs::remove_file(entry.path())?;
+            }
+        }
 
-        let mut dot_c = String::new();
-        dot_f
-            .read_to_string(&mut dot_c)
-            .with_context(|e| format_err!("could not read .bins file:\n{}", e))?;
+        debug!(self.logger, "slimmed source"; "pkg" => source.summary(), "kept" => keep.len());
+
+        Ok(true)
+    }
+
+    /// Brings back everything that `slim_source` removed from a source, so that it can be built
+    /// again. The source is retrieved again the way it was the first time, and has to hash to
+    /// the same thing as it did before it was slimmed.
+    pub fn restore_source(&self, source: &Source) -> Result<()> {
+        if !source.is_slim() {
+            return Ok(());
+        }
+
+        let loc = source.location();
+        let dir = &source.inner.path;
+        let pkg = PackageId::new(source.meta().name().clone(), source.inner.res.clone());
+        let source_key = format!("src/{}", Self::get_source_dir(loc, true));
+
+        clear_dir_except(dir.path(), &[Path::new(".dirlock")])?;
+        if !(self.import_tarball(&pkg, loc, dir)? || self.fetch_stored(&source_key, dir)?) {
+            self.retrieve_mirrored(loc, dir, false, |dl_online| {
+                if self.offline && dl_online {
+                    Err(self.missing_source(loc))
+                } else {
+                    self.shell.println(
+                        style("Retrieving").cyan(),
+                        format!("{} (to build it again)", source.pretty_summary()),
+                        Verbosity::Normal,
+                    );
+                    Ok(())
+                }
+            })
+            .with_context(|e| {
+                format_err!(
+                    "couldn't restore the slimmed source of {}: {}",
+                    source.pretty_summary(),
+                    e
+                )
+            })?;
+        }
+
+        // The source is somewhere in its directory in the cache, which is where its sidecar is
+        let entry = dir
+            .path()
+            .ancestors()
+            .find(|x| x.parent() == Some(&self.layout.src))
+            .unwrap_or_else(|| dir.path());
+        let hash = hash_folder(source.meta(), dir.path(), entry, loc)?;
+        if hash != source.hash() {
+            bail!(
+                "the source of {} has changed since it was slimmed: its hash was {}, but now it's {}",
+                source.pretty_summary(),
+                source.hash(),
+                hash
+            )
+        }
+
+        debug!(self.logger, "restored slimmed source"; "pkg" => source.summary());
+
+        Ok(())
+    }
+
+    /// Packs a freshly retrieved source into a compressed archive next to its directory, then
+    /// removes the directory and returns an extracted view of the archive in its place.
+    fn compress_source(&self, dir: DirLock) -> Result<DirLock> {
+        let name = dir
+            .path()
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        let archive = self
+            .layout
+            .src
+            .join(format!("{}{}", name, SOURCE_ARCHIVE_EXT));
+        let part = self.layout.src.join(format!("{}.src.part", name));
+
+        let file = File::create(&part)
+            .with_context(|e| format_err!("couldn't create archive {}: {}", part.display(), e))?;
+        let mut tar = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+        tar.follow_symlinks(false);
+        let walker = WalkDir::new(dir.path())
+            .min_depth(1)
+            .follow_links(false)
+            .into_iter()
+            .filter_entry(|x| x.file_name() != ".dirlock");
+        for item in walker {
+            let item = item?;
+            let suffix = item.path().strip_prefix(dir.path()).unwrap();
+            tar.append_path_with_name(item.path(), suffix)
+                .with_context(|e| {
+                    format_err!("couldn't archive {}: {}", item.path().display(), e)
+                })?;
+        }
+        tar.into_inner()?.finish()?;
+
+        // The archive only shows up once it's complete, so anyone who sees it can use it
+        fs::rename(&part, &archive)?;
+        // The lockfile goes away along with the directory, which tells anyone waiting on the lock
+        // to start over (and find the archive)
+        remove_dir_all::remove_dir_all(dir.path())?;
+        let _ = fs::remove_file(sibling_file(dir.path(), merkle::SIDECAR_EXT));
+        drop(dir);
+
+        debug!(
+            self.logger, "compressed source";
+            "archive" => archive.display()
+        );
+
+        self.extract_source(&archive)
+    }
+
+    /// Extracts a compressed source into a working directory under `tmp`, returning a lock on
+    /// the working directory.
+    ///
+    /// The working directory is emptied before the source is extracted into it, so it never has
+    /// leftovers from earlier builds. It's kept around for as long as it's locked (i.e. for as
+    /// long as the Source which is made from it lives); working directories which aren't locked
+    /// anymore are removed the next time a source is extracted.
+    fn extract_source(&self, archive: &Path) -> Result<DirLock> {
+        self.sweep_extracted();
+
+        let name = archive.file_name().unwrap().to_string_lossy();
+        let hash = name.trim_end_matches(SOURCE_ARCHIVE_EXT);
+        let dir = self.lock(&self.layout.tmp.join(format!("src-{}", hash)))?;
+        clear_dir_except(dir.path(), &[Path::new(".dirlock")])?;
+
+        let file = File::open(archive)
+            .with_context(|e| format_err!("couldn't open archive {}: {}", archive.display(), e))?;
+        unpack_archive(GzDecoder::new(BufReader::new(file)), dir.path()).with_context(|e| {
+            format_err!("couldn't extract archive {}: {}", archive.display(), e)
+        })?;
+
+        debug!(
+            self.logger, "extracted source";
+            "archive" => archive.display(),
+            "dir" => dir.path().display()
+        );
+
+        Ok(dir)
+    }
+
+    /// Removes the working directories of compressed sources which nobody is using anymore.
+    fn sweep_extracted(&self) {
+        let entries = match fs::read_dir(&self.layout.tmp) {
+            Ok(entries) => entries.filter_map(|x| x.ok()),
+            Err(_) => return,
+        };
+
+        for entry in entries {
+            if !entry.file_name().to_string_lossy().starts_with("src-") {
+                continue;
+            }
+
+            let lock = match DirLock::try_acquire(&entry.path()) {
+                Ok(Some(lock)) => lock,
+                _ => continue,
+            };
+            lock.warn_reclaimed(self.shell);
+
+            // A workspace member of the source might still be locked on its own even though the
+            // source as a whole isn't
+            let in_use = WalkDir::new(lock.path())
+                .min_depth(2)
+                .follow_links(false)
+                .into_iter()
+                .filter_map(|x| x.ok())
+                .any(|x| x.file_name() == ".dirlock");
+            if !in_use {
+                let _ = remove_dir_all::remove_dir_all(lock.path());
+            }
+        }
+    }
+
+    /// The error for a source which has to be downloaded while we're offline.
+    fn missing_source(&self, loc: &DirectRes) -> failure::Error {
+        if self.frozen {
+            let msg = format!("{} isn't cached, and --frozen forbids downloading it", loc);
+            Error::OfflineMissingPackage.context(msg).into()
+        } else {
+            Error::OfflineMissingPackage.into()
+        }
+    }
+
+    /// Gets the hash a package from `loc` is cached under. Indices are always kept under their
+    /// hashes; `Cache::source_dir` names the directory of a source.
+    pub fn get_source_dir(loc: &DirectRes, include_tag: bool) -> String {
+        let mut hasher = Hasher::new();
+        if include_tag {
+            hasher.input(loc.cache_key().as_bytes());
+        } else {
+            hasher.input(loc.lowkey_cache_key().as_bytes());
+        }
+        hasher.finish()
+    }
+
+    /// Gets the name of the directory a package from `loc` is kept in under `src`: the readable
+    /// name it was given when it was retrieved, or else its hash. Which one it is doesn't depend
+    /// on the cache's `source_naming`, so nothing gets lost by changing it.
+    pub fn source_dir(&self, loc: &DirectRes, include_tag: bool) -> String {
+        source_name(&self.layout.src, &Self::get_source_dir(loc, include_tag))
+    }
+
+    /// Locks the directory under `src` which the source from `loc` is retrieved into. If the
+    /// source doesn't have one yet and the cache's `source_naming` says so, it's given a readable
+    /// name, which has `version` in it if that's known.
+    fn lock_source(
+        &self,
+        pkg: &PackageId,
+        version: Option<&Version>,
+        loc: &DirectRes,
+    ) -> Result<DirLock> {
+        let hash = Self::get_source_dir(loc, true);
+        let name = self.source_dir(loc, true);
+        if self.source_naming == SourceNaming::Hashed
+            || name != hash
+            || self.layout.src.join(&hash).exists()
+        {
+            return self.lock(&self.layout.src.join(name));
+        }
+
+        for name in SourceNaming::candidates(pkg.name(), version, &hash).iter() {
+            let dir = self.lock(&self.layout.src.join(name))?;
+            // Somebody else might've named the source, or taken the name for another one, while
+            // we were waiting for the lock
+            let named = self.source_dir(loc, true);
+            if named != hash {
+                drop(dir);
+                return self.lock(&self.layout.src.join(named));
+            }
+            if !is_empty_entry(dir.path()) || source_names(&self.layout.src).contains_key(name) {
+                continue;
+            }
+
+            fs::write(
+                sibling_file(&self.layout.src.join(&hash), SOURCE_NAME_EXT),
+                name,
+            )
+            .with_context(|e| format_err!("couldn't name source directory {}: {}", name, e))?;
+            return Ok(dir);
+        }
+
+        self.lock(&self.layout.src.join(hash))
+    }
+
+    /// Return the build directory exists, else None.
+    pub fn checkout_build(
+        &self,
+        hash: &BuildHash,
+        profile: &BuildProfile,
+    ) -> Result<Option<Binary>> {
+        if let Some(path) = self.check_build(hash, profile) {
+            return Ok(Some(Binary::new(self.lock_cached(&path)?)));
+        }
+
+        // Local builds are never stored, so they're only ever in the local build directory
+        let key = Self::build_key(hash, profile);
+        if self.local_builds.is_some() || !self.has_stored(&key)? {
+            return Ok(None);
+        }
+
+        // The build directory is where the storage's builds are checked out to, so it might be
+        // there from last time already
+        let dir = self.lock(&self.layout.build.join(Self::build_dir(hash, profile)))?;
+        if is_empty_entry(dir.path()) {
+            self.fetch_stored(&key, &dir)?;
+        }
+
+        Ok(Some(Binary::new(dir)))
+    }
+
+    /// Returns the cached build of the package `root` with the given targets, if there is one.
+    ///
+    /// `graph` is the graph of packages `root` is built as part of, and its BuildHash is worked out
+    /// the same way the JobQueue does it (see `BuildHash::for_job`).
+    pub fn locate_build(
+        &self,
+        root: &Source,
+        graph: &Graph<Source>,
+        targets: &Targets,
+        bcx: &BuildContext,
+    ) -> Result<Option<Binary>> {
+        let hash = BuildHash::for_job(root, graph, targets, bcx)?;
+        self.checkout_build(&hash, &bcx.profile)
+    }
+
+    /// Returns the include paths that editor tooling needs to check the package `sum` in `graph`
+    /// the way it'd be built: for each of its dependencies, the source directories of its lib
+    /// targets, followed by the directories of its cached build if it's been built already.
+    ///
+    /// Dependencies always come before the packages which depend on them.
+    pub fn resolve_path_for_ide(
+        &self,
+        sum: &Summary,
+        graph: &Graph<Source>,
+        bcx: &BuildContext,
+    ) -> Result<Vec<PathBuf>> {
+        let (root, _) = graph
+            .get_by_summary(sum)
+            .ok_or_else(|| format_err!("package {} isn't part of the graph", sum))?;
+        let targets = Targets::new(vec![Target::Lib(false)]);
+
+        let mut paths = vec![];
+        for (ix, dep) in graph
+            .sub_tree_sorted(root, |s| s.summary())
+            .into_iter()
+            .rev()
+        {
+            if ix == root {
+                continue;
+            }
+
+            for (_, lib) in dep.meta().targets.lib_targets() {
+                paths.push(dep.path().join(&lib.path.0));
+            }
+            if let Some(binary) = self.locate_build(dep, graph, &targets, bcx)? {
+                paths.extend(binary.include_dirs());
+            }
+        }
+
+        Ok(paths)
+    }
+
+    /// Locks the build with the given hash and profile, so that no other `elba` builds the same
+    /// thing at the same time. This gives up if whoever else is building it doesn't finish within
+    /// the Cache's lock timeout.
+    ///
+    /// Whoever holds the lock should check whether the build was stored while they were waiting
+    /// for it (see `with_build_lock`), rather than building it again.
+    pub fn acquire_build_lock(&self, hash: &BuildHash, profile: &BuildProfile) -> Result<DirLock> {
+        self.lock(&self.build_lock_dir(hash, profile))
+    }
+
+    /// Like `acquire_build_lock`, but returns None straight away if someone else is holding the
+    /// lock, instead of waiting for them.
+    pub fn try_acquire_build_lock(
+        &self,
+        hash: &BuildHash,
+        profile: &BuildProfile,
+    ) -> Result<Option<DirLock>> {
+        let lock = DirLock::try_acquire(&self.build_lock_dir(hash, profile))?;
+        if let Some(lock) = &lock {
+            lock.warn_reclaimed(self.shell);
+        }
+
+        Ok(lock)
+    }
+
+    /// The directory whose lock is the build lock of the build with the given hash and profile.
+    fn build_lock_dir(&self, hash: &BuildHash, profile: &BuildProfile) -> PathBuf {
+        let locks = match &self.local_builds {
+            Some(dir) => dir.join(".locks"),
+            None => self.layout.tmp.join("build-locks"),
+        };
+        locks.join(Self::build_dir(hash, profile))
+    }
+
+    /// Runs `f` to build and store the build with the given hash and profile, while holding its
+    /// build lock. If someone else stored the build while we were waiting for the lock, that's
+    /// returned instead, and `f` is never run.
+    pub fn with_build_lock<F>(
+        &self,
+        hash: &BuildHash,
+        profile: &BuildProfile,
+        f: F,
+    ) -> Result<Binary>
+    where
+        F: FnOnce() -> Result<Binary>,
+    {
+        let _lock = self.acquire_build_lock(hash, profile)?;
+        match self.checkout_build(hash, profile)? {
+            Some(binary) => Ok(binary),
+            None => f(),
+        }
+    }
+
+    /// Returns a lock on a temporary build directory.
+    /// Note that the format of this directory should be an OutputLayout.
+    pub fn checkout_tmp(&self, hash: &BuildHash, profile: &BuildProfile) -> Result<OutputLayout> {
+        let path = self.layout.tmp.join(Self::build_dir(hash, profile));
+        let lock = self.lock(&path)?;
+        if lock.path().exists() {
+            clear_dir_except(lock.path(), &[Path::new(".dirlock")]).context(format_err!(
+                "couldn't remove existing output path: {}",
+                lock.path().display()
+            ))?;
+        }
+        OutputLayout::new(lock)
+    }
+
+    pub fn store_bins(&self, bins: &[(PathBuf, String)], force: bool) -> Result<()> {
+        // We use a file .bins in the bin directory to keep track of installed bins
+        let mut dot_f = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .read(true)
+            .open(self.layout.bin.join(".bins"))
+            .with_context(|e| format_err!("could not open .bins file:\n{}", e))?;
+
+        let mut dot_c = String::new();
+        dot_f
+            .read_to_string(&mut dot_c)
+            .with_context(|e| format_err!("could not read .bins file:\n{}", e))?;
 
         let mut dot: IndexMap<String, String> = toml::from_str(&dot_c)
             .with_context(|e| format_err!("could not deserialize .bins file:\n{}", e))?;
@@ -419,32 +1665,341 @@ impl Cache {
         Ok(c)
     }
 
-    pub fn store_build(&self, from: &Path, hash: &BuildHash) -> Result<Binary> {
-        let dest = self.layout.build.join(&hash.0);
+    /// Stores the build in `from` in the Cache's storage, and checks it out of there into the
+    /// cache's build directory. Local builds are copied straight into the local build directory
+    /// instead.
+    pub fn store_build(
+        &self,
+        from: &Path,
+        hash: &BuildHash,
+        profile: &BuildProfile,
+    ) -> Result<Binary> {
+        let dest = self.builds_dir().join(Self::build_dir(hash, profile));
 
         if !dest.exists() {
             fs::create_dir_all(&dest)?;
         }
 
-        let dest = DirLock::acquire(&dest)?;
+        let dest = self.lock(&dest)?;
 
-        clear_dir(dest.path())?;
-        copy_dir(from, dest.path(), false)?;
+        clear_dir_except(dest.path(), &[Path::new(".dirlock")])?;
+        if self.local_builds.is_some() {
+            copy_dir(from, dest.path(), false)?;
+        } else {
+            let key = Self::build_key(hash, profile);
+            self.keep_stored(&key, from)?;
+            self.fetch_stored(&key, &dest)?;
+        }
 
         Ok(Binary::new(dest))
     }
 
-    fn check_build(&self, hash: &BuildHash) -> Option<PathBuf> {
-        let path = self.layout.build.join(&hash.0);
+    /// Returns where the source of a package is cached, if it is.
+    ///
+    /// `loc` is where the package was retrieved from: its own resolution for direct packages, or
+    /// the location of its index entry otherwise. The path is only returned if the manifest found
+    /// there matches the name and version of the Summary. For a compressed source, this is the
+    /// path of its archive.
+    pub fn which(&self, sum: &Summary, loc: &DirectRes) -> Option<PathBuf> {
+        let path = self.check_source(loc)?;
+        let mut sub = PathBuf::new();
+        let mut contents = read_source_file(&path, Path::new(MANIFEST_FILE))?;
+
+        if let Some(p) = Manifest::workspace(&contents).and_then(|ws| ws.get(sum.name()).cloned()) {
+            let (dir, file) = member_manifest(&p);
+            contents = read_source_file(&path, &dir.join(file))?;
+            sub = dir;
+        }
+
+        let manifest = Manifest::from_str(&contents).ok()?;
+        if manifest.name() != sum.name() || manifest.version() != sum.version() {
+            None
+        } else if path.is_dir() {
+            Some(path.join(sub))
+        } else {
+            Some(path)
+        }
+    }
+
+    /// Returns where a package from `loc` lives in the cache, if it's there already. A tarball
+    /// which is only stored compressed gives the path of its archive.
+    ///
+    /// Tarballs are looked for in the base cache first, if there is one.
+    pub fn check_source(&self, loc: &DirectRes) -> Option<PathBuf> {
+        if let DirectRes::Dir { path } = loc {
+            return Some(path.clone()).filter(|x| x.exists());
+        }
+
+        let base = self.base.as_ref().filter(|_| loc.is_tar());
+        let found = base
+            .and_then(|base| self.find_source(&base.src, loc))
+            .or_else(|| self.find_source(&self.layout.src, loc));
+
+        match &found {
+            Some(path) => debug!(
+                self.logger, "cache hit";
+                "url" => loc.to_string(),
+                "path" => path.display()
+            ),
+            None => debug!(self.logger, "cache miss"; "url" => loc.to_string()),
+        }
+
+        found
+    }
 
-        if path.exists() {
+    /// Returns where a package from `loc` lives in the source directory `src`, if it's there,
+    /// under whatever name it was given there (see `SourceNaming`).
+    fn find_source(&self, src: &Path, loc: &DirectRes) -> Option<PathBuf> {
+        let name = source_name(src, &Self::get_source_dir(loc, true));
+        let path = src.join(&name);
+        let archive = src.join(format!("{}{}", name, SOURCE_ARCHIVE_EXT));
+        // A retrieval which failed leaves its directory behind with nothing in it
+        if path.exists() && !is_empty_entry(&path) {
             Some(path)
+        } else if loc.is_tar() && archive.exists() {
+            Some(archive)
         } else {
             None
         }
     }
 
+    /// Builds are keyed by their profile as well as their hash, so that builds made with
+    /// different profiles never overwrite each other.
+    fn build_dir(hash: &BuildHash, profile: &BuildProfile) -> String {
+        format!("{}-{}", profile.name, hash.0)
+    }
+
+    /// The key of a build in the Cache's storage.
+    fn build_key(hash: &BuildHash, profile: &BuildProfile) -> String {
+        format!("build/{}", Self::build_dir(hash, profile))
+    }
+
+    /// The Cache's storage: the one it was given, or else its own directories.
+    fn storage(&self) -> Arc<dyn Storage> {
+        match &self.storage {
+            Some(storage) => storage.clone(),
+            None => Arc::new(LocalFs::new(&self.layout)),
+        }
+    }
+
+    /// Returns whether the Cache's storage has an entry under `key`.
+    fn has_stored(&self, key: &str) -> Result<bool> {
+        let found = self
+            .storage()
+            .contains(key)
+            .with_context(|e| format_err!("couldn't look for {} in storage: {}", key, e))?;
+
+        Ok(found)
+    }
+
+    /// Fetches the entry `key` from the Cache's storage into `dir`, returning whether anything
+    /// was fetched. Whatever a failed fetch left behind is cleared out again.
+    fn fetch_stored(&self, key: &str, dir: &DirLock) -> Result<bool> {
+        let fetched = self.storage().fetch(key, dir.path()).with_context(|e| {
+            let _ = clear_dir_except(dir.path(), &[Path::new(".dirlock")]);
+            format_err!("couldn't fetch {} from storage: {}", key, e)
+        })?;
+        if fetched {
+            debug!(self.logger, "fetched from storage"; "key" => key);
+        }
+
+        Ok(fetched)
+    }
+
+    /// Hands the contents of `dir` over to the Cache's storage to keep under `key`.
+    fn keep_stored(&self, key: &str, dir: &Path) -> Result<()> {
+        self.storage()
+            .store(key, dir)
+            .with_context(|e| format_err!("couldn't keep {} in storage: {}", key, e))?;
+
+        Ok(())
+    }
+
+    /// Returns where the build with the given hash and profile is, if it's in the base cache or
+    /// the local build directory. Anything else is up to the Cache's storage.
+    fn check_build(&self, hash: &BuildHash, profile: &BuildProfile) -> Option<PathBuf> {
+        let dir = Self::build_dir(hash, profile);
+
+        self.base
+            .iter()
+            .map(|base| base.build.as_path())
+            .chain(self.local_builds.as_deref())
+            .map(|builds| builds.join(&dir))
+            .find(|path| path.exists())
+    }
+
     pub fn get_indices(&self, index_reses: &[DirectRes], eager: bool, offline: bool) -> Indices {
+        // Indices which can't be loaded are skipped with a warning, so this never fails
+        let indices = self
+            .walk_indices(index_reses, eager, offline, |e| {
+                self.shell
+                    .println(style("[warn]").yellow().bold(), e, Verbosity::Quiet);
+                Ok(())
+            })
+            .unwrap_or_default();
+
+        let mut indices = Indices::new(indices);
+        indices.strict = self.strict_indices;
+        indices
+    }
+
+    /// Downloads the given indices and every index they depend on into the cache (or updates them
+    /// if they're there already), without loading any of them. This is meant for warming up the
+    /// `indices` directory of a cache ahead of time; unlike `get_indices`, an index which can't be
+    /// retrieved is an error.
+    ///
+    /// Sparse indices only have their config fetched; their entries are still fetched when
+    /// they're first needed.
+    pub fn fetch_index_only(&self, index_reses: &[DirectRes]) -> Result<()> {
+        self.walk_indices(index_reses, true, false, Err)?;
+
+        Ok(())
+    }
+
+    /// Retrieves every package a lockfile pins into the cache, up to `download_jobs` of them at
+    /// once, so that an offline build of the locked project has everything it needs.
+    ///
+    /// Tarballs which are already cached are skipped, and directory dependencies are left where
+    /// they are. Packages pinned to an index are looked up in that index to find out where they
+    /// come from. Each tarball's checksum is checked as it's downloaded, and each source has to
+    /// declare the version it was locked to.
+    pub fn warm_from_lock(&self, lock: &LockfileToml, download_jobs: u32) -> Result<()> {
+        let index_reses = lock
+            .packages
+            .iter()
+            .filter_map(|pkg| match pkg.sum.resolution() {
+                Resolution::Index(ir) => Some(ir.res.clone()),
+                Resolution::Direct(_) => None,
+            })
+            .unique()
+            .collect::<Vec<_>>();
+        let mut indices = self.get_indices(&index_reses, false, self.offline);
+
+        let mut pending = vec![];
+        for pkg in &lock.packages {
+            let loc = match pkg.sum.resolution() {
+                Resolution::Direct(DirectRes::Dir { .. }) => continue,
+                Resolution::Direct(direct) => direct.clone(),
+                Resolution::Index(_) => indices
+                    .select(&pkg.sum)
+                    .context(format_err!("unable to find package {}", pkg.sum))?
+                    .location
+                    .clone(),
+            };
+            if !loc.is_tar() || self.check_source(&loc).is_none() {
+                pending.push((pkg.sum.clone(), loc));
+            }
+        }
+        // Nothing else needs the indices, so they're unlocked before the downloads start
+        drop(indices);
+
+        debug!(self.logger, "warming cache from lockfile"; "pending" => pending.len());
+        // The errors are reported in the order of the lockfile, not in the order they happened in
+        for (res, _) in self.retrieve_all(pending, download_jobs, false)? {
+            res?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks out the sources of the given packages from where they're located, up to
+    /// `download_jobs` of them at once. Each package gets its result and how long it took,
+    /// in the order the packages were given in.
+    ///
+    /// Nothing new is started once the deadline has passed. The packages which are still being
+    /// retrieved by then are waited for, and then this fails with `Error::DeadlineExceeded`,
+    /// listing the packages which were left.
+    pub fn retrieve_all(
+        &self,
+        pending: Vec<(Summary, DirectRes)>,
+        download_jobs: u32,
+        offline: bool,
+    ) -> Result<Vec<(Result<Source>, Duration)>> {
+        let wanted = pending
+            .iter()
+            .map(|(sum, _)| sum.clone())
+            .collect::<Vec<_>>();
+        let queue = Arc::new(Mutex::new(
+            pending.into_iter().enumerate().collect::<VecDeque<_>>(),
+        ));
+        let (tx, rx) = mpsc::channel();
+        let mut workers = vec![];
+        for _ in 0..(jobs(download_jobs) as usize).min(wanted.len()) {
+            let (queue, tx, cache) = (queue.clone(), tx.clone(), self.clone());
+            workers.push(thread::spawn(move || loop {
+                let (i, (sum, loc)) = match queue.lock().unwrap().pop_front() {
+                    Some(next) => next,
+                    None => break,
+                };
+                // Nothing new gets started once the deadline has passed
+                if cache.check_deadline(None::<String>).is_err() {
+                    break;
+                }
+
+                let start = Instant::now();
+                let res = cache
+                    .checkout_summary(&sum, &loc, false, offline, || {
+                        cache.shell.println(
+                            style("Retrieving").cyan(),
+                            sum.to_string(),
+                            Verbosity::Normal,
+                        );
+                    })
+                    .context(format_err!("unable to retrieve package {}", sum))
+                    .map(|(_, source)| source)
+                    .map_err(failure::Error::from);
+                if tx.send((i, res, start.elapsed())).is_err() {
+                    break;
+                }
+            }));
+        }
+        drop(tx);
+
+        // The downloads which are still going when the deadline passes give up once their
+        // requests time out, so the workers always finish; nobody is left writing to the cache
+        // once we've returned
+        let mut results = rx.iter().collect::<Vec<_>>();
+        for worker in workers {
+            if worker.join().is_err() {
+                bail!("a download worker panicked")
+            }
+        }
+
+        let left = wanted
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !results.iter().any(|(done, _, _)| done == i))
+            .map(|(_, sum)| sum)
+            .collect::<Vec<_>>();
+        if !left.is_empty() {
+            self.check_deadline(&left)?;
+            bail!(
+                "packages were left unretrieved: {}",
+                left.iter().map(|x| x.to_string()).join(", ")
+            )
+        }
+
+        results.sort_by_key(|(i, _, _)| *i);
+        Ok(results
+            .into_iter()
+            .map(|(_, res, time)| (res, time))
+            .collect())
+    }
+
+    /// Retrieves and loads the given indices and (breadth-first) all the indices they depend on.
+    /// Whenever an index can't be retrieved or loaded, its error goes to `fail`, which decides
+    /// whether to give up or to carry on without that index.
+    fn walk_indices<F>(
+        &self,
+        index_reses: &[DirectRes],
+        eager: bool,
+        offline: bool,
+        mut fail: F,
+    ) -> Result<Vec<Index>>
+    where
+        F: FnMut(failure::Error) -> Result<()>,
+    {
+        let offline = offline || self.offline;
         let mut indices = vec![];
         let mut seen = vec![];
         let mut q: VecDeque<DirectRes> = index_reses.iter().cloned().collect();
@@ -456,20 +2011,25 @@ impl Cache {
 
             // We special-case a local dir index because `dir` won't exist for it.
             if let DirectRes::Dir { path } = &index {
-                let lock = match DirLock::acquire(path) {
+                let lock = match self.lock(path) {
                     Ok(dir) => dir,
                     Err(e) => {
-                        self.shell.println(
-                            style("[warn]").yellow().bold(),
-                            format!("Couldn't lock dir index {}: {}", path.display(), e),
-                            Verbosity::Quiet,
-                        );
+                        fail(format_err!(
+                            "Couldn't lock dir index {}: {}",
+                            path.display(),
+                            e
+                        ))?;
                         continue;
                     }
                 };
 
                 let ix = Index::from_disk(index.clone(), lock);
-                if let Ok(ix) = ix {
+                if let Ok(mut ix) = ix {
+                    if let Err(e) = self.trust_index(&mut ix) {
+                        fail(e)?;
+                        continue;
+                    }
+                    debug!(self.logger, "loaded index"; "cause" => "dir", "url" => index.to_string());
                     for dependent in ix.depends().cloned().map(|i| i.res) {
                         q.push_back(dependent);
                     }
@@ -480,22 +2040,30 @@ impl Cache {
             }
 
             let index_path = self.layout.indices.join(Self::get_index_dir(&index));
-            let dir = match DirLock::acquire(&index_path) {
+            let dir = match self.lock(&index_path) {
                 Ok(dir) => dir,
                 Err(e) => {
-                    self.shell.println(
-                        style("[warn]").yellow().bold(),
-                        format!("Couldn't lock cached index {}: {}", index, e),
-                        Verbosity::Quiet,
-                    );
+                    fail(format_err!("Couldn't lock cached index {}: {}", index, e))?;
                     continue;
                 }
             };
 
-            let res = index.retrieve(&self.client, &dir, eager, |dl_online| {
+            let start = Instant::now();
+            let refreshed = Cell::new(false);
+            let res = self.retrieve_index(&index, &dir, eager, |dl_online| {
                 if offline && dl_online {
+                    if self.frozen {
+                        bail!(
+                            "index {} isn't cached, and --frozen forbids downloading it",
+                            index
+                        )
+                    }
                     return Err(format_err!("Offline mode; can't update indices"));
                 }
+                if dl_online && !refreshed.get() {
+                    info!(self.logger, "index refresh started"; "url" => index.to_string());
+                    refreshed.set(true);
+                }
                 self.shell.println(
                     style("Retrieving").cyan(),
                     format!("index {}", &index),
@@ -504,67 +2072,1010 @@ impl Cache {
                 Ok(())
             });
 
-            match res {
-                Ok(_) => {
-                    let ix = Index::from_disk(index.clone(), dir);
-                    match ix {
-                        Ok(ix) => {
-                            for dependent in ix.depends().cloned().map(|i| i.res) {
-                                q.push_back(dependent);
-                            }
-                            seen.push(index);
-                            indices.push(ix);
-                        }
-                        Err(e) => {
-                            self.shell.println(
-                                style("[warn]").yellow().bold(),
-                                format!("Invalid/corrupt index {}: {}", index, e),
-                                Verbosity::Quiet,
-                            );
-                        }
-                    }
-                }
-                Err(e) => {
-                    self.shell.println(
-                        style("[warn]").yellow().bold(),
-                        format!("Couldn't retrieve cache {}: {}", index, e),
-                        Verbosity::Quiet,
-                    );
-                }
+            match res {
+                Ok(_) => {
+                    // Where the index came from is kept next to it, so that `doctor` can
+                    // retrieve it again if it gets corrupted
+                    let origin = self
+                        .layout
+                        .indices
+                        .join(format!("{}.res", Self::get_index_dir(&index)));
+                    let ir = IndexRes { res: index.clone() }.to_string();
+                    if fs::read_to_string(&origin).ok() != Some(ir.clone()) {
+                        let _ = fs::write(&origin, ir);
+                    }
+                    let ix = self
+                        .load_cached_index(&index, dir, offline)
+                        .and_then(|mut ix| {
+                            if !offline {
+                                ix.connect(&self.client, &self.credentials);
+                                if let (Some(remote), DirectRes::Sparse { url }) =
+                                    (&mut ix.remote, self.mirrors.apply(&index)?)
+                                {
+                                    remote.url = url;
+                                }
+                            }
+                            self.trust_index(&mut ix).map(|_| ix)
+                        });
+                    match ix {
+                        Ok(ix) => {
+                            if refreshed.get() {
+                                info!(
+                                    self.logger, "index refreshed";
+                                    "url" => index.to_string(),
+                                    "duration_ms" => start.elapsed().as_millis() as u64
+                                );
+                            } else {
+                                debug!(
+                                    self.logger, "loaded index";
+                                    "cause" => "cached",
+                                    "url" => index.to_string()
+                                );
+                            }
+                            for dependent in ix.depends().cloned().map(|i| i.res) {
+                                q.push_back(dependent);
+                            }
+                            seen.push(index);
+                            indices.push(ix);
+                        }
+                        Err(e) => {
+                            fail(format_err!("Invalid/corrupt index {}: {}", index, e))?;
+                        }
+                    }
+                }
+                Err(e) => {
+                    fail(format_err!("Couldn't retrieve cache {}: {}", index, e))?;
+                }
+            }
+        }
+
+        Ok(indices)
+    }
+
+    /// Loads an index which was retrieved into the cache. If what's on disk is corrupt (say, because
+    /// the disk filled up while it was being downloaded), the index is thrown away and retrieved
+    /// again from scratch, which can only be done when we're online.
+    fn load_cached_index(&self, index: &DirectRes, dir: DirLock, offline: bool) -> Result<Index> {
+        let path = dir.path().to_owned();
+        let err = match Index::from_disk(index.clone(), dir) {
+            Ok(ix) => return Ok(ix),
+            Err(e) => e,
+        };
+
+        if offline {
+            bail!(
+                "the cached copy of the index is corrupt ({}); run elba again without offline mode to repair it",
+                err
+            )
+        }
+
+        self.shell.println(
+            style("[warn]").yellow().bold(),
+            format!(
+                "the cached copy of index {} is corrupt ({}); retrieving it again",
+                index, err
+            ),
+            Verbosity::Normal,
+        );
+        let dir = self.lock(&path)?;
+        clear_dir_except(dir.path(), &[Path::new(".dirlock")])?;
+        self.retrieve_index(index, &dir, true, |_| {
+            self.shell.println(
+                style("Retrieving").cyan(),
+                format!("index {}", index),
+                Verbosity::Normal,
+            );
+            Ok(())
+        })?;
+
+        Index::from_disk(index.clone(), dir)
+    }
+
+    /// Checks the signature of an index against the key configured for it. An index which
+    /// declares a key has to have one configured, and an index with a configured key has to be
+    /// signed with it, whichever key (if any) the index itself declares.
+    fn trust_index(&self, ix: &mut Index) -> Result<()> {
+        let key = match (self.index_keys.get(&ix.id.res), &ix.config.index.public_key) {
+            (None, None) => return Ok(()),
+            (None, Some(declared)) => bail!(
+                "index {} is signed with key {}, which isn't trusted; add it to `index_keys` in \
+                 the configuration to use the index",
+                ix.id,
+                declared
+            ),
+            (Some(key), Some(declared)) if key != declared => bail!(
+                "index {} is signed with key {}, but the configuration expects {}",
+                ix.id,
+                declared,
+                key
+            ),
+            (Some(key), _) => key.clone(),
+        };
+        // A sparse index is never all on disk at once, so only its entries can be checked
+        if !ix.id.res.is_sparse() {
+            ix.verify_signature(&key)?;
+        }
+        // Entries are always checked against the trusted key, even if the index doesn't declare it
+        ix.config.index.public_key = Some(key);
+
+        Ok(())
+    }
+
+    fn get_index_dir(loc: &DirectRes) -> String {
+        Self::get_source_dir(loc, false)
+    }
+
+    /// A fingerprint of the state of every cached index, which changes whenever an index is
+    /// downloaded or updated. Only the commit a git index is checked out at, or else the
+    /// `index.toml` of the index, is looked at, so this stays cheap however many entries the
+    /// indices have.
+    ///
+    /// Local dir indices aren't cached, but read where they are, so every file of the ones in
+    /// `index_reses` (and of the ones any index depends on) is looked at instead, by its size and
+    /// modification time.
+    pub fn index_fingerprint(&self, index_reses: &[DirectRes]) -> String {
+        fn input_meta(hash: &mut Hasher, meta: &fs::Metadata) {
+            let mtime = meta
+                .modified()
+                .ok()
+                .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or_default();
+            hash.input(meta.len().to_le_bytes());
+            hash.input(mtime.as_nanos().to_le_bytes());
+        }
+
+        let mut hash = Hasher::new();
+        let mut dirs = fs::read_dir(&self.layout.indices)
+            .map(|x| {
+                x.filter_map(|x| x.ok())
+                    .map(|x| x.path())
+                    .filter(|x| x.is_dir())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        dirs.sort();
+
+        let mut q = index_reses.iter().cloned().collect::<VecDeque<_>>();
+        for dir in &dirs {
+            hash.input(dir.to_string_lossy().as_bytes());
+            hash.input([0]);
+
+            // Cached indices can depend on dir indices too
+            q.extend(index_dependencies(dir));
+
+            let head = Repository::open(dir)
+                .ok()
+                .and_then(|repo| repo.head().ok()?.target());
+            if let Some(head) = head {
+                hash.input(head.as_bytes());
+                continue;
+            }
+
+            if let Ok(meta) = fs::metadata(dir.join("index.toml")) {
+                input_meta(&mut hash, &meta);
+            }
+        }
+
+        let mut seen = vec![];
+        while let Some(index) = q.pop_front() {
+            let path = match index {
+                DirectRes::Dir { path } => path,
+                _ => continue,
+            };
+            if seen.contains(&path) {
+                continue;
+            }
+            hash.input(path.to_string_lossy().as_bytes());
+            hash.input([0]);
+
+            // Locking the index touches its lockfile, which doesn't change anything in it
+            let mut files = WalkDir::new(&path)
+                .follow_links(false)
+                .into_iter()
+                .filter_entry(|x| x.file_name() != ".git" && x.file_name() != ".dirlock")
+                .filter_map(|x| x.ok())
+                .filter(|x| x.file_type().is_file())
+                .map(|x| x.into_path())
+                .collect::<Vec<_>>();
+            files.sort();
+            for file in files {
+                if let Ok(meta) = fs::metadata(&file) {
+                    hash.input(
+                        file.strip_prefix(&path)
+                            .unwrap()
+                            .to_string_lossy()
+                            .as_bytes(),
+                    );
+                    hash.input([0]);
+                    input_meta(&mut hash, &meta);
+                }
+            }
+
+            q.extend(index_dependencies(&path));
+            seen.push(path);
+        }
+
+        hash.finish()
+    }
+
+    /// Returns all of the package hashes available in this cache.
+    pub fn cached_packages(&self) -> IndexSet<String> {
+        let walker = WalkDir::new(&self.layout.src)
+            .min_depth(1)
+            .into_iter()
+            .filter_map(|e| e.ok());
+
+        let names = source_names(&self.layout.src);
+        let mut res = IndexSet::new();
+
+        for dir in walker {
+            let fname = dir
+                .path()
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_string();
+
+            let hash = if dir.path().join("elba.toml").exists() {
+                fname
+            } else if is_source_archive(dir.path()) {
+                fname.trim_end_matches(SOURCE_ARCHIVE_EXT).to_string()
+            } else {
+                continue;
+            };
+
+            // Sources cached under a hash made with some other algorithm, or under a name they
+            // weren't given, will never be looked up
+            if parse_hash(&hash).is_some() || names.contains_key(&hash) {
+                res.insert(hash);
+            }
+        }
+
+        res
+    }
+
+    /// Builds a catalog of every source and build in this cache, which can be queried over and
+    /// over without scanning the cache again (see `Catalog`).
+    pub fn catalog(&self) -> Result<Catalog> {
+        let mut catalog = Catalog::default();
+        catalog.refresh(self)?;
+
+        Ok(catalog)
+    }
+
+    /// Looks through the cache for the problems described in `IssueKind`, and fixes them if `fix`
+    /// is set. Every fix locks the directory it's fixing first; problems in directories which
+    /// somebody is using right now are neither reported nor fixed, since they might just be
+    /// something that's still in progress.
+    ///
+    /// Checking the sources reads every file in the cache which has a recorded hash, so this can
+    /// take a while on a big cache.
+    pub fn doctor(&self, fix: bool) -> Result<DoctorReport> {
+        let mut report = DoctorReport::default();
+
+        // Stale locks go first, so that whatever they were guarding is checked as unused
+        for dir in self.layout.dirs().iter() {
+            let locked = WalkDir::new(dir)
+                .follow_links(false)
+                .into_iter()
+                .filter_map(|x| x.ok())
+                .filter(|x| x.file_name() == ".dirlock")
+                .map(|x| x.path().parent().unwrap().to_owned())
+                .collect::<Vec<_>>();
+
+            for path in locked {
+                if DirLock::probe(&path)? == LockState::Stale {
+                    let fixed = fix && self.fix_locked(&path, |_| Ok(()))?;
+                    report.push(IssueKind::StaleLock, path, fixed);
+                }
+            }
+        }
+
+        for dir in &[&self.layout.src, &self.layout.indices] {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                let owner = match partial_owner(&path) {
+                    Some(owner) => owner,
+                    None => continue,
+                };
+                if DirLock::probe(&owner)? == LockState::Locked {
+                    continue;
+                }
+
+                let fixed = fix
+                    && self.fix_locked(&owner, |lock| {
+                        if path != lock.path() {
+                            if path.is_dir() {
+                                remove_dir_all::remove_dir_all(&path)?;
+                            } else {
+                                fs::remove_file(&path)?;
+                            }
+                        }
+                        // A directory that a tarball was being unpacked into is only half
+                        // unpacked (a source which was being compressed is still whole, though)
+                        let name = path.to_string_lossy();
+                        let unpacking = name.ends_with(".tar.gz") || name.ends_with(".root");
+                        if unpacking || is_empty_entry(lock.path()) {
+                            remove_dir_all::remove_dir_all(lock.path())?;
+                        }
+                        Ok(())
+                    })?;
+                report.push(IssueKind::PartialDownload, path, fixed);
+            }
+        }
+
+        for entry in fs::read_dir(&self.layout.indices)? {
+            let path = entry?.path();
+            if !path.is_dir() || is_empty_entry(&path) {
+                continue;
+            }
+            let config = fs::read_to_string(path.join("index.toml"))
+                .map_err(failure::Error::from)
+                .and_then(|x| IndexConfig::from_str(&x));
+            if config.is_ok() || DirLock::probe(&path)? == LockState::Locked {
+                continue;
+            }
+
+            let fixed = fix && self.fix_locked(&path, |lock| self.repair_index(lock))?;
+            report.push(IssueKind::CorruptIndex, path, fixed);
+        }
+
+        for entry in fs::read_dir(&self.layout.src)? {
+            let path = entry?.path();
+            let sidecar = sibling_file(&path, merkle::SIDECAR_EXT);
+            if !path.is_dir() || !sidecar.exists() || DirLock::probe(&path)? == LockState::Locked {
+                continue;
+            }
+            if tampered_files(&path, &sidecar)?.is_empty() {
+                continue;
+            }
+
+            let fixed = fix
+                && self.fix_locked(&path, |lock| {
+                    remove_dir_all::remove_dir_all(lock.path())?;
+                    fs::remove_file(&sidecar)?;
+                    Ok(())
+                })?;
+            report.push(IssueKind::ChecksumMismatch, path, fixed);
+        }
+
+        for entry in fs::read_dir(&self.layout.build)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            if !path.is_dir() || DirLock::probe(&path)? == LockState::Locked {
+                continue;
+            }
+            if parse_build_dir(&name).is_some() && !is_empty_entry(&path) {
+                continue;
+            }
+
+            let fixed = fix
+                && self.fix_locked(&path, |lock| {
+                    remove_dir_all::remove_dir_all(lock.path())?;
+                    Ok(())
+                })?;
+            report.push(IssueKind::OrphanedBuild, path, fixed);
+        }
+
+        Ok(report)
+    }
+
+    /// Locks `path` and runs `f` on it, returning whether it could be locked; a directory which
+    /// someone else has locked in the meantime is left alone.
+    fn fix_locked(&self, path: &Path, f: impl FnOnce(&DirLock) -> Result<()>) -> Result<bool> {
+        match DirLock::try_acquire(path)? {
+            Some(lock) => {
+                lock.warn_reclaimed(self.shell);
+                f(&lock).with_context(|e| format_err!("couldn't fix {}: {}", path.display(), e))?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Retrieves a corrupt index again from where it came from. Indices which don't know where
+    /// they came from (or can't be retrieved again because we're offline) are thrown away
+    /// instead, so that they're retrieved from scratch the next time they're used.
+    fn repair_index(&self, dir: &DirLock) -> Result<()> {
+        let origin = sibling_file(dir.path(), "res");
+        let res = fs::read_to_string(&origin)
+            .ok()
+            .and_then(|x| IndexRes::from_str(&x).ok())
+            .map(|x| x.res);
+
+        match res {
+            Some(res) if !self.offline => {
+                clear_dir_except(dir.path(), &[Path::new(".dirlock")])?;
+                self.retrieve_index(&res, dir, true, |_| {
+                    self.shell.println(
+                        style("Retrieving").cyan(),
+                        format!("index {}", res),
+                        Verbosity::Normal,
+                    );
+                    Ok(())
+                })?;
+                let contents = fs::read_to_string(dir.path().join("index.toml"))?;
+                IndexConfig::from_str(&contents)?;
+            }
+            _ => remove_dir_all::remove_dir_all(dir.path())?,
+        }
+
+        Ok(())
+    }
+
+    /// Bundles part of the cache into a single gzipped tarball at `dest`, which can be imported
+    /// into another cache with `Cache::import`.
+    ///
+    /// Each entry (a single source, build, or index directory) is locked while it's being
+    /// archived, so the archive never contains a half-written entry.
+    pub fn export(&self, dest: &Path, filter: ExportFilter) -> Result<()> {
+        let file = File::create(dest)
+            .with_context(|e| format_err!("couldn't create archive {}: {}", dest.display(), e))?;
+        let enc = GzEncoder::new(file, Compression::default());
+        let mut tar = tar::Builder::new(enc);
+        tar.follow_symlinks(false);
+        // Sources are exported under their hashes, since their readable names only mean anything
+        // in this cache
+        let names = source_names(&self.layout.src);
+
+        for (kind, dir) in self.export_kinds(&filter) {
+            if !dir.exists() {
+                continue;
+            }
+
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let archived = is_source_archive(&entry.path());
+                if !(archived || entry.file_type()?.is_dir())
+                    || !self.should_export(&entry.path(), &filter)
+                {
+                    continue;
+                }
+
+                let mut file_name = entry.file_name().to_string_lossy().to_string();
+                if kind == "src" {
+                    let stem = file_name.trim_end_matches(SOURCE_ARCHIVE_EXT);
+                    if let Some(hash) = names.get(stem) {
+                        file_name = file_name.replacen(stem, hash, 1);
+                    }
+                }
+                let name = Path::new(kind).join(file_name);
+                if archived {
+                    // Archives are only ever put in place whole, so they don't need to be locked
+                    tar.append_path_with_name(entry.path(), &name)
+                        .with_context(|e| {
+                            format_err!("couldn't archive {}: {}", entry.path().display(), e)
+                        })?;
+                    debug!(
+                        self.logger, "exported entry";
+                        "entry" => name.display()
+                    );
+                    continue;
+                }
+
+                let lock = self.lock(&entry.path())?;
+                let walker = WalkDir::new(lock.path())
+                    .follow_links(false)
+                    .into_iter()
+                    .filter_entry(|x| x.file_name() != ".dirlock");
+
+                for item in walker {
+                    let item = item?;
+                    let suffix = name.join(item.path().strip_prefix(lock.path()).unwrap());
+                    tar.append_path_with_name(item.path(), &suffix)
+                        .with_context(|e| {
+                            format_err!("couldn't archive {}: {}", item.path().display(), e)
+                        })?;
+                }
+
+                debug!(
+                    self.logger, "exported entry";
+                    "entry" => name.display()
+                );
+            }
+        }
+
+        tar.into_inner()?.finish()?;
+
+        Ok(())
+    }
+
+    /// Unpacks an archive created by `Cache::export` into this cache.
+    ///
+    /// An entry from the archive only replaces an existing entry if it's newer (i.e. its most
+    /// recently modified file is newer than any file in the existing entry).
+    pub fn import(&self, archive: &Path) -> Result<ImportReport> {
+        let file = File::open(archive)
+            .with_context(|e| format_err!("couldn't open archive {}: {}", archive.display(), e))?;
+        // The unpacked archive is removed however the import goes
+        let scratch = ScratchDir(self.layout.tmp.join(format!("import-{}", process::id())));
+        let tmp = &scratch.0;
+        clear_dir(tmp)?;
+        unpack_archive(GzDecoder::new(file), tmp).with_context(|e| {
+            format_err!("couldn't unpack archive {}: {}", archive.display(), e)
+        })?;
+
+        let mut report = ImportReport::default();
+
+        for (kind, dir) in self.export_kinds(&ExportFilter::All) {
+            let from_dir = tmp.join(kind);
+            if !from_dir.exists() {
+                continue;
+            }
+
+            for entry in fs::read_dir(&from_dir)? {
+                let entry = entry?;
+                let mut file_name = entry.file_name().to_string_lossy().to_string();
+                let name = format!("{}/{}", kind, file_name);
+                // Sources go wherever this cache keeps the source with their hash
+                if kind == "src" {
+                    let stem = file_name.trim_end_matches(SOURCE_ARCHIVE_EXT).to_owned();
+                    file_name = file_name.replacen(&stem, &source_name(dir, &stem), 1);
+                }
+
+                if is_source_archive(&entry.path()) {
+                    let to = dir.join(&file_name);
+                    if newest_mtime(&to) >= newest_mtime(&entry.path()) {
+                        report.skipped.push(name);
+                    } else {
+                        fs::rename(entry.path(), &to)
+                            .or_else(|_| fs::copy(entry.path(), &to).map(|_| ()))?;
+                        debug!(self.logger, "imported entry"; "entry" => &name);
+                        report.imported.push(name);
+                    }
+                    continue;
+                }
+
+                if !entry.file_type()?.is_dir() {
+                    continue;
+                }
+
+                let dest = self.lock(&dir.join(&file_name))?;
+
+                if newest_mtime(dest.path()) >= newest_mtime(&entry.path()) {
+                    report.skipped.push(name);
+                    continue;
+                }
+
+                for old in fs::read_dir(dest.path())? {
+                    let old = old?;
+                    if old.file_name() == ".dirlock" {
+                        continue;
+                    }
+                    if old.file_type()?.is_dir() {
+                        remove_dir_all::remove_dir_all(old.path())?;
+                    } else {
+                        fs::remove_file(old.path())?;
+                    }
+                }
+
+                for new in fs::read_dir(entry.path())? {
+                    let new = new?;
+                    let to = dest.path().join(new.file_name());
+                    if new.file_type()?.is_dir() {
+                        move_dir(&new.path(), &to)?;
+                    } else {
+                        fs::rename(new.path(), &to)
+                            .or_else(|_| fs::copy(new.path(), &to).map(|_| ()))?;
+                    }
+                }
+
+                debug!(self.logger, "imported entry"; "entry" => &name);
+                report.imported.push(name);
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn export_kinds(&self, filter: &ExportFilter) -> Vec<(&'static str, &Path)> {
+        let mut kinds = vec![];
+        match filter {
+            ExportFilter::All => {
+                kinds.push(("src", self.layout.src.as_path()));
+                kinds.push(("build", self.layout.build.as_path()));
+                kinds.push(("indices", self.layout.indices.as_path()));
+            }
+            ExportFilter::Sources => kinds.push(("src", self.layout.src.as_path())),
+            ExportFilter::Builds => kinds.push(("build", self.layout.build.as_path())),
+            ExportFilter::Packages(_) => {
+                kinds.push(("src", self.layout.src.as_path()));
+                kinds.push(("indices", self.layout.indices.as_path()));
+            }
+        }
+        kinds
+    }
+
+    fn should_export(&self, path: &Path, filter: &ExportFilter) -> bool {
+        let pkgs = match filter {
+            ExportFilter::Packages(pkgs) => pkgs,
+            _ => return true,
+        };
+
+        // Indices are always exported with packages so that they can be resolved.
+        if !path.starts_with(&self.layout.src) {
+            return true;
+        }
+
+        let manifest = read_source_file(path, Path::new("elba.toml"))
+            .and_then(|x| Manifest::from_str(&x).ok());
+        let manifest = match manifest {
+            Some(m) => m,
+            None => return false,
+        };
+        let name = path.file_name().unwrap().to_string_lossy();
+        let hash = name.trim_end_matches(SOURCE_ARCHIVE_EXT);
+
+        pkgs.iter().any(|pkg| {
+            pkg.name() == manifest.name()
+                && match pkg.resolution() {
+                    Resolution::Direct(loc) => [true, false].iter().any(|&tag| {
+                        self.source_dir(loc, tag) == hash || Self::get_source_dir(loc, tag) == hash
+                    }),
+                    Resolution::Index(_) => true,
+                }
+        })
+    }
+}
+
+/// What `Cache::doctor` found wrong with a cache.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DoctorReport {
+    pub issues: Vec<Issue>,
+}
+
+impl DoctorReport {
+    fn push(&mut self, kind: IssueKind, path: PathBuf, fixed: bool) {
+        self.issues.push(Issue { kind, path, fixed });
+    }
+
+    /// The issues which were found but not fixed.
+    pub fn unfixed(&self) -> impl Iterator<Item = &Issue> {
+        self.issues.iter().filter(|x| !x.fixed)
+    }
+}
+
+/// A single problem found by `Cache::doctor`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Issue {
+    pub kind: IssueKind,
+    /// The file or directory with the problem.
+    pub path: PathBuf,
+    /// Whether the problem was fixed. Nothing is fixed unless `Cache::doctor` is asked to, and
+    /// even then, a problem is left alone if its directory is locked before it can be fixed.
+    pub fixed: bool,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.kind, self.path.display())?;
+        if self.fixed {
+            write!(f, " (fixed)")?;
+        }
+        Ok(())
+    }
+}
+
+/// The kinds of problems `Cache::doctor` looks for, and how each of them gets fixed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IssueKind {
+    /// A lockfile left behind by a process which is gone. The lock is reclaimed and released.
+    StaleLock,
+    /// What's left of a download which was cut off: a `.part` file, a tarball which was never
+    /// unpacked, or an empty source or index directory. It's deleted, along with the directory
+    /// it was being retrieved into.
+    PartialDownload,
+    /// A cached index whose configuration can't be read. It's retrieved again, or thrown away to
+    /// be retrieved the next time it's used if we're offline.
+    CorruptIndex,
+    /// A cached source whose files don't match the hashes recorded for them when it was
+    /// retrieved. It's deleted, so that it's retrieved again the next time it's used.
+    ChecksumMismatch,
+    /// A build directory which will never be used: one which isn't named like a build made with
+    /// the current hashing algorithm, or one which was never filled in. It's deleted.
+    OrphanedBuild,
+}
+
+impl fmt::Display for IssueKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IssueKind::StaleLock => write!(f, "stale lock"),
+            IssueKind::PartialDownload => write!(f, "partial download"),
+            IssueKind::CorruptIndex => write!(f, "corrupt index"),
+            IssueKind::ChecksumMismatch => write!(f, "checksum mismatch"),
+            IssueKind::OrphanedBuild => write!(f, "orphaned build"),
+        }
+    }
+}
+
+/// Which parts of the cache to include when exporting it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExportFilter {
+    /// Every cached source, build, and index.
+    All,
+    /// Only the cached sources.
+    Sources,
+    /// Only the cached builds.
+    Builds,
+    /// Only the cached sources of the given packages, along with every index so that they can
+    /// still be resolved.
+    Packages(Vec<PackageId>),
+}
+
+/// What happened to the entries of an archive when it was imported.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ImportReport {
+    /// The entries (e.g. `src/<hash>`) which were added to the cache or replaced older entries.
+    pub imported: Vec<String>,
+    /// The entries which were skipped because the cache already had a copy at least as new.
+    pub skipped: Vec<String>,
+}
+
+/// A scratch directory which is removed, along with everything in it, once it's dropped.
+struct ScratchDir(PathBuf);
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all::remove_dir_all(&self.0);
+    }
+}
+
+/// An in-memory index of the sources and builds in a cache, for callers which look things up in
+/// the cache over and over (like an editor integration) and can't afford to scan it every time.
+/// Catalogs are made with `Cache::catalog`, and don't change until they're refreshed.
+#[derive(Debug, Default, Clone)]
+pub struct Catalog {
+    /// Every cached source, keyed by the hash it's cached under.
+    sources: IndexMap<String, CachedSource>,
+    /// Every cached build, keyed by the name of its directory.
+    builds: IndexMap<String, CachedBuild>,
+}
+
+/// A source in a `Catalog`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedSource {
+    pub name: Name,
+    pub version: Version,
+    /// The name of the directory the source is cached under (see `Cache::source_dir`).
+    pub hash: String,
+    /// The directory of the source, or its archive if it's compressed.
+    pub path: PathBuf,
+}
+
+/// A build in a `Catalog`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedBuild {
+    pub hash: BuildHash,
+    /// The name of the profile the package was built with.
+    pub profile: String,
+    pub path: PathBuf,
+}
+
+impl Catalog {
+    /// Brings the catalog up to date with the cache. Only the entries which are new since the
+    /// last refresh have to be read, so this is cheap when not much has changed; entries which
+    /// have disappeared from the cache are dropped.
+    pub fn refresh(&mut self, cache: &Cache) -> Result<()> {
+        let mut sources = IndexMap::new();
+        for entry in fs::read_dir(&cache.layout.src)? {
+            let path = entry?.path();
+            let fname = path.file_name().unwrap().to_string_lossy().to_string();
+            let hash = if is_source_archive(&path) {
+                fname.trim_end_matches(SOURCE_ARCHIVE_EXT).to_string()
+            } else if path.join("elba.toml").exists() {
+                fname
+            } else {
+                continue;
+            };
+
+            if let Some(known) = self.sources.swap_remove(&hash) {
+                sources.insert(hash, known);
+                continue;
+            }
+
+            // Sources with a manifest we can't read (like workspaces) can't be looked up anyways
+            let manifest = read_source_file(&path, Path::new("elba.toml"))
+                .and_then(|x| Manifest::from_str(&x).ok());
+            if let Some(manifest) = manifest {
+                let source = CachedSource {
+                    name: manifest.name().clone(),
+                    version: manifest.version().clone(),
+                    hash: hash.clone(),
+                    path,
+                };
+                sources.insert(hash, source);
+            }
+        }
+
+        let mut builds = IndexMap::new();
+        for entry in fs::read_dir(&cache.layout.build)? {
+            let path = entry?.path();
+            let fname = path.file_name().unwrap().to_string_lossy().to_string();
+            if let Some(known) = self.builds.swap_remove(&fname) {
+                builds.insert(fname, known);
+                continue;
             }
+
+            let (profile, hash) = match parse_build_dir(&fname) {
+                Some(x) if path.is_dir() => x,
+                _ => continue,
+            };
+
+            let build = CachedBuild {
+                hash,
+                profile: profile.to_string(),
+                path,
+            };
+            builds.insert(fname, build);
         }
 
-        Indices::new(indices)
+        self.sources = sources;
+        self.builds = builds;
+
+        Ok(())
     }
 
-    fn get_index_dir(loc: &DirectRes) -> String {
-        Self::get_source_dir(loc, false)
+    pub fn sources(&self) -> impl Iterator<Item = &CachedSource> {
+        self.sources.values()
     }
 
-    /// Returns all of the package hashes available in this cache.
-    pub fn cached_packages(&self) -> IndexSet<String> {
-        let walker = WalkDir::new(&self.layout.src)
-            .min_depth(1)
-            .into_iter()
-            .filter_map(|e| e.ok());
+    /// Every cached source of the package `name`.
+    pub fn sources_of<'a>(&'a self, name: &'a Name) -> impl Iterator<Item = &'a CachedSource> {
+        self.sources.values().filter(move |x| &x.name == name)
+    }
 
-        let mut res = IndexSet::new();
+    pub fn builds(&self) -> impl Iterator<Item = &CachedBuild> {
+        self.builds.values()
+    }
 
-        for dir in walker {
-            let fname = dir
-                .path()
-                .file_name()
-                .unwrap()
-                .to_string_lossy()
-                .to_string();
+    /// Every cached build with the given hash, one for each profile it was built with.
+    pub fn builds_of<'a>(&'a self, hash: &'a BuildHash) -> impl Iterator<Item = &'a CachedBuild> {
+        self.builds.values().filter(move |x| &x.hash == hash)
+    }
 
-            if dir.path().join("elba.toml").exists() {
-                res.insert(fname);
-            }
+    /// The cached build with the given hash and profile, if there is one.
+    pub fn build(&self, hash: &BuildHash, profile: &BuildProfile) -> Option<&CachedBuild> {
+        self.builds.get(&Cache::build_dir(hash, profile))
+    }
+}
+
+/// Splits the name of a build directory into its profile and hash. Build directories are named
+/// `<profile>-<hash>`, and hashes have exactly one dash.
+fn parse_build_dir(name: &str) -> Option<(&str, BuildHash)> {
+    let mut parts = name.rsplitn(3, '-');
+    let (digest, version, profile) = (parts.next()?, parts.next()?, parts.next()?);
+    let hash = format!("{}-{}", version, digest);
+    parse_hash(&hash)?;
+
+    Some((profile, BuildHash(hash)))
+}
+
+/// Whether a file in a tarball dir is named like a tarball of the package `name`: the
+/// `<group>_<name>-` prefix has to be followed by a whole version and an extension, so that
+/// `foo_bar-1.0.0.tar.gz` is a tarball of `foo/bar` but `foo_bar-baz-1.0.0.tar.gz` isn't.
+fn is_tarball_of(file: &str, name: &Name) -> bool {
+    let prefix = format!("{}_{}-", name.group(), name.name());
+    if !file.starts_with(&prefix) {
+        return false;
+    }
+
+    let rest = &file[prefix.len()..];
+    rest.match_indices('.')
+        .any(|(ix, _)| Version::parse(&rest[..ix]).is_ok())
+}
+
+/// The files a retrieval leaves next to the directory it's retrieving into while it's going on.
+const PARTIAL_EXTS: &[&str] = &[".src.part", ".part", ".tar.gz", ".root"];
+
+/// If `path` (in the source or index cache) is something a retrieval left behind, returns the
+/// directory which the retrieval was going into. Directories with nothing in them are their own
+/// partial downloads.
+fn partial_owner(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_string_lossy();
+    if name.ends_with(SOURCE_ARCHIVE_EXT) {
+        return None;
+    }
+    if let Some(ext) = PARTIAL_EXTS.iter().find(|x| name.ends_with(*x)) {
+        return Some(path.with_file_name(&name[..name.len() - ext.len()]));
+    }
+
+    if path.is_dir() && is_empty_entry(path) {
+        Some(path.to_owned())
+    } else {
+        None
+    }
+}
+
+/// The indices that the index in `dir` says it depends on, if it can be read.
+fn index_dependencies(dir: &Path) -> Vec<DirectRes> {
+    fs::read_to_string(dir.join("index.toml"))
+        .ok()
+        .and_then(|x| IndexConfig::from_str(&x).ok())
+        .map(|config| {
+            config
+                .index
+                .dependencies
+                .into_iter()
+                .map(|(_, x)| x.res)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Points the locks on anything inside of `from` to the same place inside of `to`, after `from` was
+/// moved there.
+fn follow_locks(locks: &mut [DirLock], from: &Path, to: &Path) {
+    for lock in locks {
+        let moved = lock.path().strip_prefix(from).ok().map(|x| to.join(x));
+        if let Some(moved) = moved {
+            lock.moved_to(&moved);
         }
+    }
+}
 
-        res
+/// Whether a directory has nothing in it except maybe a lockfile.
+fn is_empty_entry(dir: &Path) -> bool {
+    fs::read_dir(dir)
+        .map(|mut x| x.all(|x| x.map(|x| x.file_name() == ".dirlock").unwrap_or(false)))
+        .unwrap_or(false)
+}
+
+/// The path of a file kept next to a cache entry, like the `.res` of an index.
+fn sibling_file(dir: &Path, ext: &str) -> PathBuf {
+    let mut res = dir.as_os_str().to_owned();
+    res.push(".");
+    res.push(ext);
+    PathBuf::from(res)
+}
+
+/// Whether a path in the source cache is the archive of a compressed source.
+fn is_source_archive(path: &Path) -> bool {
+    path.is_file() && path.to_string_lossy().ends_with(SOURCE_ARCHIVE_EXT)
+}
+
+/// Reads a file from a cached source, which is either a directory or the archive of a compressed
+/// source. `file` is relative to the root of the source.
+fn read_source_file(source: &Path, file: &Path) -> Option<String> {
+    if !is_source_archive(source) {
+        return fs::read_to_string(source.join(file)).ok();
     }
+
+    let archive = BufReader::new(File::open(source).ok()?);
+    let mut archive = tar::Archive::new(GzDecoder::new(archive));
+    let mut entry = archive
+        .entries()
+        .ok()?
+        .filter_map(|x| x.ok())
+        .find(|x| x.path().map(|p| p == file).unwrap_or(false))?;
+
+    let mut contents = String::new();
+    entry.read_to_string(&mut contents).ok()?;
+    Some(contents)
+}
+
+/// Returns the total size of the files in a directory.
+fn dir_size(dir: &Path) -> u64 {
+    WalkDir::new(dir)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|x| x.file_name() != ".dirlock")
+        .filter_map(|x| x.ok())
+        .filter(|x| !x.file_type().is_dir())
+        .filter_map(|x| x.metadata().ok().map(|m| m.len()))
+        .sum()
+}
+
+/// Returns the modification time of the most recently modified file in a directory (or of the file
+/// itself, if `dir` is a file).
+fn newest_mtime(dir: &Path) -> Option<SystemTime> {
+    WalkDir::new(dir)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|x| x.file_name() != ".dirlock")
+        .filter_map(|x| x.ok())
+        .filter(|x| !x.file_type().is_dir())
+        .filter_map(|x| x.metadata().ok().and_then(|m| m.modified().ok()))
+        .max()
 }
 
 /// Layouts encapsulate the logic behind our directory structure.
@@ -593,6 +3104,24 @@ impl Layout {
 
         Ok(())
     }
+
+    /// Returns every directory of this layout.
+    pub fn dirs(&self) -> [&PathBuf; 5] {
+        [&self.bin, &self.src, &self.build, &self.tmp, &self.indices]
+    }
+
+    /// Returns the equivalent Layout with all of its directories under `root`.
+    pub fn relocated(&self, root: &Path) -> Layout {
+        let f = |p: &Path| root.join(p.file_name().unwrap_or_default());
+
+        Layout {
+            bin: f(&self.bin),
+            src: f(&self.src),
+            build: f(&self.build),
+            tmp: f(&self.tmp),
+            indices: f(&self.indices),
+        }
+    }
 }
 
 // TODO: Somehow keep track of which targets have been built, so that if a rebuild needs to happen,
@@ -608,6 +3137,9 @@ pub struct OutputLayout {
     pub docs: PathBuf,
     pub lib: PathBuf,
     pub build: PathBuf,
+    /// Where builds are kept when they're cached locally instead of globally (see
+    /// `Cache::with_local_builds`). Unlike `build`, this isn't cleared after every build.
+    pub deps: PathBuf,
     pub hash: Option<BuildHash>,
 }
 
@@ -623,6 +3155,7 @@ impl OutputLayout {
             docs: root.join("docs"),
             lib: root.join("lib"),
             build: root.join("build"),
+            deps: root.join("deps"),
             hash: fs::read(root.join("hash"))
                 .map(|x| BuildHash(String::from_utf8_lossy(&x).to_string()))
                 .ok(),
@@ -635,6 +3168,7 @@ impl OutputLayout {
         fs::create_dir_all(&layout.docs)?;
         fs::create_dir_all(&layout.lib)?;
         fs::create_dir_all(&layout.build)?;
+        fs::create_dir_all(&layout.deps)?;
 
         Ok(layout)
     }
@@ -649,11 +3183,32 @@ impl OutputLayout {
     pub fn is_built(&self, hash: &BuildHash) -> bool {
         self.hash.as_ref() == Some(hash)
     }
+
+    /// Where the outputs of a lib target go: `lib` itself for the lib target, or a directory of
+    /// its own under it for a named one (as given by `Targets::lib_targets`).
+    pub fn lib_dir(&self, name: Option<&str>) -> PathBuf {
+        match name {
+            Some(name) => self.lib.join(LIBS_DIR).join(name),
+            None => self.lib.clone(),
+        }
+    }
+}
+
+/// Where the Source returned by `Cache::checkout_source_origin` came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SourceOrigin {
+    /// Nothing had to be downloaded: the source was already in the cache (or on disk, for local
+    /// packages and tarball directories), or an existing git checkout could be reused.
+    Cached,
+    /// The source was downloaded. `bytes` is how much space it takes up once retrieved, and
+    /// `duration` is how long retrieving it took.
+    Downloaded { bytes: u64, duration: Duration },
 }
 
 /// Information about the source of package that is available somewhere in the file system.
 /// Packages are stored as directories on disk (not archives because it would just be a bunch of
-/// pointless unpacking-repacking).
+/// pointless unpacking-repacking). If the Cache keeps a source compressed, the Source points at an
+/// extracted view of it instead.
 #[derive(Debug, Clone)]
 pub struct Source {
     inner: Arc<SourceInner>,
@@ -669,6 +3224,10 @@ struct SourceInner {
     /// The path to the package.
     path: DirLock,
     hash: String,
+    /// The warnings found when validating the package's manifest.
+    lints: Vec<Lint>,
+    /// The environment variables declared in `[build.env]`, with their values worked out.
+    env: IndexMap<String, String>,
 }
 
 impl Source {
@@ -694,49 +3253,128 @@ impl Source {
     /// redownloaded completely; for git repos, if the resolution is to use master, then the same
     /// folder will be used, but will be checked out to the latest master every time.
     pub fn from_folder(pkg: &PackageId, path: DirLock, location: DirectRes) -> Result<Self> {
-        let toml_path = path.path().join("elba.toml");
-        if toml_path.exists() {
-            let file = fs::File::open(toml_path).context(format_err!(
-                "package {} at {} is missing manifest",
+        Source::from_folder_with(pkg, path, location, Path::new(MANIFEST_FILE))
+    }
+
+    /// Like `from_folder`, but the package's manifest is the file named `manifest_file` in the
+    /// folder instead of `elba.toml`.
+    ///
+    /// Only an `elba.toml` can be stood in for by an ipkg file; any other manifest has to exist.
+    pub fn from_folder_with(
+        pkg: &PackageId,
+        path: DirLock,
+        location: DirectRes,
+        manifest_file: &Path,
+    ) -> Result<Self> {
+        let entry = path.path().to_owned();
+        Source::load_folder(pkg, path, &entry, location, manifest_file)
+    }
+
+    /// Loads the package from `path`, which is in the folder `entry` that `from_folder_with` was
+    /// given (or is `entry` itself).
+    fn load_folder(
+        pkg: &PackageId,
+        path: DirLock,
+        entry: &Path,
+        location: DirectRes,
+        manifest_file: &Path,
+    ) -> Result<Self> {
+        // A package in a subdirectory of a repository is only made up of that subdirectory
+        let path = match &location {
+            DirectRes::Git {
+                subdir: Some(subdir),
+                ..
+            } => {
+                let sub = path.path().join(&subdir.0);
+                if !sub.is_dir() {
+                    bail!(
+                        "package {} is supposed to be in {}, but the repository has no such \
+                         directory",
+                        pkg,
+                        subdir.0.display()
+                    )
+                }
+                let lock = DirLock::acquire(&sub)?;
+                // Like with workspaces, we immediately release our lock on the parent folder
+                drop(path);
+                lock
+            }
+            _ => path,
+        };
+
+        let toml_path = path.path().join(manifest_file);
+        let manifest = if toml_path.exists() {
+            let file = fs::File::open(&toml_path).context(format_err!(
+                "couldn't read the manifest of package {} ({})",
                 pkg,
-                path.path().display()
+                toml_path.display()
             ))?;
             let mut file = BufReader::new(file);
             let mut contents = String::new();
             file.read_to_string(&mut contents)?;
             if let Some(x) = Manifest::workspace(&contents) {
                 if let Some(p) = x.get(pkg.name()) {
-                    let lock = DirLock::acquire(&path.path().join(&p.0))?;
+                    let (dir, file) = member_manifest(p);
+                    let lock = DirLock::acquire(&path.path().join(dir))?;
                     // We immediately release our lock on the parent folder
                     drop(path);
-                    return Source::from_folder(pkg, lock, location);
+                    return Source::load_folder(pkg, lock, entry, location, &file);
                 }
             }
-        }
 
-        let (_, manifest) = find_manifest(path.path(), true, None)?;
+            Manifest::from_str(&contents)
+                .with_context(|e| format_err!("invalid manifest {}: {}", toml_path.display(), e))?
+        } else if manifest_file == Path::new(MANIFEST_FILE) {
+            find_manifest(path.path(), true, None)
+                .with_context(|e| {
+                    format_err!(
+                        "package {} has no manifest at {}: {}",
+                        pkg,
+                        toml_path.display(),
+                        e
+                    )
+                })?
+                .1
+        } else {
+            bail!(
+                "package {} has no manifest: {} doesn't exist",
+                pkg,
+                toml_path.display()
+            )
+        };
 
         if manifest.name() != pkg.name() {
             bail!(
-                "names don't match: {} was declared, but {} was found in elba.toml",
+                "names don't match: {} was declared, but {} was found in {}",
                 pkg.name(),
-                manifest.name()
+                manifest.name(),
+                manifest_file.display()
             )
         }
 
-        // Creating the hash
-        let walker = manifest
-            .list_files(path.path(), path.path(), |entry| {
-                entry.file_name() != ".git" && entry.file_name() != "target"
-            })?
-            .filter(valid_file);
-
-        let mut hash = Sha256::new();
-        for f in walker {
-            let mut file = File::open(f.path())?;
-            io::copy(&mut file, &mut hash)?;
-        }
-        let hash = hex::encode(hash.result());
+        manifest.check_elba_version()?;
+
+        // Manifests with errors never make it this far, so everything left is a warning
+        let lints = manifest.validate();
+        let env = manifest
+            .build
+            .resolve_env(|var| env::var(var).ok())
+            .with_context(|e| format_err!("package {}: {}", pkg, e))?;
+
+        // A package which is kept out of the build cache is rebuilt every time anyways, so there's
+        // no point in reading all of its files; it just goes by where it is. A slimmed source
+        // doesn't have all of its files anymore, so it goes by the hash it had when it still did.
+        let hash = if manifest.package.exclude_from_cache && location.is_dir() {
+            let mut hasher = Hasher::new();
+            hasher.input(b"uncached\0");
+            hasher.input(location.cache_key().as_bytes());
+            hasher.finish()
+        } else {
+            match fs::read_to_string(path.path().join(SLIM_FILE)) {
+                Ok(hash) => hash.trim().to_string(),
+                Err(_) => hash_folder(&manifest, path.path(), entry, &location)?,
+            }
+        };
 
         Ok(Source {
             inner: Arc::new(SourceInner {
@@ -749,10 +3387,128 @@ impl Source {
                 location,
                 path,
                 hash,
+                lints,
+                env,
             }),
         })
     }
 
+    /// Whether builds of this package, and of every package which depends on it, are kept out of
+    /// the build cache and redone every time, because its manifest says `exclude-from-cache`. Only
+    /// directory packages can opt out, since they're the only ones which change in place.
+    ///
+    /// The files of such a package aren't hashed, so its hash only says where it is.
+    pub fn is_excluded_from_cache(&self) -> bool {
+        self.meta().package.exclude_from_cache && self.location().is_dir()
+    }
+
+    /// Whether this source has been slimmed down (see `Cache::slim_source`).
+    pub fn is_slim(&self) -> bool {
+        self.path().join(SLIM_FILE).is_file()
+    }
+
+    /// The files that the targets of this package are built from, relative to its root: the
+    /// modules of the lib targets, and the main modules of the binary, test and example targets.
+    pub fn target_files(&self) -> Vec<PathBuf> {
+        let targets = &self.meta().targets;
+        let mut files = vec![];
+
+        for (_, lib) in targets.lib_targets() {
+            for mod_name in &lib.mods {
+                let path: PathBuf = mod_name.trim_matches('.').replace(".", "/").into();
+                for ext in &["idr", "lidr"] {
+                    let file = lib.path.0.join(&path).with_extension(ext);
+                    if self.path().join(&file).is_file() {
+                        files.push(file);
+                    }
+                }
+            }
+        }
+
+        let bins = targets
+            .bin
+            .iter()
+            .cloned()
+            .chain(targets.test.iter().cloned().map(BinTarget::from))
+            .chain(targets.example.iter().cloned().map(BinTarget::from));
+        for bin in bins {
+            if let Some((src, main)) = bin.resolve_bin(self.path()) {
+                if let Ok(file) = src.join(main).strip_prefix(self.path()) {
+                    files.push(file.to_path_buf());
+                }
+            }
+        }
+
+        files
+    }
+
+    /// Checks that the manifest of this package declares the version `version`.
+    pub fn verify_version(&self, version: &Version) -> Result<()> {
+        let declared = self.meta().version();
+        if declared != version {
+            return Err(Error::VersionMismatch
+                .context(format_err!(
+                    "versions don't match: {} {} was retrieved, but its manifest says it's version {}",
+                    self.meta().name(),
+                    version,
+                    declared
+                ))
+                .into());
+        }
+
+        Ok(())
+    }
+
+    /// Checks that every module of the lib targets and the main module of every binary target
+    /// actually exist in this package, so that a broken package is caught before it gets built.
+    /// Every missing file is reported, not just the first one.
+    /// Virtual packages (see `Manifest::is_virtual`) have nothing to check.
+    pub fn verify_targets(&self) -> Result<()> {
+        if self.meta().is_virtual() {
+            return Ok(());
+        }
+
+        let targets = &self.meta().targets;
+        let mut missing = vec![];
+
+        for (_, lib) in targets.lib_targets() {
+            let src_path = self.path().join(&lib.path.0);
+            for mod_name in &lib.mods {
+                let path: PathBuf = mod_name.trim_matches('.').replace(".", "/").into();
+                if !src_path.join(&path).with_extension("idr").is_file()
+                    && !src_path.join(&path).with_extension("lidr").is_file()
+                {
+                    missing.push(format!(
+                        "lib module {} (expected {})",
+                        mod_name,
+                        lib.path.0.join(&path).with_extension("idr").display()
+                    ));
+                }
+            }
+        }
+
+        for bin in &targets.bin {
+            if bin.resolve_bin(self.path()).is_none() {
+                missing.push(format!(
+                    "main module {} of binary {} (under {})",
+                    bin.main,
+                    bin.name,
+                    bin.path.0.display()
+                ));
+            }
+        }
+
+        if !missing.is_empty() {
+            bail!(
+                "package {} declares targets which don't exist:\n  {}",
+                self.pretty_summary(),
+                missing.join("\n  ")
+            )
+        }
+
+        Ok(())
+    }
+
     pub fn pretty_summary(&self) -> String {
         format!(
             "{} {} ({})",
@@ -779,10 +3535,44 @@ impl Source {
         &self.inner.location
     }
 
+    /// The environment variables to build the package with.
+    pub fn env(&self) -> &IndexMap<String, String> {
+        &self.inner.env
+    }
+
+    /// The problems with the package's manifest which aren't bad enough to reject it.
+    pub fn lints(&self) -> &[Lint] {
+        &self.inner.lints
+    }
+
     pub fn hash(&self) -> &str {
         &self.inner.hash
     }
 
+    /// The dependencies of this package which apply on the given platform.
+    pub fn dependencies<'a>(
+        &'a self,
+        platform: &'a Platform,
+    ) -> impl Iterator<Item = (&'a Name, &'a DepReq)> {
+        self.meta().dependencies_for(platform)
+    }
+
+    /// The renamed dependencies of this package, mapping the name each of them is declared with
+    /// to the real name of the package it refers to (see `Manifest::aliases`).
+    pub fn aliases(&self) -> IndexMap<Name, Name> {
+        self.meta().aliases()
+    }
+
+    /// The features declared by this package, along with what each of them enables.
+    pub fn features(&self) -> &IndexMap<String, Vec<String>> {
+        &self.meta().features
+    }
+
+    /// The optional dependencies which are turned on by the given features.
+    pub fn feature_deps(&self, features: &[String]) -> Result<IndexSet<Name>> {
+        self.meta().enabled_deps(features)
+    }
+
     pub fn path(&self) -> &Path {
         self.inner.path.path()
     }
@@ -796,6 +3586,306 @@ impl PartialEq for Source {
 
 impl Eq for Source {}
 
+/// How `Graph::to_tree` prints a tree of dependencies.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TreeOptions {
+    /// How many levels of dependencies to show below the top of the tree. Everything is shown if
+    /// this is None.
+    pub depth: Option<usize>,
+    /// If this is set, the tree shows the packages which depend on the package with this name
+    /// (and what depends on those, and so on) instead of the dependencies of the root.
+    pub invert: Option<Name>,
+}
+
+impl Graph<Source> {
+    /// Finds the node of the package `sum`, for when there's a Summary of it at hand but not the
+    /// Source itself.
+    pub fn get_by_summary(&self, sum: &Summary) -> Option<(NodeIndex, &Source)> {
+        self.inner.node_references().find(|(_, source)| {
+            source.meta().name() == sum.name()
+                && source.meta().version() == sum.version()
+                && source.inner.res == *sum.resolution()
+        })
+    }
+
+    /// Renders the graph in Graphviz's DOT format, to be piped to something like `dot -Tsvg`.
+    ///
+    /// Nodes are labelled with the name and version of their package, and edges with the
+    /// requirement that the dependent package has on the dependency. Edges for dev-dependencies
+    /// are dashed. A package which shows up more than once (say, in two different versions) gets
+    /// a node for each copy, and those nodes are drawn in red.
+    pub fn to_dot(&self) -> String {
+        let mut copies = IndexMap::new();
+        for (_, source) in self.inner.node_references() {
+            *copies.entry(source.meta().name()).or_insert(0) += 1;
+        }
+
+        let mut res = String::from("digraph {\n");
+        for (ix, source) in self.inner.node_references() {
+            let label = format!("{} {}", source.meta().name(), source.meta().version());
+            let color = if copies[source.meta().name()] > 1 {
+                ", color=red"
+            } else {
+                ""
+            };
+            res.push_str(&format!(
+                "    {} [label={:?}{}];\n",
+                ix.index(),
+                label,
+                color
+            ));
+        }
+
+        for edge in self.inner.edge_references() {
+            let meta = self.inner[edge.source()].meta();
+            let name = self.inner[edge.target()].meta().name();
+            let (normal, dev) = (find_dep(meta, name, false), find_dep(meta, name, true));
+
+            let mut attrs = vec![];
+            if let Some(req) = normal.or(dev) {
+                attrs.push(format!("label={:?}", dep_label(req)));
+            }
+            if normal.is_none() && dev.is_some() {
+                attrs.push("style=dashed".to_string());
+            }
+            let attrs = if attrs.is_empty() {
+                String::new()
+            } else {
+                format!(" [{}]", attrs.join(", "))
+            };
+            res.push_str(&format!(
+                "    {} -> {}{};\n",
+                edge.source().index(),
+                edge.target().index(),
+                attrs
+            ));
+        }
+
+        res.push_str("}\n");
+        res
+    }
+
+    /// Renders the graph as an indented tree, one package (with its version) per line, starting
+    /// from the root:
+    ///
+    /// ```text
+    /// a/root 1.0.0
+    /// ├── a/left 1.0.0
+    /// │   └── a/base 1.0.0
+    /// │       └── a/leaf 1.0.0
+    /// └── a/right 1.0.0
+    ///     └── a/base 1.0.0 (*)
+    /// ```
+    ///
+    /// The dependencies of a package are only shown the first time it comes up; after that, it's
+    /// marked with `(*)`. Dependencies are sorted by name and version. With `opts.invert`, every
+    /// copy of the package gets a tree of its own, separated by blank lines.
+    pub fn to_tree(&self, opts: &TreeOptions) -> Result<String> {
+        let (tops, dir) = match &opts.invert {
+            Some(name) => {
+                let tops = self
+                    .inner
+                    .node_references()
+                    .filter(|(_, x)| x.meta().name() == name)
+                    .map(|(ix, _)| ix)
+                    .collect::<Vec<_>>();
+                if tops.is_empty() {
+                    bail!("package {} isn't in the dependency graph", name)
+                }
+                (tops, Direction::Incoming)
+            }
+            None if self.inner.node_count() == 0 => return Ok(String::new()),
+            None => (vec![NodeIndex::new(0)], Direction::Outgoing),
+        };
+
+        let mut res = String::new();
+        for (i, &top) in tops.iter().enumerate() {
+            if i > 0 {
+                res.push('\n');
+            }
+            res.push_str(&tree_label(&self.inner[top]));
+            self.write_tree(top, dir, opts.depth, "", &mut HashSet::new(), &mut res);
+        }
+
+        Ok(res)
+    }
+
+    /// Writes the rest of the line of the node `ix` in a tree (whose label has already been
+    /// written), followed by the lines of its children.
+    fn write_tree(
+        &self,
+        ix: NodeIndex,
+        dir: Direction,
+        depth: Option<usize>,
+        indent: &str,
+        shown: &mut HashSet<NodeIndex>,
+        res: &mut String,
+    ) {
+        let mut children = self.inner.neighbors_directed(ix, dir).collect::<Vec<_>>();
+        children.sort_by(|&a, &b| {
+            let (a, b) = (self.inner[a].meta(), self.inner[b].meta());
+            (a.name(), a.version()).cmp(&(b.name(), b.version()))
+        });
+        children.dedup();
+
+        if !children.is_empty() && shown.contains(&ix) {
+            res.push_str(" (*)\n");
+            return;
+        }
+        res.push('\n');
+        if children.is_empty() || depth == Some(0) {
+            return;
+        }
+        shown.insert(ix);
+
+        for (i, &child) in children.iter().enumerate() {
+            let last = i + 1 == children.len();
+            res.push_str(indent);
+            res.push_str(if last { "└── " } else { "├── " });
+            res.push_str(&tree_label(&self.inner[child]));
+
+            let indent = format!("{}{}", indent, if last { "    " } else { "│   " });
+            self.write_tree(child, dir, depth.map(|x| x - 1), &indent, shown, res);
+        }
+    }
+}
+
+/// The files of the package with the manifest `manifest` in the folder `path` which make up its
+/// hash.
+fn hashed_files(manifest: &Manifest, path: &Path) -> Result<impl Iterator<Item = PathBuf>> {
+    let (target, vendor) = (manifest.target_dir(path), path.join(VENDOR_DIR));
+    let files = manifest
+        .list_files(path, path, move |entry| {
+            entry.file_name() != ".git"
+                && entry.path() != target
+                && entry.path() != vendor
+                && entry.file_name() != ".dirlock"
+        })?
+        .filter(valid_file)
+        .map(|x| x.into_path());
+
+    Ok(files)
+}
+
+/// Works out the hash of the package with the manifest `manifest` in the folder `path` (see
+/// `Source::from_folder`), which is in the directory `entry` of the cache (or is `entry` itself).
+/// Only the files which changed since the last time the package was hashed are read again; see
+/// the `merkle` module.
+fn hash_folder(
+    manifest: &Manifest,
+    path: &Path,
+    entry: &Path,
+    location: &DirectRes,
+) -> Result<String> {
+    let files = hashed_files(manifest, path)?;
+    let sidecar = sidecar_path(manifest, path, entry, location);
+    let mut tree = merkle::Tree::load(&sidecar);
+    // The sidecar is only there to speed things up, so it's fine if we can't write it. If nothing
+    // had to be hashed again, it's left alone (the package might not be writable).
+    if tree.update(path, files)? > 0 {
+        let _ = tree.save(&sidecar);
+    }
+
+    Ok(match location {
+        // The exact commit a git dependency was checked out to is part of its identity, unless
+        // the package is only a subdirectory of the repository: commits which only touch the
+        // rest of the repository don't change it
+        DirectRes::Git {
+            rev: Some(rev),
+            subdir: None,
+            ..
+        } => {
+            let mut hash = Hasher::new();
+            hash.input(tree.root());
+            hash.input(rev.as_bytes());
+            hash.finish()
+        }
+        _ => hexify_hash(HashAlgo::CURRENT, &tree.root()),
+    })
+}
+
+/// Where the merkle sidecar of the package in `path` is kept (see `merkle`): in the target
+/// directory of a local package, or next to the directory `entry` of a source in the cache.
+fn sidecar_path(manifest: &Manifest, path: &Path, entry: &Path, location: &DirectRes) -> PathBuf {
+    if location.is_dir() {
+        manifest.target_dir(path).join(merkle::SIDECAR)
+    } else {
+        source_sidecar(entry, path)
+    }
+}
+
+/// Where the merkle sidecar of the package in `path` is kept, when it's in the directory `entry`
+/// of a source in the cache. The sidecar is always kept next to `entry`, so that nothing is ever
+/// added to the source; a package in a subdirectory of `entry` (a workspace member, or part of a
+/// git repository) gets the path of that subdirectory in the sidecar's name.
+fn source_sidecar(entry: &Path, path: &Path) -> PathBuf {
+    match path.strip_prefix(entry) {
+        Ok(sub) if sub != Path::new("") => {
+            let sub = sub
+                .components()
+                .map(|x| x.as_os_str().to_string_lossy())
+                .join(".");
+            sibling_file(entry, &format!("{}.{}", sub, merkle::SIDECAR_EXT))
+        }
+        _ => sibling_file(entry, merkle::SIDECAR_EXT),
+    }
+}
+
+/// The files of the package in `root` which don't match its merkle tree in `sidecar` anymore (see
+/// `merkle::Tree::mismatched`). If the package's manifest can't be read, there's no telling which
+/// files belong to it, so only the files the tree knows about are checked.
+fn tampered_files(root: &Path, sidecar: &Path) -> Result<Vec<String>> {
+    let tree = merkle::Tree::load(sidecar);
+    let manifest = fs::read_to_string(root.join(MANIFEST_FILE))
+        .ok()
+        .and_then(|x| Manifest::from_str(&x).ok());
+
+    Ok(match manifest {
+        Some(manifest) => tree.mismatched(root, hashed_files(&manifest, root)?),
+        None => tree.mismatched(root, tree.files(root)),
+    })
+}
+
+/// How a package is shown in `Graph::to_tree`.
+fn tree_label(source: &Source) -> String {
+    format!("{} {}", source.meta().name(), source.meta().version())
+}
+
+/// The requirement a manifest has on the package `name`, among either its dependencies or its
+/// dev-dependencies (including the platform-specific ones for any platform). A renamed dependency
+/// is found by the real name of the package it refers to.
+fn find_dep<'a>(meta: &'a Manifest, name: &Name, dev: bool) -> Option<&'a DepReq> {
+    let deps = |x: &'a PlatformDeps| {
+        if dev {
+            &x.dev_dependencies
+        } else {
+            &x.dependencies
+        }
+    };
+    let top = if dev {
+        &meta.dev_dependencies
+    } else {
+        &meta.dependencies
+    };
+    let find = |deps: &'a IndexMap<Name, DepReq>| {
+        deps.iter()
+            .find(|(n, dep)| dep.package().unwrap_or(n) == name)
+            .map(|(_, dep)| dep)
+    };
+
+    find(top).or_else(|| meta.platform_deps.values().find_map(|x| find(deps(x))))
+}
+
+/// How a dependency is written in a manifest, for labelling the edges of `Graph::to_dot`.
+fn dep_label(req: &DepReq) -> String {
+    match req {
+        DepReq::Registry(c) | DepReq::RegLong { version: c, .. } => c.to_string(),
+        DepReq::Local { path, .. } => format!("dir+{}", path.display()),
+        DepReq::Git { git, tag, .. } => format!("git+{}#{}", git, tag),
+        DepReq::Tar { tar, .. } => tar.to_string(),
+    }
+}
+
 /// Information about a built library that is available somewhere in the file system.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Binary {
@@ -808,22 +3898,77 @@ impl Binary {
             target: Arc::new(target),
         }
     }
+
+    /// The directories that modules are imported from when building against this library: the
+    /// build itself, and the outputs of each of its named lib targets.
+    pub fn include_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = fs::read_dir(self.target.path().join(LIBS_DIR))
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.path())
+                    .filter(|path| path.is_dir())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        // The order shouldn't depend on the filesystem
+        dirs.sort();
+        dirs.insert(0, self.target.path().to_path_buf());
+
+        dirs
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct BuildHash(pub String);
 
 impl BuildHash {
+    /// Hashes the build of `root`. Only `root` and the packages it depends on (its sub tree of
+    /// `sources`) go into the hash, so a package is built once for every project which depends on
+    /// it the same way, no matter what else those projects depend on.
     pub fn new(
         root: &Source,
         sources: &Graph<Source>,
         targets: &Targets,
         ctx: &BuildContext,
         codegen: bool,
-    ) -> Self {
-        let mut hasher = Sha256::default();
-        for (_, src) in sources.sub_tree(sources.find_id(root).unwrap()) {
+    ) -> Result<Self> {
+        let mut hasher = Hasher::new();
+        // The order has to be the same every time, or identical builds would get different hashes
+        let root_id = sources.find_id(root).ok_or_else(|| {
+            format_err!(
+                "can't hash the build of {}: it isn't part of the package graph",
+                root.pretty_summary()
+            )
+        })?;
+        for (_, src) in sources.sub_tree_sorted(root_id, |s| s.hash().to_owned()) {
             hasher.input(&src.hash().as_bytes());
+            // A package built with a different environment might come out differently
+            for (name, value) in src.env() {
+                hasher.input(name.as_bytes());
+                hasher.input([0]);
+                hasher.input(value.as_bytes());
+                hasher.input([0]);
+            }
+            // And so might a package whose build scripts changed
+            for script in &[PREBUILD_SCRIPT, POSTBUILD_SCRIPT] {
+                if let Some(cmd) = src.meta().scripts.get(*script) {
+                    hasher.input(script.as_bytes());
+                    hasher.input([0]);
+                    hasher.input(cmd.as_bytes());
+                    hasher.input([0]);
+                }
+            }
+            // Or with different flags. Flags are hashed sorted and without duplicates, so that
+            // the same set of flags always hashes the same
+            let mut flags = ctx.flags_for(src.meta().name()).to_vec();
+            flags.sort();
+            flags.dedup();
+            for flag in &flags {
+                hasher.input(flag.as_bytes());
+                hasher.input([0]);
+            }
+            hasher.input([0]);
         }
 
         // Take into account the build context
@@ -833,6 +3978,10 @@ impl BuildHash {
         for opt in &ctx.opts {
             hasher.input(opt.as_bytes());
         }
+        hasher.input(ctx.profile.name.as_bytes());
+        for opt in &ctx.profile.opts {
+            hasher.input(opt.as_bytes());
+        }
         if codegen {
             hasher.input(ctx.backend.name.as_bytes());
             for opt in &ctx.backend.opts {
@@ -853,8 +4002,26 @@ impl BuildHash {
             let bytes: [u8; 5] = t.as_bytes();
             hasher.input(&bytes);
         }
-        let hash = hex::encode(hasher.result());
+        Ok(BuildHash(hasher.finish()))
+    }
 
-        BuildHash(hash)
+    /// The BuildHash of `root` when it's built as part of the graph `sources`. Codegen targets
+    /// are always codegenned for dependencies, but for the root of the graph only if `ctx.codegen`
+    /// is set.
+    pub fn for_job(
+        root: &Source,
+        sources: &Graph<Source>,
+        targets: &Targets,
+        ctx: &BuildContext,
+    ) -> Result<Self> {
+        let is_root = sources.find_id(root) == Some(NodeIndex::new(0));
+
+        BuildHash::new(
+            root,
+            sources,
+            targets,
+            ctx,
+            (!is_root || ctx.codegen) && targets.is_codegen(),
+        )
     }
 }
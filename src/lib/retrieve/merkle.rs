@@ -0,0 +1,214 @@
+//! Merkle trees of the files in a package, used to hash Sources.
+//!
+//! Every file in a package gets a leaf holding the hash of its path and its contents, and the
+//! root of the tree built on top of the leaves is the hash of the whole package. The leaves are
+//! always sorted by path, so the root doesn't depend on the order that files are found in.
+//!
+//! The leaves are kept in a sidecar file along with the size and modification time of each file.
+//! The next time the package is hashed, only the files whose size or modification time changed
+//! have to be read again; everything else is taken straight from the sidecar. A local package
+//! keeps its sidecar in its target directory, while a source in the cache keeps it next to its
+//! directory, so that the source is left exactly as it was retrieved.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::util::error::Result;
+
+/// The name of the sidecar of a local package, which is kept in the package's target directory.
+pub const SIDECAR: &str = ".elba-hashes.json";
+
+/// The extension of the sidecar kept next to the directory of a source in the cache.
+pub const SIDECAR_EXT: &str = "hashes.json";
+
+/// The hash of a single file, along with what the file looked like when it was hashed.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+struct Leaf {
+    size: u64,
+    mtime: (u64, u32),
+    hash: String,
+}
+
+impl Leaf {
+    /// Whether `file` still has the contents this leaf was made from. Only a file with the same
+    /// size can, and one which hasn't been touched since it was hashed is taken to; anything
+    /// else has to be read again to find out.
+    fn matches(&self, file: &Path) -> bool {
+        let meta = match fs::metadata(file) {
+            Ok(meta) => meta,
+            Err(_) => return false,
+        };
+        if meta.len() != self.size {
+            return false;
+        }
+        if mtime(&meta).ok() == Some(self.mtime) {
+            return true;
+        }
+
+        hash_file(file).map(|x| x == self.hash).unwrap_or(false)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Tree {
+    /// The leaves of the tree, keyed by the path of their file relative to the package root.
+    leaves: BTreeMap<String, Leaf>,
+}
+
+impl Tree {
+    /// Loads the tree stored in a sidecar. A missing or unreadable sidecar gives an empty tree,
+    /// which just means that every file gets hashed again.
+    pub fn load(sidecar: &Path) -> Self {
+        fs::read(sidecar)
+            .ok()
+            .and_then(|x| serde_json::from_slice(&x).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, sidecar: &Path) -> Result<()> {
+        if let Some(parent) = sidecar.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(sidecar, serde_json::to_vec(self)?)?;
+
+        Ok(())
+    }
+
+    /// Brings the tree up to date with the files of a package, returning how many of them had to
+    /// be hashed again. Leaves for files which aren't in `files` anymore are dropped.
+    pub fn update(&mut self, root: &Path, files: impl Iterator<Item = PathBuf>) -> Result<usize> {
+        let mut leaves = BTreeMap::new();
+        let mut rehashed = 0;
+
+        for file in files {
+            let key = key(root, &file);
+            let meta = fs::metadata(&file)?;
+            let mtime = mtime(&meta)?;
+
+            let leaf = match self.leaves.remove(&key) {
+                Some(leaf) if leaf.size == meta.len() && leaf.mtime == mtime => leaf,
+                _ => {
+                    rehashed += 1;
+                    Leaf {
+                        size: meta.len(),
+                        mtime,
+                        hash: hash_file(&file)?,
+                    }
+                }
+            };
+
+            leaves.insert(key, leaf);
+        }
+
+        self.leaves = leaves;
+
+        Ok(rehashed)
+    }
+
+    /// Compares the tree against the files a package has now, returning the paths (sorted) of
+    /// the files which were added, changed or removed since the tree was last updated. Leaves are
+    /// checked the same way as in `update`, except that a file which was touched without being
+    /// changed doesn't count as changed.
+    pub fn mismatched(&self, root: &Path, files: impl Iterator<Item = PathBuf>) -> Vec<String> {
+        let mut missing = self
+            .leaves
+            .keys()
+            .map(|x| x.as_str())
+            .collect::<BTreeSet<_>>();
+        let mut changed = vec![];
+
+        for file in files {
+            let key = key(root, &file);
+            match self.leaves.get(&key) {
+                Some(leaf) => {
+                    missing.remove(key.as_str());
+                    if !leaf.matches(&file) {
+                        changed.push(key);
+                    }
+                }
+                None => changed.push(key),
+            }
+        }
+
+        changed.extend(missing.into_iter().map(|x| x.to_owned()));
+        changed.sort();
+        changed
+    }
+
+    /// The files the tree has leaves for, under the package root `root`.
+    pub fn files<'a>(&'a self, root: &'a Path) -> impl Iterator<Item = PathBuf> + 'a {
+        self.leaves.keys().map(move |x| root.join(x))
+    }
+
+    /// The root hash of the tree.
+    ///
+    /// Leaf nodes are the hash of a file's path and contents hash, and each inner node is the
+    /// hash of its two children; the two kinds of nodes are prefixed differently so that one
+    /// can't pass for the other. A node without a sibling is carried up to the next level as-is.
+    pub fn root(&self) -> Vec<u8> {
+        let mut level = self
+            .leaves
+            .iter()
+            .map(|(path, leaf)| {
+                let mut hash = Sha256::new();
+                hash.input([0]);
+                hash.input(path.as_bytes());
+                hash.input([0]);
+                hash.input(leaf.hash.as_bytes());
+                hash.result().to_vec()
+            })
+            .collect::<Vec<_>>();
+
+        if level.is_empty() {
+            return Sha256::digest(&[]).to_vec();
+        }
+
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [l, r] => {
+                        let mut hash = Sha256::new();
+                        hash.input([1]);
+                        hash.input(l);
+                        hash.input(r);
+                        hash.result().to_vec()
+                    }
+                    [x] => x.clone(),
+                    _ => unreachable!(),
+                })
+                .collect();
+        }
+
+        level.pop().unwrap()
+    }
+}
+
+/// The key of a file's leaf: its path relative to the package root, with forward slashes.
+fn key(root: &Path, file: &Path) -> String {
+    file.strip_prefix(root)
+        .unwrap_or(file)
+        .to_string_lossy()
+        .replace("\\", "/")
+}
+
+fn mtime(meta: &fs::Metadata) -> Result<(u64, u32)> {
+    let mtime = meta.modified()?.duration_since(UNIX_EPOCH)?;
+    Ok((mtime.as_secs(), mtime.subsec_nanos()))
+}
+
+fn hash_file(file: &Path) -> Result<String> {
+    // Each file is streamed into the hasher, so large packages never have to be held in memory
+    // all at once.
+    let mut hash = Sha256::new();
+    io::copy(&mut File::open(file)?, &mut hash)?;
+    Ok(hex::encode(hash.result()))
+}
@@ -5,26 +5,30 @@
 //! retrieval of packages from various different sources (hopefully in parallel).
 
 pub mod cache;
+pub mod merkle;
+pub mod storage;
 
-use std::borrow::Cow;
+use std::{borrow::Cow, collections::HashMap, time::Duration};
 
 use console::style;
 use failure::{format_err, ResultExt};
 use indexmap::{indexmap, IndexMap, IndexSet};
 use itertools::Either::{self, Left, Right};
+use petgraph::{graph::NodeIndex, visit::IntoNodeReferences};
 use semver::Version;
 use semver_constraints::{Constraint, Interval, Range, Relation};
 use slog::{debug, info, o, trace, Logger};
 
 pub use self::cache::{Cache, Source};
 use crate::{
-    package::{PackageId, Summary},
+    package::{cfg::Platform, manifest::Manifest, Name, PackageId, Summary},
     remote::{
         resolution::{DirectRes, IndexRes, Resolution},
         Indices, ResolvedEntry,
     },
     resolve::incompat::{Incompatibility, IncompatibilityCause},
     util::{
+        config::jobs,
         error::{Error, Result},
         graph::Graph,
         shell::{Shell, Verbosity},
@@ -55,6 +59,17 @@ pub struct Retriever<'cache> {
     offline_cache: Option<IndexSet<String>>,
     sources: IndexMap<PackageId, Source>,
     pub res_mapping: IndexMap<PackageId, PackageId>,
+    /// The features which have been requested of each package so far.
+    pub features: IndexMap<Name, IndexSet<String>>,
+    /// The sources which replace every requirement on a package with the given name, taken from
+    /// the root package's `[override]` table.
+    pub overrides: IndexMap<Name, DirectRes>,
+    /// The platform whose platform-specific dependencies should be pulled in.
+    pub platform: Platform,
+    /// How long resolving and retrieving packages took, for the build report.
+    pub timings: Timings,
+    /// How many packages `retrieve_packages` downloads at once; 0 means one for every core.
+    pub download_jobs: u32,
 }
 
 impl<'cache> Retriever<'cache> {
@@ -71,6 +86,7 @@ impl<'cache> Retriever<'cache> {
     ) -> Self {
         let logger = plog.new(o!("phase" => "retrieve", "root" => root.to_string()));
 
+        let offline = offline || cache.offline;
         let offline_cache = if offline {
             shell.println(
                 style("[warn]").yellow().bold(),
@@ -83,7 +99,11 @@ impl<'cache> Retriever<'cache> {
         };
 
         let (indices, indices_set, reses) = match reses {
-            Left(v) => (cache.get_indices(&v, false, offline), false, v),
+            Left(v) => {
+                let mut indices = cache.get_indices(&v, false, offline);
+                indices.alias(ixmap);
+                (indices, false, v)
+            }
             Right(e) => (e, true, vec![]),
         };
 
@@ -101,50 +121,128 @@ impl<'cache> Retriever<'cache> {
             offline_cache,
             sources: indexmap!(),
             res_mapping: indexmap!(),
+            features: indexmap!(),
+            overrides: indexmap!(),
+            platform: Platform::default(),
+            timings: Timings::default(),
+            download_jobs: 0,
+        }
+    }
+
+    /// Records the features that the root package's manifest asks to be turned on for its
+    /// dependencies.
+    ///
+    /// Features are unified: if any package asks for a feature, it's turned on for everyone.
+    /// Renamed dependencies get the features under the name of the package they refer to.
+    pub fn request_features(&mut self, manifest: &Manifest) {
+        let platform = self.platform.clone();
+        for (n, dep) in manifest
+            .dependencies_for(&platform)
+            .chain(manifest.dev_dependencies_for(&platform))
+        {
+            self.add_features(dep.package().unwrap_or(n), dep.features());
         }
     }
 
+    /// Records the features that the packages in a solve ask to be turned on for their
+    /// dependencies, returning whether any of them weren't turned on already.
+    ///
+    /// The features of each package stay the same for the length of a solve, so which ones end
+    /// up turned on doesn't depend on the order the packages were visited in. If this turns on
+    /// anything new, the packages have to be resolved again, since the features can pull in more
+    /// optional dependencies.
+    pub fn requested_features(&mut self, solve: &Graph<Summary>) -> Result<bool> {
+        let mut requests = vec![];
+        for (_, sum) in solve.inner.node_references() {
+            // A local root package's requests come from `request_features` instead
+            if sum == &self.root && sum.resolution().direct().is_some() {
+                continue;
+            }
+
+            let features = self.features_of(sum.name());
+            if sum.resolution().direct().is_some() {
+                let meta = self.direct_checkout(sum.id(), None, false)?.meta().clone();
+                let enabled = meta.enabled_deps(&features)?;
+                for (n, dep) in meta.dependencies_for(&self.platform) {
+                    if !dep.is_optional() || enabled.contains(n) {
+                        requests
+                            .push((dep.package().unwrap_or(n).clone(), dep.features().to_vec()));
+                    }
+                }
+            } else {
+                let entry = self.select(sum)?.into_owned();
+                for dep in entry.active_deps(&features)? {
+                    requests.push((dep.name.clone(), dep.features.clone()));
+                }
+            }
+        }
+
+        let mut changed = false;
+        for (name, features) in requests {
+            changed |= self.add_features(&name, &features);
+        }
+
+        Ok(changed)
+    }
+
+    /// Turns on the given features of a package, returning whether any of them weren't on yet.
+    fn add_features(&mut self, name: &Name, features: &[String]) -> bool {
+        let on = self.features.entry(name.clone()).or_default();
+        let before = on.len();
+        on.extend(features.iter().cloned());
+        on.len() != before
+    }
+
+    /// The features which have been requested of a package, in a fixed order.
+    fn features_of(&self, name: &Name) -> Vec<String> {
+        let mut features = self
+            .features
+            .get(name)
+            .map(|fs| fs.iter().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        features.sort();
+        features
+    }
+
     /// Loads all of the packages selected in a Solve into the Cache, returning a new graph of all
     /// the Sources.
     ///
-    /// This downloads all the packages into the cache. If we wanted to parallelize downloads
-    /// later, this is where we'd deal with all the Tokio stuff.
+    /// This downloads all the packages into the cache, up to `download_jobs` of them at once.
     pub fn retrieve_packages(&mut self, solve: &Graph<Summary>) -> Result<Graph<Source>> {
-        // let mut prg = 0;
-        // Until pb.println gets added, we can't use progress bars
-        // let pb = ProgressBar::new(solve.inner.raw_nodes().len() as u64);
-        // pb.set_style(ProgressStyle::default_bar().template("  [-->] {bar} {pos}/{len}"));
-
-        info!(self.logger, "beginning bulk package retrieval");
+        info!(self.logger, "beginning bulk package retrieval"; "jobs" => jobs(self.download_jobs));
 
-        let sources = solve.map(|_, sum| {
+        // Finding out where everything is comes first, since that needs the indices
+        let mut retrieved = HashMap::new();
+        let mut pending = vec![];
+        for (ix, sum) in solve.inner.node_references() {
             let loc = match sum.resolution() {
                 Resolution::Direct(direct) => direct.clone(),
-                Resolution::Index(_) => self.select(sum).unwrap().into_owned().location,
+                Resolution::Index(_) => self.select(sum)?.into_owned().location,
             };
 
             if let Some(s) = self.remove(sum.id()) {
-                // prg += 1;
-                // pb.set_position(prg);
-                Ok(s)
+                retrieved.insert(ix, s);
             } else {
-                let source = self
-                    .cache
-                    .checkout_source(sum.id(), &loc, false, self.offline_cache.is_some(), || {
-                        self.shell.println(
-                            style("Retrieving").cyan(),
-                            sum.to_string(),
-                            Verbosity::Normal,
-                        );
-                    })
-                    .context(format_err!("unable to retrieve package {}", sum))?;
-                // prg += 1;
-                // pb.set_position(prg);
-                Ok(source.1)
+                pending.push((ix, sum, loc));
             }
-        })?;
+        }
+
+        // The errors are reported in the order of the solve, not in the order they happened in
+        let (ixs, pending): (Vec<_>, Vec<_>) = pending
+            .into_iter()
+            .map(|(ix, sum, loc)| (ix, (sum.clone(), loc)))
+            .unzip();
+        let results =
+            self.cache
+                .retrieve_all(pending, self.download_jobs, self.offline_cache.is_some())?;
+        for (ix, (source, time)) in ixs.into_iter().zip(results) {
+            let source = source?;
+            self.timings.downloads.insert(source.summary(), time);
+            retrieved.insert(ix, source);
+        }
+
+        let sources = solve.map(|ix, _| Ok(retrieved.remove(&ix).unwrap()))?;
 
-        // pb.finish_and_clear();
         self.shell.println(
             style("Cached").dim(),
             format!("packages in {}", self.cache.layout.src.display()),
@@ -156,6 +254,42 @@ impl<'cache> Retriever<'cache> {
         Ok(sources)
     }
 
+    /// Works out what `retrieve_packages` would have to do to load all the packages selected in
+    /// a Solve, without retrieving anything.
+    ///
+    /// Only the indices which have already been loaded are consulted, so this never touches the
+    /// network.
+    pub fn plan_packages(&mut self, solve: &Graph<Summary>) -> Result<FetchPlan> {
+        let mut packages = vec![];
+
+        for (_, sum) in solve.sub_tree(NodeIndex::new(0)) {
+            let (location, size) = match sum.resolution() {
+                Resolution::Direct(direct) => (direct.clone(), None),
+                Resolution::Index(_) => {
+                    let entry = self
+                        .indices
+                        .select(sum)
+                        .context(format_err!("unable to find package {}", sum))?;
+                    (entry.location.clone(), entry.meta.size)
+                }
+            };
+
+            // Packages which were checked out during resolution are already in the cache
+            let cached = self.res_mapping.contains_key(sum.id())
+                || self.sources.contains_key(sum.id())
+                || self.cache.check_source(&location).is_some();
+
+            packages.push(PlannedFetch {
+                summary: sum.clone(),
+                location,
+                cached,
+                size,
+            });
+        }
+
+        Ok(FetchPlan { packages })
+    }
+
     /// Chooses the best version of a package given a constraint.
     pub fn best(&mut self, pkg: &PackageId, con: &Constraint, minimize: bool) -> Result<Version> {
         // With stuff from lockfiles, we try to retrieve whatever version was specified in the
@@ -213,12 +347,13 @@ impl<'cache> Retriever<'cache> {
 
         self.get_indices();
 
+        // Prereleases are only ever chosen if the constraint asks for them
         let (mut pre, mut not_pre): (Vec<Version>, Vec<Version>) = self
             .entries(pkg)?
             .into_owned()
             .into_iter()
             .map(|v| v.0)
-            .filter(|v| con.satisfies(v))
+            .filter(|v| con.satisfies(v) && (!v.is_prerelease() || allows_prerelease(con, v)))
             .partition(|v| v.is_prerelease());
 
         let res = if !not_pre.is_empty() {
@@ -258,7 +393,10 @@ impl<'cache> Retriever<'cache> {
             for dep in &self.root_deps {
                 res.push(Incompatibility::from_dep(
                     pkg.clone(),
-                    (dep.0.clone(), dep.1.complement()),
+                    (
+                        overridden(&self.overrides, dep.0.clone()),
+                        dep.1.complement(),
+                    ),
                 ));
             }
             trace!(
@@ -273,16 +411,15 @@ impl<'cache> Retriever<'cache> {
         // If this is a DirectRes dep, we ask the cache for info.
         if pkg.resolution().direct().is_some() {
             let ixmap = self.ixmap.clone();
-            let deps = self
-                .direct_checkout(pkg.id(), None, false)?
-                .meta()
-                .deps(&ixmap, parent_pkg, false)?;
+            let features = self.features_of(pkg.name());
+            let meta = self.direct_checkout(pkg.id(), None, false)?.meta().clone();
+            let deps = meta.deps(&ixmap, parent_pkg, false, &features, &self.platform)?;
 
             let mut res = vec![];
             for dep in deps {
                 res.push(Incompatibility::from_dep(
                     pkg.clone(),
-                    (dep.0.clone(), dep.1.complement()),
+                    (overridden(&self.overrides, dep.0), dep.1.complement()),
                 ));
             }
             trace!(
@@ -294,13 +431,15 @@ impl<'cache> Retriever<'cache> {
             return Ok(res);
         }
 
+        let overrides = self.overrides.clone();
+        let features = self.features_of(pkg.name());
         let entries = self.entries(pkg.id())?;
         let l = entries.len();
 
-        let (ix, ver, start_deps) = entries
+        let (ix, ver, entry) = entries
             .get_full(pkg.version())
-            .map(|x| (x.0, x.1, &x.2.dependencies))
-            .ok_or_else(|| Error::PackageNotFound)?;
+            .ok_or(Error::PackageNotFound)?;
+        let start_deps = entry.active_deps(&features)?;
         let mut res = vec![];
 
         for dep in start_deps {
@@ -312,7 +451,8 @@ impl<'cache> Retriever<'cache> {
             while lix > 0 {
                 lix -= 1;
                 let new = entries.get_index(lix).unwrap();
-                let new_deps = &new.1.dependencies;
+                // A version without the requested features can't share this one's range
+                let new_deps = new.1.active_deps(&features).unwrap_or_default();
                 let mut seen = false;
                 for new_dep in new_deps {
                     if dep.name == new_dep.name && dep.index == new_dep.index {
@@ -334,7 +474,7 @@ impl<'cache> Retriever<'cache> {
             while rix < l - 1 {
                 rix += 1;
                 let new = entries.get_index(rix).unwrap();
-                let new_deps = &new.1.dependencies;
+                let new_deps = new.1.active_deps(&features).unwrap_or_default();
                 let mut seen = false;
                 for new_dep in new_deps {
                     if dep.name == new_dep.name && dep.index == new_dep.index {
@@ -365,7 +505,10 @@ impl<'cache> Retriever<'cache> {
                 Interval::Closed(upper.clone(), false)
             };
 
-            let dep_pkg = PackageId::new(dep.name.clone(), dep.index.clone().into());
+            let dep_pkg = overridden(
+                &overrides,
+                PackageId::new(dep.name.clone(), dep.index.clone().into()),
+            );
 
             let cs = indexmap!(
                 pkg.id().clone() => Range::new(nl, nu).unwrap().into(),
@@ -393,7 +536,7 @@ impl<'cache> Retriever<'cache> {
                 .map(|x| {
                     x.iter()
                         .filter(|(_, e)| {
-                            let hash = Cache::get_source_dir(&e.location, false);
+                            let hash = self.cache.source_dir(&e.location, false);
                             cache.contains(&hash)
                         })
                         .count()
@@ -406,13 +549,18 @@ impl<'cache> Retriever<'cache> {
 
     pub fn select(&mut self, sum: &Summary) -> Result<Cow<ResolvedEntry>> {
         if let Some(cache) = self.offline_cache.as_ref() {
-            let selected = self.indices.select(sum)?;
-            let hash = Cache::get_source_dir(&selected.location, false);
+            let selected = self.indices.select(sum)?.clone();
+            Self::warn_indices(&mut self.indices, self.shell);
+            let hash = self.cache.source_dir(&selected.location, false);
             if cache.contains(&hash) {
-                let mut selected = selected.clone();
-                selected.location = DirectRes::Dir {
-                    path: self.cache.layout.src.join(&hash),
-                };
+                let mut selected = selected;
+                // Compressed sources are loaded from their original location, which doesn't
+                // have to be downloaded again
+                if self.cache.layout.src.join(&hash).is_dir() {
+                    selected.location = DirectRes::Dir {
+                        path: self.cache.layout.src.join(&hash),
+                    };
+                }
                 Ok(Cow::Owned(selected))
             } else {
                 Err(Error::PackageNotFound)?
@@ -424,6 +572,7 @@ impl<'cache> Retriever<'cache> {
                 self.get_indices();
                 self.select(sum)
             } else {
+                Self::warn_indices(&mut self.indices, self.shell);
                 Ok(Cow::Borrowed(self.indices.select(sum)?))
             }
         }
@@ -432,12 +581,15 @@ impl<'cache> Retriever<'cache> {
     pub fn entries(&mut self, pkg: &PackageId) -> Result<Cow<IndexMap<Version, ResolvedEntry>>> {
         if let Some(cache) = self.offline_cache.as_ref() {
             let mut entries = self.indices.entries(pkg)?.clone();
+            Self::warn_indices(&mut self.indices, self.shell);
             for (_, e) in entries.iter_mut() {
-                let hash = Cache::get_source_dir(&e.location, false);
+                let hash = self.cache.source_dir(&e.location, false);
                 if cache.contains(&hash) {
-                    e.location = DirectRes::Dir {
-                        path: self.cache.layout.src.join(&hash),
-                    };
+                    if self.cache.layout.src.join(&hash).is_dir() {
+                        e.location = DirectRes::Dir {
+                            path: self.cache.layout.src.join(&hash),
+                        };
+                    }
                 } else {
                     return Err(Error::PackageNotFound)?;
                 }
@@ -451,11 +603,22 @@ impl<'cache> Retriever<'cache> {
                 self.get_indices();
                 self.entries(pkg)
             } else {
+                Self::warn_indices(&mut self.indices, self.shell);
                 Ok(Cow::Borrowed(self.indices.entries(pkg)?))
             }
         }
     }
 
+    /// Fails if the Cache's deadline has passed, listing what's still `pending` (see
+    /// `Cache::check_deadline`).
+    pub fn check_deadline<I>(&self, pending: I) -> Result<()>
+    where
+        I: IntoIterator,
+        I::Item: std::fmt::Display,
+    {
+        self.cache.check_deadline(pending)
+    }
+
     pub fn root(&self) -> &Summary {
         &self.root
     }
@@ -514,12 +677,30 @@ impl<'cache> Retriever<'cache> {
         }
     }
 
+    /// Warns about the conflicts between indices and the ignored parts of entries which loading
+    /// packages turned up.
+    fn warn_indices(indices: &mut Indices, shell: Shell) {
+        for conflict in indices.conflicts.drain(..) {
+            shell.println(
+                style("[warn]").yellow().bold(),
+                format!("{} (using the one from `{}`)", conflict, conflict.used.0),
+                Verbosity::Quiet,
+            );
+        }
+        for warning in indices.warnings.drain(..) {
+            shell.println(style("[warn]").yellow().bold(), warning, Verbosity::Normal);
+        }
+    }
+
     fn get_indices(&mut self) {
         if !self.indices_set {
             debug!(self.logger, "updating indices eagerly");
+            // We have to release our locks on the old indices before we can retrieve them again.
+            self.indices = Indices::default();
             self.indices = self
                 .cache
                 .get_indices(&self.reses, true, self.offline_cache.is_some());
+            self.indices.alias(self.ixmap);
             self.indices_set = true;
             self.shell.println(
                 style("Cached").dim(),
@@ -529,3 +710,68 @@ impl<'cache> Retriever<'cache> {
         }
     }
 }
+
+/// What would happen to a single package when retrieving a Solve.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlannedFetch {
+    pub summary: Summary,
+    /// Where the package would be retrieved from.
+    pub location: DirectRes,
+    /// Whether the package is already in the cache, so nothing has to be downloaded for it.
+    pub cached: bool,
+    /// The size of the download in bytes, if the index knows it.
+    pub size: Option<u64>,
+}
+
+/// Everything that would be retrieved for a Solve; see `Retriever::plan_packages`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FetchPlan {
+    pub packages: Vec<PlannedFetch>,
+}
+
+impl FetchPlan {
+    /// The packages which would have to be downloaded.
+    pub fn downloads(&self) -> impl Iterator<Item = &PlannedFetch> {
+        self.packages.iter().filter(|x| !x.cached)
+    }
+
+    /// The total size of the packages which would have to be downloaded, counting only the ones
+    /// whose size is known.
+    pub fn download_size(&self) -> u64 {
+        self.downloads().filter_map(|x| x.size).sum()
+    }
+}
+
+/// How long the Retriever spent getting the packages of a build ready; see `BuildReport`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Timings {
+    /// How long resolving took; this is left for whoever drives the resolver to fill in.
+    pub resolve: Duration,
+    /// How long each package took to retrieve, keyed by the summary of its Source.
+    pub downloads: IndexMap<String, Duration>,
+}
+
+/// Redirects a dependency to its overridden source, if it has one.
+fn overridden(overrides: &IndexMap<Name, DirectRes>, pid: PackageId) -> PackageId {
+    match overrides.get(pid.name()) {
+        Some(res) => PackageId::new(pid.name().clone(), res.clone().into()),
+        None => pid,
+    }
+}
+
+/// Whether a constraint opts into a prerelease version. Like with Cargo, a constraint only asks for
+/// the prereleases of the versions it names, and only when it names them as prereleases or with a
+/// bang: `<! 1.1.0` opts into `1.1.0-beta.1`, but not into `2.0.0-alpha.1`.
+fn allows_prerelease(con: &Constraint, v: &Version) -> bool {
+    let names_v = |bound: &Interval| match bound {
+        Interval::Closed(b, pre_ok) | Interval::Open(b, pre_ok) => {
+            (b.is_prerelease() || *pre_ok)
+                && (b.major, b.minor, b.patch) == (v.major, v.minor, v.patch)
+        }
+        Interval::Unbounded => false,
+    };
+
+    con.retrieve()
+        .iter()
+        .any(|range| names_v(range.lower()) || names_v(range.upper()))
+}
@@ -0,0 +1,97 @@
+//! Where the cache keeps sources and builds.
+//!
+//! The cache always works with sources and builds in its own local directories, since that's what
+//! the compiler needs, but it goes through a `Storage` to find out whether it has something, to
+//! get it into those directories, and to keep whatever it retrieves or builds.
+//!
+//! The default storage, `LocalFs`, is just the local directories themselves. Other storages can
+//! keep things somewhere shared (an S3 bucket, say), so that a whole team can reuse each other's
+//! builds.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use failure::format_err;
+
+use crate::{
+    retrieve::cache::{source_name, Layout},
+    util::{clear_dir_except, copy_dir, error::Result},
+};
+
+/// A place to keep the contents of the cache's directories.
+///
+/// Entries are whole directory trees, named by keys like `build/debug-<hash>` or
+/// `src/<source hash>`; the part before the slash is the cache directory the entry belongs in.
+/// Only tarball sources and builds are ever stored, since neither changes once it's been made.
+///
+/// The directories being fetched into or stored are locked while that happens, so the
+/// `.dirlock` file in them should be left alone.
+pub trait Storage: fmt::Debug + Send + Sync {
+    /// Returns whether there's an entry stored under `key`.
+    fn contains(&self, key: &str) -> Result<bool>;
+
+    /// Copies the entry stored under `key` into the directory `dest`, which is empty save for its
+    /// lockfile, returning whether anything was copied. `dest` is the cache's own directory for
+    /// the entry; if that's where the storage keeps the entry anyways, there's nothing to copy.
+    fn fetch(&self, key: &str, dest: &Path) -> Result<bool>;
+
+    /// Stores the contents of the directory `from` under `key`, replacing whatever was there.
+    fn store(&self, key: &str, from: &Path) -> Result<()>;
+}
+
+/// The local filesystem: entries are kept in the `src` and `build` directories of a Layout. For
+/// the Layout of the cache itself, that means fetching and storing an entry which is already in
+/// place doesn't copy anything.
+#[derive(Debug, Clone)]
+pub struct LocalFs {
+    src: PathBuf,
+    build: PathBuf,
+}
+
+impl LocalFs {
+    pub fn new(layout: &Layout) -> Self {
+        LocalFs {
+            src: layout.src.clone(),
+            build: layout.build.clone(),
+        }
+    }
+
+    /// The directory the entry `key` is kept in. Sources are kept under whatever name they were
+    /// given (see `SourceNaming`).
+    pub fn path(&self, key: &str) -> Result<PathBuf> {
+        let mut parts = key.splitn(2, '/');
+        match (parts.next(), parts.next()) {
+            (Some("src"), Some(hash)) => Ok(self.src.join(source_name(&self.src, hash))),
+            (Some("build"), Some(name)) => Ok(self.build.join(name)),
+            _ => Err(format_err!("invalid storage key `{}`", key)),
+        }
+    }
+}
+
+impl Storage for LocalFs {
+    fn contains(&self, key: &str) -> Result<bool> {
+        Ok(self.path(key)?.exists())
+    }
+
+    fn fetch(&self, key: &str, dest: &Path) -> Result<bool> {
+        let path = self.path(key)?;
+        if path == dest || !path.exists() {
+            return Ok(false);
+        }
+        copy_dir(&path, dest, false)?;
+
+        Ok(true)
+    }
+
+    fn store(&self, key: &str, from: &Path) -> Result<()> {
+        let path = self.path(key)?;
+        if path != from {
+            clear_dir_except(&path, &[Path::new(".dirlock")])?;
+            copy_dir(from, &path, false)?;
+        }
+
+        Ok(())
+    }
+}
@@ -8,14 +8,21 @@
 
 use super::shell::{Shell, Verbosity};
 use crate::{
-    remote::resolution::{DirectRes, IndexRes},
-    retrieve::cache::Layout,
+    remote::{
+        auth::Credential,
+        resolution::{DirectRes, IndexRes},
+        signature::PublicKey,
+    },
+    retrieve::cache::{Layout, SourceNaming, DEFAULT_CONNECT_TIMEOUT, DEFAULT_REQUEST_TIMEOUT},
 };
 use config;
 use directories::{BaseDirs, ProjectDirs};
 use indexmap::{indexmap, IndexMap};
 use serde::{Deserialize, Serialize};
-use std::{env, path::PathBuf};
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
 use url::Url;
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -35,34 +42,80 @@ pub struct Config {
     pub indices: IndexMap<String, IndexRes>,
     #[serde(default)]
     pub backend: Vec<Backend>,
+    #[serde(default)]
+    pub build_profile: Vec<BuildProfile>,
+    /// Credentials for private indices and tarballs, keyed by host.
+    #[serde(default)]
+    pub credentials: IndexMap<String, Credential>,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub net: NetConfig,
+    /// Source replacement rules: url prefixes mapped to the prefix of their mirror.
+    #[serde(default)]
+    pub mirrors: IndexMap<String, String>,
+    /// The keys signed indices have to be signed with, keyed by the alias or resolution of the
+    /// index.
+    #[serde(default)]
+    pub index_keys: IndexMap<String, PublicKey>,
+    /// Whether a package which two indices define differently is an error instead of a warning.
+    #[serde(default)]
+    pub strict_indices: bool,
+    /// How many packages to build at once; 0 means one for every core (see `jobs`).
+    #[serde(default)]
+    pub build_jobs: u32,
+    /// How many packages to download at once; 0 means one for every core (see `jobs`).
+    #[serde(default)]
+    pub download_jobs: u32,
 }
 
 fn default_compiler() -> String {
     "idris".to_string()
 }
 
+/// The environment variable which sets how many packages to build at once, taking precedence
+/// over the `build_jobs` key of the configuration.
+pub const BUILD_JOBS_ENV: &str = "ELBA_BUILD_JOBS";
+
+/// The environment variable which sets how many packages to download at once, taking
+/// precedence over the `download_jobs` key of the configuration.
+pub const DOWNLOAD_JOBS_ENV: &str = "ELBA_DOWNLOAD_JOBS";
+
+/// The number of jobs to actually run at once when `configured` were asked for: 0 is taken to
+/// mean "as many as there are cores".
+pub fn jobs(configured: u32) -> u32 {
+    if configured == 0 {
+        num_cpus::get().max(1) as u32
+    } else {
+        configured
+    }
+}
+
+/// The user-level configuration files, in the order that they're merged (later files take
+/// precedence over earlier ones).
+pub fn global_config_files() -> Vec<PathBuf> {
+    vec![
+        BaseDirs::new().unwrap().home_dir().join(".elba/config"),
+        ProjectDirs::from("", "", "elba")
+            .unwrap()
+            .config_dir()
+            .join("config"),
+    ]
+}
+
 impl Config {
     pub fn new() -> Result<Config, config::ConfigError> {
         let mut c = config::Config::new();
 
         let cwd = env::current_dir();
 
-        c.merge(
-            config::File::from(BaseDirs::new().unwrap().home_dir().join(".elba/config"))
-                .format(config::FileFormat::Toml)
-                .required(false),
-        )?;
-
-        c.merge(
-            config::File::from(
-                ProjectDirs::from("", "", "elba")
-                    .unwrap()
-                    .config_dir()
-                    .join("config"),
-            )
-            .format(config::FileFormat::Toml)
-            .required(false),
-        )?;
+        for file in global_config_files() {
+            c.merge(
+                config::File::from(file)
+                    .format(config::FileFormat::Toml)
+                    .required(false),
+            )?;
+        }
 
         if let Ok(cwd) = cwd {
             for parent in cwd.ancestors().collect::<Vec<_>>().iter().rev() {
@@ -101,6 +154,19 @@ impl Config {
         self.backend.iter().find(|x| x.name == name).cloned()
     }
 
+    /// Returns the build profile with the given name. The `debug` and `release` profiles always
+    /// exist, even if they aren't configured.
+    pub fn get_build_profile(&self, name: &str) -> Option<BuildProfile> {
+        self.build_profile
+            .iter()
+            .find(|x| x.name == name)
+            .cloned()
+            .or_else(|| match name {
+                "debug" | "release" => Some(BuildProfile::new(name)),
+                _ => None,
+            })
+    }
+
     pub fn shell(&self) -> Shell {
         Shell {
             verbosity: self.term.verbosity,
@@ -116,6 +182,14 @@ impl Config {
             tmp: self.directories.cache.join("tmp"),
         }
     }
+
+    /// The layout of the read-only base cache, if there is one (see `Cache::base`).
+    pub fn base_layout(&self) -> Option<Layout> {
+        self.cache
+            .base
+            .as_ref()
+            .map(|base| self.layout().relocated(base))
+    }
 }
 
 impl Default for Config {
@@ -128,6 +202,15 @@ impl Default for Config {
             directories: Directories::default(),
             indices: IndexMap::default(),
             backend: Vec::default(),
+            build_profile: Vec::default(),
+            credentials: IndexMap::default(),
+            cache: CacheConfig::default(),
+            net: NetConfig::default(),
+            mirrors: IndexMap::new(),
+            index_keys: IndexMap::new(),
+            strict_indices: false,
+            build_jobs: 0,
+            download_jobs: 0,
         }
     }
 }
@@ -144,15 +227,47 @@ fn default_aliases() -> IndexMap<String, String> {
 fn default_indices() -> IndexMap<String, IndexRes> {
     let repo = Url::parse("https://github.com/elba/index").unwrap();
     let tag = "master".to_string();
-    indexmap!("official".to_string() => DirectRes::Git { repo, tag }.into())
+    indexmap!("official".to_string() => DirectRes::Git {
+        repo,
+        tag,
+        rev: None,
+        subdir: None,
+    }
+    .into())
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Profile {
     pub name: String,
     pub email: String,
 }
 
+impl Profile {
+    /// Reads the `[profile]` section of a single config file, if the file exists and has one.
+    pub fn from_file(path: &Path) -> Result<Option<Profile>, config::ConfigError> {
+        let mut c = config::Config::new();
+        c.merge(
+            config::File::from(path)
+                .format(config::FileFormat::Toml)
+                .required(false),
+        )?;
+
+        match c.get::<Profile>("profile") {
+            Ok(p) => Ok(Some(p)),
+            Err(config::ConfigError::NotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Looks for a profile in the user-level configuration files.
+    pub fn global() -> Option<Profile> {
+        global_config_files()
+            .iter()
+            .rev()
+            .find_map(|f| Profile::from_file(f).ok().flatten())
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Term {
     #[serde(default = "default_color")]
@@ -174,6 +289,56 @@ impl Default for Term {
     }
 }
 
+/// How the global cache stores what it's cached.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct CacheConfig {
+    /// Whether downloaded tarballs should be kept compressed, and only extracted when they're
+    /// needed.
+    #[serde(default)]
+    pub compress_sources: bool,
+    /// Local directories to look for package tarballs in before downloading them.
+    #[serde(default)]
+    pub tarball_dirs: Vec<PathBuf>,
+    /// Whether tarball sources should be slimmed down to the files of their targets once they've
+    /// been built.
+    #[serde(default)]
+    pub slim_sources: bool,
+    /// How the directories of sources in the cache are named.
+    #[serde(default)]
+    pub source_naming: SourceNaming,
+    /// A read-only cache to look in for sources and builds before the global cache.
+    #[serde(default)]
+    pub base: Option<PathBuf>,
+}
+
+/// How `elba` talks to servers.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NetConfig {
+    /// How many seconds to wait for a connection to a server.
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    /// How many seconds a single request to a server may take.
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+}
+
+impl Default for NetConfig {
+    fn default() -> Self {
+        NetConfig {
+            connect_timeout: default_connect_timeout(),
+            request_timeout: default_request_timeout(),
+        }
+    }
+}
+
+fn default_connect_timeout() -> u64 {
+    DEFAULT_CONNECT_TIMEOUT.as_secs()
+}
+
+fn default_request_timeout() -> u64 {
+    DEFAULT_REQUEST_TIMEOUT.as_secs()
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Directories {
     #[serde(default = "default_bin_dir")]
@@ -236,3 +401,27 @@ impl Default for Backend {
         }
     }
 }
+
+/// A named set of compiler flags to build packages with (e.g. `debug` or `release`). Builds made
+/// with different profiles are kept separate from each other.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BuildProfile {
+    pub name: String,
+    #[serde(default)]
+    pub opts: Vec<String>,
+}
+
+impl BuildProfile {
+    pub fn new(name: &str) -> Self {
+        BuildProfile {
+            name: name.to_string(),
+            opts: Vec::default(),
+        }
+    }
+}
+
+impl Default for BuildProfile {
+    fn default() -> Self {
+        BuildProfile::new("debug")
+    }
+}
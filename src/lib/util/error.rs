@@ -17,6 +17,28 @@ pub enum Error {
     NoConflictRes,
     #[fail(display = "could not download package")]
     CannotDownload,
+    #[fail(display = "timed out waiting for a lock")]
+    LockTimeout,
+    #[fail(display = "the deadline passed before everything was done")]
+    DeadlineExceeded,
+    #[fail(display = "package isn't cached, and can't be downloaded in offline mode")]
+    OfflineMissingPackage,
+    #[fail(display = "not authorized to access the server")]
+    Unauthorized,
+    #[fail(display = "index entry isn't valid json")]
+    MalformedIndexEntry,
+    #[fail(display = "index entry is missing a required field")]
+    MissingIndexField,
+    #[fail(display = "index entry is filed under a different package than the one it's for")]
+    InvalidIndex,
+    #[fail(display = "version has already been published with different contents")]
+    VersionAlreadyPublished,
+    #[fail(display = "package's manifest declares a different version than it was retrieved as")]
+    VersionMismatch,
+    #[fail(display = "package can't be built with this version of elba")]
+    UnsupportedElbaVersion,
+    #[fail(display = "archive has an entry which would be unpacked outside of it")]
+    UnsafeArchivePath,
     #[doc(hidden)]
     #[fail(display = "if you see this error, everything is wrong")]
     __Nonexhaustive,
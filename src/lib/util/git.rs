@@ -28,10 +28,13 @@
 // IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
-use crate::util::error::Result;
+use crate::{
+    remote::auth::{Credential, Credentials},
+    util::error::Result,
+};
 use failure::{format_err, ResultExt};
 use git2;
-use std::{env, fs, path::Path};
+use std::{env, fmt, fs, path::Path, process::Command};
 use url::Url;
 
 pub fn init(path: &Path) -> Result<()> {
@@ -39,9 +42,16 @@ pub fn init(path: &Path) -> Result<()> {
     Ok(())
 }
 
-pub fn clone(url: &Url, into: &Path) -> Result<git2::Repository> {
+/// Returns the `user.name` and `user.email` from a git config, if both are set.
+pub fn author(cfg: &git2::Config) -> Option<(String, String)> {
+    let name = cfg.get_string("user.name").ok()?;
+    let email = cfg.get_string("user.email").ok()?;
+    Some((name, email))
+}
+
+pub fn clone(url: &Url, into: &Path, auth: &Credentials) -> Result<git2::Repository> {
     let git_config = git2::Config::open_default()?;
-    with_fetch_options(&git_config, &url, &mut |opts| {
+    with_fetch_options(&git_config, auth, url, &mut |opts| {
         let repo = git2::build::RepoBuilder::new()
             .fetch_options(opts)
             .clone(url.as_str(), into)?;
@@ -50,9 +60,9 @@ pub fn clone(url: &Url, into: &Path) -> Result<git2::Repository> {
     })
 }
 
-pub fn update_submodules(repo: &git2::Repository) -> Result<()> {
+pub fn update_submodules(repo: &git2::Repository, auth: &Credentials) -> Result<()> {
     for mut child in repo.submodules()? {
-        update_submodule(repo, &mut child).with_context(|_| {
+        update_submodule(repo, &mut child, auth).with_context(|_| {
             format!(
                 "failed to update submodule `{}`",
                 child.name().unwrap_or("")
@@ -62,7 +72,11 @@ pub fn update_submodules(repo: &git2::Repository) -> Result<()> {
     Ok(())
 }
 
-fn update_submodule(parent: &git2::Repository, child: &mut git2::Submodule) -> Result<()> {
+fn update_submodule(
+    parent: &git2::Repository,
+    child: &mut git2::Submodule,
+    auth: &Credentials,
+) -> Result<()> {
     child.init(false)?;
     let url = child
         .url()
@@ -86,7 +100,7 @@ fn update_submodule(parent: &git2::Repository, child: &mut git2::Submodule) -> R
     let mut repo = match head_and_repo {
         Ok((head, repo)) => {
             if child.head_id() == head {
-                return update_submodules(&repo);
+                return update_submodules(&repo, auth);
             }
             repo
         }
@@ -100,7 +114,7 @@ fn update_submodule(parent: &git2::Repository, child: &mut git2::Submodule) -> R
     // Fetch data from origin and reset to the head commit
     let refspec = "refs/heads/*:refs/heads/*";
     let url = Url::parse(url)?;
-    fetch(&mut repo, &url, refspec).with_context(|_| {
+    fetch(&mut repo, &url, refspec, auth).with_context(|_| {
         format_err!(
             "failed to fetch submodule `{}` from {}",
             child.name().unwrap_or(""),
@@ -110,10 +124,15 @@ fn update_submodule(parent: &git2::Repository, child: &mut git2::Submodule) -> R
 
     let obj = repo.find_object(head, None)?;
     reset(&repo, &obj)?;
-    update_submodules(&repo)
+    update_submodules(&repo, auth)
 }
 
-pub fn fetch(repo: &mut git2::Repository, url: &Url, refspec: &str) -> Result<()> {
+pub fn fetch(
+    repo: &mut git2::Repository,
+    url: &Url,
+    refspec: &str,
+    auth: &Credentials,
+) -> Result<()> {
     // The `fetch` operation here may fail spuriously due to a corrupt
     // repository. It could also fail, however, for a whole slew of other
     // reasons (aka network related reasons). We want Cargo to automatically
@@ -126,7 +145,7 @@ pub fn fetch(repo: &mut git2::Repository, url: &Url, refspec: &str) -> Result<()
     // blown away the repository, then we want to return the error as-is.
     let mut repo_reinitialized = false;
     let git_config = git2::Config::open_default()?;
-    with_fetch_options(&git_config, url, &mut |mut opts| {
+    with_fetch_options(&git_config, auth, url, &mut |mut opts| {
         loop {
             let res = repo
                 .remote_anonymous(url.as_str())?
@@ -183,7 +202,102 @@ fn reinitialize(repo: &mut git2::Repository) -> Result<()> {
     Ok(())
 }
 
-fn with_authentication<T, F>(url: &str, cfg: &git2::Config, mut f: F) -> Result<T>
+/// The places a username and password for a git remote are looked for, in the order they're
+/// tried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UserPass {
+    /// The credentials for the remote's host in elba's configuration (or environment). A token is
+    /// sent as the password, with the username from the url or `x-access-token`.
+    Configured,
+    /// git's `credential.helper`s.
+    Helper,
+    /// The program named by `GIT_ASKPASS`, `core.askPass` or `SSH_ASKPASS`, which is asked for a
+    /// username and then a password just like git asks it.
+    Askpass,
+}
+
+impl UserPass {
+    pub const ALL: [UserPass; 3] = [UserPass::Configured, UserPass::Helper, UserPass::Askpass];
+
+    /// Looks for a username and password for the remote at `url` here, using `username` if the
+    /// remote already gave one.
+    pub fn lookup(
+        self,
+        cfg: &git2::Config,
+        auth: &Credentials,
+        url: &str,
+        username: Option<&str>,
+    ) -> Option<(String, String)> {
+        match self {
+            UserPass::Configured => match auth.get(&Url::parse(url).ok()?)? {
+                Credential::Token { token } => {
+                    Some((username.unwrap_or("x-access-token").to_string(), token))
+                }
+                Credential::Basic { username, password } => {
+                    Some((username, password.unwrap_or_default()))
+                }
+            },
+            UserPass::Helper => git2::CredentialHelper::new(url)
+                .config(cfg)
+                .username(username)
+                .execute(),
+            UserPass::Askpass => {
+                let program = env::var("GIT_ASKPASS")
+                    .ok()
+                    .or_else(|| cfg.get_string("core.askPass").ok())
+                    .or_else(|| env::var("SSH_ASKPASS").ok())
+                    .filter(|x| !x.is_empty())?;
+                let url = Url::parse(url).ok()?;
+                let origin = format!("{}://{}", url.scheme(), url.host_str()?);
+                let username = match username {
+                    Some(username) => username.to_string(),
+                    None => askpass(&program, &format!("Username for '{}': ", origin))?,
+                };
+                let password = askpass(
+                    &program,
+                    &format!(
+                        "Password for '{}://{}@{}': ",
+                        url.scheme(),
+                        username,
+                        url.host_str()?
+                    ),
+                )?;
+
+                Some((username, password))
+            }
+        }
+    }
+}
+
+impl fmt::Display for UserPass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UserPass::Configured => write!(f, "elba's [credentials]"),
+            UserPass::Helper => write!(f, "git's `credential.helper`"),
+            UserPass::Askpass => write!(f, "the askpass program"),
+        }
+    }
+}
+
+/// Asks an askpass program for something, with the prompt as its only argument, returning the
+/// first line it answers with.
+fn askpass(program: &str, prompt: &str) -> Option<String> {
+    let output = Command::new(program).arg(prompt).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8(output.stdout)
+        .ok()
+        .and_then(|x| x.lines().next().map(str::to_string))
+}
+
+fn with_authentication<T, F>(
+    url: &str,
+    cfg: &git2::Config,
+    auth: &Credentials,
+    mut f: F,
+) -> Result<T>
 where
     F: FnMut(&mut git2::Credentials) -> Result<T>,
 {
@@ -191,7 +305,9 @@ where
     cred_helper.config(cfg);
 
     let mut ssh_username_requested = false;
-    let mut cred_helper_bad = None;
+    let mut userpass_requested = false;
+    let mut userpass_sources = UserPass::ALL.iter().copied();
+    let mut userpass_tried = Vec::new();
     let mut ssh_agent_attempts = Vec::new();
     let mut any_attempts = false;
     let mut tried_sshkey = false;
@@ -242,15 +358,18 @@ where
             return git2::Cred::ssh_key_from_agent(username);
         }
 
-        // Sometimes libgit2 will ask for a username/password in plaintext. This
-        // is where Cargo would have an interactive prompt if we supported it,
-        // but we currently don't! Right now the only way we support fetching a
-        // plaintext password is through the `credential.helper` support, so
-        // fetch that here.
+        // Sometimes libgit2 will ask for a username/password in plaintext. We
+        // don't have an interactive prompt, so we look for one in each of the
+        // places in `UserPass` in turn, moving on to the next one every time
+        // we're asked again (because the last one was turned down).
         if allowed.contains(git2::CredentialType::USER_PASS_PLAINTEXT) {
-            let r = git2::Cred::credential_helper(cfg, url, username);
-            cred_helper_bad = Some(r.is_err());
-            return r;
+            userpass_requested = true;
+            for source in &mut userpass_sources {
+                if let Some((user, pass)) = source.lookup(cfg, auth, url, username) {
+                    userpass_tried.push(source);
+                    return git2::Cred::userpass_plaintext(&user, &pass);
+                }
+            }
         }
 
         // I'm... not sure what the DEFAULT kind of authentication is, but seems
@@ -347,18 +466,28 @@ where
                 names
             ));
         }
-        if let Some(failed_cred_helper) = cred_helper_bad {
-            if failed_cred_helper {
-                msg.push_str(
-                    "\nattempted to find username/password via \
-                     git's `credential.helper` support, but failed",
-                );
+        if userpass_requested {
+            let host = Url::parse(url)
+                .ok()
+                .and_then(|x| x.host_str().map(str::to_string))
+                .unwrap_or_else(|| url.to_string());
+            if userpass_tried.is_empty() {
+                msg.push_str(&format!(
+                    "\n{} asked for a username and password, but none were found; \
+                     configure a git credential helper (see `git help credentials`), \
+                     set GIT_ASKPASS, or give a token for {} in elba's [credentials]",
+                    host, host
+                ));
             } else {
-                msg.push_str(
-                    "\nattempted to find username/password via \
-                     `credential.helper`, but maybe the found \
-                     credentials were incorrect",
-                );
+                let tried = userpass_tried
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                msg.push_str(&format!(
+                    "\n{} turned down the username and password from {}",
+                    host, tried
+                ));
             }
         }
         msg
@@ -368,10 +497,11 @@ where
 
 pub fn with_fetch_options<T>(
     git_config: &git2::Config,
+    auth: &Credentials,
     url: &Url,
     cb: &mut dyn FnMut(git2::FetchOptions) -> Result<T>,
 ) -> Result<T> {
-    with_authentication(url.as_str(), git_config, |f| {
+    with_authentication(url.as_str(), git_config, auth, |f| {
         let mut rcb = git2::RemoteCallbacks::new();
         rcb.credentials(f);
 
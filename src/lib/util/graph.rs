@@ -1,4 +1,6 @@
 use crate::util::error::Result;
+use failure::bail;
+use itertools::Itertools;
 use petgraph::{
     self,
     graph::NodeIndex,
@@ -6,7 +8,8 @@ use petgraph::{
     Direction,
 };
 use std::{
-    collections::HashMap,
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
     ops::{Index, IndexMut},
 };
 
@@ -30,6 +33,7 @@ impl<T: Eq> Graph<T> {
         self.inner.raw_nodes().get(0).map(|node| &node.weight)
     }
 
+    /// Finds the index of `node`, or `None` if it isn't in the graph.
     pub fn find_id(&self, node: &T) -> Option<NodeIndex> {
         self.inner
             .node_references()
@@ -55,6 +59,58 @@ impl<T: Eq> Graph<T> {
             .map(move |node_id| (node_id, &self.inner[node_id]))
     }
 
+    /// Like `sub_tree`, but in a topological order (every node comes before the nodes it points
+    /// to) which doesn't depend on the order that nodes and edges were added to the graph in.
+    /// Whenever there's a choice of which node comes next, the one with the smallest `key` wins.
+    pub fn sub_tree_sorted<K, F>(&self, root_id: NodeIndex, key: F) -> Vec<(NodeIndex, &T)>
+    where
+        K: Ord,
+        F: Fn(&T) -> K,
+    {
+        let nodes = self
+            .sub_tree(root_id)
+            .map(|(ix, _)| ix)
+            .collect::<HashSet<_>>();
+        let mut indegree = nodes
+            .iter()
+            .map(|&ix| {
+                let parents = self.parents(ix).filter(|(p, _)| nodes.contains(p)).count();
+                (ix, parents)
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut ready = indegree
+            .iter()
+            .filter(|(_, &d)| d == 0)
+            .map(|(&ix, _)| Reverse((key(&self.inner[ix]), ix)))
+            .collect::<BinaryHeap<_>>();
+        let mut res = Vec::with_capacity(nodes.len());
+
+        while let Some(Reverse((_, ix))) = ready.pop() {
+            res.push((ix, &self.inner[ix]));
+            for (child, weight) in self.children(ix) {
+                let d = indegree.get_mut(&child).unwrap();
+                *d -= 1;
+                if *d == 0 {
+                    ready.push(Reverse((key(weight), child)));
+                }
+            }
+        }
+
+        // Nodes on a cycle never run out of parents; they go at the end so they aren't lost.
+        if res.len() < nodes.len() {
+            let mut rest = indegree
+                .into_iter()
+                .filter(|(_, d)| *d > 0)
+                .map(|(ix, _)| (key(&self.inner[ix]), ix))
+                .collect::<Vec<_>>();
+            rest.sort();
+            res.extend(rest.into_iter().map(|(_, ix)| (ix, &self.inner[ix])));
+        }
+
+        res
+    }
+
     /// Traverse all direct children of the given node
     pub fn children<'a>(
         &'a self,
@@ -75,6 +131,85 @@ impl<T: Eq> Graph<T> {
             .map(move |node_id| (node_id, &self.inner[node_id]))
     }
 
+    /// Finds a cycle in the graph, if there is one. The cycle starts and ends with the same node,
+    /// so a graph where `a` depends on `b` which depends on `a` gives `[a, b, a]`.
+    pub fn detect_cycle(&self) -> Option<Vec<NodeIndex>> {
+        let mut done = HashSet::new();
+
+        for start in self.inner.node_indices() {
+            if done.contains(&start) {
+                continue;
+            }
+
+            // An iterative depth-first search; `path` is the chain of nodes from `start` to the
+            // node currently being visited, along with the children which haven't been visited.
+            let mut path = vec![(start, self.inner.neighbors(start).collect::<Vec<_>>())];
+            let mut on_path = HashSet::new();
+            on_path.insert(start);
+
+            while let Some((node, children)) = path.last_mut() {
+                let node = *node;
+                match children.pop() {
+                    Some(child) if on_path.contains(&child) => {
+                        let from = path.iter().position(|(n, _)| *n == child).unwrap();
+                        let mut cycle = path[from..].iter().map(|(n, _)| *n).collect::<Vec<_>>();
+                        cycle.push(child);
+                        return Some(cycle);
+                    }
+                    Some(child) if !done.contains(&child) => {
+                        on_path.insert(child);
+                        path.push((child, self.inner.neighbors(child).collect()));
+                    }
+                    Some(_) => {}
+                    None => {
+                        on_path.remove(&node);
+                        done.insert(node);
+                        path.pop();
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Fails with an error spelling out the offending chain (e.g. `a -> b -> a`) if the graph has
+    /// a cycle in it, using `name` to name each node.
+    pub fn check_acyclic<F>(&self, name: F) -> Result<()>
+    where
+        F: Fn(&T) -> String,
+    {
+        if let Some(cycle) = self.detect_cycle() {
+            bail!(
+                "dependency cycle detected: {}",
+                cycle
+                    .into_iter()
+                    .map(|ix| name(&self.inner[ix]))
+                    .join(" -> ")
+            )
+        }
+
+        Ok(())
+    }
+
+    /// Connects every parent of `node` straight to every child of it, and then removes every
+    /// edge to or from `node`. The node itself stays in the graph (so that no indices change),
+    /// but it isn't reachable from anywhere anymore.
+    pub fn bypass(&mut self, node: NodeIndex) {
+        let parents = self.parents(node).map(|(ix, _)| ix).collect::<Vec<_>>();
+        let children = self.children(node).map(|(ix, _)| ix).collect::<Vec<_>>();
+        for &parent in &parents {
+            for &child in &children {
+                self.inner.update_edge(parent, child, ());
+            }
+        }
+
+        self.inner.retain_edges(|g, edge| {
+            let (from, to) = g.edge_endpoints(edge).unwrap();
+            from != node && to != node
+        });
+    }
+
     pub fn map<U, F>(&self, mut f: F) -> Result<Graph<U>>
     where
         U: Eq,
@@ -0,0 +1,108 @@
+//! Hashes which remember the algorithm that made them.
+//!
+//! Every hash that ends up on disk (the names of cache directories, the hashes of Sources, and
+//! build hashes) is written as `<version>-<hex>`, where the version says which algorithm made it.
+//! Hashes from before versions existed are plain hex, and count as version 1.
+//!
+//! If the algorithm ever changes, hashes made by the new one can't collide with the old ones:
+//! cache entries made with a different algorithm just aren't found anymore, and get created again
+//! from scratch.
+
+use std::fmt;
+
+use sha2::{Digest, Sha256};
+
+/// An algorithm used to make hashes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HashAlgo {
+    /// SHA-256, with the version prefix. Plain SHA-256 hashes without one are version 1.
+    Sha256,
+}
+
+impl HashAlgo {
+    /// The algorithm all new hashes are made with.
+    pub const CURRENT: HashAlgo = HashAlgo::Sha256;
+
+    /// The version which goes in the prefix of hashes made with this algorithm.
+    pub fn version(self) -> u32 {
+        match self {
+            HashAlgo::Sha256 => 2,
+        }
+    }
+
+    pub fn from_version(version: u32) -> Option<Self> {
+        match version {
+            2 => Some(HashAlgo::Sha256),
+            _ => None,
+        }
+    }
+
+    /// How many bytes long the hashes made with this algorithm are.
+    pub fn digest_len(self) -> usize {
+        match self {
+            HashAlgo::Sha256 => 32,
+        }
+    }
+}
+
+impl fmt::Display for HashAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HashAlgo::Sha256 => write!(f, "sha256"),
+        }
+    }
+}
+
+/// Hashes data with the current algorithm.
+#[derive(Clone, Debug, Default)]
+pub struct Hasher(Sha256);
+
+impl Hasher {
+    pub fn new() -> Self {
+        Hasher::default()
+    }
+
+    pub fn algo(&self) -> HashAlgo {
+        HashAlgo::CURRENT
+    }
+
+    pub fn input<B: AsRef<[u8]>>(&mut self, data: B) {
+        self.0.input(data);
+    }
+
+    /// The hash of everything given to the hasher, with its version prefix.
+    pub fn finish(self) -> String {
+        let algo = self.algo();
+        hexify_hash(algo, &self.0.result())
+    }
+}
+
+/// Writes out a hash made with `algo` along with its version prefix.
+pub fn hexify_hash(algo: HashAlgo, hash: &[u8]) -> String {
+    format!("{}-{}", algo.version(), hex::encode(hash))
+}
+
+/// Splits a hash written out by `hexify_hash` into its algorithm and hex digest.
+///
+/// Returns None for hashes made with an algorithm we don't know about (including unprefixed
+/// hashes from before versions existed), and for anything that isn't a hash at all.
+pub fn parse_hash(hash: &str) -> Option<(HashAlgo, &str)> {
+    let mut parts = hash.splitn(2, '-');
+    let (version, digest) = (parts.next()?, parts.next()?);
+
+    // `u32::from_str` would also take a leading `+`, which `hexify_hash` never writes
+    if version.is_empty() || !version.bytes().all(|x| x.is_ascii_digit()) {
+        return None;
+    }
+    let algo = HashAlgo::from_version(version.parse().ok()?)?;
+
+    let valid = digest.len() == algo.digest_len() * 2
+        && digest
+            .bytes()
+            .all(|x| x.is_ascii_digit() || (b'a'..=b'f').contains(&x));
+    if valid {
+        Some((algo, digest))
+    } else {
+        None
+    }
+}
@@ -2,24 +2,153 @@
 //!
 //! As it is currently designed, `elba` doesn't need to lock individual files. It does, however,
 //! need to lock directories to prevent other processes from using them.
+//!
+//! Each lockfile records the PID of the process which owns it and when it was locked. If a
+//! process crashes while holding a lock, the next process to come along will notice that the
+//! recorded owner is dead (or that the lock is so old that its PID was probably reused) and
+//! reclaim the lock.
 
-use failure::{bail, format_err, Error, ResultExt};
+use crate::util::{
+    error::Error as ErrorKind,
+    shell::{Shell, Verbosity},
+};
+use console::style;
+use failure::{bail, format_err, Error, Fail, ResultExt};
 use fs2::FileExt;
 use std::{
-    fs,
+    fmt,
+    fs::{self, File},
+    io::{self, prelude::*, SeekFrom},
     path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+/// How often a contended lock is polled when waiting for it with a timeout.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How old a lock has to be before it's considered stale regardless of whether its owner seems to
+/// be alive, since the owner's PID might have been reused by an unrelated process.
+const STALE_AGE: Duration = Duration::from_secs(60 * 60 * 24);
+
 /// A lock on a directory. This just generates a sibling file to the directory which indicates that
 /// the directory is locked.
-#[derive(Debug, PartialEq, Eq)]
+///
+/// The lockfile is held open (and locked) for as long as the DirLock lives, so other processes
+/// trying to lock the same directory will wait until it's dropped.
+///
+/// Locks aren't reentrant: locking a directory again from the thread which already holds the lock
+/// on it waits for a release which never comes, so `acquire` blocks forever and `acquire_timeout`
+/// times out. Other threads of the same process wait for each other like separate processes do.
+///
+/// Everything which empties or copies a locked directory has to leave its `.dirlock` alone;
+/// without it, the next process to come along would lock the directory while it's still in use.
+#[derive(Debug)]
 pub struct DirLock {
     path: PathBuf,
     lock_path: PathBuf,
+    /// The open lockfile, or nothing for a directory which isn't actually locked (see
+    /// `DirLock::unlocked`).
+    file: Option<File>,
+    /// The PID of the process which left behind the stale lock that this one reclaimed.
+    reclaimed: Option<u32>,
+}
+
+/// Whether a directory is locked, as seen by `DirLock::probe`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockState {
+    /// The directory doesn't have a lockfile.
+    Unlocked,
+    /// Someone is holding the lock on the directory.
+    Locked,
+    /// The directory has a lockfile, but whoever left it there is gone. The next process to lock
+    /// the directory will reclaim it.
+    Stale,
+}
+
+/// How long we're willing to wait for a lock.
+enum Wait {
+    Forever,
+    Until(Instant),
+    Never,
 }
 
 impl DirLock {
+    /// Locks a directory, blocking until any other lock on it is released. This never returns if
+    /// the current thread already holds the lock (see `DirLock`).
     pub fn acquire(path: &Path) -> Result<Self, Error> {
+        DirLock::lock(path, Wait::Forever).map(|x| x.unwrap())
+    }
+
+    /// Locks a directory, waiting at most `timeout` for any other lock on it to be released.
+    ///
+    /// If the deadline passes and the directory is still locked, this returns an
+    /// `ErrorKind::LockTimeout` error mentioning the contended directory.
+    pub fn acquire_timeout(path: &Path, timeout: Duration) -> Result<Self, Error> {
+        match DirLock::lock(path, Wait::Until(Instant::now() + timeout))? {
+            Some(lock) => Ok(lock),
+            None => Err(ErrorKind::LockTimeout
+                .context(format!(
+                    "timed out after {}s waiting for the lock on {}",
+                    timeout.as_secs(),
+                    path.display()
+                ))
+                .into()),
+        }
+    }
+
+    /// Tries to lock a directory without blocking, returning `None` if it's already locked.
+    pub fn try_acquire(path: &Path) -> Result<Option<Self>, Error> {
+        DirLock::lock(path, Wait::Never)
+    }
+
+    /// Stands in for a lock on a directory which nobody ever writes to (like a read-only cache),
+    /// without touching the directory at all. The directory has to exist already.
+    pub fn unlocked(path: &Path) -> Result<Self, Error> {
+        if !path.is_dir() {
+            bail!("{} isn't a directory", path.display())
+        }
+
+        Ok(DirLock {
+            path: path.to_path_buf(),
+            lock_path: path.join(".dirlock"),
+            file: None,
+            reclaimed: None,
+        })
+    }
+
+    /// Checks whether a directory is locked, without locking it (or reclaiming a stale lock on
+    /// it).
+    pub fn probe(path: &Path) -> Result<LockState, Error> {
+        let lock_path = path.join(".dirlock");
+        let f = match fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&lock_path)
+        {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(LockState::Unlocked),
+            Err(e) => bail!("couldn't open lockfile {}: {}", lock_path.display(), e),
+        };
+
+        match f.try_lock_exclusive() {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {
+                return Ok(LockState::Locked)
+            }
+            Err(e) => bail!("couldn't lock lockfile {}: {}", lock_path.display(), e),
+        }
+
+        let owner = Owner::read(&f, &lock_path);
+        let _ = f.unlock();
+
+        match owner? {
+            Some(ref owner) if !owner.is_stale() => Ok(LockState::Locked),
+            _ => Ok(LockState::Stale),
+        }
+    }
+
+    fn lock(path: &Path, wait: Wait) -> Result<Option<Self>, Error> {
         fs::create_dir_all(&path).with_context(|e| {
             format_err!(
                 "couldn't create dir {} while locking: {}",
@@ -30,55 +159,223 @@ impl DirLock {
 
         let lock_path = path.join(".dirlock");
 
-        let f = fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&lock_path)
-            .with_context(|e| {
-                format_err!("couldn't open lockfile {}: {}", lock_path.display(), e)
-            })?;
+        loop {
+            let f = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&lock_path)
+                .with_context(|e| {
+                    format_err!("couldn't open lockfile {}: {}", lock_path.display(), e)
+                })?;
 
-        if f.metadata()
-            .with_context(|e| format_err!("couldn't get lockfile metadata: {}", e))?
-            .len()
-            != 0
-        {
-            bail!(
-                "lockfile name conflict with existing file {}",
-                lock_path.display()
-            )
-        }
+            let locked = match wait {
+                Wait::Forever => {
+                    f.lock_exclusive().with_context(|e| {
+                        format_err!("couldn't lock lockfile {}: {}", lock_path.display(), e)
+                    })?;
+                    true
+                }
+                Wait::Until(_) | Wait::Never => match f.try_lock_exclusive() {
+                    Ok(()) => true,
+                    Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => false,
+                    Err(e) => bail!("couldn't lock lockfile {}: {}", lock_path.display(), e),
+                },
+            };
 
-        f.lock_exclusive().with_context(|e| {
-            format_err!("couldn't lock lockfile {}: {}", lock_path.display(), e)
-        })?;
+            if locked {
+                // The previous owner of the lock deletes the lockfile when it's done with it, so
+                // we might've just locked a file which no longer exists; if so, we start over.
+                if !is_lockfile(&f, &lock_path) {
+                    continue;
+                }
 
-        Ok(DirLock {
-            path: path.to_path_buf(),
-            lock_path,
-        })
+                // Even though we have the lock, the lockfile might still claim to be owned by a
+                // live process on filesystems where file locks don't work. Otherwise, the previous
+                // owner crashed before it could clean up after itself.
+                match Owner::read(&f, &lock_path)? {
+                    Some(ref owner) if !owner.is_stale() => {}
+                    owner => {
+                        Owner::current().write(&f).with_context(|e| {
+                            format_err!("couldn't write lockfile {}: {}", lock_path.display(), e)
+                        })?;
+
+                        return Ok(Some(DirLock {
+                            path: path.to_path_buf(),
+                            lock_path,
+                            file: Some(f),
+                            reclaimed: owner.map(|x| x.pid),
+                        }));
+                    }
+                }
+            }
+
+            match wait {
+                Wait::Forever => thread::sleep(POLL_INTERVAL),
+                Wait::Until(deadline) if Instant::now() < deadline => {
+                    thread::sleep(POLL_INTERVAL);
+                }
+                _ => return Ok(None),
+            }
+        }
     }
 
     pub fn path(&self) -> &Path {
         &self.path
     }
+
+    /// Follows the locked directory to where it was moved while it was locked, so that the lock
+    /// cleans up the lockfile there when it's dropped.
+    pub fn moved_to(&mut self, path: &Path) {
+        self.path = path.to_path_buf();
+        self.lock_path = path.join(".dirlock");
+    }
+
+    /// The PID of the process which crashed while holding the lock on this directory, if this lock
+    /// had to be reclaimed from it.
+    pub fn reclaimed(&self) -> Option<u32> {
+        self.reclaimed
+    }
+
+    /// Tells the user that this lock had to be reclaimed, if it did.
+    pub fn warn_reclaimed(&self, shell: Shell) {
+        if let Some(pid) = self.reclaimed {
+            shell.println(
+                style("[warn]").yellow().bold(),
+                format!(
+                    "Reclaiming stale lock {} left by process {}",
+                    self.lock_path.display(),
+                    pid
+                ),
+                Verbosity::Normal,
+            );
+        }
+    }
 }
 
+impl PartialEq for DirLock {
+    fn eq(&self, other: &DirLock) -> bool {
+        self.path == other.path && self.lock_path == other.lock_path
+    }
+}
+
+impl Eq for DirLock {}
+
 impl Drop for DirLock {
     fn drop(&mut self) {
-        let f = fs::OpenOptions::new()
-            .read(true)
-            .create(true)
-            .open(&self.lock_path);
+        // We remove the lockfile before unlocking it so that anyone waiting on it knows to start
+        // over with a fresh one.
+        if let Some(file) = &self.file {
+            let _ = fs::remove_file(&self.lock_path);
+            let _ = file.unlock();
+        }
+    }
+}
 
-        if let Ok(f) = f {
-            let _ = f.unlock();
+/// The process which owns a lock, as recorded in its lockfile.
+#[derive(Debug, PartialEq, Eq)]
+struct Owner {
+    pid: u32,
+    /// When the lock was acquired, in seconds since the Unix epoch.
+    time: u64,
+}
+
+impl Owner {
+    fn current() -> Owner {
+        Owner {
+            pid: process::id(),
+            time: now(),
         }
+    }
+
+    /// Reads the owner recorded in a lockfile, if there is one.
+    fn read(mut f: &File, lock_path: &Path) -> Result<Option<Owner>, Error> {
+        let mut contents = String::new();
+        f.seek(SeekFrom::Start(0))
+            .and_then(|_| f.read_to_string(&mut contents))
+            .with_context(|e| {
+                format_err!("couldn't read lockfile {}: {}", lock_path.display(), e)
+            })?;
+
+        if contents.is_empty() {
+            return Ok(None);
+        }
+
+        let mut lines = contents.lines();
+        let pid = lines.next().and_then(|x| x.parse().ok());
+        let time = lines.next().and_then(|x| x.parse().ok());
 
-        let _ = fs::remove_file(&self.lock_path);
+        match (pid, time) {
+            (Some(pid), Some(time)) => Ok(Some(Owner { pid, time })),
+            _ => bail!(
+                "lockfile name conflict with existing file {}",
+                lock_path.display()
+            ),
+        }
+    }
+
+    fn write(&self, mut f: &File) -> std::io::Result<()> {
+        f.set_len(0)?;
+        f.seek(SeekFrom::Start(0))?;
+        f.write_all(self.to_string().as_bytes())?;
+        f.flush()
+    }
+
+    fn is_stale(&self) -> bool {
+        now().saturating_sub(self.time) >= STALE_AGE.as_secs() || !is_alive(self.pid)
+    }
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.pid)?;
+        writeln!(f, "{}", self.time)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
+
+/// Checks whether a process with the given PID is running.
+#[cfg(unix)]
+fn is_alive(pid: u32) -> bool {
+    // Sending signal 0 doesn't actually send anything, but still checks if the process exists.
+    // EPERM means the process exists but belongs to someone else.
+    let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    res == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Checks whether a process with the given PID is running.
+///
+/// We have no way of checking this here, so we assume that it is; stale locks will still be
+/// reclaimed once they're old enough.
+#[cfg(not(unix))]
+fn is_alive(_pid: u32) -> bool {
+    true
+}
+
+/// Checks that the file we've opened is still the one at the lockfile's path.
+#[cfg(unix)]
+fn is_lockfile(f: &File, lock_path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (f.metadata(), fs::metadata(lock_path)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
     }
 }
 
+/// Checks that the file we've opened is still the one at the lockfile's path.
+#[cfg(not(unix))]
+fn is_lockfile(_f: &File, lock_path: &Path) -> bool {
+    lock_path.exists()
+}
+
 #[cfg(test)]
 mod test {
     extern crate tempdir;
@@ -113,4 +410,130 @@ mod test {
 
         assert!(lock.is_err());
     }
+
+    #[test]
+    fn dirlock_records_owner() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let _lock = DirLock::acquire(tmp.path()).unwrap();
+
+        let contents = fs::read_to_string(tmp.path().join(".dirlock")).unwrap();
+        assert_eq!(
+            contents.lines().next(),
+            Some(process::id().to_string().as_str())
+        );
+    }
+
+    #[test]
+    fn dirlock_stale_dead_pid() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        // PIDs can't be this high, so this process is definitely dead.
+        let stale = Owner {
+            pid: i32::MAX as u32,
+            time: now(),
+        };
+        fs::write(tmp.path().join(".dirlock"), stale.to_string()).unwrap();
+
+        let lock = DirLock::try_acquire(tmp.path()).unwrap();
+        assert_eq!(
+            lock.as_ref().and_then(|x| x.reclaimed()),
+            Some(i32::MAX as u32)
+        );
+
+        let f = File::open(tmp.path().join(".dirlock")).unwrap();
+        let owner = Owner::read(&f, &tmp.path().join(".dirlock")).unwrap();
+        assert_eq!(owner.map(|x| x.pid), Some(process::id()));
+    }
+
+    #[test]
+    fn dirlock_stale_old() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        // Our own PID is alive, but the lock is so old that the PID must have been reused.
+        let stale = Owner {
+            pid: process::id(),
+            time: 0,
+        };
+        fs::write(tmp.path().join(".dirlock"), stale.to_string()).unwrap();
+
+        assert!(DirLock::try_acquire(tmp.path()).unwrap().is_some());
+    }
+
+    #[test]
+    fn dirlock_live_owner() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        fs::write(tmp.path().join(".dirlock"), Owner::current().to_string()).unwrap();
+
+        assert!(DirLock::try_acquire(tmp.path()).unwrap().is_none());
+    }
+
+    #[test]
+    fn dirlock_probe() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        assert_eq!(DirLock::probe(tmp.path()).unwrap(), LockState::Unlocked);
+
+        let lock = DirLock::acquire(tmp.path()).unwrap();
+        assert_eq!(DirLock::probe(tmp.path()).unwrap(), LockState::Locked);
+        drop(lock);
+
+        let stale = Owner {
+            pid: i32::MAX as u32,
+            time: now(),
+        };
+        fs::write(tmp.path().join(".dirlock"), stale.to_string()).unwrap();
+        assert_eq!(DirLock::probe(tmp.path()).unwrap(), LockState::Stale);
+        // Probing never reclaims anything
+        assert_eq!(
+            fs::read_to_string(tmp.path().join(".dirlock")).unwrap(),
+            stale.to_string()
+        );
+    }
+
+    #[test]
+    fn dirlock_try_acquire() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let lock = DirLock::try_acquire(tmp.path()).unwrap();
+        assert!(lock.is_some());
+        assert!(DirLock::try_acquire(tmp.path()).unwrap().is_none());
+
+        drop(lock);
+        assert!(DirLock::try_acquire(tmp.path()).unwrap().is_some());
+    }
+
+    #[test]
+    fn dirlock_unlocked() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let lock = DirLock::unlocked(tmp.path()).unwrap();
+        assert!(!tmp.path().join(".dirlock").exists());
+        // It doesn't keep anyone else out, either
+        assert!(DirLock::try_acquire(tmp.path()).unwrap().is_some());
+        drop(lock);
+
+        assert!(DirLock::unlocked(&tmp.path().join("nonexistent")).is_err());
+    }
+
+    #[test]
+    fn dirlock_timeout() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let _lock = DirLock::acquire(tmp.path()).unwrap();
+
+        let err = DirLock::acquire_timeout(tmp.path(), Duration::from_millis(100)).unwrap_err();
+        assert_eq!(
+            err.find_root_cause().downcast_ref::<ErrorKind>(),
+            Some(&ErrorKind::LockTimeout)
+        );
+        assert!(err.to_string().contains(&tmp.path().display().to_string()));
+    }
+
+    #[test]
+    fn dirlock_timeout_released() {
+        let tmp = tempdir::TempDir::new("elba").unwrap();
+        let lock = DirLock::acquire(tmp.path()).unwrap();
+
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            drop(lock);
+        });
+
+        DirLock::acquire_timeout(tmp.path(), Duration::from_secs(10)).unwrap();
+        handle.join().unwrap();
+    }
 }
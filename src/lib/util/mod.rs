@@ -4,6 +4,7 @@ pub mod config;
 pub mod error;
 pub mod git;
 pub mod graph;
+pub mod hash;
 pub mod lock;
 pub mod parser;
 pub mod read2;
@@ -67,13 +68,44 @@ impl<'de> Deserialize<'de> for SubPath {
     }
 }
 
-pub fn copy_dir_iter(walker: impl Iterator<Item = DirEntry>, from: &Path, to: &Path) -> Result<()> {
-    for entry in walker {
-        let to_p = to.join(entry.path().strip_prefix(from).unwrap());
-        // Make sure that the file exists before we try copying
-        fs::create_dir_all(to_p.parent().unwrap())?;
-        fs::File::create(&to_p).context(format_err!("couldn't create file {}", to_p.display()))?;
-        let _ = fs::copy(entry.path(), &to_p).with_context(|e| {
+/// Copies a single entry found while walking `from` over to the same place under `to`.
+///
+/// Files keep their permissions and symlinks are copied as symlinks rather than as whatever they
+/// point to, so a cyclic link can't send us in circles. Sockets and fifos only mean something to
+/// a running process, so they're skipped; anything else that isn't a file, directory, or symlink
+/// is an error.
+fn copy_entry(entry: &DirEntry, from: &Path, to: &Path) -> Result<()> {
+    let to_p = to.join(entry.path().strip_prefix(from).unwrap());
+    let ft = entry.file_type();
+
+    if ft.is_dir() {
+        fs::create_dir_all(&to_p)
+            .with_context(|e| format_err!("couldn't create directory {}: {}", to_p.display(), e))?;
+        return Ok(());
+    }
+
+    if is_ephemeral(&ft) {
+        return Ok(());
+    }
+
+    if let Some(parent) = to_p.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if ft.is_symlink() {
+        let target = fs::read_link(entry.path()).with_context(|e| {
+            format_err!("couldn't read symlink {}: {}", entry.path().display(), e)
+        })?;
+        if fs::symlink_metadata(&to_p).is_ok() {
+            fs::remove_file(&to_p).with_context(|e| {
+                format_err!("couldn't replace {} with a symlink: {}", to_p.display(), e)
+            })?;
+        }
+        symlink(&target, &to_p)
+            .with_context(|e| format_err!("couldn't create symlink {}: {}", to_p.display(), e))?;
+    } else if ft.is_file() {
+        // fs::copy carries the permissions of the file over, executable bits included.
+        fs::copy(entry.path(), &to_p).with_context(|e| {
             format_err!(
                 "couldn't copy {} to {}:\n{}",
                 entry.path().display(),
@@ -81,32 +113,118 @@ pub fn copy_dir_iter(walker: impl Iterator<Item = DirEntry>, from: &Path, to: &P
                 e
             )
         })?;
+    } else {
+        bail!(
+            "couldn't copy {}: it isn't a file, directory, or symlink",
+            entry.path().display()
+        )
     }
 
     Ok(())
 }
 
+#[cfg(unix)]
+fn is_ephemeral(ft: &fs::FileType) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    ft.is_socket() || ft.is_fifo()
+}
+
+#[cfg(not(unix))]
+fn is_ephemeral(_ft: &fs::FileType) -> bool {
+    false
+}
+
+pub fn copy_dir_iter(walker: impl Iterator<Item = DirEntry>, from: &Path, to: &Path) -> Result<()> {
+    for entry in walker {
+        copy_entry(&entry, from, to)?;
+    }
+
+    Ok(())
+}
+
+/// Copies the contents of a directory into another one. Symlinks inside the directory are never
+/// followed; see `copy_entry` for how each kind of entry is handled.
+///
+/// The lockfile of a locked directory isn't copied, since it would lock the copy as well.
 pub fn copy_dir(from: &Path, to: &Path, gitless: bool) -> Result<()> {
+    let lockfile = from.join(".dirlock");
     let walker = WalkDir::new(from)
-        .follow_links(true)
+        .follow_links(false)
         .into_iter()
-        .filter_entry(|x| x.path() != to && (!gitless || x.file_name() != ".git"))
-        .filter_map(|x| {
-            x.ok()
-                .and_then(|x| if valid_file(&x) { Some(x) } else { None })
-        });
+        .filter_entry(|x| {
+            x.path() != to && x.path() != lockfile && (!gitless || x.file_name() != ".git")
+        })
+        .filter_map(|x| x.ok());
 
     copy_dir_iter(walker, from, to)
 }
 
+/// Moves a directory from one place to another, preserving any symlinks inside it. If the
+/// directory can't just be renamed (e.g. because it's being moved to another filesystem), it's
+/// copied over and then removed.
+pub fn move_dir(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+
+    for entry in WalkDir::new(from).follow_links(false) {
+        copy_entry(&entry?, from, to)?;
+    }
+
+    remove_dir_all::remove_dir_all(from)?;
+
+    Ok(())
+}
+
+/// Creates a symlink at `link` pointing to `target`.
+pub fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    symlink::symlink_auto(target, link)
+}
+
 pub fn clear_dir(dir: &Path) -> Result<()> {
+    clear_dir_except(dir, &[])
+}
+
+/// Empties a directory (creating it if it doesn't exist), except for the paths in `keep`.
+///
+/// The paths in `keep` are relative to `dir` and can point to anything inside of it: keeping
+/// `build/Main.ibc` keeps that one file and the `build` directory around it, while everything else
+/// in `build` still gets removed.
+pub fn clear_dir_except(dir: &Path, keep: &[&Path]) -> Result<()> {
     if dir.exists() {
-        remove_dir_all::remove_dir_all(dir)?;
+        remove_except(dir, Path::new(""), keep)?;
     }
     fs::create_dir_all(dir)?;
     Ok(())
 }
 
+fn remove_except(root: &Path, sub: &Path, keep: &[&Path]) -> Result<()> {
+    for entry in fs::read_dir(root.join(sub))? {
+        let entry = entry?;
+        let rel = sub.join(entry.file_name());
+        if keep.iter().any(|k| *k == rel) {
+            continue;
+        }
+
+        let ft = entry.file_type()?;
+        if ft.is_dir() && keep.iter().any(|k| k.starts_with(&rel)) {
+            remove_except(root, &rel, keep)?;
+        } else if ft.is_dir() {
+            remove_dir_all::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path()).with_context(|e| {
+                format_err!("couldn't remove {}: {}", entry.path().display(), e)
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
 pub fn valid_file(entry: &DirEntry) -> bool {
     entry.file_type().is_file()
 }
@@ -145,3 +263,120 @@ pub fn fmt_output(c: &Output) -> String {
     res.pop();
     res
 }
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn copy_dir_executable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let from = tempdir::TempDir::new("elba").unwrap();
+        let to = tempdir::TempDir::new("elba").unwrap();
+        let script = from.path().join("bin/run.sh");
+        fs::create_dir_all(script.parent().unwrap()).unwrap();
+        fs::write(&script, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        copy_dir(from.path(), to.path(), false).unwrap();
+
+        let mode = fs::metadata(to.path().join("bin/run.sh"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o755);
+    }
+
+    #[test]
+    fn copy_dir_relative_symlink() {
+        let from = tempdir::TempDir::new("elba").unwrap();
+        let to = tempdir::TempDir::new("elba").unwrap();
+        fs::create_dir_all(from.path().join("src")).unwrap();
+        fs::write(from.path().join("src/Main.idr"), "main : IO ()").unwrap();
+        symlink(Path::new("src/Main.idr"), &from.path().join("Main.idr")).unwrap();
+
+        copy_dir(from.path(), to.path(), false).unwrap();
+
+        let link = to.path().join("Main.idr");
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("src/Main.idr"));
+        assert_eq!(fs::read_to_string(&link).unwrap(), "main : IO ()");
+    }
+
+    #[test]
+    fn copy_dir_cyclic_symlink() {
+        let from = tempdir::TempDir::new("elba").unwrap();
+        let to = tempdir::TempDir::new("elba").unwrap();
+        fs::create_dir_all(from.path().join("a")).unwrap();
+        symlink(Path::new(".."), &from.path().join("a/up")).unwrap();
+        symlink(Path::new("loop"), &from.path().join("loop")).unwrap();
+
+        copy_dir(from.path(), to.path(), false).unwrap();
+
+        assert_eq!(
+            fs::read_link(to.path().join("a/up")).unwrap(),
+            Path::new("..")
+        );
+        assert_eq!(
+            fs::read_link(to.path().join("loop")).unwrap(),
+            Path::new("loop")
+        );
+    }
+
+    #[test]
+    fn copy_dir_skips_lockfile() {
+        let from = tempdir::TempDir::new("elba").unwrap();
+        let to = tempdir::TempDir::new("elba").unwrap();
+        let _lock = crate::util::lock::DirLock::acquire(from.path()).unwrap();
+        fs::write(from.path().join("Main.idr"), "main : IO ()").unwrap();
+
+        copy_dir(from.path(), to.path(), false).unwrap();
+
+        assert!(to.path().join("Main.idr").exists());
+        assert!(!to.path().join(".dirlock").exists());
+    }
+
+    #[test]
+    fn clear_dir_except_keeps_whitelist() {
+        let dir = tempdir::TempDir::new("elba").unwrap();
+        let p = dir.path();
+        fs::create_dir_all(p.join("build/Data")).unwrap();
+        fs::write(p.join("build/Main.ibc"), "").unwrap();
+        fs::write(p.join("build/Stale.ibc"), "").unwrap();
+        fs::write(p.join("build/Data/List.ibc"), "").unwrap();
+        fs::create_dir_all(p.join("cache")).unwrap();
+        fs::write(p.join("cache/keep.txt"), "").unwrap();
+        fs::write(p.join("elba.toml"), "").unwrap();
+
+        clear_dir_except(p, &[Path::new("build/Main.ibc"), Path::new("cache")]).unwrap();
+
+        assert!(p.join("build/Main.ibc").exists());
+        assert!(p.join("cache/keep.txt").exists());
+        assert!(!p.join("build/Stale.ibc").exists());
+        assert!(!p.join("build/Data").exists());
+        assert!(!p.join("elba.toml").exists());
+    }
+
+    #[test]
+    fn clear_dir_removes_everything() {
+        let dir = tempdir::TempDir::new("elba").unwrap();
+        let p = dir.path().join("out");
+        fs::create_dir_all(p.join("build")).unwrap();
+        fs::write(p.join("build/Main.ibc"), "").unwrap();
+        symlink(Path::new("build"), &p.join("link")).unwrap();
+
+        clear_dir(&p).unwrap();
+
+        assert!(p.is_dir());
+        assert_eq!(fs::read_dir(&p).unwrap().count(), 0);
+        clear_dir(&dir.path().join("missing")).unwrap();
+        assert!(dir.path().join("missing").is_dir());
+    }
+}
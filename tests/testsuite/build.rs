@@ -1,3 +1,678 @@
 // At the moment, this set of tests is one big TODO.
 // If we test the build process, we're gonna need the Idris compiler, but this is untenable for
 // Travis...
+
+use super::util::{rerun_with_env, shell, LOGGER};
+use elba::{
+    build::{Target, Targets},
+    cli::{
+        build::{
+            fetch, find_manifest, root_targets, solve_local, update, workspace_members, BuildCtx,
+        },
+        index::package,
+    },
+    package::{
+        cfg::Platform,
+        lockfile::SOLVE_CACHE_FILE,
+        manifest::{Manifest, TARGET_DIR_ENV},
+        Name, PackageId,
+    },
+    remote::resolution::{DirectRes, IndexRes, Resolution},
+    retrieve::cache::{
+        Layout, OutputLayout, SourceNaming, DEFAULT_CONNECT_TIMEOUT, DEFAULT_REQUEST_TIMEOUT,
+    },
+    util::{config::BuildProfile, lock::DirLock},
+};
+use indexmap::{indexmap, IndexMap};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tempdir::TempDir;
+use url::Url;
+
+fn write_project(root: &Path, build: &str) -> Manifest {
+    let manifest = format!(
+        r#"[package]
+name = "target/dir"
+version = "1.0.0"
+authors = []
+
+[targets.lib]
+mods = ["Target.Dir"]
+{}"#,
+        build
+    );
+    fs::write(root.join("elba.toml"), &manifest).unwrap();
+    fs::create_dir_all(root.join("src/Target")).unwrap();
+    fs::write(root.join("src/Target/Dir.idr"), "module Target.Dir").unwrap();
+
+    Manifest::from_str(&manifest).unwrap()
+}
+
+#[test]
+fn build_target_dir() {
+    let root = TempDir::new("elba").unwrap();
+    let manifest = write_project(root.path(), "[build]\ntarget-dir = \"out\"\n");
+
+    let lock = DirLock::acquire(&manifest.target_dir(root.path()).join("debug")).unwrap();
+    let layout = OutputLayout::new(lock).unwrap();
+    assert!(layout.bin.starts_with(root.path().join("out/debug")));
+    assert!(root.path().join("out/debug/bin").is_dir());
+    drop(layout);
+
+    let (tarball, _, _) = package(root.path()).unwrap();
+    assert_eq!(tarball, root.path().join("out/target_dir-1.0.0.tar.gz"));
+    assert!(tarball.exists());
+    assert!(!root.path().join("target").exists());
+}
+
+#[test]
+fn build_target_dir_env() {
+    let elsewhere = TempDir::new("elba").unwrap();
+    let target = elsewhere.path().to_string_lossy().into_owned();
+    if rerun_with_env("build::build_target_dir_env", &[(TARGET_DIR_ENV, &target)]) {
+        return;
+    }
+
+    let root = TempDir::new("elba").unwrap();
+    write_project(root.path(), "[build]\ntarget-dir = \"out\"\n");
+
+    // The environment wins over the manifest
+    let (tarball, _, _) = package(root.path()).unwrap();
+    let target = PathBuf::from(std::env::var_os(TARGET_DIR_ENV).unwrap());
+    assert_eq!(tarball, target.join("target_dir-1.0.0.tar.gz"));
+    assert!(!root.path().join("out").exists());
+}
+
+#[test]
+fn build_examples_opt_in() {
+    let root = TempDir::new("elba").unwrap();
+    let manifest = write_project(
+        root.path(),
+        "\n[[targets.bin]]\nname = \"app\"\nmain = \"Main\"\n\n[[targets.test]]\nmain = \"Test\"\n\n[[targets.example]]\nname = \"demo\"\nmain = \"Demo\"\n",
+    );
+    assert_eq!(manifest.targets.example[0].path.0, Path::new("examples"));
+
+    // A plain build leaves tests and examples out
+    assert_eq!(
+        root_targets(&manifest, &(false, false, None, None, None)).unwrap(),
+        Targets(vec![Target::Lib(false), Target::Bin(0)])
+    );
+    assert_eq!(
+        root_targets(
+            &manifest,
+            &(false, false, None, Some(vec![]), Some(vec!["demo"]))
+        )
+        .unwrap(),
+        Targets(vec![
+            Target::Lib(false),
+            Target::Bin(0),
+            Target::Test(0),
+            Target::Example(0)
+        ])
+    );
+
+    let err = root_targets(&manifest, &(false, false, None, None, Some(vec!["nope"]))).unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("doesn't have an example named `nope` (it has `demo`)"),
+        "{}",
+        err
+    );
+}
+
+fn build_ctx(cache: &Path) -> BuildCtx {
+    BuildCtx {
+        compiler: "idris".to_string(),
+        indices: IndexMap::new(),
+        global_cache: Layout {
+            bin: cache.join("bin"),
+            build: cache.join("build"),
+            indices: cache.join("indices"),
+            src: cache.join("src"),
+            tmp: cache.join("tmp"),
+        },
+        base_cache: None,
+        logger: LOGGER.clone(),
+        threads: 1,
+        download_jobs: 1,
+        shell: shell(),
+        offline: false,
+        opts: vec![],
+        features: vec![],
+        profile: BuildProfile::new("debug"),
+        platform: Platform::default(),
+        credentials: IndexMap::new(),
+        compress_sources: false,
+        slim_sources: false,
+        source_naming: SourceNaming::default(),
+        mirrors: IndexMap::new(),
+        index_keys: IndexMap::new(),
+        tarball_dirs: vec![],
+        frozen: false,
+        strict_indices: false,
+        local_builds: false,
+        connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+        request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        deadline: None,
+    }
+}
+
+/// Resolves a project, returning the version of its (local) dependency in the solve.
+fn solved_dep_version(ctx: &BuildCtx, project: &Path) -> String {
+    let mut version = None;
+    solve_local(ctx, project, 1, None, |_, _, solve| {
+        version = solve
+            .inner
+            .raw_nodes()
+            .iter()
+            .find(|x| x.weight.name().as_str() == "solve/dep")
+            .map(|x| x.weight.version().to_string());
+        Ok(String::new())
+    })
+    .unwrap();
+    version.unwrap()
+}
+
+#[test]
+fn build_solve_cache() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = TempDir::new("elba").unwrap();
+    let project = root.path().join("root");
+    let dep = root.path().join("dep");
+    fs::create_dir_all(&project).unwrap();
+    fs::create_dir_all(&dep).unwrap();
+    let manifest = "[package]\nname = 'solve/root'\nversion = '1.0.0'\nauthors = []\n\n[dependencies]\n'solve/dep' = { path = '../dep' }\n";
+    fs::write(project.join("elba.toml"), manifest).unwrap();
+    fs::write(
+        dep.join("elba.toml"),
+        "[package]\nname = 'solve/dep'\nversion = '1.0.0'\nauthors = []\n",
+    )
+    .unwrap();
+
+    let ctx = build_ctx(cache.path());
+    assert_eq!(solved_dep_version(&ctx, &project), "1.0.0");
+    let saved = project.join("target").join(SOLVE_CACHE_FILE);
+    assert!(saved.exists());
+
+    // Nothing changed, so the saved solve is used as is (even though it's been tampered with)
+    let tampered = fs::read_to_string(&saved)
+        .unwrap()
+        .replace("\"1.0.0\"", "\"1.0.1\"");
+    fs::write(&saved, tampered).unwrap();
+    assert_eq!(solved_dep_version(&ctx, &project), "1.0.1");
+
+    // Changing the manifest throws the saved solve away
+    fs::write(
+        project.join("elba.toml"),
+        format!("{}# changed\n", manifest),
+    )
+    .unwrap();
+    assert_eq!(solved_dep_version(&ctx, &project), "1.0.0");
+
+    // So does changing the manifest of a local dependency
+    fs::write(
+        dep.join("elba.toml"),
+        "[package]\nname = 'solve/dep'\nversion = '1.1.0'\nauthors = []\n",
+    )
+    .unwrap();
+    assert_eq!(solved_dep_version(&ctx, &project), "1.1.0");
+}
+
+#[test]
+fn build_solve_cache_dir_index() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = TempDir::new("elba").unwrap();
+    let project = root.path().join("root");
+    let ix = root.path().join("index");
+    fs::create_dir_all(&project).unwrap();
+    fs::create_dir_all(ix.join("solve")).unwrap();
+    fs::write(
+        project.join("elba.toml"),
+        "[package]\nname = 'solve/root'\nversion = '1.0.0'\nauthors = []\n\n[dependencies]\n'solve/dep' = '1'\n",
+    )
+    .unwrap();
+    fs::write(
+        ix.join("index.toml"),
+        "[index]\nsecure = false\ndependencies = {}\n",
+    )
+    .unwrap();
+    let entry = |version: &str| {
+        format!(
+            "{{ \"name\": \"solve/dep\", \"version\": \"{}\", \"dependencies\": [], \"yanked\": false, \"location\": \"tar+https://example.com/dep-{}.tar.gz\" }}\n",
+            version, version
+        )
+    };
+    fs::write(ix.join("solve/dep"), entry("1.0.0")).unwrap();
+
+    let mut ctx = build_ctx(cache.path());
+    ctx.indices = indexmap!("local".to_string() => index(&format!("index+dir+{}", ix.display())));
+    assert_eq!(solved_dep_version(&ctx, &project), "1.0.0");
+    let saved = project.join("target").join(SOLVE_CACHE_FILE);
+    let tampered = fs::read_to_string(&saved)
+        .unwrap()
+        .replace("\"1.0.0\"", "\"1.0.1\"");
+    fs::write(&saved, tampered).unwrap();
+    assert_eq!(solved_dep_version(&ctx, &project), "1.0.1");
+
+    // A local index is read where it is, so adding an entry to it throws the saved solve away
+    // (the lockfile still keeps the dependency at the version it was at)
+    fs::write(
+        ix.join("solve/dep"),
+        format!("{}{}", entry("1.0.0"), entry("1.1.0")),
+    )
+    .unwrap();
+    assert_eq!(solved_dep_version(&ctx, &project), "1.0.0");
+}
+
+#[test]
+fn build_aliased_dependency() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = TempDir::new("elba").unwrap();
+    let project = root.path().join("root");
+    fs::create_dir_all(&project).unwrap();
+    fs::write(
+        project.join("elba.toml"),
+        "[package]\nname = 'alias/root'\nversion = '1.0.0'\nauthors = []\n\n[dependencies]\n'alias/short' = { path = '../dep', package = 'alias/dep' }\n'other/dep' = { path = '../other' }\n",
+    )
+    .unwrap();
+    for (dir, name) in &[("dep", "alias/dep"), ("other", "other/dep")] {
+        let dir = root.path().join(dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("elba.toml"),
+            format!(
+                "[package]\nname = '{}'\nversion = '1.0.0'\nauthors = []\n",
+                name
+            ),
+        )
+        .unwrap();
+    }
+
+    let mut found = None;
+    solve_local(
+        &build_ctx(cache.path()),
+        &project,
+        1,
+        None,
+        |_, mut retriever, solve| {
+            let sources = retriever.retrieve_packages(&solve)?;
+            let names = sources
+                .inner
+                .raw_nodes()
+                .iter()
+                .map(|x| x.weight.meta().name().to_string())
+                .collect::<Vec<_>>();
+            // The dependency is resolved under its real name, from where the alias points to
+            let dep = sources
+                .inner
+                .raw_nodes()
+                .iter()
+                .find(|x| x.weight.meta().name().as_str() == "alias/dep")
+                .map(|x| x.weight.path().canonicalize().unwrap());
+            let aliases = sources.root().unwrap().aliases();
+            found = Some((names, dep, aliases));
+            Ok(String::new())
+        },
+    )
+    .unwrap();
+
+    let (names, dep, aliases) = found.unwrap();
+    assert_eq!(names.len(), 3);
+    assert!(!names.contains(&"alias/short".to_string()));
+    assert_eq!(dep, Some(root.path().join("dep").canonicalize().unwrap()));
+    assert_eq!(
+        aliases,
+        indexmap!(Name::from_str("alias/short").unwrap() => Name::from_str("alias/dep").unwrap())
+    );
+}
+
+/// Commits a package named `vendor/dep` to a new git repository in `origin`.
+fn vendor_origin(origin: &Path) -> Url {
+    let repo = git2::Repository::init(origin).unwrap();
+    fs::write(
+        origin.join("elba.toml"),
+        "[package]\nname = 'vendor/dep'\nversion = '1.0.0'\nauthors = []\n",
+    )
+    .unwrap();
+
+    let mut index = repo.index().unwrap();
+    index
+        .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+        .unwrap();
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let sig = git2::Signature::now("elba", "elba@example.com").unwrap();
+    repo.commit(Some("refs/heads/master"), &sig, &sig, "init", &tree, &[])
+        .unwrap();
+
+    Url::from_file_path(origin).unwrap()
+}
+
+#[test]
+fn build_vendored() {
+    let root = TempDir::new("elba").unwrap();
+    let origin = TempDir::new("elba").unwrap();
+    let global = TempDir::new("elba").unwrap();
+    let url = vendor_origin(origin.path());
+    let project = root.path().join("root");
+    fs::create_dir_all(&project).unwrap();
+    fs::write(
+        project.join("elba.toml"),
+        format!(
+            "[package]\nname = 'vendor/root'\nversion = '1.0.0'\nauthors = []\n\n\
+             [dependencies]\n'vendor/dep' = {{ git = '{}' }}\n",
+            url
+        ),
+    )
+    .unwrap();
+
+    // Lock the project with the global cache, then vendor everything it locked
+    update(&build_ctx(global.path()), &project, None).unwrap();
+    let vendored = build_ctx(global.path()).vendored(&project).unwrap();
+    // A dry run only says what would be downloaded
+    let planned = fetch(&vendored, &project, true).unwrap();
+    assert!(planned.starts_with("1 of 2 packages"), "{}", planned);
+    assert!(project
+        .join("vendor/src")
+        .read_dir()
+        .unwrap()
+        .next()
+        .is_none());
+    fetch(&vendored, &project, false).unwrap();
+    assert!(project
+        .join("vendor/src")
+        .read_dir()
+        .unwrap()
+        .next()
+        .is_some());
+
+    // With the global cache and the dependency's origin gone, the vendored cache is all there is
+    let global = global.into_path();
+    fs::remove_dir_all(&global).unwrap();
+    fs::remove_dir_all(origin.into_path()).unwrap();
+
+    let ctx = BuildCtx {
+        offline: true,
+        ..build_ctx(&global)
+    }
+    .vendored(&project)
+    .unwrap();
+    let mut found = None;
+    solve_local(&ctx, &project, 1, None, |_, mut retriever, solve| {
+        let sources = retriever.retrieve_packages(&solve)?;
+        found = sources
+            .inner
+            .raw_nodes()
+            .iter()
+            .find(|x| x.weight.meta().name().as_str() == "vendor/dep")
+            .map(|x| x.weight.path().to_owned());
+        Ok(String::new())
+    })
+    .unwrap();
+
+    assert!(found.unwrap().starts_with(project.join("vendor/src")));
+    assert!(!global.exists());
+}
+
+const FROZEN_ROOT: &str = "[package]\nname = 'frozen/root'\nversion = '1.0.0'\nauthors = []\n\n[dependencies]\n'frozen/dep' = { path = '../dep' }\n";
+
+/// Makes a project with a single local dependency, and locks it.
+fn frozen_project(root: &Path, cache: &Path) -> PathBuf {
+    let project = root.join("root");
+    let dep = root.join("dep");
+    fs::create_dir_all(&project).unwrap();
+    fs::create_dir_all(&dep).unwrap();
+    fs::write(project.join("elba.toml"), FROZEN_ROOT).unwrap();
+    fs::write(
+        dep.join("elba.toml"),
+        "[package]\nname = 'frozen/dep'\nversion = '1.0.0'\nauthors = []\n",
+    )
+    .unwrap();
+
+    solve_local(&build_ctx(cache), &project, 1, None, |_, _, _| {
+        Ok(String::new())
+    })
+    .unwrap();
+    project
+}
+
+/// Resolves a project in frozen mode, from scratch.
+fn solve_frozen(cache: &Path, project: &Path) -> Result<String, String> {
+    let _ = fs::remove_file(project.join("target").join(SOLVE_CACHE_FILE));
+    let ctx = BuildCtx {
+        frozen: true,
+        ..build_ctx(cache)
+    };
+    solve_local(&ctx, project, 1, None, |_, _, solve| {
+        Ok(solve.inner.node_count().to_string())
+    })
+    .map_err(|e| e.to_string())
+}
+
+#[test]
+fn frozen_pinned() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = TempDir::new("elba").unwrap();
+    let project = frozen_project(root.path(), cache.path());
+    let lock = fs::read(project.join("elba.lock")).unwrap();
+
+    assert_eq!(solve_frozen(cache.path(), &project), Ok("2".to_string()));
+    assert_eq!(fs::read(project.join("elba.lock")).unwrap(), lock);
+}
+
+#[test]
+fn frozen_missing_lock() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = TempDir::new("elba").unwrap();
+    let project = frozen_project(root.path(), cache.path());
+    fs::remove_file(project.join("elba.lock")).unwrap();
+
+    let err = solve_frozen(cache.path(), &project).unwrap_err();
+    assert!(err.contains("--frozen forbids creating it"), "{}", err);
+    assert!(!project.join("elba.lock").exists());
+}
+
+#[test]
+fn frozen_manifest_drift() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = TempDir::new("elba").unwrap();
+    let project = frozen_project(root.path(), cache.path());
+    let lock = fs::read(project.join("elba.lock")).unwrap();
+
+    let other = root.path().join("other");
+    fs::create_dir_all(&other).unwrap();
+    fs::write(
+        other.join("elba.toml"),
+        "[package]\nname = 'frozen/other'\nversion = '1.0.0'\nauthors = []\n",
+    )
+    .unwrap();
+    fs::write(
+        project.join("elba.toml"),
+        format!("{}'frozen/other' = {{ path = '../other' }}\n", FROZEN_ROOT),
+    )
+    .unwrap();
+
+    let err = solve_frozen(cache.path(), &project).unwrap_err();
+    assert!(err.contains("out of date with elba.toml"), "{}", err);
+    assert!(err.contains("added frozen/other"), "{}", err);
+    assert_eq!(fs::read(project.join("elba.lock")).unwrap(), lock);
+}
+
+#[test]
+fn frozen_lock_change() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = TempDir::new("elba").unwrap();
+    let project = frozen_project(root.path(), cache.path());
+    let lock = fs::read(project.join("elba.lock")).unwrap();
+
+    // The manifest still allows the dependency, but resolving it would lock a new version
+    fs::write(
+        root.path().join("dep/elba.toml"),
+        "[package]\nname = 'frozen/dep'\nversion = '1.1.0'\nauthors = []\n",
+    )
+    .unwrap();
+
+    let err = solve_frozen(cache.path(), &project).unwrap_err();
+    assert!(err.contains("would change elba.lock"), "{}", err);
+    assert!(err.contains("1.1.0"), "{}", err);
+    assert_eq!(fs::read(project.join("elba.lock")).unwrap(), lock);
+}
+
+#[test]
+fn frozen_update() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = TempDir::new("elba").unwrap();
+    let project = frozen_project(root.path(), cache.path());
+
+    let ctx = BuildCtx {
+        frozen: true,
+        ..build_ctx(cache.path())
+    };
+    let err = update(&ctx, &project, None).unwrap_err().to_string();
+    assert!(err.contains("--frozen"), "{}", err);
+}
+
+fn index(res: &str) -> IndexRes {
+    IndexRes::from_str(res).unwrap()
+}
+
+/// The indices that the workspace member in `tests/data/workspace/<member>` is built with.
+fn workspace_indices(member: &str) -> IndexMap<String, IndexRes> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/data/workspace")
+        .join(member);
+    let (root, manifest) = find_manifest(&path, false, None).unwrap();
+    let config = indexmap!("official".to_string() => index("index+dir+/srv/indices/official"));
+
+    manifest.indices_for(&root, &config)
+}
+
+#[test]
+fn workspace_indices_inherited() {
+    let indices = workspace_indices("member");
+    assert_eq!(
+        indices.keys().collect::<Vec<_>>(),
+        vec!["official", "shared", "corp", "local"]
+    );
+    assert_eq!(indices["local"], index("index+dir+/srv/indices/local"));
+
+    // The member's dependencies can name indices declared by the workspace root
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/workspace/member");
+    let (root, manifest) = find_manifest(&path, false, None).unwrap();
+    let pid = PackageId::new(
+        manifest.name().clone(),
+        DirectRes::Dir { path: root.clone() }.into(),
+    );
+    let deps = manifest
+        .deps(&indices, &pid, false, &[], &Platform::default())
+        .unwrap();
+    let reses = deps
+        .keys()
+        .map(|pid| (pid.name().to_string(), pid.resolution().clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        reses,
+        vec![
+            (
+                "ws/shared".to_string(),
+                Resolution::Index(index("index+dir+/srv/indices/shared"))
+            ),
+            (
+                "ws/local".to_string(),
+                Resolution::Index(index("index+dir+/srv/indices/local"))
+            ),
+        ]
+    );
+}
+
+#[test]
+fn workspace_indices_overridden() {
+    // Redeclaring an index replaces it without moving it
+    let indices = workspace_indices("override");
+    assert_eq!(
+        indices.keys().collect::<Vec<_>>(),
+        vec!["official", "shared", "corp"]
+    );
+    assert_eq!(indices["corp"], index("index+dir+/srv/indices/corp-mirror"));
+}
+
+#[test]
+fn workspace_indices_outside() {
+    // A package which isn't a member of any workspace only gets the configured indices
+    let root = TempDir::new("elba").unwrap();
+    let manifest = write_project(root.path(), "");
+    let config = indexmap!("official".to_string() => index("index+dir+/srv/indices/official"));
+    assert_eq!(manifest.indices_for(root.path(), &config), config);
+
+    // Nor does one which is under a workspace root, but isn't listed as a member
+    fs::write(
+        root.path().join("elba.toml"),
+        "[workspace]\n'other/pkg' = 'other'\n\n[indices]\nshared = 'index+dir+/srv/shared'\n",
+    )
+    .unwrap();
+    let stray = root.path().join("stray");
+    fs::create_dir_all(&stray).unwrap();
+    let manifest = write_project(&stray, "");
+    assert_eq!(manifest.indices_for(&stray, &config), config);
+}
+
+#[test]
+fn workspace_default_members() {
+    let ws = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/workspace");
+
+    // Only the default members are built from the workspace root, unless --all is passed
+    assert_eq!(
+        workspace_members(&ws, false).unwrap(),
+        Some(vec![ws.join("member")])
+    );
+    assert_eq!(
+        workspace_members(&ws, true).unwrap(),
+        Some(vec![ws.join("member"), ws.join("override")])
+    );
+
+    // Within a member, it's just that member which gets built
+    assert_eq!(
+        workspace_members(&ws.join("override"), false).unwrap(),
+        None
+    );
+}
+
+#[test]
+fn workspace_default_members_root_package() {
+    let root = TempDir::new("elba").unwrap();
+    write_project(root.path(), "");
+    let member = root.path().join("member");
+    fs::create_dir_all(&member).unwrap();
+    write_project(&member, "");
+
+    // A workspace root with a package of its own builds just that, unless --all is passed
+    let manifest = fs::read_to_string(root.path().join("elba.toml")).unwrap();
+    fs::write(
+        root.path().join("elba.toml"),
+        format!("{}\n[workspace]\n'target/dir' = 'member'\n", manifest),
+    )
+    .unwrap();
+    assert_eq!(workspace_members(root.path(), false).unwrap(), None);
+    assert_eq!(
+        workspace_members(root.path(), true).unwrap(),
+        Some(vec![member.clone()])
+    );
+
+    // Default members have to be in the workspace
+    fs::write(
+        root.path().join("elba.toml"),
+        "[workspace]\ndefault-members = ['target/other']\n'target/dir' = 'member'\n",
+    )
+    .unwrap();
+    let err = workspace_members(root.path(), false)
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("target/other"), "{}", err);
+    assert_eq!(
+        workspace_members(root.path(), true).unwrap(),
+        Some(vec![member])
+    );
+}
@@ -0,0 +1,4160 @@
+use super::{
+    index::serve_sparse,
+    resolution::{response, serve_once, serve_tarball, tar_res},
+    util::{rerun_with_env, shell, INDEX_DIR, IXMAP, LOGGER},
+};
+use elba::{
+    build::{
+        context::{BuildContext, Compiler},
+        job::JobQueue,
+        report::{BuildReport, BUILD_REPORT_FILE},
+        Target, Targets,
+    },
+    package::{lockfile::LockfileToml, manifest::BuildConfig, Name, PackageId, Summary},
+    remote::{
+        archive::{ArchiveExtractor, ArchiveFormats},
+        mirror::Mirrors,
+        resolution::{unpack_archive, DirectRes, IndexRes, Resolution},
+    },
+    resolve::Resolver,
+    retrieve::{
+        cache::{
+            BuildHash, Cache, DoctorReport, ExportFilter, IssueKind, Layout, OutputLayout, Source,
+            SourceNaming, SourceOrigin, TreeOptions, OFFLINE_ENV,
+        },
+        merkle,
+        storage::{LocalFs, Storage},
+        Retriever,
+    },
+    util::{
+        config::{Backend, BuildProfile},
+        error::Error,
+        graph::Graph,
+        hash::{hexify_hash, parse_hash, HashAlgo, Hasher},
+        lock::{DirLock, LockState},
+        symlink, SubPath,
+    },
+};
+use filetime::{set_file_mtime, FileTime};
+use flate2::{write::GzEncoder, Compression};
+use indexmap::{indexmap, IndexMap};
+use itertools::Either::{Left, Right};
+use petgraph::graph::NodeIndex;
+use semver::Version;
+use sha2::{Digest, Sha256};
+use slog::{o, Drain, Level, Logger, OwnedKVList, Record, KV};
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+use tempdir::TempDir;
+use url::Url;
+
+fn new_cache(root: &TempDir) -> Cache {
+    let path = root.path();
+    let layout = Layout {
+        bin: path.join("bin"),
+        build: path.join("build"),
+        indices: path.join("indices"),
+        src: path.join("src"),
+        tmp: path.join("tmp"),
+    };
+
+    Cache::from_disk(&LOGGER, layout, None, shell()).unwrap()
+}
+
+#[test]
+fn cache_relocate_symlinks() {
+    let old = TempDir::new("elba").unwrap();
+    let new = TempDir::new("elba").unwrap();
+    let external = TempDir::new("elba").unwrap();
+    let mut cache = new_cache(&old);
+
+    // A package which lives in the cache, and another which is a symlinked dir dependency
+    let pkg = cache.layout.src.join("pkg");
+    fs::create_dir_all(&pkg).unwrap();
+    fs::write(pkg.join("elba.toml"), b"internal").unwrap();
+    fs::write(external.path().join("elba.toml"), b"external").unwrap();
+    symlink(&pkg, &cache.layout.build.join("internal")).unwrap();
+    symlink(external.path(), &cache.layout.src.join("external")).unwrap();
+
+    let new_root = new.path().join("elba");
+    cache.relocate(&new_root).unwrap();
+
+    assert_eq!(cache.layout.src, new_root.join("src"));
+    assert!(!old.path().join("src").exists());
+
+    let internal = new_root.join("build/internal");
+    assert_eq!(fs::read_link(&internal).unwrap(), new_root.join("src/pkg"));
+    assert_eq!(fs::read(internal.join("elba.toml")).unwrap(), b"internal");
+
+    let external_link = new_root.join("src/external");
+    assert_eq!(fs::read_link(&external_link).unwrap(), external.path());
+    assert_eq!(
+        fs::read(external_link.join("elba.toml")).unwrap(),
+        b"external"
+    );
+}
+
+#[test]
+fn cache_relocate_locked() {
+    let old = TempDir::new("elba").unwrap();
+    let new = TempDir::new("elba").unwrap();
+    let mut cache = new_cache(&old);
+
+    let lock = DirLock::acquire(&cache.layout.src.join("pkg")).unwrap();
+    assert!(cache.relocate(new.path()).is_err());
+    assert!(old.path().join("src/pkg").exists());
+    assert_eq!(cache.layout.src, old.path().join("src"));
+
+    drop(lock);
+    cache.relocate(new.path()).unwrap();
+}
+
+#[test]
+fn cache_relocate_stale_lock() {
+    let old = TempDir::new("elba").unwrap();
+    let new = TempDir::new("elba").unwrap();
+    let mut cache = new_cache(&old);
+
+    let crashed = cache.layout.src.join("crashed");
+    fs::create_dir_all(&crashed).unwrap();
+    fs::write(crashed.join("elba.toml"), BAR_MANIFEST).unwrap();
+    let now = SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap();
+    // PIDs can't be this high, so the process which left the lock is definitely gone
+    let stale = format!("{}\n{}\n", i32::MAX, now.as_secs());
+    fs::write(crashed.join(".dirlock"), &stale).unwrap();
+
+    cache.relocate(new.path()).unwrap();
+
+    // The stale lock is reclaimed for the move and released afterwards, wherever it ended up
+    let moved = new.path().join("src/crashed");
+    assert!(moved.join("elba.toml").exists());
+    assert!(!moved.join(".dirlock").exists());
+    assert!(!crashed.exists());
+    assert_eq!(DirLock::probe(&moved).unwrap(), LockState::Unlocked);
+}
+
+#[test]
+fn cache_relocate_storage() {
+    let old = TempDir::new("elba").unwrap();
+    let new = TempDir::new("elba").unwrap();
+    let mut cache = new_cache(&old);
+    let profile = BuildProfile::new("debug");
+
+    let out = TempDir::new("elba").unwrap();
+    fs::write(out.path().join("lib.ibc"), b"built").unwrap();
+    let moved = BuildHash("0123456789abcdef".to_string());
+    drop(cache.store_build(out.path(), &moved, &profile).unwrap());
+
+    let new_root = new.path().join("elba");
+    cache.relocate(&new_root).unwrap();
+
+    // Builds which were moved are still found, and new ones go in the new directories
+    let binary = cache.checkout_build(&moved, &profile).unwrap().unwrap();
+    assert_eq!(
+        binary.target.path(),
+        new_root.join("build/debug-0123456789abcdef")
+    );
+    drop(binary);
+    let stored = BuildHash("fedcba9876543210".to_string());
+    let binary = cache.store_build(out.path(), &stored, &profile).unwrap();
+    assert_eq!(
+        binary.target.path(),
+        new_root.join("build/debug-fedcba9876543210")
+    );
+    drop(binary);
+    assert!(cache.checkout_build(&stored, &profile).unwrap().is_some());
+    assert!(!old.path().join("build").exists());
+}
+
+const BAR_MANIFEST: &str = r#"
+[package]
+name = 'no_conflict/bar'
+version = '1.0.0'
+authors = ['me']
+"#;
+
+/// Populates a cache with the (shared) source of every package in the test index.
+fn warm_cache(cache: &Cache) -> String {
+    let loc = DirectRes::from_str("dir+test").unwrap();
+    let hash = Cache::get_source_dir(&loc, false);
+    let dir = cache.layout.src.join(&hash);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("elba.toml"), BAR_MANIFEST).unwrap();
+    fs::create_dir_all(cache.layout.build.join("some_build")).unwrap();
+    fs::write(cache.layout.build.join("some_build/hash"), b"build").unwrap();
+
+    hash
+}
+
+fn resolve_offline(cache: &Cache) -> bool {
+    let index = DirectRes::Dir {
+        path: INDEX_DIR.path().to_owned(),
+    };
+    let mut ixs = cache.get_indices(std::slice::from_ref(&index), false, true);
+    let root = PackageId::new(
+        Name::from_str("no_conflict/root").unwrap(),
+        Resolution::Index(IndexRes { res: index }),
+    );
+    let root = Summary::new(root, Version::parse("1.0.0").unwrap());
+    let root_deps = ixs
+        .select(&root)
+        .unwrap()
+        .dependencies
+        .iter()
+        .cloned()
+        .map(|d| (PackageId::new(d.name, Resolution::Index(d.index)), d.req))
+        .collect::<Vec<_>>();
+
+    let mut retriever = Retriever::new(
+        &cache.logger,
+        cache,
+        root,
+        root_deps,
+        Right(ixs),
+        Graph::default(),
+        &IXMAP,
+        shell(),
+        true,
+    );
+    let logger = retriever.logger.clone();
+    let resolver = Resolver::new(&logger, &mut retriever);
+
+    resolver.solve().is_ok()
+}
+
+fn export(cache: &Cache, filter: ExportFilter, dir: &Path) -> std::path::PathBuf {
+    let archive = dir.join("cache.tar.gz");
+    cache.export(&archive, filter).unwrap();
+    archive
+}
+
+#[test]
+fn cache_export_import_offline() {
+    let old = TempDir::new("elba").unwrap();
+    let new = TempDir::new("elba").unwrap();
+    let out = TempDir::new("elba").unwrap();
+    let warm = new_cache(&old);
+    let cold = new_cache(&new);
+    let hash = warm_cache(&warm);
+
+    assert!(!resolve_offline(&cold));
+
+    let archive = export(&warm, ExportFilter::All, out.path());
+    let report = cold.import(&archive).unwrap();
+
+    assert!(report.imported.contains(&format!("src/{}", hash)));
+    assert!(report.imported.contains(&"build/some_build".to_string()));
+    assert!(report.skipped.is_empty());
+    assert_eq!(
+        fs::read_to_string(cold.layout.src.join(&hash).join("elba.toml")).unwrap(),
+        BAR_MANIFEST
+    );
+    assert!(resolve_offline(&cold));
+}
+
+#[test]
+fn cache_import_keeps_newer() {
+    let old = TempDir::new("elba").unwrap();
+    let new = TempDir::new("elba").unwrap();
+    let out = TempDir::new("elba").unwrap();
+    let warm = new_cache(&old);
+    let cold = new_cache(&new);
+    let hash = warm_cache(&warm);
+    let archive = export(&warm, ExportFilter::Sources, out.path());
+
+    // The local copy was modified after the archive was made, so it shouldn't be replaced.
+    let local = cold.layout.src.join(&hash);
+    fs::create_dir_all(&local).unwrap();
+    fs::write(local.join("elba.toml"), b"newer").unwrap();
+
+    let report = cold.import(&archive).unwrap();
+    assert_eq!(report.skipped, vec![format!("src/{}", hash)]);
+    assert!(report.imported.is_empty());
+    assert_eq!(fs::read(local.join("elba.toml")).unwrap(), b"newer");
+}
+
+#[test]
+fn cache_import_failed_cleans_up() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+    let archive = root.path().join("broken.tar.gz");
+    fs::write(&archive, b"not an archive").unwrap();
+
+    assert!(cache.import(&archive).is_err());
+    assert!(!cache
+        .layout
+        .tmp
+        .join(format!("import-{}", std::process::id()))
+        .exists());
+}
+
+#[test]
+fn cache_export_filter() {
+    let old = TempDir::new("elba").unwrap();
+    let out = TempDir::new("elba").unwrap();
+    let warm = new_cache(&old);
+    let hash = warm_cache(&warm);
+
+    let builds = TempDir::new("elba").unwrap();
+    let cache = new_cache(&builds);
+    let report = cache
+        .import(&export(&warm, ExportFilter::Builds, out.path()))
+        .unwrap();
+    assert_eq!(report.imported, vec!["build/some_build".to_string()]);
+
+    let index = IndexRes {
+        res: DirectRes::Dir {
+            path: INDEX_DIR.path().to_owned(),
+        },
+    };
+    let pkg = |name| PackageId::new(Name::from_str(name).unwrap(), index.clone().into());
+
+    let some = TempDir::new("elba").unwrap();
+    let cache = new_cache(&some);
+    let filter = ExportFilter::Packages(vec![pkg("no_conflict/bar")]);
+    let report = cache.import(&export(&warm, filter, out.path())).unwrap();
+    assert_eq!(report.imported, vec![format!("src/{}", hash)]);
+
+    let none = TempDir::new("elba").unwrap();
+    let cache = new_cache(&none);
+    let filter = ExportFilter::Packages(vec![pkg("no_conflict/foo")]);
+    let report = cache.import(&export(&warm, filter, out.path())).unwrap();
+    assert!(report.imported.is_empty());
+}
+
+#[test]
+fn cache_which() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+    let loc = DirectRes::from_str("tar+https://example.com/bar.tar.gz").unwrap();
+    let pid = PackageId::new(
+        Name::from_str("no_conflict/bar").unwrap(),
+        Resolution::Direct(loc.clone()),
+    );
+    let sum = Summary::new(pid.clone(), Version::parse("1.0.0").unwrap());
+
+    assert_eq!(cache.which(&sum, &loc), None);
+
+    let dir = cache.layout.src.join(Cache::get_source_dir(&loc, true));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("elba.toml"), BAR_MANIFEST).unwrap();
+
+    assert_eq!(cache.which(&sum, &loc), Some(dir));
+
+    // A different version of the package isn't what's cached there
+    let other = Summary::new(pid, Version::parse("2.0.0").unwrap());
+    assert_eq!(cache.which(&other, &loc), None);
+}
+
+#[test]
+fn cache_multiple_libs() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+    let pkg = TempDir::new("elba").unwrap();
+    fs::write(
+        pkg.path().join("elba.toml"),
+        r#"[package]
+name = "multi/libs"
+version = "1.0.0"
+authors = []
+
+[targets.lib]
+mods = ["Multi"]
+
+[targets.libs.parser]
+path = "parser"
+mods = ["Multi.Parser"]
+"#,
+    )
+    .unwrap();
+    for file in &["src/Multi.idr", "parser/Multi/Parser.idr"] {
+        let path = pkg.path().join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, b"").unwrap();
+    }
+    let source = load_source(pkg.path(), "multi/libs");
+    source.verify_targets().unwrap();
+    assert_eq!(
+        source.target_files(),
+        vec![
+            PathBuf::from("src/Multi.idr"),
+            PathBuf::from("parser/Multi/Parser.idr")
+        ]
+    );
+
+    // Each lib target's outputs go somewhere different, so they're never mixed up
+    let out = TempDir::new("elba").unwrap();
+    let layout = OutputLayout::new(DirLock::acquire(out.path()).unwrap()).unwrap();
+    let (main_dir, parser_dir) = (layout.lib_dir(None), layout.lib_dir(Some("parser")));
+    assert_eq!(main_dir, layout.lib);
+    assert_ne!(main_dir, parser_dir);
+    assert!(parser_dir.starts_with(&layout.lib));
+    fs::create_dir_all(&parser_dir).unwrap();
+    fs::write(main_dir.join("Multi.ibc"), b"main").unwrap();
+    fs::write(parser_dir.join("Multi.ibc"), b"parser").unwrap();
+
+    // Whatever depends on the build imports from both
+    let hash = BuildHash("0123456789abcdef".to_string());
+    let binary = cache
+        .store_build(&layout.lib, &hash, &BuildProfile::new("debug"))
+        .unwrap();
+    let dirs = binary.include_dirs();
+    assert_eq!(dirs.len(), 2);
+    assert_eq!(dirs[0], binary.target.path());
+    assert_eq!(fs::read(dirs[0].join("Multi.ibc")).unwrap(), b"main");
+    assert_eq!(fs::read(dirs[1].join("Multi.ibc")).unwrap(), b"parser");
+}
+
+#[test]
+fn cache_build_profiles() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+    let hash = BuildHash("0123456789abcdef".to_string());
+    let debug = BuildProfile::new("debug");
+    let release = BuildProfile::new("release");
+
+    let out = TempDir::new("elba").unwrap();
+    for profile in &[&debug, &release] {
+        fs::write(out.path().join("lib.ibc"), profile.name.as_bytes()).unwrap();
+        cache.store_build(out.path(), &hash, profile).unwrap();
+    }
+
+    let debug_bin = cache.checkout_build(&hash, &debug).unwrap().unwrap();
+    let release_bin = cache.checkout_build(&hash, &release).unwrap().unwrap();
+    assert_ne!(debug_bin.target.path(), release_bin.target.path());
+    assert_eq!(
+        fs::read(debug_bin.target.path().join("lib.ibc")).unwrap(),
+        b"debug"
+    );
+    assert_eq!(
+        fs::read(release_bin.target.path().join("lib.ibc")).unwrap(),
+        b"release"
+    );
+
+    let debug_tmp = cache.checkout_tmp(&hash, &debug).unwrap();
+    let release_tmp = cache.checkout_tmp(&hash, &release).unwrap();
+    assert_ne!(debug_tmp.root, release_tmp.root);
+
+    assert!(cache
+        .checkout_build(&hash, &BuildProfile::new("other"))
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn cache_build_lock_contended() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+    let hash = BuildHash("0123456789abcdef".to_string());
+    let profile = BuildProfile::new("debug");
+    let builds = Arc::new(Mutex::new(0));
+
+    let handles = (0..2)
+        .map(|_| {
+            let (cache, hash, profile) = (cache.clone(), hash.clone(), profile.clone());
+            let builds = builds.clone();
+            thread::spawn(move || {
+                let binary = cache
+                    .with_build_lock(&hash, &profile, || {
+                        *builds.lock().unwrap() += 1;
+                        // Give the other thread plenty of time to start waiting on us
+                        thread::sleep(Duration::from_millis(200));
+                        let out = TempDir::new("elba").unwrap();
+                        fs::write(out.path().join("lib.ibc"), b"built").unwrap();
+                        cache.store_build(out.path(), &hash, &profile)
+                    })
+                    .unwrap();
+                binary.target.path().to_owned()
+            })
+        })
+        .collect::<Vec<_>>();
+    let paths = handles
+        .into_iter()
+        .map(|h| h.join().unwrap())
+        .collect::<Vec<_>>();
+
+    // Whoever got the lock second used what the first one stored
+    assert_eq!(*builds.lock().unwrap(), 1);
+    assert_eq!(paths[0], paths[1]);
+    assert_eq!(fs::read(paths[0].join("lib.ibc")).unwrap(), b"built");
+}
+
+#[test]
+fn cache_build_keeps_lockfiles() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+    let hash = BuildHash("0123456789abcdef".to_string());
+    let profile = BuildProfile::new("debug");
+
+    // Clearing out a locked directory mustn't unlock it
+    let tmp = cache.checkout_tmp(&hash, &profile).unwrap();
+    assert!(tmp.root.join(".dirlock").exists());
+    assert!(DirLock::try_acquire(&tmp.root).unwrap().is_none());
+
+    fs::write(tmp.root.join("lib.ibc"), b"built").unwrap();
+    let binary = cache.store_build(&tmp.root, &hash, &profile).unwrap();
+    assert!(DirLock::try_acquire(binary.target.path())
+        .unwrap()
+        .is_none());
+    drop(binary);
+
+    // The copy doesn't take the temporary directory's lockfile along with it
+    let binary = cache.checkout_build(&hash, &profile).unwrap().unwrap();
+    assert_eq!(
+        fs::read(binary.target.path().join("lib.ibc")).unwrap(),
+        b"built"
+    );
+}
+
+/// The files of a stored entry, with their paths relative to the entry.
+type StoredFiles = Vec<(PathBuf, Vec<u8>)>;
+
+/// A Storage which keeps everything in memory, as a stand-in for a shared remote storage.
+#[derive(Debug, Default)]
+struct MemoryStorage {
+    entries: Mutex<HashMap<String, StoredFiles>>,
+}
+
+impl MemoryStorage {
+    fn keys(&self) -> Vec<String> {
+        let mut keys = self
+            .entries
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn contains(&self, key: &str) -> Result<bool, failure::Error> {
+        Ok(self.entries.lock().unwrap().contains_key(key))
+    }
+
+    fn fetch(&self, key: &str, dest: &Path) -> Result<bool, failure::Error> {
+        let entries = self.entries.lock().unwrap();
+        let files = match entries.get(key) {
+            Some(files) => files,
+            None => return Ok(false),
+        };
+        for (path, contents) in files {
+            fs::create_dir_all(dest.join(path).parent().unwrap())?;
+            fs::write(dest.join(path), contents)?;
+        }
+
+        Ok(true)
+    }
+
+    fn store(&self, key: &str, from: &Path) -> Result<(), failure::Error> {
+        let mut files = vec![];
+        for entry in walkdir::WalkDir::new(from) {
+            let entry = entry?;
+            if entry.file_type().is_file() && entry.file_name() != ".dirlock" {
+                let path = entry.path().strip_prefix(from).unwrap().to_owned();
+                files.push((path, fs::read(entry.path())?));
+            }
+        }
+        self.entries.lock().unwrap().insert(key.to_owned(), files);
+
+        Ok(())
+    }
+}
+
+#[test]
+fn cache_storage_builds() {
+    let storage = Arc::new(MemoryStorage::default());
+    let hash = BuildHash("0123456789abcdef".to_string());
+    let profile = BuildProfile::new("debug");
+
+    let first = TempDir::new("elba").unwrap();
+    let cache = new_cache(&first).with_storage(storage.clone());
+    let out = TempDir::new("elba").unwrap();
+    fs::create_dir_all(out.path().join("Data")).unwrap();
+    fs::write(out.path().join("Data/Lib.ibc"), b"built").unwrap();
+    drop(cache.store_build(out.path(), &hash, &profile).unwrap());
+    assert_eq!(storage.keys(), vec!["build/debug-0123456789abcdef"]);
+
+    // Another cache sharing the storage gets the build without building it
+    let second = TempDir::new("elba").unwrap();
+    let other = new_cache(&second).with_storage(storage.clone());
+    let binary = other.checkout_build(&hash, &profile).unwrap().unwrap();
+    assert!(binary.target.path().starts_with(second.path()));
+    assert_eq!(
+        fs::read(binary.target.path().join("Data/Lib.ibc")).unwrap(),
+        b"built"
+    );
+    drop(binary);
+
+    // ...while builds which were never stored still have to be built
+    assert!(other
+        .checkout_build(&BuildHash("fedcba9876543210".to_string()), &profile)
+        .unwrap()
+        .is_none());
+    assert!(!second.path().join("build/debug-fedcba9876543210").exists());
+}
+
+#[test]
+fn cache_storage_local_fs() {
+    let hash = BuildHash("0123456789abcdef".to_string());
+    let profile = BuildProfile::new("debug");
+
+    // By default, a build is stored right where it's checked out
+    let first = TempDir::new("elba").unwrap();
+    let cache = new_cache(&first);
+    let out = TempDir::new("elba").unwrap();
+    fs::write(out.path().join("lib.ibc"), b"built").unwrap();
+    let binary = cache.store_build(out.path(), &hash, &profile).unwrap();
+    assert_eq!(
+        binary.target.path(),
+        first.path().join("build/debug-0123456789abcdef")
+    );
+    drop(binary);
+
+    // Another cache can share the first one's directories as its storage
+    let second = TempDir::new("elba").unwrap();
+    let shared = LocalFs::new(&cache.layout);
+    let other = new_cache(&second).with_storage(Arc::new(shared));
+    let binary = other.checkout_build(&hash, &profile).unwrap().unwrap();
+    assert!(binary.target.path().starts_with(second.path()));
+    assert_eq!(
+        fs::read(binary.target.path().join("lib.ibc")).unwrap(),
+        b"built"
+    );
+}
+
+#[test]
+fn cache_storage_sources() {
+    let storage = Arc::new(MemoryStorage::default());
+    let first = TempDir::new("elba").unwrap();
+    let cache = new_cache(&first).with_storage(storage.clone());
+    let (loc, source) = checkout_body(&cache, pkg_tarball()).unwrap();
+    let hash = source.hash().to_owned();
+    drop(source);
+    assert_eq!(
+        storage.keys(),
+        vec![format!("src/{}", Cache::get_source_dir(&loc, true))]
+    );
+
+    // Another cache sharing the storage doesn't have to download the source, so it can even be
+    // loaded offline
+    let second = TempDir::new("elba").unwrap();
+    let other = new_cache(&second).with_storage(storage.clone());
+    let pkg = PackageId::new(
+        Name::from_str("compressed/pkg").unwrap(),
+        Resolution::Direct(loc.clone()),
+    );
+    let (_, source) = other
+        .checkout_source(&pkg, &loc, false, true, || {})
+        .unwrap();
+    assert!(source.path().starts_with(second.path()));
+    assert_eq!(source.hash(), hash);
+
+    // Without the storage, it couldn't have
+    let third = TempDir::new("elba").unwrap();
+    assert!(new_cache(&third)
+        .checkout_source(&pkg, &loc, false, true, || {})
+        .is_err());
+}
+
+fn write_package(root: &Path, name: &str) {
+    fs::write(
+        root.join("elba.toml"),
+        format!(
+            r#"[package]
+name = "{}"
+version = "1.0.0"
+authors = []
+
+[targets.lib]
+mods = []
+"#,
+            name
+        ),
+    )
+    .unwrap();
+}
+
+fn load_source(root: &Path, name: &str) -> Source {
+    let res = DirectRes::Dir {
+        path: root.to_owned(),
+    };
+    let pkg = PackageId::new(Name::from_str(name).unwrap(), res.clone().into());
+
+    Source::from_folder(&pkg, DirLock::acquire(root).unwrap(), res).unwrap()
+}
+
+/// Computes the root of the Merkle tree of some files from scratch, reading each file into memory.
+fn merkle_root(root: &Path, files: &[PathBuf]) -> String {
+    let mut level = files
+        .iter()
+        .map(|f| {
+            let path = f.strip_prefix(root).unwrap().to_string_lossy().into_owned();
+            let contents = hex::encode(Sha256::digest(&fs::read(f).unwrap()));
+            let mut hash = Sha256::new();
+            hash.input([0]);
+            hash.input(path.as_bytes());
+            hash.input([0]);
+            hash.input(contents.as_bytes());
+            hash.result().to_vec()
+        })
+        .collect::<Vec<_>>();
+
+    while level.len() > 1 {
+        let mut next = vec![];
+        for pair in level.chunks(2) {
+            if pair.len() == 2 {
+                let mut hash = Sha256::new();
+                hash.input([1]);
+                hash.input(&pair[0]);
+                hash.input(&pair[1]);
+                next.push(hash.result().to_vec());
+            } else {
+                next.push(pair[0].clone());
+            }
+        }
+        level = next;
+    }
+
+    hexify_hash(HashAlgo::CURRENT, &level[0])
+}
+
+#[test]
+fn source_hash_large() {
+    let root = TempDir::new("elba").unwrap();
+    write_package(root.path(), "large/large");
+    fs::create_dir_all(root.path().join("data")).unwrap();
+    for i in 0..16u8 {
+        let chunk = (0..1 << 18).map(|x| (x as u8) ^ i).collect::<Vec<_>>();
+        fs::write(root.path().join(format!("data/{}.bin", i)), chunk).unwrap();
+    }
+
+    let source = load_source(root.path(), "large/large");
+
+    // Hashing the streamed files should be the same as hashing their buffered contents.
+    let mut files = source
+        .meta()
+        .list_files(root.path(), root.path(), |entry| {
+            entry.file_name() != ".git"
+                && entry.file_name() != "target"
+                && entry.file_name() != ".dirlock"
+        })
+        .unwrap()
+        .filter(|x| x.file_type().is_file())
+        .map(|x| x.into_path())
+        .collect::<Vec<_>>();
+    files.sort();
+
+    assert_eq!(source.hash(), merkle_root(root.path(), &files));
+}
+
+#[test]
+fn source_custom_manifest() {
+    let root = TempDir::new("elba").unwrap();
+    write_package(root.path(), "custom/custom");
+    fs::rename(
+        root.path().join("elba.toml"),
+        root.path().join("custom.toml"),
+    )
+    .unwrap();
+
+    let res = DirectRes::Dir {
+        path: root.path().to_owned(),
+    };
+    let pkg = PackageId::new(Name::from_str("custom/custom").unwrap(), res.clone().into());
+    let lock = || DirLock::acquire(root.path()).unwrap();
+
+    let source =
+        Source::from_folder_with(&pkg, lock(), res.clone(), Path::new("custom.toml")).unwrap();
+    assert_eq!(source.meta().name(), pkg.name());
+
+    // Without an elba.toml, the error has to say which file is missing
+    let err = Source::from_folder_with(&pkg, lock(), res.clone(), Path::new("other.toml"))
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(err.contains(&root.path().join("other.toml").display().to_string()));
+
+    // A workspace member can point straight at a manifest with another name
+    let ws = TempDir::new("elba").unwrap();
+    fs::create_dir_all(ws.path().join("pkgs/a")).unwrap();
+    fs::write(
+        ws.path().join("elba.toml"),
+        "[workspace]\n\"custom/custom\" = \"pkgs/a/custom.toml\"\n",
+    )
+    .unwrap();
+    fs::rename(
+        root.path().join("custom.toml"),
+        ws.path().join("pkgs/a/custom.toml"),
+    )
+    .unwrap();
+    let res = DirectRes::Dir {
+        path: ws.path().to_owned(),
+    };
+    let pkg = PackageId::new(Name::from_str("custom/custom").unwrap(), res.clone().into());
+    let source = Source::from_folder(&pkg, DirLock::acquire(ws.path()).unwrap(), res).unwrap();
+    assert_eq!(source.meta().name(), pkg.name());
+    assert_eq!(
+        source.path().canonicalize().unwrap(),
+        ws.path().join("pkgs/a").canonicalize().unwrap()
+    );
+}
+
+#[test]
+fn hash_prefix_stable() {
+    // These hashes name directories in existing caches, so they can't change by accident
+    let mut hash = Hasher::new();
+    hash.input(b"elba");
+    assert_eq!(
+        hash.finish(),
+        format!("2-{}", hex::encode(Sha256::digest(b"elba")))
+    );
+    assert_eq!(HashAlgo::CURRENT.version(), 2);
+    assert_eq!(hexify_hash(HashAlgo::Sha256, &[0xab, 0x01]), "2-ab01");
+
+    let loc = DirectRes::from_str("dir+test").unwrap();
+    let dir = Cache::get_source_dir(&loc, false);
+    assert_eq!(
+        dir,
+        format!(
+            "2-{}",
+            hex::encode(Sha256::digest(loc.cache_key().as_bytes()))
+        )
+    );
+    assert_eq!(parse_hash(&dir).map(|x| x.0), Some(HashAlgo::Sha256));
+}
+
+#[test]
+fn hash_prefix_parse() {
+    let digest = hex::encode(Sha256::digest(b"elba"));
+    let hash = format!("2-{}", digest);
+    assert_eq!(parse_hash(&hash), Some((HashAlgo::Sha256, digest.as_str())));
+
+    // Unprefixed hashes are from before versions existed
+    assert_eq!(parse_hash(&digest), None);
+    // Unknown versions, malformed versions, and bad digests
+    assert_eq!(parse_hash(&format!("3-{}", digest)), None);
+    assert_eq!(parse_hash(&format!("+2-{}", digest)), None);
+    assert_eq!(parse_hash(&format!("-{}", digest)), None);
+    assert_eq!(parse_hash(&format!("2-{}", &digest[1..])), None);
+    assert_eq!(parse_hash(&format!("2-{}", digest.to_uppercase())), None);
+    assert_eq!(parse_hash("2-"), None);
+}
+
+#[test]
+fn cached_packages_skip_old_hashes() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+    let hash = warm_cache(&cache);
+
+    // A source cached by an older elba, under a plain hash
+    let old = cache.layout.src.join(hash.trim_start_matches("2-"));
+    fs::create_dir_all(&old).unwrap();
+    fs::write(old.join("elba.toml"), BAR_MANIFEST).unwrap();
+
+    let cached = cache.cached_packages();
+    assert!(cached.contains(&hash));
+    assert_eq!(cached.len(), 1);
+}
+
+#[test]
+fn cache_catalog() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+    let hash = warm_cache(&cache);
+    let bar = Name::from_str("no_conflict/bar").unwrap();
+
+    let mut catalog = cache.catalog().unwrap();
+    let sources = catalog.sources_of(&bar).collect::<Vec<_>>();
+    assert_eq!(sources.len(), 1);
+    assert_eq!(sources[0].version, Version::parse("1.0.0").unwrap());
+    assert_eq!(sources[0].hash, hash);
+    assert_eq!(sources[0].path, cache.layout.src.join(&hash));
+    // Directories in the build cache which aren't builds are left out
+    assert_eq!(catalog.builds().count(), 0);
+
+    // A new source and a new build show up once the catalog is refreshed
+    let (_, pkg) = checkout_body(&cache, pkg_tarball()).unwrap();
+    let build = BuildHash(hash.clone());
+    let profile = BuildProfile::new("debug");
+    let out = TempDir::new("elba").unwrap();
+    drop(cache.store_build(out.path(), &build, &profile).unwrap());
+    assert!(catalog.build(&build, &profile).is_none());
+
+    catalog.refresh(&cache).unwrap();
+    let pkgs = catalog
+        .sources_of(pkg.meta().name())
+        .map(|x| x.path.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(pkgs, vec![pkg.path().to_owned()]);
+    let built = catalog.build(&build, &profile).unwrap();
+    assert_eq!(built.profile, "debug");
+    assert_eq!(
+        built.path,
+        cache.layout.build.join(format!("debug-{}", hash))
+    );
+    assert_eq!(catalog.builds_of(&build).count(), 1);
+
+    // Sources which are gone from the cache are dropped
+    fs::remove_dir_all(cache.layout.src.join(&hash)).unwrap();
+    catalog.refresh(&cache).unwrap();
+    assert_eq!(catalog.sources_of(&bar).count(), 0);
+    assert_eq!(catalog.sources().count(), 1);
+}
+
+#[test]
+fn source_hash_order() {
+    let a = TempDir::new("elba").unwrap();
+    let b = TempDir::new("elba").unwrap();
+    write_package(a.path(), "order/order");
+    write_package(b.path(), "order/order");
+
+    let names = ["src/z.idr", "src/a.idr", "src/m/n.idr", "b.txt"];
+    for dir in &[a.path(), b.path()] {
+        fs::create_dir_all(dir.join("src/m")).unwrap();
+    }
+    for name in names.iter() {
+        fs::write(a.path().join(name), name).unwrap();
+    }
+    for name in names.iter().rev() {
+        fs::write(b.path().join(name), name).unwrap();
+    }
+
+    assert_eq!(
+        load_source(a.path(), "order/order").hash(),
+        load_source(b.path(), "order/order").hash()
+    );
+}
+
+#[test]
+fn source_hash_mtime() {
+    let a = TempDir::new("elba").unwrap();
+    let b = TempDir::new("elba").unwrap();
+    for (dir, secs) in &[(a.path(), 1_000_000), (b.path(), 2_000_000_000)] {
+        write_package(dir, "mtime/mtime");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/A.idr"), "module A").unwrap();
+
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(*secs);
+        for file in &["elba.toml", "src/A.idr"] {
+            set_file_mtime(dir.join(file), FileTime::from_system_time(mtime)).unwrap();
+        }
+    }
+
+    // Only the contents of the files count, not when they were last touched
+    assert_eq!(
+        load_source(a.path(), "mtime/mtime").hash(),
+        load_source(b.path(), "mtime/mtime").hash()
+    );
+}
+
+#[test]
+fn source_hash_incremental() {
+    let root = TempDir::new("elba").unwrap();
+    write_package(root.path(), "incr/incr");
+    fs::create_dir_all(root.path().join("src")).unwrap();
+    fs::write(root.path().join("src/A.idr"), "module A").unwrap();
+    fs::write(root.path().join("src/B.idr"), "module B").unwrap();
+
+    let first = load_source(root.path(), "incr/incr").hash().to_owned();
+    let sidecar = root.path().join("target").join(merkle::SIDECAR);
+    assert!(sidecar.exists());
+
+    let files = || {
+        vec![
+            root.path().join("elba.toml"),
+            root.path().join("src/A.idr"),
+            root.path().join("src/B.idr"),
+        ]
+        .into_iter()
+    };
+
+    // Nothing changed, so nothing has to be hashed again
+    let mut tree = merkle::Tree::load(&sidecar);
+    assert_eq!(tree.update(root.path(), files()).unwrap(), 0);
+
+    fs::write(root.path().join("src/B.idr"), "module B\n").unwrap();
+    assert_eq!(tree.update(root.path(), files()).unwrap(), 1);
+
+    let second = load_source(root.path(), "incr/incr").hash().to_owned();
+    assert_ne!(first, second);
+
+    // Throwing the sidecar away doesn't change the hash
+    fs::remove_file(&sidecar).unwrap();
+    assert_eq!(load_source(root.path(), "incr/incr").hash(), second);
+}
+
+#[test]
+fn source_hash_target_dir() {
+    let root = TempDir::new("elba").unwrap();
+    write_package(root.path(), "out/out");
+    let mut manifest = fs::read_to_string(root.path().join("elba.toml")).unwrap();
+    manifest.push_str("\n[build]\ntarget-dir = \"out\"\n");
+    fs::write(root.path().join("elba.toml"), manifest).unwrap();
+
+    let first = load_source(root.path(), "out/out").hash().to_owned();
+    assert!(root.path().join("out").join(merkle::SIDECAR).exists());
+
+    // The configured target directory is left out of the hash, wherever it is
+    fs::write(root.path().join("out/artifact"), "built").unwrap();
+    assert_eq!(load_source(root.path(), "out/out").hash(), first);
+
+    // ...and so is the vendored cache
+    fs::create_dir_all(root.path().join("vendor/src/dep")).unwrap();
+    fs::write(root.path().join("vendor/src/dep/elba.toml"), "").unwrap();
+    assert_eq!(load_source(root.path(), "out/out").hash(), first);
+}
+
+#[test]
+fn source_verify_targets() {
+    let root = TempDir::new("elba").unwrap();
+    fs::write(
+        root.path().join("elba.toml"),
+        r#"[package]
+name = "targets/pkg"
+version = "1.0.0"
+authors = []
+
+[targets.lib]
+mods = ["Data.Here", "Data.Literate", "Data.Gone"]
+
+[[targets.bin]]
+name = "here"
+main = "Main"
+
+[[targets.bin]]
+name = "gone"
+main = "Missing.main"
+"#,
+    )
+    .unwrap();
+    fs::create_dir_all(root.path().join("src/Data")).unwrap();
+    fs::write(root.path().join("src/Data/Here.idr"), "module Data.Here").unwrap();
+    fs::write(
+        root.path().join("src/Data/Literate.lidr"),
+        "> module Data.Literate",
+    )
+    .unwrap();
+    fs::write(root.path().join("src/Main.idr"), "module Main").unwrap();
+
+    let err = load_source(root.path(), "targets/pkg")
+        .verify_targets()
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("Data.Gone"));
+    assert!(err.contains("Missing.main"));
+    assert!(!err.contains("Data.Here"));
+    assert!(!err.contains("Data.Literate"));
+
+    // Checking the package out fails straight away
+    let res = DirectRes::Dir {
+        path: root.path().to_owned(),
+    };
+    let pkg = PackageId::new(Name::from_str("targets/pkg").unwrap(), res.clone().into());
+    let cache = new_cache(&TempDir::new("elba").unwrap());
+    assert!(cache
+        .checkout_source(&pkg, &res, false, false, || {})
+        .is_err());
+
+    fs::create_dir_all(root.path().join("src/Data")).unwrap();
+    fs::write(root.path().join("src/Data/Gone.idr"), "module Data.Gone").unwrap();
+    fs::write(root.path().join("src/Missing.idr"), "module Missing").unwrap();
+    load_source(root.path(), "targets/pkg")
+        .verify_targets()
+        .unwrap();
+    assert!(cache
+        .checkout_source(&pkg, &res, false, false, || {})
+        .is_ok());
+}
+
+#[test]
+fn source_graph_cycle() {
+    let dirs = (0..3)
+        .map(|_| TempDir::new("elba").unwrap())
+        .collect::<Vec<_>>();
+    let names = ["cycle/a", "cycle/b", "cycle/c"];
+
+    let mut inner = petgraph::Graph::new();
+    let nodes = dirs
+        .iter()
+        .zip(names.iter())
+        .map(|(dir, name)| {
+            write_package(dir.path(), name);
+            inner.add_node(load_source(dir.path(), name))
+        })
+        .collect::<Vec<_>>();
+    inner.add_edge(nodes[0], nodes[1], ());
+    inner.add_edge(nodes[1], nodes[2], ());
+    let mut graph = Graph::new(inner);
+    assert_eq!(graph.detect_cycle(), None);
+    assert!(graph.check_acyclic(|s| s.meta().name().to_string()).is_ok());
+
+    graph.inner.add_edge(nodes[2], nodes[0], ());
+    assert_eq!(
+        graph.detect_cycle(),
+        Some(vec![nodes[0], nodes[1], nodes[2], nodes[0]])
+    );
+    let err = graph
+        .check_acyclic(|s| s.meta().name().to_string())
+        .unwrap_err()
+        .to_string();
+    assert!(
+        err.contains("cycle/a -> cycle/b -> cycle/c -> cycle/a"),
+        "{}",
+        err
+    );
+}
+
+/// Builds a graph of the root package `sources[0]` which depends on `sources[1]` and
+/// `sources[2]`, both of which depend on `sources[3]`, adding nodes and edges in the given order.
+fn diamond(sources: &[Source], order: &[usize]) -> Graph<Source> {
+    let mut inner = petgraph::Graph::new();
+    let mut nodes = vec![None; sources.len()];
+    for &ix in order {
+        nodes[ix] = Some(inner.add_node(sources[ix].clone()));
+    }
+    let edges = [(0, 1), (0, 2), (1, 3), (2, 3)];
+    for &(from, to) in order
+        .iter()
+        .flat_map(|&ix| edges.iter().filter(move |e| e.0 == ix))
+    {
+        inner.add_edge(nodes[from].unwrap(), nodes[to].unwrap(), ());
+    }
+
+    Graph::new(inner)
+}
+
+#[test]
+fn source_graph_dot() {
+    let manifests = [
+        (
+            "dot/root",
+            "1.0.0",
+            "[dependencies]\n\"dot/lib\" = \"1.0.0\"\n\"dot/other\" = { path = \"../other\" }\n\n\
+             [dev_dependencies]\n\"dot/test\" = \"0.1.0\"\n",
+        ),
+        ("dot/lib", "1.0.0", ""),
+        (
+            "dot/other",
+            "1.0.0",
+            "[dependencies]\n\"dot/lib\" = \"2.0.0\"\n",
+        ),
+        ("dot/lib", "2.0.0", ""),
+        ("dot/test", "0.1.0", ""),
+    ];
+    let dirs = (0..manifests.len())
+        .map(|_| TempDir::new("elba").unwrap())
+        .collect::<Vec<_>>();
+    let sources = dirs
+        .iter()
+        .zip(manifests.iter())
+        .map(|(dir, (name, version, deps))| {
+            let manifest = format!(
+                "[package]\nname = \"{}\"\nversion = \"{}\"\nauthors = []\n\n{}\n[targets.lib]\nmods = []\n",
+                name, version, deps
+            );
+            fs::write(dir.path().join("elba.toml"), manifest).unwrap();
+            load_source(dir.path(), name)
+        })
+        .collect::<Vec<_>>();
+
+    let mut inner = petgraph::Graph::new();
+    let nodes = sources
+        .iter()
+        .map(|x| inner.add_node(x.clone()))
+        .collect::<Vec<_>>();
+    for &(from, to) in &[(0, 1), (0, 2), (2, 3), (0, 4)] {
+        inner.add_edge(nodes[from], nodes[to], ());
+    }
+    let dot = Graph::new(inner).to_dot();
+
+    let lines = dot.lines().collect::<Vec<_>>();
+    assert_eq!(lines.first(), Some(&"digraph {"));
+    assert_eq!(lines.last(), Some(&"}"));
+    for line in &[
+        "    0 [label=\"dot/root 1.0.0\"];",
+        "    2 [label=\"dot/other 1.0.0\"];",
+        // Both copies of dot/lib stand out
+        "    1 [label=\"dot/lib 1.0.0\", color=red];",
+        "    3 [label=\"dot/lib 2.0.0\", color=red];",
+        "    0 -> 1 [label=\">=1.0.0 <2.0.0\"];",
+        "    0 -> 2 [label=\"dir+../other\"];",
+        "    2 -> 3 [label=\">=2.0.0 <3.0.0\"];",
+        "    0 -> 4 [label=\">=0.1.0 <0.2.0\", style=dashed];",
+    ] {
+        assert!(lines.contains(line), "{} isn't in:\n{}", line, dot);
+    }
+}
+
+#[test]
+fn source_graph_tree() {
+    let names = [
+        "tree/root",
+        "tree/right",
+        "tree/left",
+        "tree/base",
+        "tree/leaf",
+    ];
+    let dirs = (0..names.len())
+        .map(|_| TempDir::new("elba").unwrap())
+        .collect::<Vec<_>>();
+    let mut inner = petgraph::Graph::new();
+    let nodes = dirs
+        .iter()
+        .zip(names.iter())
+        .map(|(dir, name)| {
+            write_package(dir.path(), name);
+            inner.add_node(load_source(dir.path(), name))
+        })
+        .collect::<Vec<_>>();
+    for &(from, to) in &[(0, 1), (0, 2), (1, 3), (2, 3), (3, 4)] {
+        inner.add_edge(nodes[from], nodes[to], ());
+    }
+    let graph = Graph::new(inner);
+
+    let tree = |depth, invert: Option<&str>| {
+        let opts = TreeOptions {
+            depth,
+            invert: invert.map(|x| Name::from_str(x).unwrap()),
+        };
+        graph.to_tree(&opts)
+    };
+
+    // Children are sorted, and the second time tree/base comes up its dependencies aren't shown
+    assert_eq!(
+        tree(None, None).unwrap(),
+        "tree/root 1.0.0
+├── tree/left 1.0.0
+│   └── tree/base 1.0.0
+│       └── tree/leaf 1.0.0
+└── tree/right 1.0.0
+    └── tree/base 1.0.0 (*)
+"
+    );
+    assert_eq!(
+        tree(Some(1), None).unwrap(),
+        "tree/root 1.0.0
+├── tree/left 1.0.0
+└── tree/right 1.0.0
+"
+    );
+    assert_eq!(
+        tree(None, Some("tree/leaf")).unwrap(),
+        "tree/leaf 1.0.0
+└── tree/base 1.0.0
+    ├── tree/left 1.0.0
+    │   └── tree/root 1.0.0
+    └── tree/right 1.0.0
+        └── tree/root 1.0.0
+"
+    );
+    assert_eq!(
+        tree(Some(1), Some("tree/base")).unwrap(),
+        "tree/base 1.0.0
+├── tree/left 1.0.0
+└── tree/right 1.0.0
+"
+    );
+    assert!(tree(None, Some("tree/missing")).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn build_hash_stable_order() {
+    let root = TempDir::new("elba").unwrap();
+    let dirs = (0..4)
+        .map(|_| TempDir::new("elba").unwrap())
+        .collect::<Vec<_>>();
+    let sources = dirs
+        .iter()
+        .zip(["order/root", "order/left", "order/right", "order/base"].iter())
+        .map(|(dir, name)| {
+            write_package(dir.path(), name);
+            load_source(dir.path(), name)
+        })
+        .collect::<Vec<_>>();
+
+    let ctx = BuildContext {
+        backend: Backend::default(),
+        codegen: false,
+        // Any program will do, since the compiler never gets run
+        compiler: Compiler::new("true").unwrap(),
+        cache: new_cache(&root),
+        threads: 1,
+        opts: vec![],
+        profile: BuildProfile::new("debug"),
+        flags: IndexMap::new(),
+    };
+    let targets = Targets::new(vec![Target::Lib(false)]);
+    let hash =
+        |graph: &Graph<Source>| BuildHash::new(&sources[0], graph, &targets, &ctx, false).unwrap();
+
+    let first = diamond(&sources, &[0, 1, 2, 3]);
+    let second = diamond(&sources, &[3, 2, 0, 1]);
+    let third = diamond(&sources, &[2, 3, 1, 0]);
+    assert_eq!(hash(&first), hash(&second));
+    assert_eq!(hash(&first), hash(&third));
+
+    // The sorted order is topological: the root comes first and the shared dependency last
+    let order = second
+        .sub_tree_sorted(second.find_id(&sources[0]).unwrap(), |s| {
+            s.hash().to_owned()
+        })
+        .into_iter()
+        .map(|(_, s)| s.meta().name().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(order.first().map(|x| x.as_str()), Some("order/root"));
+    assert_eq!(order.last().map(|x| x.as_str()), Some("order/base"));
+    assert_eq!(order.len(), 4);
+
+    // Flags for a dependency change the hash of everything which depends on it
+    let mut flagged = ctx.clone();
+    flagged.flags.insert(
+        Name::from_str("order/base").unwrap(),
+        vec!["--total".to_owned()],
+    );
+    assert_ne!(
+        hash(&first),
+        BuildHash::new(&sources[0], &first, &targets, &flagged, false).unwrap()
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn build_hash_not_in_graph() {
+    let root = TempDir::new("elba").unwrap();
+    let dirs = (0..2)
+        .map(|_| TempDir::new("elba").unwrap())
+        .collect::<Vec<_>>();
+    let sources = dirs
+        .iter()
+        .zip(["member/pkg", "stray/pkg"].iter())
+        .map(|(dir, name)| {
+            write_package(dir.path(), name);
+            load_source(dir.path(), name)
+        })
+        .collect::<Vec<_>>();
+    let mut inner = petgraph::Graph::new();
+    inner.add_node(sources[0].clone());
+    let graph = Graph::new(inner);
+
+    let ctx = BuildContext {
+        backend: Backend::default(),
+        codegen: false,
+        compiler: Compiler::new("true").unwrap(),
+        cache: new_cache(&root),
+        threads: 1,
+        opts: vec![],
+        profile: BuildProfile::new("debug"),
+        flags: IndexMap::new(),
+    };
+    let targets = Targets::new(vec![Target::Lib(false)]);
+
+    assert!(BuildHash::new(&sources[0], &graph, &targets, &ctx, false).is_ok());
+    let err = BuildHash::new(&sources[1], &graph, &targets, &ctx, false).unwrap_err();
+    assert!(err.to_string().contains("stray/pkg"), "{}", err);
+    assert!(ctx
+        .cache
+        .locate_build(&sources[1], &graph, &targets, &ctx)
+        .is_err());
+
+    // Nodes can be found from a Summary alone
+    let summary = |ix: usize, name: &str| {
+        let res = DirectRes::Dir {
+            path: dirs[ix].path().to_owned(),
+        };
+        let pkg = PackageId::new(Name::from_str(name).unwrap(), res.into());
+        Summary::new(pkg, Version::parse("1.0.0").unwrap())
+    };
+    let (ix, found) = graph.get_by_summary(&summary(0, "member/pkg")).unwrap();
+    assert_eq!(ix.index(), 0);
+    assert_eq!(found.hash(), sources[0].hash());
+    assert!(graph.get_by_summary(&summary(1, "stray/pkg")).is_none());
+}
+
+#[cfg(unix)]
+#[test]
+fn build_hash_env() {
+    // The variable is changed in between loads below, which is only safe with no other tests
+    // running in the same process
+    if rerun_with_env("cache::build_hash_env", &[]) {
+        return;
+    }
+
+    let root = TempDir::new("elba").unwrap();
+    let pkg = TempDir::new("elba").unwrap();
+    write_package(pkg.path(), "env/pkg");
+    let manifest = fs::read_to_string(pkg.path().join("elba.toml")).unwrap();
+    fs::write(
+        pkg.path().join("elba.toml"),
+        format!(
+            "{}\n[build.env]\nGREETING = \"hello ${{ELBA_TEST_BUILD_HASH_ENV}}\"\n",
+            manifest
+        ),
+    )
+    .unwrap();
+
+    let ctx = BuildContext {
+        backend: Backend::default(),
+        codegen: false,
+        compiler: Compiler::new("true").unwrap(),
+        cache: new_cache(&root),
+        threads: 1,
+        opts: vec![],
+        profile: BuildProfile::new("debug"),
+        flags: IndexMap::new(),
+    };
+    let targets = Targets::new(vec![Target::Lib(false)]);
+    let build_hash = |value: &str| {
+        std::env::set_var("ELBA_TEST_BUILD_HASH_ENV", value);
+        let source = load_source(pkg.path(), "env/pkg");
+        std::env::remove_var("ELBA_TEST_BUILD_HASH_ENV");
+
+        let mut inner = petgraph::Graph::new();
+        inner.add_node(source.clone());
+        let hash = BuildHash::new(&source, &Graph::new(inner), &targets, &ctx, false).unwrap();
+        // The source holds the lock on its directory, so it has to go before the next load
+        (
+            source.hash().to_string(),
+            source.env()["GREETING"].clone(),
+            hash,
+        )
+    };
+
+    let (first, first_env, first_hash) = build_hash("world");
+    assert_eq!(first_env, "hello world");
+    let (second, second_env, second_hash) = build_hash("there");
+    assert_eq!(second_env, "hello there");
+
+    // The files didn't change, but the environment did
+    assert_eq!(first, second);
+    assert_ne!(first_hash, second_hash);
+    assert_eq!(build_hash("world").2, first_hash);
+}
+
+#[test]
+fn build_hash_examples() {
+    let root = TempDir::new("elba").unwrap();
+    let pkg = TempDir::new("elba").unwrap();
+    fs::write(
+        pkg.path().join("elba.toml"),
+        "[package]\nname = \"examples/pkg\"\nversion = \"1.0.0\"\nauthors = []\n\n\
+         [targets.lib]\nmods = []\n\n\
+         [[targets.test]]\nmain = \"Test\"\n\n\
+         [[targets.example]]\nname = \"demo\"\nmain = \"Demo\"\n",
+    )
+    .unwrap();
+    let source = load_source(pkg.path(), "examples/pkg");
+    let mut inner = petgraph::Graph::new();
+    inner.add_node(source.clone());
+    let graph = Graph::new(inner);
+    let ctx = BuildContext {
+        backend: Backend::default(),
+        codegen: false,
+        compiler: Compiler::new("true").unwrap(),
+        cache: new_cache(&root),
+        threads: 1,
+        opts: vec![],
+        profile: BuildProfile::new("debug"),
+        flags: IndexMap::new(),
+    };
+    let hash = |targets: Vec<Target>| {
+        BuildHash::for_job(&source, &graph, &Targets::new(targets), &ctx).unwrap()
+    };
+
+    // Builds with tests or examples never stand in for the plain library build, or each other
+    let lib = hash(vec![Target::Lib(false)]);
+    let test = hash(vec![Target::Lib(false), Target::Test(0)]);
+    let example = hash(vec![Target::Lib(false), Target::Example(0)]);
+    assert_ne!(lib, test);
+    assert_ne!(lib, example);
+    assert_ne!(test, example);
+}
+
+#[cfg(unix)]
+#[test]
+fn build_hash_flags() {
+    let root = TempDir::new("elba").unwrap();
+    let pkg = TempDir::new("elba").unwrap();
+    write_package(pkg.path(), "flags/pkg");
+    let source = load_source(pkg.path(), "flags/pkg");
+    let mut inner = petgraph::Graph::new();
+    inner.add_node(source.clone());
+    let graph = Graph::new(inner);
+    let targets = Targets::new(vec![Target::Lib(false)]);
+
+    let ctx = |flags: &[&str]| {
+        let mut config = BuildConfig::default();
+        config.flags.insert(
+            Name::from_str("flags/pkg").unwrap(),
+            flags.iter().map(|x| x.to_string()).collect(),
+        );
+        BuildContext {
+            backend: Backend::default(),
+            codegen: false,
+            compiler: Compiler::new("true").unwrap(),
+            cache: new_cache(&root),
+            threads: 1,
+            opts: vec![],
+            profile: BuildProfile::new("debug"),
+            flags: config.flags(),
+        }
+    };
+    let hash = |ctx: &BuildContext| BuildHash::for_job(&source, &graph, &targets, ctx).unwrap();
+
+    let total = ctx(&["--total", "-p  contrib"]);
+    let reordered = ctx(&["-p contrib", "--total", "--total"]);
+    let other = ctx(&["--total"]);
+    assert_eq!(hash(&total), hash(&reordered));
+    assert_ne!(hash(&total), hash(&other));
+    assert_ne!(hash(&other), hash(&ctx(&[])));
+    // They're passed to the compiler as they're written, though
+    assert_eq!(
+        reordered
+            .flag_args(source.meta().name())
+            .collect::<Vec<_>>(),
+        vec!["-p", "contrib", "--total", "--total"]
+    );
+
+    // The same flags in any order share a build, but other flags don't
+    let out = TempDir::new("elba").unwrap();
+    drop(
+        total
+            .cache
+            .store_build(out.path(), &hash(&total), &total.profile)
+            .unwrap(),
+    );
+    drop(
+        reordered
+            .cache
+            .locate_build(&source, &graph, &targets, &reordered)
+            .unwrap()
+            .unwrap(),
+    );
+    assert!(other
+        .cache
+        .locate_build(&source, &graph, &targets, &other)
+        .unwrap()
+        .is_none());
+}
+
+#[cfg(unix)]
+#[test]
+fn cache_locate_build() {
+    let root = TempDir::new("elba").unwrap();
+    let pkg = TempDir::new("elba").unwrap();
+    write_package(pkg.path(), "locate/pkg");
+    let source = load_source(pkg.path(), "locate/pkg");
+
+    let ctx = BuildContext {
+        backend: Backend::default(),
+        codegen: false,
+        compiler: Compiler::new("true").unwrap(),
+        cache: new_cache(&root),
+        threads: 1,
+        opts: vec![],
+        profile: BuildProfile::new("debug"),
+        flags: IndexMap::new(),
+    };
+    let targets = Targets::new(vec![Target::Lib(false)]);
+    let mut inner = petgraph::Graph::new();
+    inner.add_node(source.clone());
+    let graph = Graph::new(inner);
+
+    assert!(ctx
+        .cache
+        .locate_build(&source, &graph, &targets, &ctx)
+        .unwrap()
+        .is_none());
+
+    let out = TempDir::new("elba").unwrap();
+    fs::write(out.path().join("lib.ibc"), b"built").unwrap();
+    let hash = BuildHash::for_job(&source, &graph, &targets, &ctx).unwrap();
+    drop(
+        ctx.cache
+            .store_build(out.path(), &hash, &ctx.profile)
+            .unwrap(),
+    );
+
+    let binary = ctx
+        .cache
+        .locate_build(&source, &graph, &targets, &ctx)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        fs::read(binary.target.path().join("lib.ibc")).unwrap(),
+        b"built"
+    );
+    drop(binary);
+
+    // A build with other targets is a different build
+    let other = Targets::new(vec![Target::Lib(true)]);
+    assert!(ctx
+        .cache
+        .locate_build(&source, &graph, &other, &ctx)
+        .unwrap()
+        .is_none());
+}
+
+#[cfg(unix)]
+#[test]
+fn build_report_cache_hits() {
+    let root = TempDir::new("elba").unwrap();
+    let out = TempDir::new("elba").unwrap();
+    let dirs = (0..2)
+        .map(|_| TempDir::new("elba").unwrap())
+        .collect::<Vec<_>>();
+    let sources = dirs
+        .iter()
+        .zip(["report/root", "report/dep"].iter())
+        .map(|(dir, name)| {
+            write_package(dir.path(), name);
+            fs::create_dir_all(dir.path().join("src")).unwrap();
+            load_source(dir.path(), name)
+        })
+        .collect::<Vec<_>>();
+
+    let ctx = BuildContext {
+        backend: Backend::default(),
+        codegen: false,
+        // The root doesn't have any modules, so the compiler is never run
+        compiler: Compiler::new("true").unwrap(),
+        cache: new_cache(&root),
+        threads: 1,
+        opts: vec![],
+        profile: BuildProfile::new("debug"),
+        flags: IndexMap::new(),
+    };
+    let targets = Targets::new(vec![Target::Lib(false)]);
+
+    let mut inner = petgraph::Graph::new();
+    let root_node = inner.add_node(sources[0].clone());
+    let dep_node = inner.add_node(sources[1].clone());
+    inner.add_edge(root_node, dep_node, ());
+    let graph = Graph::new(inner);
+
+    // Only the dependency has been built before
+    let built = TempDir::new("elba").unwrap();
+    let dep_hash = BuildHash::new(&sources[1], &graph, &targets, &ctx, false).unwrap();
+    ctx.cache
+        .store_build(built.path(), &dep_hash, &ctx.profile)
+        .unwrap();
+
+    let layout = OutputLayout::new(DirLock::acquire(out.path()).unwrap()).unwrap();
+    let queue = JobQueue::new(graph, &targets, Some(layout), ctx, &LOGGER, shell()).unwrap();
+    let report = queue.exec().unwrap().2;
+
+    let dep = &report.packages[&sources[1].summary()];
+    assert!(dep.cached);
+    assert_eq!(dep.hash, dep_hash.0);
+    assert_eq!(dep.compile, Duration::from_secs(0));
+    assert!(!report.packages[&sources[0].summary()].cached);
+
+    let written: BuildReport =
+        serde_json::from_slice(&fs::read(out.path().join(BUILD_REPORT_FILE)).unwrap()).unwrap();
+    assert_eq!(written.packages.len(), 2);
+    assert!(written.packages[&sources[1].summary()].cached);
+    assert_eq!(
+        written.packages[&sources[1].summary()].compile,
+        Duration::from_secs(0)
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn build_local_cache() {
+    let root = TempDir::new("elba").unwrap();
+    let out = TempDir::new("elba").unwrap();
+    let dirs = (0..2)
+        .map(|_| TempDir::new("elba").unwrap())
+        .collect::<Vec<_>>();
+    let sources = dirs
+        .iter()
+        .zip(["local/root", "local/dep"].iter())
+        .map(|(dir, name)| {
+            write_package(dir.path(), name);
+            fs::create_dir_all(dir.path().join("src")).unwrap();
+            load_source(dir.path(), name)
+        })
+        .collect::<Vec<_>>();
+    let targets = Targets::new(vec![Target::Lib(false)]);
+
+    let mut inner = petgraph::Graph::new();
+    let root_node = inner.add_node(sources[0].clone());
+    let dep_node = inner.add_node(sources[1].clone());
+    inner.add_edge(root_node, dep_node, ());
+    let graph = Graph::new(inner);
+
+    let build = || {
+        let layout = OutputLayout::new(DirLock::acquire(out.path()).unwrap()).unwrap();
+        let ctx = BuildContext {
+            backend: Backend::default(),
+            codegen: false,
+            // Neither package has any modules, so the compiler is never run
+            compiler: Compiler::new("true").unwrap(),
+            cache: new_cache(&root).with_local_builds(&layout.deps),
+            threads: 1,
+            opts: vec![],
+            profile: BuildProfile::new("debug"),
+            flags: IndexMap::new(),
+        };
+        let queue =
+            JobQueue::new(graph.clone(), &targets, Some(layout), ctx, &LOGGER, shell()).unwrap();
+        queue.exec().unwrap().2
+    };
+
+    let report = build();
+    assert!(!report.packages[&sources[1].summary()].cached);
+
+    // The dependency's build stays in the project, and the global cache never sees it
+    let hash = &report.packages[&sources[1].summary()].hash;
+    assert!(out
+        .path()
+        .join("deps")
+        .join(format!("debug-{}", hash))
+        .exists());
+    assert_eq!(fs::read_dir(root.path().join("build")).unwrap().count(), 0);
+
+    // ...but the next build of the project still reuses it. The root's own outputs are thrown
+    // away first, since otherwise they're up to date and its dependencies aren't even looked at
+    fs::remove_file(out.path().join("hash")).unwrap();
+    let report = build();
+    let dep = &report.packages[&sources[1].summary()];
+    assert!(dep.cached);
+    assert_eq!(&dep.hash, hash);
+    assert_eq!(fs::read_dir(root.path().join("build")).unwrap().count(), 0);
+}
+
+#[cfg(unix)]
+#[test]
+fn build_shared_across_projects() {
+    let root = TempDir::new("elba").unwrap();
+    let dirs = (0..4)
+        .map(|_| TempDir::new("elba").unwrap())
+        .collect::<Vec<_>>();
+    let sources = dirs
+        .iter()
+        .zip(["share/app", "share/tool", "share/mid", "share/leaf"].iter())
+        .map(|(dir, name)| {
+            write_package(dir.path(), name);
+            fs::create_dir_all(dir.path().join("src")).unwrap();
+            load_source(dir.path(), name)
+        })
+        .collect::<Vec<_>>();
+    let targets = Targets::new(vec![Target::Lib(false)]);
+
+    // Two unrelated projects which have nothing in common but the leaf: one depends on it
+    // directly, and the other through another package
+    let mut inner = petgraph::Graph::new();
+    let app = inner.add_node(sources[0].clone());
+    let leaf = inner.add_node(sources[3].clone());
+    inner.add_edge(app, leaf, ());
+    let app = Graph::new(inner);
+
+    let mut inner = petgraph::Graph::new();
+    let tool = inner.add_node(sources[1].clone());
+    let mid = inner.add_node(sources[2].clone());
+    let leaf = inner.add_node(sources[3].clone());
+    inner.add_edge(tool, mid, ());
+    inner.add_edge(mid, leaf, ());
+    let tool = Graph::new(inner);
+
+    let build = |graph: &Graph<Source>| {
+        let out = TempDir::new("elba").unwrap();
+        let layout = OutputLayout::new(DirLock::acquire(out.path()).unwrap()).unwrap();
+        let ctx = BuildContext {
+            backend: Backend::default(),
+            codegen: false,
+            // None of the packages have any modules, so the compiler is never run
+            compiler: Compiler::new("true").unwrap(),
+            cache: new_cache(&root),
+            threads: 1,
+            opts: vec![],
+            profile: BuildProfile::new("debug"),
+            flags: IndexMap::new(),
+        };
+        let queue =
+            JobQueue::new(graph.clone(), &targets, Some(layout), ctx, &LOGGER, shell()).unwrap();
+        queue.exec().unwrap().2
+    };
+
+    let first = build(&app);
+    let leaf = &first.packages[&sources[3].summary()];
+    assert!(!leaf.cached);
+
+    // The leaf's build only depends on the leaf itself, so the other project reuses it
+    let second = build(&tool);
+    assert!(second.packages[&sources[3].summary()].cached);
+    assert_eq!(second.packages[&sources[3].summary()].hash, leaf.hash);
+    assert!(!second.packages[&sources[2].summary()].cached);
+    assert_eq!(fs::read_dir(root.path().join("build")).unwrap().count(), 2);
+}
+
+#[cfg(unix)]
+#[test]
+fn build_excluded_from_cache() {
+    let root = TempDir::new("elba").unwrap();
+    let out = TempDir::new("elba").unwrap();
+    let dirs = (0..3)
+        .map(|_| TempDir::new("elba").unwrap())
+        .collect::<Vec<_>>();
+    let names = ["excluded/root", "excluded/dev", "excluded/leaf"];
+    for (dir, name) in dirs.iter().zip(names.iter()) {
+        write_package(dir.path(), name);
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+    }
+    // The package in the middle is under development
+    let manifest = fs::read_to_string(dirs[1].path().join("elba.toml")).unwrap();
+    fs::write(
+        dirs[1].path().join("elba.toml"),
+        manifest.replace("authors = []", "authors = []\nexclude-from-cache = true"),
+    )
+    .unwrap();
+    let targets = Targets::new(vec![Target::Lib(false)]);
+
+    let build = || {
+        let sources = dirs
+            .iter()
+            .zip(names.iter())
+            .map(|(dir, name)| load_source(dir.path(), name))
+            .collect::<Vec<_>>();
+        let mut inner = petgraph::Graph::new();
+        let nodes = sources
+            .iter()
+            .map(|s| inner.add_node(s.clone()))
+            .collect::<Vec<_>>();
+        inner.add_edge(nodes[0], nodes[1], ());
+        inner.add_edge(nodes[1], nodes[2], ());
+        let graph = Graph::new(inner);
+
+        let layout = OutputLayout::new(DirLock::acquire(out.path()).unwrap()).unwrap();
+        let ctx = BuildContext {
+            backend: Backend::default(),
+            codegen: false,
+            // None of the packages have any modules, so the compiler is never run
+            compiler: Compiler::new("true").unwrap(),
+            cache: new_cache(&root),
+            threads: 1,
+            opts: vec![],
+            profile: BuildProfile::new("debug"),
+            flags: IndexMap::new(),
+        };
+        let queue = JobQueue::new(graph, &targets, Some(layout), ctx, &LOGGER, shell()).unwrap();
+        let report = queue.exec().unwrap().2;
+        names
+            .iter()
+            .zip(sources.iter())
+            .map(|(name, s)| (*name, report.packages[&s.summary()].cached))
+            .collect::<Vec<_>>()
+    };
+
+    let cached =
+        |name: &str, report: &[(&str, bool)]| report.iter().find(|(n, _)| *n == name).unwrap().1;
+    let first = build();
+    assert!(first.iter().all(|(_, cached)| !cached));
+
+    // Nothing changed, but the package and everything built on top of it are built again anyways.
+    // Its own dependency is cached like anything else.
+    let second = build();
+    assert!(!cached("excluded/root", &second));
+    assert!(!cached("excluded/dev", &second));
+    assert!(cached("excluded/leaf", &second));
+    assert_eq!(fs::read_dir(root.path().join("build")).unwrap().count(), 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn cache_ide_paths() {
+    let root = TempDir::new("elba").unwrap();
+    let out = TempDir::new("elba").unwrap();
+    let dirs = (0..3)
+        .map(|_| TempDir::new("elba").unwrap())
+        .collect::<Vec<_>>();
+    let sources = dirs
+        .iter()
+        .zip(["ide/root", "ide/mid", "ide/leaf"].iter())
+        .map(|(dir, name)| {
+            write_package(dir.path(), name);
+            fs::create_dir_all(dir.path().join("src")).unwrap();
+            load_source(dir.path(), name)
+        })
+        .collect::<Vec<_>>();
+
+    // root depends on mid and leaf, and mid depends on leaf too
+    let mut inner = petgraph::Graph::new();
+    let nodes = sources
+        .iter()
+        .map(|s| inner.add_node(s.clone()))
+        .collect::<Vec<_>>();
+    inner.add_edge(nodes[0], nodes[1], ());
+    inner.add_edge(nodes[0], nodes[2], ());
+    inner.add_edge(nodes[1], nodes[2], ());
+    let graph = Graph::new(inner);
+    let summary = |ix: usize| {
+        let res = DirectRes::Dir {
+            path: dirs[ix].path().to_owned(),
+        };
+        let meta = sources[ix].meta();
+        Summary::new(
+            PackageId::new(meta.name().clone(), res.into()),
+            meta.version().clone(),
+        )
+    };
+
+    let ctx = || BuildContext {
+        backend: Backend::default(),
+        codegen: false,
+        // None of the packages have any modules, so the compiler is never run
+        compiler: Compiler::new("true").unwrap(),
+        cache: new_cache(&root),
+        threads: 1,
+        opts: vec![],
+        profile: BuildProfile::new("debug"),
+        flags: IndexMap::new(),
+    };
+
+    // Before anything's built, there are only the sources of the dependencies
+    let bcx = ctx();
+    let paths = bcx
+        .cache
+        .resolve_path_for_ide(&summary(0), &graph, &bcx)
+        .unwrap();
+    assert_eq!(
+        paths,
+        vec![dirs[2].path().join("src"), dirs[1].path().join("src")]
+    );
+
+    let layout = OutputLayout::new(DirLock::acquire(out.path()).unwrap()).unwrap();
+    let targets = Targets::new(vec![Target::Lib(false)]);
+    let queue = JobQueue::new(
+        graph.clone(),
+        &targets,
+        Some(layout),
+        ctx(),
+        &LOGGER,
+        shell(),
+    )
+    .unwrap();
+    let report = queue.exec().unwrap().2;
+
+    // ...and afterwards, each of their builds comes right after their sources
+    let build = |ix: usize| {
+        let hash = &report.packages[&sources[ix].summary()].hash;
+        root.path().join("build").join(format!("debug-{}", hash))
+    };
+    let bcx = ctx();
+    let paths = bcx
+        .cache
+        .resolve_path_for_ide(&summary(0), &graph, &bcx)
+        .unwrap();
+    assert_eq!(
+        paths,
+        vec![
+            dirs[2].path().join("src"),
+            build(2),
+            dirs[1].path().join("src"),
+            build(1),
+        ]
+    );
+    assert!(paths.iter().all(|p| p.exists()));
+
+    // A package in the middle of the graph only gets its own dependencies
+    let paths = bcx
+        .cache
+        .resolve_path_for_ide(&summary(1), &graph, &bcx)
+        .unwrap();
+    assert_eq!(paths, vec![dirs[2].path().join("src"), build(2)]);
+}
+
+#[cfg(unix)]
+#[test]
+fn build_skips_virtual_packages() {
+    let root = TempDir::new("elba").unwrap();
+    let out = TempDir::new("elba").unwrap();
+    let dirs = (0..3)
+        .map(|_| TempDir::new("elba").unwrap())
+        .collect::<Vec<_>>();
+    let names = ["virt/root", "virt/meta", "virt/dep"];
+    for (dir, name) in dirs.iter().zip(names.iter()) {
+        write_package(dir.path(), name);
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+    }
+    // The middle package only exists to pull in its dependencies
+    fs::write(
+        dirs[1].path().join("elba.toml"),
+        "[package]\nname = \"virt/meta\"\nversion = \"1.0.0\"\nauthors = []\nbuild = false\n",
+    )
+    .unwrap();
+    let sources = dirs
+        .iter()
+        .zip(names.iter())
+        .map(|(dir, name)| load_source(dir.path(), name))
+        .collect::<Vec<_>>();
+    assert!(sources[1].meta().is_virtual());
+    sources[1].verify_targets().unwrap();
+
+    let ctx = BuildContext {
+        backend: Backend::default(),
+        codegen: false,
+        compiler: Compiler::new("true").unwrap(),
+        cache: new_cache(&root),
+        threads: 1,
+        opts: vec![],
+        profile: BuildProfile::new("debug"),
+        flags: IndexMap::new(),
+    };
+    let targets = Targets::new(vec![Target::Lib(false)]);
+
+    let mut inner = petgraph::Graph::new();
+    let root_node = inner.add_node(sources[0].clone());
+    let meta_node = inner.add_node(sources[1].clone());
+    let dep_node = inner.add_node(sources[2].clone());
+    inner.add_edge(root_node, meta_node, ());
+    inner.add_edge(meta_node, dep_node, ());
+    let graph = Graph::new(inner);
+
+    let built = TempDir::new("elba").unwrap();
+    let dep_hash = BuildHash::new(&sources[2], &graph, &targets, &ctx, false).unwrap();
+    ctx.cache
+        .store_build(built.path(), &dep_hash, &ctx.profile)
+        .unwrap();
+
+    let layout = OutputLayout::new(DirLock::acquire(out.path()).unwrap()).unwrap();
+    let queue = JobQueue::new(graph, &targets, Some(layout), ctx, &LOGGER, shell()).unwrap();
+
+    // The root depends on the dependency of the virtual package directly
+    let children = queue
+        .graph
+        .children(root_node)
+        .map(|(ix, _)| ix)
+        .collect::<Vec<_>>();
+    assert_eq!(children, vec![dep_node]);
+    assert!(queue.graph[meta_node].work.is_none());
+
+    let report = queue.exec().unwrap().2;
+    assert!(!report.packages.contains_key(&sources[1].summary()));
+    assert!(report.packages[&sources[2].summary()].cached);
+}
+
+/// Builds a package with a lib target and the given build scripts as the root of a graph,
+/// returning the log its scripts wrote to and the result of the build.
+#[cfg(unix)]
+fn build_with_scripts(scripts: &str) -> (String, Result<(), String>) {
+    let root = TempDir::new("elba").unwrap();
+    let out = TempDir::new("elba").unwrap();
+    let dir = TempDir::new("elba").unwrap();
+    fs::create_dir_all(dir.path().join("src")).unwrap();
+    fs::write(
+        dir.path().join("elba.toml"),
+        format!(
+            "[package]\nname = \"hooks/pkg\"\nversion = \"1.0.0\"\nauthors = []\n\n[targets.lib]\nmods = []\n\n[scripts]\n{}",
+            scripts
+        ),
+    )
+    .unwrap();
+    let source = load_source(dir.path(), "hooks/pkg");
+
+    let ctx = BuildContext {
+        backend: Backend::default(),
+        codegen: false,
+        compiler: Compiler::new("true").unwrap(),
+        cache: new_cache(&root),
+        threads: 1,
+        opts: vec![],
+        profile: BuildProfile::new("debug"),
+        flags: IndexMap::new(),
+    };
+    let targets = Targets::new(vec![Target::Lib(false)]);
+    let mut inner = petgraph::Graph::new();
+    inner.add_node(source);
+
+    let layout = OutputLayout::new(DirLock::acquire(out.path()).unwrap()).unwrap();
+    let queue = JobQueue::new(
+        Graph::new(inner),
+        &targets,
+        Some(layout),
+        ctx,
+        &LOGGER,
+        shell(),
+    )
+    .unwrap();
+    let res = queue.exec().map(|_| ()).map_err(|e| e.to_string());
+
+    let log = fs::read_to_string(out.path().join("log")).unwrap_or_default();
+    (log, res)
+}
+
+#[cfg(unix)]
+#[test]
+fn build_scripts_order() {
+    if rerun_with_env(
+        "cache::build_scripts_order",
+        &[("ELBA_SCRIPT_TEST_LEAK", "leaked")],
+    ) {
+        return;
+    }
+
+    let (log, res) = build_with_scripts(
+        r#"prebuild = 'echo "pre $ELBA_PKG_NAME ${ELBA_SCRIPT_TEST_LEAK:-clean}" >> "$ELBA_OUT_DIR/log"'
+postbuild = 'test -d lib && echo "post $ELBA_PKG_VERSION" >> log'
+"#,
+    );
+    res.unwrap();
+    assert_eq!(log, "pre hooks/pkg clean\npost 1.0.0\n");
+
+    // A failing script fails the build, and what it said is shown
+    let (log, res) = build_with_scripts(
+        r#"prebuild = 'echo "pre" >> "$ELBA_OUT_DIR/log"'
+postbuild = 'echo "postbuild went wrong" >&2; exit 1'
+"#,
+    );
+    assert_eq!(log, "pre\n");
+    assert!(res.is_err());
+
+    let (log, res) = build_with_scripts(
+        r#"prebuild = 'echo "prebuild went wrong" >&2; exit 1'
+postbuild = 'echo "post" >> log'
+"#,
+    );
+    assert_eq!(log, "");
+    assert!(res.is_err());
+}
+
+/// Builds a root package which depends on one package for each of `scripts` (the prebuild
+/// scripts of those packages), with at most `threads` builds at once.
+#[cfg(unix)]
+fn build_queue(scripts: &[String], threads: u32) -> Result<(), String> {
+    build_queue_held(scripts, threads, None)
+}
+
+/// Like `build_queue`, but if `held` is given, the build lock of the `held.0`th package is held
+/// (as if by another process building it) until the file `held.1` shows up.
+#[cfg(unix)]
+fn build_queue_held(
+    scripts: &[String],
+    threads: u32,
+    held: Option<(usize, &Path)>,
+) -> Result<(), String> {
+    let root = TempDir::new("elba").unwrap();
+    let out = TempDir::new("elba").unwrap();
+    let dirs = (0..=scripts.len())
+        .map(|_| TempDir::new("elba").unwrap())
+        .collect::<Vec<_>>();
+
+    let mut inner = petgraph::Graph::new();
+    let mut nodes = vec![];
+    for (i, dir) in dirs.iter().enumerate() {
+        let name = format!("queue/p{}", i);
+        let script = if i == 0 {
+            "echo root >> \"$ELBA_OUT_DIR/log\"".to_string()
+        } else {
+            scripts[i - 1].clone()
+        };
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::write(
+            dir.path().join("elba.toml"),
+            format!(
+                "[package]\nname = \"{}\"\nversion = \"1.0.0\"\nauthors = []\n\n[targets.lib]\nmods = []\n\n[scripts]\nprebuild = '{}'\n",
+                name, script
+            ),
+        )
+        .unwrap();
+        nodes.push(inner.add_node(load_source(dir.path(), &name)));
+    }
+    for node in &nodes[1..] {
+        inner.add_edge(nodes[0], *node, ());
+    }
+
+    let ctx = BuildContext {
+        backend: Backend::default(),
+        codegen: false,
+        compiler: Compiler::new("true").unwrap(),
+        cache: new_cache(&root),
+        threads,
+        opts: vec![],
+        profile: BuildProfile::new("debug"),
+        flags: IndexMap::new(),
+    };
+    let targets = Targets::new(vec![Target::Lib(false)]);
+    let graph = Graph::new(inner);
+    let holder = held.map(|(ix, until)| {
+        let source = &graph[NodeIndex::new(ix)];
+        let hash = BuildHash::for_job(source, &graph, &targets, &ctx).unwrap();
+        let lock = ctx.cache.acquire_build_lock(&hash, &ctx.profile).unwrap();
+        let until = until.to_owned();
+        thread::spawn(move || {
+            let start = Instant::now();
+            while !until.exists() && start.elapsed() < Duration::from_secs(10) {
+                thread::sleep(Duration::from_millis(50));
+            }
+            drop(lock);
+            until.exists()
+        })
+    });
+
+    let layout = OutputLayout::new(DirLock::acquire(out.path()).unwrap()).unwrap();
+    let queue = JobQueue::new(graph, &targets, Some(layout), ctx, &LOGGER, shell()).unwrap();
+    let res = queue.exec().map(|_| ()).map_err(|e| e.to_string());
+    // Whatever the queue was waiting on has to have been built while the lock was held
+    if let Some(holder) = holder {
+        assert!(holder.join().unwrap());
+    }
+
+    // The root's script only runs if everything it depends on was built
+    let log = fs::read_to_string(out.path().join("log")).unwrap_or_default();
+    assert_eq!(log == "root\n", res.is_ok());
+    res
+}
+
+#[cfg(unix)]
+#[test]
+fn build_queue_limit() {
+    let shared = TempDir::new("elba").unwrap();
+    let s = shared.path().display();
+    // Each build counts how many builds are going on (including itself) while it runs
+    let scripts = (0..4)
+        .map(|i| {
+            format!(
+                "touch {s}/running.{i}; ls {s} | grep -c running >> {s}/counts; sleep 0.2; rm {s}/running.{i}",
+                s = s,
+                i = i
+            )
+        })
+        .collect::<Vec<_>>();
+
+    build_queue(&scripts, 2).unwrap();
+
+    let counts = fs::read_to_string(shared.path().join("counts")).unwrap();
+    let counts = counts
+        .lines()
+        .map(|x| x.trim().parse::<u32>().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(counts.len(), 4);
+    assert!(counts.iter().all(|n| (1..=2).contains(n)), "{:?}", counts);
+}
+
+#[cfg(unix)]
+#[test]
+fn build_queue_failure() {
+    let shared = TempDir::new("elba").unwrap();
+    let s = shared.path().display();
+    let scripts = vec!["exit 1".to_string(), format!("touch {}/built", s)];
+
+    // The package which failed stops the root from being built, but the package which doesn't
+    // depend on it still is
+    let err = build_queue(&scripts, 1).unwrap_err();
+    assert!(err.contains("couldn't be built"));
+    assert!(shared.path().join("built").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn build_queue_build_lock_held() {
+    let shared = TempDir::new("elba").unwrap();
+    let s = shared.path().display();
+    let scripts = vec![
+        format!("touch {}/built.1", s),
+        format!("touch {}/built.2", s),
+    ];
+
+    // The second package is being built by someone else, which doesn't stop the first one from
+    // being built in the meantime
+    let until = shared.path().join("built.1");
+    build_queue_held(&scripts, 2, Some((2, &until))).unwrap();
+    assert!(shared.path().join("built.2").exists());
+}
+
+#[test]
+fn build_hash_scripts() {
+    let dirs = (0..3)
+        .map(|_| TempDir::new("elba").unwrap())
+        .collect::<Vec<_>>();
+    let root = TempDir::new("elba").unwrap();
+    let ctx = BuildContext {
+        backend: Backend::default(),
+        codegen: false,
+        compiler: Compiler::new("true").unwrap(),
+        cache: new_cache(&root),
+        threads: 1,
+        opts: vec![],
+        profile: BuildProfile::new("debug"),
+        flags: IndexMap::new(),
+    };
+    let targets = Targets::new(vec![Target::Lib(false)]);
+
+    let hashes = dirs
+        .iter()
+        .zip(&["", "prebuild = 'make'\n", "postbuild = 'make'\n"])
+        .map(|(dir, scripts)| {
+            fs::write(
+                dir.path().join("elba.toml"),
+                format!(
+                    "[package]\nname = \"hooks/pkg\"\nversion = \"1.0.0\"\nauthors = []\n\n[scripts]\n{}",
+                    scripts
+                ),
+            )
+            .unwrap();
+            let source = load_source(dir.path(), "hooks/pkg");
+            let mut inner = petgraph::Graph::new();
+            inner.add_node(source.clone());
+            BuildHash::new(&source, &Graph::new(inner), &targets, &ctx, false).unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    assert_ne!(hashes[0], hashes[1]);
+    assert_ne!(hashes[0], hashes[2]);
+    assert_ne!(hashes[1], hashes[2]);
+}
+
+/// Retrieves a root package with four dependencies, each served as a tarball which takes a while
+/// to download, returning the most downloads which were going on at once.
+fn most_downloads_at_once(download_jobs: u32) -> usize {
+    let root = TempDir::new("elba").unwrap();
+    let project = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+    write_package(project.path(), "jobs/root");
+
+    // How many downloads are going on, and the most there have been at once
+    let open = Arc::new(Mutex::new((0, 0)));
+    let summary = |name: &str, res: DirectRes| {
+        Summary::new(
+            PackageId::new(Name::from_str(name).unwrap(), res.into()),
+            Version::parse("1.0.0").unwrap(),
+        )
+    };
+
+    let mut inner = petgraph::Graph::new();
+    let root_sum = summary(
+        "jobs/root",
+        DirectRes::Dir {
+            path: project.path().to_owned(),
+        },
+    );
+    let root_node = inner.add_node(root_sum.clone());
+    let mut servers = vec![];
+    for i in 0..4 {
+        let name = format!("jobs/dep{}", i);
+        let mut tar = tar::Builder::new(GzEncoder::new(vec![], Compression::default()));
+        let manifest = format!(
+            "[package]\nname = \"{}\"\nversion = \"1.0.0\"\nauthors = []\n",
+            name
+        );
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, "elba.toml", manifest.as_bytes())
+            .unwrap();
+        let body = tar.into_inner().unwrap().finish().unwrap();
+
+        let (served, open) = (body.clone(), open.clone());
+        let (url, server) = serve_once(move |_| {
+            {
+                let mut open = open.lock().unwrap();
+                open.0 += 1;
+                open.1 = open.1.max(open.0);
+            }
+            thread::sleep(Duration::from_millis(150));
+            open.lock().unwrap().0 -= 1;
+            response("200 OK", "", &served)
+        });
+        servers.push(server);
+
+        let dep = inner.add_node(summary(&name, tar_res(url, &body)));
+        inner.add_edge(root_node, dep, ());
+    }
+
+    let mut retriever = Retriever::new(
+        &cache.logger,
+        &cache,
+        root_sum,
+        vec![],
+        Left(vec![]),
+        Graph::default(),
+        &IXMAP,
+        shell(),
+        false,
+    );
+    retriever.download_jobs = download_jobs;
+    let sources = retriever.retrieve_packages(&Graph::new(inner)).unwrap();
+    assert_eq!(sources.inner.node_count(), 5);
+    assert_eq!(retriever.timings.downloads.len(), 5);
+    for server in servers {
+        server.join().unwrap();
+    }
+
+    let most = open.lock().unwrap().1;
+    most
+}
+
+#[test]
+fn retrieve_download_jobs() {
+    assert_eq!(most_downloads_at_once(1), 1);
+    assert_eq!(most_downloads_at_once(2), 2);
+}
+
+/// A tarball of a package with nothing but a manifest, which a mock server only sends after
+/// `delay`. The server thread is left hanging around, since the client gives up on it.
+fn slow_package(name: &str, delay: Duration) -> (Summary, DirectRes) {
+    let manifest = format!(
+        "[package]\nname = \"{}\"\nversion = \"1.0.0\"\nauthors = []\n",
+        name
+    );
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    let mut tar = tar::Builder::new(GzEncoder::new(vec![], Compression::default()));
+    tar.append_data(&mut header, "elba.toml", manifest.as_bytes())
+        .unwrap();
+    let body = tar.into_inner().unwrap().finish().unwrap();
+
+    let served = body.clone();
+    let (url, _) = serve_once(move |_| {
+        thread::sleep(delay);
+        response("200 OK", "", &served)
+    });
+    let res = tar_res(url, &body);
+    let sum = Summary::new(
+        PackageId::new(Name::from_str(name).unwrap(), res.clone().into()),
+        Version::parse("1.0.0").unwrap(),
+    );
+
+    (sum, res)
+}
+
+#[test]
+fn retrieve_request_timeout() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root)
+        .with_timeouts(Duration::from_secs(10), Duration::from_millis(300))
+        .unwrap();
+    let (sum, res) = slow_package("slow/pkg", Duration::from_secs(3));
+
+    let start = Instant::now();
+    assert!(cache
+        .checkout_source(sum.id(), &res, false, false, || {})
+        .is_err());
+    assert!(start.elapsed() < Duration::from_secs(3));
+}
+
+#[test]
+fn retrieve_deadline() {
+    let root = TempDir::new("elba").unwrap();
+    let project = TempDir::new("elba").unwrap();
+    write_package(project.path(), "deadline/root");
+    let cache = new_cache(&root).with_deadline(Some(Instant::now() + Duration::from_millis(500)));
+
+    let root_sum = Summary::new(
+        PackageId::new(
+            Name::from_str("deadline/root").unwrap(),
+            DirectRes::Dir {
+                path: project.path().to_owned(),
+            }
+            .into(),
+        ),
+        Version::parse("1.0.0").unwrap(),
+    );
+    let mut inner = petgraph::Graph::new();
+    let root_node = inner.add_node(root_sum.clone());
+    for name in &["deadline/one", "deadline/two"] {
+        let (sum, _) = slow_package(name, Duration::from_secs(3));
+        let dep = inner.add_node(sum);
+        inner.add_edge(root_node, dep, ());
+    }
+
+    let mut retriever = Retriever::new(
+        &cache.logger,
+        &cache,
+        root_sum,
+        vec![],
+        Left(vec![]),
+        Graph::default(),
+        &IXMAP,
+        shell(),
+        false,
+    );
+    retriever.download_jobs = 1;
+
+    // The downloads are abandoned at the deadline instead of waited out
+    let start = Instant::now();
+    let err = retriever.retrieve_packages(&Graph::new(inner)).unwrap_err();
+    assert!(start.elapsed() < Duration::from_secs(3));
+    assert!(err
+        .iter_chain()
+        .any(|c| c.downcast_ref::<Error>() == Some(&Error::DeadlineExceeded)));
+
+    // Both the download that was going on and the one that never started are still pending
+    let msg = err
+        .iter_chain()
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+    assert!(msg.contains("deadline/one"), "{}", msg);
+    assert!(msg.contains("deadline/two"), "{}", msg);
+}
+
+/// Tries to retrieve a tarball which isn't in the cache, returning whether that failed because
+/// we're offline.
+fn offline_missing(cache: &Cache, offline: bool) -> bool {
+    let loc = DirectRes::from_str("tar+https://example.com/offline.tar.gz").unwrap();
+    let pkg = PackageId::new(
+        Name::from_str("offline/pkg").unwrap(),
+        Resolution::Direct(loc.clone()),
+    );
+
+    match cache.checkout_source(&pkg, &loc, false, offline, || {}) {
+        Ok(_) => false,
+        Err(e) => e
+            .iter_chain()
+            .any(|c| c.downcast_ref::<Error>() == Some(&Error::OfflineMissingPackage)),
+    }
+}
+
+#[test]
+fn cache_offline_flag() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+
+    assert!(!cache.offline);
+    assert!(offline_missing(&cache, true));
+}
+
+#[test]
+fn cache_offline_env() {
+    if rerun_with_env("cache::cache_offline_env", &[(OFFLINE_ENV, "1")]) {
+        return;
+    }
+
+    let root = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+
+    // The environment makes the cache offline even though the caller didn't ask for it
+    assert!(cache.offline);
+    assert!(offline_missing(&cache, false));
+}
+
+#[test]
+fn cache_index_fingerprint() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+    let index = cache.layout.indices.join("some_index");
+    fs::create_dir_all(index.join("group")).unwrap();
+    fs::write(index.join("index.toml"), "[index]\n").unwrap();
+    let before = cache.index_fingerprint(&[]);
+
+    // Fetching more entries doesn't change anything that's already been resolved
+    fs::write(index.join("group/pkg"), "{}").unwrap();
+    assert_eq!(cache.index_fingerprint(&[]), before);
+
+    fs::write(index.join("index.toml"), "[index]\nsecure = false\n").unwrap();
+    assert_ne!(cache.index_fingerprint(&[]), before);
+}
+
+#[test]
+fn cache_frozen() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root).with_frozen(true);
+
+    // Frozen means offline, and the error says why nothing could be downloaded
+    assert!(cache.offline);
+    let loc = DirectRes::from_str("tar+https://example.com/offline.tar.gz").unwrap();
+    let pkg = PackageId::new(
+        Name::from_str("offline/pkg").unwrap(),
+        Resolution::Direct(loc.clone()),
+    );
+    let err = cache
+        .checkout_source(&pkg, &loc, false, false, || {})
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "tar+https://example.com/offline.tar.gz isn't cached, and --frozen forbids downloading it"
+    );
+    assert!(err
+        .iter_chain()
+        .any(|c| c.downcast_ref::<Error>() == Some(&Error::OfflineMissingPackage)));
+}
+
+#[test]
+fn cache_fetch_index_only() {
+    let mut files = HashMap::new();
+    files.insert(
+        "/index.toml",
+        "[index]\nname = \"base\"\nsecure = false\ndependencies = {}\n".to_string(),
+    );
+    let (base, base_log) = serve_sparse(files);
+    let mut files = HashMap::new();
+    files.insert(
+        "/index.toml",
+        format!(
+            "[index]\nname = \"top\"\nsecure = false\ndependencies = {{ base = \"{}\" }}\n",
+            base
+        ),
+    );
+    let (top, top_log) = serve_sparse(files);
+
+    let root = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+    let reses = [top.res.clone()];
+    assert!(cache
+        .get_indices(&reses, false, true)
+        .by_name("top")
+        .is_err());
+
+    // The index and the indices it depends on all get downloaded, but nothing else
+    cache.fetch_index_only(&reses).unwrap();
+    assert_eq!(*top_log.lock().unwrap(), vec!["/index.toml"]);
+    assert_eq!(*base_log.lock().unwrap(), vec!["/index.toml"]);
+
+    let ixs = cache.get_indices(&reses, false, true);
+    assert!(ixs.by_name("top").is_ok());
+    assert!(ixs.by_name("base").is_ok());
+
+    // Unlike get_indices, an index which can't be retrieved is an error
+    let (gone, _) = serve_sparse(HashMap::new());
+    assert!(cache.fetch_index_only(&[gone.res]).is_err());
+}
+
+#[test]
+fn cache_repair_index() {
+    let config = "[index]\nname = \"repair\"\nsecure = false\ndependencies = {}\n";
+    let mut files = HashMap::new();
+    files.insert("/index.toml", config.to_string());
+    let (ir, log) = serve_sparse(files);
+    let reses = [ir.res.clone()];
+
+    let root = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+    assert!(cache
+        .get_indices(&reses, false, false)
+        .by_name("repair")
+        .is_ok());
+
+    // Cut the cached config off halfway through, like a download onto a full disk would
+    let cached = fs::read_dir(&cache.layout.indices)
+        .unwrap()
+        .map(|x| x.unwrap().path().join("index.toml"))
+        .find(|x| x.exists())
+        .unwrap();
+    fs::write(&cached, &config[..config.len() / 2]).unwrap();
+
+    // Offline, there's no way to fix it, so it's left alone
+    assert!(cache
+        .get_indices(&reses, false, true)
+        .by_name("repair")
+        .is_err());
+    assert_eq!(log.lock().unwrap().len(), 1);
+
+    // Online, it's retrieved again
+    assert!(cache
+        .get_indices(&reses, false, false)
+        .by_name("repair")
+        .is_ok());
+    assert_eq!(fs::read_to_string(&cached).unwrap(), config);
+    assert_eq!(*log.lock().unwrap(), vec!["/index.toml", "/index.toml"]);
+}
+
+/// A gzipped tarball of a package with a library target, to be served by `serve_tarball`.
+fn pkg_tarball() -> Vec<u8> {
+    GzEncoder::new(pkg_tar(), Compression::default())
+        .finish()
+        .unwrap()
+}
+
+/// The uncompressed tarball in `pkg_tarball`.
+fn pkg_tar() -> Vec<u8> {
+    pkg_tar_in("")
+}
+
+/// The tarball in `pkg_tar`, with the package in the directory `prefix` of it instead.
+fn pkg_tar_in(prefix: &str) -> Vec<u8> {
+    let files: &[(&str, &[u8])] = &[
+        (
+            "elba.toml",
+            b"[package]\nname = \"compressed/pkg\"\nversion = \"1.0.0\"\nauthors = []\n\n[targets.lib]\nmods = [\"Data.Pkg\"]\n",
+        ),
+        ("src/Data/Pkg.idr", b"module Data.Pkg"),
+    ];
+
+    tar_of(prefix, files)
+}
+
+/// A tarball of `files`, all in the directory `prefix` of it.
+fn tar_of(prefix: &str, files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut tar = tar::Builder::new(vec![]);
+    for (path, contents) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, format!("{}{}", prefix, path), *contents)
+            .unwrap();
+    }
+    tar.into_inner().unwrap()
+}
+
+/// The package in `pkg_tarball`, with some documentation and a test suite which aren't needed to
+/// build it.
+fn bulky_tarball() -> Vec<u8> {
+    let files: &[(&str, &[u8])] = &[
+        (
+            "elba.toml",
+            b"[package]\nname = \"compressed/pkg\"\nversion = \"1.0.0\"\nauthors = []\n\n[targets.lib]\nmods = [\"Data.Pkg\"]\n",
+        ),
+        ("src/Data/Pkg.idr", b"module Data.Pkg"),
+        ("src/Data/Pkg/Internal.md", b"# Notes"),
+        ("docs/guide.md", b"# Guide"),
+        ("README.md", b"# pkg"),
+    ];
+
+    GzEncoder::new(tar_of("", files), Compression::default())
+        .finish()
+        .unwrap()
+}
+
+/// Checks the package in `pkg_tarball` out of a fresh cache which does or doesn't compress its
+/// sources, returning the cache and the Source.
+fn checkout_tarball(root: &TempDir, compress: bool) -> (Cache, DirectRes, Source) {
+    let cache = new_cache(root).with_compressed_sources(compress);
+    let (loc, source) = checkout_body(&cache, pkg_tarball()).unwrap();
+
+    (cache, loc, source)
+}
+
+/// Serves `body` as the tarball of the package in `pkg_tarball` and checks it out of `cache`.
+fn checkout_body(cache: &Cache, body: Vec<u8>) -> Result<(DirectRes, Source), failure::Error> {
+    let (url, server) = serve_tarball(body.clone(), false);
+    let loc = tar_res(url, &body);
+    let pkg = PackageId::new(
+        Name::from_str("compressed/pkg").unwrap(),
+        Resolution::Direct(loc.clone()),
+    );
+
+    let res = cache.checkout_source(&pkg, &loc, false, false, || {});
+    // A checkout which fails might never have gotten to the server, which would then wait for
+    // the request forever
+    if res.is_ok() {
+        server.join().unwrap();
+    }
+
+    res.map(|(_, source)| (loc, source))
+}
+
+/// Checks out the package in `pkg_tar` from a tarball which has it in the directory `prefix`,
+/// with the resolution pointing at `subpath`.
+fn checkout_wrapped(
+    cache: &Cache,
+    prefix: &str,
+    subpath: Option<&str>,
+) -> Result<Source, failure::Error> {
+    let body = pkg_tar_in(prefix);
+    let (url, server) = serve_tarball(body.clone(), false);
+    let mut loc = tar_res(url, &body);
+    if let DirectRes::Tar { subpath: s, .. } = &mut loc {
+        *s = subpath.map(|x| SubPath::from_str(x).unwrap());
+    }
+    let pkg = PackageId::new(
+        Name::from_str("compressed/pkg").unwrap(),
+        Resolution::Direct(loc.clone()),
+    );
+
+    let res = cache.checkout_source(&pkg, &loc, false, false, || {});
+    // A checkout which fails might never have gotten to the server, which would then wait for
+    // the request forever
+    if res.is_ok() {
+        server.join().unwrap();
+    }
+
+    res.map(|(_, source)| source)
+}
+
+/// Commits a repository with the packages `mono/a` and `mono/b` in the directories `pkgs/a` and
+/// `pkgs/b` of it, and a stray file at its root.
+fn monorepo(root: &Path) -> Url {
+    let repo = git2::Repository::init(root).unwrap();
+    for name in &["a", "b"] {
+        let dir = root.join("pkgs").join(name);
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(
+            dir.join("elba.toml"),
+            format!(
+                "[package]\nname = \"mono/{}\"\nversion = \"1.0.0\"\nauthors = []\n\n\
+                 [targets.lib]\nmods = [\"Mod\"]\n",
+                name
+            ),
+        )
+        .unwrap();
+        fs::write(dir.join("src/Mod.idr"), format!("module Mod -- {}", name)).unwrap();
+    }
+    fs::write(root.join("README.md"), "two packages").unwrap();
+
+    let mut index = repo.index().unwrap();
+    index
+        .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+        .unwrap();
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let sig = git2::Signature::now("elba", "elba@example.com").unwrap();
+    repo.commit(Some("refs/heads/master"), &sig, &sig, "init", &tree, &[])
+        .unwrap();
+
+    Url::from_file_path(root).unwrap()
+}
+
+#[test]
+fn git_subdir() {
+    let origin = TempDir::new("elba").unwrap();
+    let url = monorepo(origin.path());
+    let root = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+
+    let checkout = |name: &str| {
+        let loc = DirectRes::from_str(&format!("git+{}#master&subdir=pkgs/{}", url, name)).unwrap();
+        let pkg = PackageId::new(
+            Name::from_str(&format!("mono/{}", name)).unwrap(),
+            Resolution::Direct(loc.clone()),
+        );
+        cache.checkout_source(&pkg, &loc, false, false, || {})
+    };
+
+    // Both packages can be loaded at the same time, each from its own checkout
+    let (a_loc, a) = checkout("a").unwrap();
+    let (_, b) = checkout("b").unwrap();
+    assert_eq!(a.meta().name().name(), "a");
+    assert_eq!(b.meta().name().name(), "b");
+    assert!(a.path().ends_with("pkgs/a"));
+    assert!(b.path().ends_with("pkgs/b"));
+    assert_ne!(a.path().parent(), b.path().parent());
+    assert_ne!(a.hash(), b.hash());
+
+    // The pinned resolution keeps the subdir
+    let a_loc = a_loc.unwrap();
+    assert!(a_loc.to_string().ends_with("&subdir=pkgs/a"));
+    assert_eq!(a.location(), &a_loc);
+
+    // Only the files of the subdirectory make it into the hash
+    let files = a
+        .meta()
+        .list_files(a.path(), a.path(), |_| true)
+        .unwrap()
+        .filter(|x| x.file_type().is_file())
+        .map(|x| x.file_name().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    assert!(!files.contains(&"README.md".to_owned()));
+
+    // Its sidecar is kept next to the checkout, so nothing is added to the repository
+    let checkout = a.path().parent().unwrap().parent().unwrap();
+    assert!(walkdir::WalkDir::new(checkout).into_iter().all(|x| !x
+        .unwrap()
+        .file_name()
+        .to_string_lossy()
+        .ends_with("hashes.json")));
+    assert!(checkout
+        .with_file_name(format!(
+            "{}.pkgs.a.{}",
+            checkout.file_name().unwrap().to_string_lossy(),
+            merkle::SIDECAR_EXT
+        ))
+        .is_file());
+
+    // Neither does the commit, so a commit which only touches the rest of the repository doesn't
+    // change the hash
+    let repo = git2::Repository::open(origin.path()).unwrap();
+    fs::write(origin.path().join("README.md"), "still two packages").unwrap();
+    let mut index = repo.index().unwrap();
+    index
+        .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+        .unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let head = repo.head().unwrap().peel_to_commit().unwrap();
+    let sig = git2::Signature::now("elba", "elba@example.com").unwrap();
+    repo.commit(Some("HEAD"), &sig, &sig, "readme", &tree, &[&head])
+        .unwrap();
+    let later_root = TempDir::new("elba").unwrap();
+    let later = new_cache(&later_root);
+    let loc = DirectRes::from_str(&format!("git+{}#master&subdir=pkgs/a", url)).unwrap();
+    let pkg = PackageId::new(
+        Name::from_str("mono/a").unwrap(),
+        Resolution::Direct(loc.clone()),
+    );
+    let (later_loc, later_a) = later
+        .checkout_source(&pkg, &loc, false, false, || {})
+        .unwrap();
+    assert_ne!(later_loc.unwrap(), a_loc);
+    assert_eq!(later_a.hash(), a.hash());
+
+    let missing = DirectRes::from_str(&format!("git+{}#master&subdir=pkgs/c", url)).unwrap();
+    let pkg = PackageId::new(
+        Name::from_str("mono/c").unwrap(),
+        Resolution::Direct(missing.clone()),
+    );
+    assert!(cache
+        .checkout_source(&pkg, &missing, false, false, || {})
+        .is_err());
+}
+
+#[test]
+fn tar_subpath() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+    let (_, plain) = checkout_body(&cache, pkg_tar()).unwrap();
+    let source = checkout_wrapped(&cache, "repo/pkgs/pkg/", Some("repo/pkgs/pkg")).unwrap();
+
+    // Only the subdirectory is kept, as the root of the package
+    assert_eq!(source.hash(), plain.hash());
+    assert!(source.path().join("elba.toml").exists());
+    assert!(!source.path().join("repo").exists());
+
+    let missing = checkout_wrapped(&cache, "repo/", Some("other")).unwrap_err();
+    assert!(format!("{}", missing.find_root_cause()).contains("doesn't have a directory other"));
+}
+
+#[test]
+fn tar_wrapper_dir() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+    let (_, plain) = checkout_body(&cache, pkg_tar()).unwrap();
+    let source = checkout_wrapped(&cache, "pkg-1.0.0/", None).unwrap();
+
+    // A single top-level directory with a manifest is taken to be the package root
+    assert_eq!(source.hash(), plain.hash());
+    assert!(source.path().join("src/Data/Pkg.idr").exists());
+    assert!(!source.path().join("pkg-1.0.0").exists());
+}
+
+#[test]
+fn checkout_source_origin() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+    let body = pkg_tarball();
+    let (url, server) = serve_tarball(body.clone(), false);
+    let loc = tar_res(url, &body);
+    let pkg = PackageId::new(
+        Name::from_str("compressed/pkg").unwrap(),
+        Resolution::Direct(loc.clone()),
+    );
+
+    let (_, _, first) = cache
+        .checkout_source_origin(&pkg, &loc, false, false, || {})
+        .unwrap();
+    server.join().unwrap();
+    match first {
+        SourceOrigin::Downloaded { bytes, .. } => assert!(bytes > 0),
+        SourceOrigin::Cached => panic!("the first checkout should download the tarball"),
+    }
+
+    // The server is gone, so the second checkout can only come out of the cache
+    let (_, _, second) = cache
+        .checkout_source_origin(&pkg, &loc, false, false, || {})
+        .unwrap();
+    assert_eq!(second, SourceOrigin::Cached);
+}
+
+/// The level, message and key-value pairs of a log record.
+type CapturedRecord = (Level, String, HashMap<String, String>);
+
+/// The records a logger has been sent.
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<CapturedRecord>>>);
+
+impl Captured {
+    /// The levels and key-value pairs of the records with the message `msg`.
+    fn find(&self, msg: &str) -> Vec<(Level, HashMap<String, String>)> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, m, _)| m == msg)
+            .map(|(level, _, kv)| (*level, kv.clone()))
+            .collect()
+    }
+}
+
+impl Drain for Captured {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), slog::Never> {
+        let mut kv = KvMap::default();
+        values.serialize(record, &mut kv).unwrap();
+        record.kv().serialize(record, &mut kv).unwrap();
+        self.0
+            .lock()
+            .unwrap()
+            .push((record.level(), record.msg().to_string(), kv.0));
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct KvMap(HashMap<String, String>);
+
+impl slog::Serializer for KvMap {
+    fn emit_arguments(&mut self, key: slog::Key, val: &std::fmt::Arguments) -> slog::Result {
+        self.0.insert(key.to_string(), val.to_string());
+        Ok(())
+    }
+}
+
+/// Commits an index named `logged` to a new git repository in `root`.
+fn git_index(root: &Path) -> DirectRes {
+    let repo = git2::Repository::init(root).unwrap();
+    fs::write(
+        root.join("index.toml"),
+        "[index]\nname = \"logged\"\nsecure = false\ndependencies = {}\n",
+    )
+    .unwrap();
+
+    let mut index = repo.index().unwrap();
+    index
+        .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+        .unwrap();
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let sig = git2::Signature::now("elba", "elba@example.com").unwrap();
+    repo.commit(Some("refs/heads/master"), &sig, &sig, "init", &tree, &[])
+        .unwrap();
+
+    DirectRes::Git {
+        repo: Url::from_file_path(root).unwrap(),
+        tag: "master".to_string(),
+        rev: None,
+        subdir: None,
+    }
+}
+
+#[test]
+fn cache_logs_retrieval() {
+    let root = TempDir::new("elba").unwrap();
+    let origin = TempDir::new("elba").unwrap();
+    let index_origin = TempDir::new("elba").unwrap();
+    let tarballs = TempDir::new("elba").unwrap();
+    let captured = Captured::default();
+    let logger = Logger::root(captured.clone().fuse(), o!());
+    let layout = new_cache(&root).layout;
+    let cache = Cache::from_disk(&logger, layout, None, shell())
+        .unwrap()
+        .with_tarball_dirs(&[tarballs.path().to_owned()]);
+
+    // Refreshing an index, then loading it from the cache
+    let index = git_index(index_origin.path());
+    let reses = [index.clone()];
+    assert!(cache
+        .get_indices(&reses, false, false)
+        .by_name("logged")
+        .is_ok());
+    let started = captured.find("index refresh started");
+    assert_eq!(started.len(), 1);
+    assert_eq!(started[0].0, Level::Info);
+    assert_eq!(started[0].1["url"], index.to_string());
+    let refreshed = captured.find("index refreshed");
+    assert_eq!(refreshed.len(), 1);
+    assert!(refreshed[0].1["duration_ms"].parse::<u64>().is_ok());
+
+    cache.get_indices(&reses, false, true);
+    let loaded = captured.find("loaded index");
+    assert_eq!(loaded.len(), 1);
+    assert_eq!(loaded[0].0, Level::Debug);
+    assert_eq!(loaded[0].1["cause"], "cached");
+
+    // Downloading a source
+    let url = monorepo(origin.path());
+    let loc = DirectRes::from_str(&format!("git+{}#master&subdir=pkgs/a", url)).unwrap();
+    let pkg = PackageId::new(
+        Name::from_str("mono/a").unwrap(),
+        Resolution::Direct(loc.clone()),
+    );
+    cache
+        .checkout_source(&pkg, &loc, false, false, || {})
+        .unwrap();
+    let started = captured.find("download started");
+    assert_eq!(started.len(), 1);
+    assert_eq!(started[0].0, Level::Info);
+    assert_eq!(started[0].1["pkg"], "mono/a");
+    assert_eq!(started[0].1["url"], loc.to_string());
+    let finished = captured.find("download finished");
+    assert_eq!(finished.len(), 1);
+    assert!(finished[0].1["bytes"].parse::<u64>().unwrap() > 0);
+    assert!(finished[0].1["duration_ms"].parse::<u64>().is_ok());
+
+    // Missing the cache and checking a tarball's checksum, then hitting the cache
+    let body = pkg_tarball();
+    fs::write(tarballs.path().join("compressed_pkg-1.0.0.tar.gz"), &body).unwrap();
+    let loc = tar_res(
+        Url::parse("https://public.invalid/pkg.tar.gz").unwrap(),
+        &body,
+    );
+    let sum = Summary::new(
+        PackageId::new(
+            Name::from_str("compressed/pkg").unwrap(),
+            Resolution::Direct(loc.clone()),
+        ),
+        Version::parse("1.0.0").unwrap(),
+    );
+    for _ in 0..2 {
+        cache
+            .checkout_summary(&sum, &loc, false, true, || {})
+            .unwrap();
+    }
+    assert_eq!(captured.find("cache miss").len(), 1);
+    assert_eq!(captured.find("cache hit").len(), 1);
+    let verified = captured.find("verified checksum");
+    assert_eq!(verified.len(), 1);
+    assert_eq!(verified[0].1["url"], loc.to_string());
+    let checked_out = captured.find("checked out package");
+    assert_eq!(checked_out.len(), 2);
+    assert_eq!(checked_out[0].1["pkg"], "compressed/pkg");
+    assert_eq!(checked_out[0].1["version"], "1.0.0");
+}
+
+#[test]
+fn cache_doctor() {
+    let root = TempDir::new("elba").unwrap();
+    let (cache, _, source) = checkout_tarball(&root, false);
+    let mismatched = source.path().to_owned();
+    drop(source);
+    fs::write(mismatched.join("src/Data/Pkg.idr"), "module Data.Evil").unwrap();
+
+    let locked = cache.layout.src.join("locked");
+    fs::create_dir_all(&locked).unwrap();
+    fs::write(locked.join("elba.toml"), BAR_MANIFEST).unwrap();
+    let now = SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap();
+    // PIDs can't be this high, so the process which left the lock is definitely gone
+    let stale = format!("{}\n{}\n", i32::MAX, now.as_secs());
+    fs::write(locked.join(".dirlock"), &stale).unwrap();
+
+    let partial = cache.layout.src.join("partial.part");
+    fs::write(&partial, b"half a tarball").unwrap();
+
+    // Whatever is locked right now might still be in progress, so it's left alone
+    let in_use = DirLock::acquire(&cache.layout.src.join("in_use")).unwrap();
+    fs::write(cache.layout.src.join("in_use.part"), b"downloading").unwrap();
+
+    let config = "[index]\nname = \"doctor\"\nsecure = false\ndependencies = {}\n";
+    let mut files = HashMap::new();
+    files.insert("/index.toml", config.to_string());
+    let (ir, log) = serve_sparse(files);
+    assert!(cache
+        .get_indices(&[ir.res], false, false)
+        .by_name("doctor")
+        .is_ok());
+    let corrupt = fs::read_dir(&cache.layout.indices)
+        .unwrap()
+        .map(|x| x.unwrap().path().join("index.toml"))
+        .find(|x| x.exists())
+        .unwrap();
+    fs::write(&corrupt, &config[..config.len() / 2]).unwrap();
+
+    let unnamed = cache.layout.build.join("some_build");
+    fs::create_dir_all(&unnamed).unwrap();
+    let empty = cache.layout.build.join(format!(
+        "release-{}",
+        hexify_hash(HashAlgo::CURRENT, &[0; 32])
+    ));
+    fs::create_dir_all(&empty).unwrap();
+
+    let kinds = |report: &DoctorReport| {
+        let mut kinds = report
+            .issues
+            .iter()
+            .map(|x| (x.kind, x.path.clone()))
+            .collect::<Vec<_>>();
+        kinds.sort_by_key(|x| x.1.clone());
+        kinds
+    };
+    let mut expected = vec![
+        (IssueKind::ChecksumMismatch, mismatched.clone()),
+        (IssueKind::StaleLock, locked.clone()),
+        (IssueKind::PartialDownload, partial.clone()),
+        (
+            IssueKind::CorruptIndex,
+            corrupt.parent().unwrap().to_owned(),
+        ),
+        (IssueKind::OrphanedBuild, unnamed.clone()),
+        (IssueKind::OrphanedBuild, empty.clone()),
+    ];
+    expected.sort_by_key(|x| x.1.clone());
+
+    // Without fixing anything, nothing changes
+    let report = cache.doctor(false).unwrap();
+    assert_eq!(kinds(&report), expected);
+    assert_eq!(report.unfixed().count(), expected.len());
+    assert_eq!(fs::read_to_string(locked.join(".dirlock")).unwrap(), stale);
+    assert!(partial.exists() && mismatched.exists() && unnamed.exists() && empty.exists());
+    assert_eq!(log.lock().unwrap().len(), 1);
+
+    let report = cache.doctor(true).unwrap();
+    assert_eq!(kinds(&report), expected);
+    assert_eq!(report.unfixed().count(), 0);
+    assert!(!locked.join(".dirlock").exists());
+    assert!(locked.join("elba.toml").exists());
+    assert!(!partial.exists() && !cache.layout.src.join("partial").exists());
+    assert!(!mismatched.exists());
+    assert!(!unnamed.exists() && !empty.exists());
+    // The corrupt index is retrieved again from where it came from
+    assert_eq!(fs::read_to_string(&corrupt).unwrap(), config);
+    assert_eq!(log.lock().unwrap().len(), 2);
+
+    assert_eq!(cache.doctor(false).unwrap(), DoctorReport::default());
+    assert!(cache.layout.src.join("in_use.part").exists());
+    drop(in_use);
+}
+
+#[test]
+fn tar_formats() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+    let (_, gzipped) = checkout_body(&cache, pkg_tarball()).unwrap();
+    let (_, plain) = checkout_body(&cache, pkg_tar()).unwrap();
+
+    // The checksums are of the tarballs as downloaded, but the package inside is the same
+    assert_eq!(plain.hash(), gzipped.hash());
+    assert!(plain.path().join("src/Data/Pkg.idr").exists());
+
+    let (_, zstd) = checkout_body(&cache, piped("zstd", &pkg_tar())).unwrap();
+    assert_eq!(zstd.hash(), gzipped.hash());
+}
+
+#[test]
+fn tar_zstd_tarball_dir() {
+    let root = TempDir::new("elba").unwrap();
+    let tarballs = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root).with_tarball_dirs(&[tarballs.path().to_owned()]);
+
+    let mut sources = vec![];
+    for (file, body) in [
+        ("compressed_pkg-1.0.0.tar", pkg_tar()),
+        ("compressed_pkg-1.0.0.tar.zst", piped("zstd", &pkg_tar())),
+    ] {
+        fs::write(tarballs.path().join(file), &body).unwrap();
+        let loc = tar_res(
+            Url::parse(&format!("https://public.invalid/{}", file)).unwrap(),
+            &body,
+        );
+        let pkg = PackageId::new(
+            Name::from_str("compressed/pkg").unwrap(),
+            Resolution::Direct(loc.clone()),
+        );
+        let (_, source) = cache
+            .checkout_source(&pkg, &loc, false, true, || ())
+            .unwrap();
+        assert!(source.path().join("src/Data/Pkg.idr").exists());
+        sources.push(source);
+    }
+
+    // The package is the same no matter how the tarball it came in was compressed
+    assert_eq!(sources[0].hash(), sources[1].hash());
+}
+
+/// `input`, compressed by piping it through `program`.
+fn piped(program: &str, input: &[u8]) -> Vec<u8> {
+    let mut child = process::Command::new(program)
+        .arg("-c")
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap().stdout
+}
+
+/// A zip archive of the package in `pkg_tar`, with a link at each path in `links` to where it's
+/// paired with.
+fn pkg_zip(links: &[(&str, &str)]) -> Vec<u8> {
+    let dir = TempDir::new("elba").unwrap();
+    unpack_archive(&pkg_tar()[..], dir.path()).unwrap();
+    for (path, link) in links {
+        symlink(Path::new(link), &dir.path().join(path)).unwrap();
+    }
+    let status = process::Command::new("zip")
+        .arg("-qry")
+        .arg("pkg.zip")
+        .arg(".")
+        .current_dir(dir.path())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    fs::read(dir.path().join("pkg.zip")).unwrap()
+}
+
+#[test]
+fn tar_external_formats() {
+    let formats = ArchiveFormats::default();
+    for (name, body) in [
+        ("xz", piped("xz", &pkg_tar())),
+        ("zstandard", piped("zstd", &pkg_tar())),
+        ("zip", pkg_zip(&[])),
+    ] {
+        let target = TempDir::new("elba").unwrap();
+        let format = formats.detect(&body).unwrap();
+        assert_eq!(format.name(), name);
+        format.extract(&mut &body[..], target.path()).unwrap();
+        assert_eq!(
+            fs::read_to_string(target.path().join("src/Data/Pkg.idr")).unwrap(),
+            "module Data.Pkg"
+        );
+        assert!(!target.path().join(".elba-archive.zip").exists());
+    }
+
+    // Links in zip archives are checked once they're unpacked
+    let target = TempDir::new("elba").unwrap();
+    let body = pkg_zip(&[("src/link", "../../outside")]);
+    let err = formats
+        .detect(&body)
+        .unwrap()
+        .extract(&mut &body[..], target.path())
+        .unwrap_err();
+    assert!(
+        err.iter_chain()
+            .any(|c| c.downcast_ref::<Error>() == Some(&Error::UnsafeArchivePath)),
+        "{}",
+        err
+    );
+    assert!(fs::symlink_metadata(target.path().join("src/link")).is_err());
+
+    // Anything which can't be decompressed is an error, not an empty package
+    let mut body = vec![0x28, 0xb5, 0x2f, 0xfd];
+    body.extend(pkg_tar());
+    let target = TempDir::new("elba").unwrap();
+    assert!(formats
+        .detect(&body)
+        .unwrap()
+        .extract(&mut &body[..], target.path())
+        .is_err());
+}
+
+/// A made-up archive format: a plain tarball with a header in front of it.
+struct Wrapped;
+
+impl ArchiveExtractor for Wrapped {
+    fn name(&self) -> &str {
+        "wrapped"
+    }
+
+    fn detect(&self, magic: &[u8]) -> bool {
+        magic.starts_with(b"WRAPPED\0")
+    }
+
+    fn extract(
+        &self,
+        archive: &mut dyn std::io::BufRead,
+        target: &Path,
+    ) -> elba::util::error::Result<()> {
+        archive.consume(8);
+        unpack_archive(archive, target)
+    }
+}
+
+#[test]
+fn tar_custom_format() {
+    let root = TempDir::new("elba").unwrap();
+    let tarballs = TempDir::new("elba").unwrap();
+    let mut body = b"WRAPPED\0".to_vec();
+    body.extend(pkg_tar());
+    fs::write(tarballs.path().join("compressed_pkg-1.0.0.wrapped"), &body).unwrap();
+    let loc = tar_res(
+        Url::parse("https://public.invalid/pkg.wrapped").unwrap(),
+        &body,
+    );
+    let pkg = PackageId::new(
+        Name::from_str("compressed/pkg").unwrap(),
+        Resolution::Direct(loc.clone()),
+    );
+
+    // Without the format, the archive would be taken to be a plain tarball
+    let cache = new_cache(&root).with_tarball_dirs(&[tarballs.path().to_owned()]);
+    assert_eq!(cache.archive_formats.detect(&body).unwrap().name(), "tar");
+
+    let cache = cache.with_archive_format(Wrapped);
+    assert_eq!(
+        cache.archive_formats.detect(&body).unwrap().name(),
+        "wrapped"
+    );
+    let (_, source) = cache
+        .checkout_source(&pkg, &loc, false, true, || ())
+        .unwrap();
+    assert!(source.path().join("src/Data/Pkg.idr").exists());
+}
+
+/// The tarball in `pkg_tar`, with one more entry added by `append_unsafe`.
+fn unsafe_tar(path: &str, link: Option<&str>) -> Vec<u8> {
+    let mut tar = tar::Builder::new(vec![]);
+    append_unsafe(&mut tar, path, link);
+
+    let mut body = tar.into_inner().unwrap();
+    // The rest of the package, without the end-of-archive blocks of the first tarball
+    body.truncate(body.len() - 1024);
+    body.extend(pkg_tar());
+    body
+}
+
+/// Appends an entry to `tar` whose path is set as is (without the checks `tar::Builder` does on
+/// paths), which links to `link` if it's given.
+fn append_unsafe(tar: &mut tar::Builder<Vec<u8>>, path: &str, link: Option<&str>) {
+    let mut header = tar::Header::new_old();
+    header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+    match link {
+        Some(link) => {
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+            header.set_size(0);
+        }
+        None => header.set_size(4),
+    }
+    header.set_mode(0o644);
+    header.set_cksum();
+    tar.append(
+        &header,
+        if link.is_some() {
+            &b""[..]
+        } else {
+            &b"evil"[..]
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn tar_unsafe_paths() {
+    let root = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+
+    for (body, entry) in [
+        (unsafe_tar("../evil", None), "../evil"),
+        (unsafe_tar("src/../../evil", None), "src/../../evil"),
+        (unsafe_tar("/tmp/evil", None), "/tmp/evil"),
+        (
+            unsafe_tar("evil", Some("../../outside")),
+            "links to ../../outside",
+        ),
+    ] {
+        let err = checkout_body(&cache, body).err().unwrap();
+        assert!(
+            err.iter_chain()
+                .any(|c| c.downcast_ref::<Error>() == Some(&Error::UnsafeArchivePath)),
+            "{}",
+            err
+        );
+        assert!(
+            err.iter_chain().any(|c| c.to_string().contains(entry)),
+            "{}",
+            err
+        );
+    }
+    assert!(!cache.layout.src.join("evil").exists());
+    assert!(!root.path().join("evil").exists());
+
+    // A link which stays inside of the package is fine
+    let (_, source) = checkout_body(&cache, unsafe_tar("src/link", Some("Data/Pkg.idr"))).unwrap();
+    assert!(source.path().join("src/Data/Pkg.idr").exists());
+}
+
+#[test]
+fn tar_chained_links() {
+    // Each link stays inside on its own, but `x` leads to the parent of the target through `r`,
+    // whichever of them comes first
+    for links in &[[("r", "."), ("x", "r/..")], [("x", "r/.."), ("r", ".")]] {
+        let mut tar = tar::Builder::new(vec![]);
+        for (path, link) in links {
+            append_unsafe(&mut tar, path, Some(link));
+        }
+        let body = tar.into_inner().unwrap();
+
+        let root = TempDir::new("elba").unwrap();
+        let target = root.path().join("pkg");
+        let err = unpack_archive(&body[..], &target).unwrap_err();
+        assert!(
+            err.iter_chain()
+                .any(|c| c.downcast_ref::<Error>() == Some(&Error::UnsafeArchivePath)),
+            "{}",
+            err
+        );
+        assert!(fs::symlink_metadata(target.join("x")).is_err());
+    }
+
+    // Links through links which stay inside are fine
+    let target = TempDir::new("elba").unwrap();
+    let mut tar = tar::Builder::new(vec![]);
+    append_unsafe(&mut tar, "r", Some("src"));
+    append_unsafe(&mut tar, "x", Some("r/Data/Pkg.idr"));
+    let mut body = tar.into_inner().unwrap();
+    body.truncate(body.len() - 1024);
+    body.extend(pkg_tar());
+    unpack_archive(&body[..], target.path()).unwrap();
+    assert_eq!(
+        fs::read_to_string(target.path().join("x")).unwrap(),
+        "module Data.Pkg"
+    );
+
+    let target = TempDir::new("elba").unwrap();
+    let body = pkg_zip(&[("r", "."), ("x", "r/..")]);
+    let err = ArchiveFormats::default()
+        .detect(&body)
+        .unwrap()
+        .extract(&mut &body[..], target.path())
+        .unwrap_err();
+    assert!(
+        err.iter_chain()
+            .any(|c| c.downcast_ref::<Error>() == Some(&Error::UnsafeArchivePath)),
+        "{}",
+        err
+    );
+    assert!(fs::symlink_metadata(target.path().join("x")).is_err());
+}
+
+#[test]
+fn cache_mirror_rewrite() {
+    let root = TempDir::new("elba").unwrap();
+    let body = pkg_tarball();
+    let (url, server) = serve_tarball(body.clone(), false);
+    let mirror = format!("{}/mirror/", url.origin().ascii_serialization());
+    let cache = new_cache(&root).with_mirrors(&indexmap!(
+        "https://public.invalid/".to_string() => mirror,
+    ));
+
+    // Nothing is listening at the original url, so this only works if the mirror is used
+    let loc = tar_res(
+        Url::parse("https://public.invalid/pkg.tar.gz").unwrap(),
+        &body,
+    );
+    let pkg = PackageId::new(
+        Name::from_str("compressed/pkg").unwrap(),
+        Resolution::Direct(loc.clone()),
+    );
+    let (_, source) = cache
+        .checkout_source(&pkg, &loc, false, false, || {})
+        .unwrap();
+    let req = server.join().unwrap();
+    assert!(req.contains("/mirror/pkg.tar.gz"), "{}", req);
+
+    // But the source is still cached and recorded under its original url
+    assert_eq!(source.location(), &loc);
+    assert!(cache.check_source(&loc).is_some());
+}
+
+#[test]
+fn cache_mirror_passthrough() {
+    let mirrors = Mirrors::new(&indexmap!(
+        "https://public.invalid/".to_string() => "https://mirror.invalid/".to_string(),
+        "https://public.invalid/elba/".to_string() => "https://elba.invalid/".to_string(),
+    ));
+    let res = |s: &str| DirectRes::from_str(s).unwrap();
+
+    // The longest matching prefix wins
+    assert_eq!(
+        mirrors
+            .apply(&res("git+https://public.invalid/elba/pkg"))
+            .unwrap(),
+        res("git+https://elba.invalid/pkg")
+    );
+    for unmatched in &[
+        "tar+https://elsewhere.invalid/public.invalid/pkg.tar.gz",
+        "git+https://github.com/elba/elba",
+        "dir+/public.invalid",
+    ] {
+        assert_eq!(mirrors.apply(&res(unmatched)).unwrap(), res(unmatched));
+    }
+}
+
+#[test]
+fn cache_tarball_dir_hit() {
+    let root = TempDir::new("elba").unwrap();
+    let tarballs = TempDir::new("elba").unwrap();
+    let body = pkg_tarball();
+    fs::write(tarballs.path().join("compressed_pkg-1.0.0.tar.gz"), &body).unwrap();
+    let cache = new_cache(&root).with_tarball_dirs(&[tarballs.path().to_owned()]);
+
+    // Nothing is listening at this url, and we're offline anyways
+    let loc = tar_res(
+        Url::parse("https://public.invalid/pkg.tar.gz").unwrap(),
+        &body,
+    );
+    let pkg = PackageId::new(
+        Name::from_str("compressed/pkg").unwrap(),
+        Resolution::Direct(loc.clone()),
+    );
+    let downloads = Cell::new(0);
+    let (_, source) = cache
+        .checkout_source(&pkg, &loc, false, true, || {
+            downloads.set(downloads.get() + 1)
+        })
+        .unwrap();
+
+    assert_eq!(downloads.get(), 0);
+    assert!(source.path().join("src/Data/Pkg.idr").exists());
+    assert!(cache.check_source(&loc).is_some());
+}
+
+#[test]
+fn cache_tarball_dir_other_package() {
+    let root = TempDir::new("elba").unwrap();
+    let tarballs = TempDir::new("elba").unwrap();
+    let body = pkg_tarball();
+    // The tarball of a package whose name only starts like this one's is never looked at
+    fs::write(
+        tarballs.path().join("compressed_pkg-extra-1.0.0.tar.gz"),
+        &body,
+    )
+    .unwrap();
+    let cache = new_cache(&root).with_tarball_dirs(&[tarballs.path().to_owned()]);
+
+    let loc = tar_res(
+        Url::parse("https://public.invalid/pkg.tar.gz").unwrap(),
+        &body,
+    );
+    let pkg = PackageId::new(
+        Name::from_str("compressed/pkg").unwrap(),
+        Resolution::Direct(loc.clone()),
+    );
+    assert!(cache
+        .checkout_source(&pkg, &loc, false, true, || ())
+        .is_err());
+
+    fs::rename(
+        tarballs.path().join("compressed_pkg-extra-1.0.0.tar.gz"),
+        tarballs.path().join("compressed_pkg-1.0.0.tar.gz"),
+    )
+    .unwrap();
+    cache
+        .checkout_source(&pkg, &loc, false, true, || ())
+        .unwrap();
+}
+
+#[test]
+fn cache_source_naming_round_trip() {
+    let root = TempDir::new("elba").unwrap();
+    let tarballs = TempDir::new("elba").unwrap();
+    let body = pkg_tarball();
+    fs::write(tarballs.path().join("compressed_pkg-1.0.0.tar.gz"), &body).unwrap();
+    let loc = tar_res(
+        Url::parse("https://public.invalid/pkgs/pkg-1.0.0.tar.gz").unwrap(),
+        &body,
+    );
+    let pkg = PackageId::new(
+        Name::from_str("compressed/pkg").unwrap(),
+        Resolution::Direct(loc.clone()),
+    );
+    let sum = Summary::new(pkg.clone(), Version::parse("1.0.0").unwrap());
+
+    for naming in &[SourceNaming::Hashed, SourceNaming::Readable] {
+        let cache = new_cache(&root)
+            .with_tarball_dirs(&[tarballs.path().to_owned()])
+            .with_source_naming(*naming);
+        fs::remove_dir_all(&cache.layout.src).unwrap();
+        fs::create_dir_all(&cache.layout.src).unwrap();
+
+        let (_, source) = cache
+            .checkout_summary(&sum, &loc, false, true, || {})
+            .unwrap();
+        drop(source);
+
+        let name = cache.source_dir(&loc, true);
+        assert_eq!(cache.check_source(&loc), Some(cache.layout.src.join(&name)));
+        assert!(cache.cached_packages().contains(&name));
+        match naming {
+            SourceNaming::Hashed => assert!(parse_hash(&name).is_some()),
+            SourceNaming::Readable => assert_eq!(name, "compressed_pkg-1.0.0"),
+        }
+
+        // Whatever the naming is changed to, the source is found where it is
+        let other = new_cache(&root).with_source_naming(match naming {
+            SourceNaming::Hashed => SourceNaming::Readable,
+            SourceNaming::Readable => SourceNaming::Hashed,
+        });
+        assert_eq!(other.source_dir(&loc, false), name);
+        assert_eq!(other.check_source(&loc), Some(other.layout.src.join(&name)));
+        other
+            .checkout_source(&pkg, &loc, false, true, || {})
+            .unwrap();
+    }
+
+    // Without a version, the source is named after the package alone
+    let cache = new_cache(&root)
+        .with_tarball_dirs(&[tarballs.path().to_owned()])
+        .with_source_naming(SourceNaming::Readable);
+    fs::remove_dir_all(&cache.layout.src).unwrap();
+    fs::create_dir_all(&cache.layout.src).unwrap();
+    cache
+        .checkout_source(&pkg, &loc, false, true, || {})
+        .unwrap();
+    assert_eq!(cache.source_dir(&loc, true), "compressed_pkg");
+}
+
+#[test]
+fn cache_source_naming_distinct() {
+    let root = TempDir::new("elba").unwrap();
+    let tarballs = TempDir::new("elba").unwrap();
+    let body = pkg_tarball();
+    fs::write(tarballs.path().join("compressed_pkg-1.0.0.tar.gz"), &body).unwrap();
+    let cache = new_cache(&root)
+        .with_tarball_dirs(&[tarballs.path().to_owned()])
+        .with_source_naming(SourceNaming::Readable);
+
+    // The same package from two places can't share a directory
+    let locs = [
+        "https://public.invalid/a/pkg.tar.gz",
+        "https://public.invalid/b/pkg.tar.gz",
+    ]
+    .iter()
+    .map(|x| tar_res(Url::parse(x).unwrap(), &body))
+    .collect::<Vec<_>>();
+    for loc in &locs {
+        let pkg = PackageId::new(
+            Name::from_str("compressed/pkg").unwrap(),
+            Resolution::Direct(loc.clone()),
+        );
+        let sum = Summary::new(pkg, Version::parse("1.0.0").unwrap());
+        cache
+            .checkout_summary(&sum, loc, false, true, || {})
+            .unwrap();
+    }
+
+    let first = cache.source_dir(&locs[0], true);
+    let second = cache.source_dir(&locs[1], true);
+    assert_eq!(first, "compressed_pkg-1.0.0");
+    // Only the source which came second gets the start of its hash added on
+    let hash = Cache::get_source_dir(&locs[1], true);
+    assert!(second.starts_with("compressed_pkg-1.0.0-"), "{}", second);
+    assert!(hash.contains(&second["compressed_pkg-1.0.0-".len()..]));
+    for (loc, name) in locs.iter().zip(&[first, second]) {
+        assert_eq!(cache.check_source(loc), Some(cache.layout.src.join(name)));
+    }
+    assert_eq!(cache.cached_packages().len(), 2);
+
+    // Sources which are exported go by their hashes, and get whatever name the cache they're
+    // imported into has for them
+    let out = TempDir::new("elba").unwrap();
+    let archive = export(&cache, ExportFilter::Sources, out.path());
+    let other = TempDir::new("elba").unwrap();
+    let other = new_cache(&other);
+    let report = other.import(&archive).unwrap();
+    assert!(report.imported.contains(&format!("src/{}", hash)));
+    assert_eq!(
+        other.check_source(&locs[1]),
+        Some(other.layout.src.join(&hash))
+    );
+}
+
+#[test]
+fn cache_restores_modified_source() {
+    let root = TempDir::new("elba").unwrap();
+    let tarballs = TempDir::new("elba").unwrap();
+    let body = pkg_tarball();
+    fs::write(tarballs.path().join("compressed_pkg-1.0.0.tar.gz"), &body).unwrap();
+    let cache = new_cache(&root).with_tarball_dirs(&[tarballs.path().to_owned()]);
+
+    let loc = tar_res(
+        Url::parse("https://public.invalid/pkg.tar.gz").unwrap(),
+        &body,
+    );
+    let pkg = PackageId::new(
+        Name::from_str("compressed/pkg").unwrap(),
+        Resolution::Direct(loc.clone()),
+    );
+    let (_, source) = cache
+        .checkout_source(&pkg, &loc, false, true, || ())
+        .unwrap();
+    let (path, hash) = (source.path().to_owned(), source.hash().to_owned());
+    let file = path.join("src/Data/Pkg.idr");
+    let pristine = fs::read(&file).unwrap();
+    drop(source);
+
+    // The hashes are kept next to the source rather than in it
+    assert!(!path.join("target").exists());
+    let sidecar = cache.layout.src.join(format!(
+        "{}.hashes.json",
+        path.file_name().unwrap().to_string_lossy()
+    ));
+    assert!(sidecar.is_file());
+
+    fs::write(&file, "module Data.Pkg\n\nevil : ()\nevil = ()\n").unwrap();
+    fs::write(path.join("src/Extra.idr"), "module Extra\n").unwrap();
+
+    let (_, source) = cache
+        .checkout_source(&pkg, &loc, false, true, || ())
+        .unwrap();
+    assert_eq!(source.path(), path.as_path());
+    assert_eq!(fs::read(&file).unwrap(), pristine);
+    assert!(!path.join("src/Extra.idr").exists());
+    assert_eq!(source.hash(), hash.as_str());
+    drop(source);
+
+    // A file which was only added counts too
+    fs::write(path.join("src/Extra.idr"), "module Extra\n").unwrap();
+    let (_, source) = cache
+        .checkout_source(&pkg, &loc, false, true, || ())
+        .unwrap();
+    assert!(!path.join("src/Extra.idr").exists());
+    assert_eq!(source.hash(), hash.as_str());
+}
+
+/// Checks the package in `bulky_tarball` out of a cache which finds it in a tarball directory,
+/// returning the cache, the package's resolution and the Source.
+fn checkout_bulky(root: &TempDir, tarballs: &TempDir) -> (Cache, DirectRes, Source) {
+    let body = bulky_tarball();
+    fs::write(tarballs.path().join("compressed_pkg-1.0.0.tar.gz"), &body).unwrap();
+    let cache = new_cache(root)
+        .with_tarball_dirs(&[tarballs.path().to_owned()])
+        .with_slim_sources(true);
+
+    let loc = tar_res(
+        Url::parse("https://public.invalid/pkg.tar.gz").unwrap(),
+        &body,
+    );
+    let pkg = PackageId::new(
+        Name::from_str("compressed/pkg").unwrap(),
+        Resolution::Direct(loc.clone()),
+    );
+    let (_, source) = cache
+        .checkout_source(&pkg, &loc, false, true, || {})
+        .unwrap();
+
+    (cache, loc, source)
+}
+
+#[test]
+fn cache_slim_source() {
+    let root = TempDir::new("elba").unwrap();
+    let tarballs = TempDir::new("elba").unwrap();
+    let (cache, loc, source) = checkout_bulky(&root, &tarballs);
+    let hash = source.hash().to_owned();
+    let path = source.path().to_owned();
+
+    assert!(cache.slim_source(&source).unwrap());
+    assert!(source.is_slim());
+    assert!(path.join("elba.toml").exists());
+    assert!(path.join("src/Data/Pkg.idr").exists());
+    assert!(!path.join("src/Data/Pkg").exists());
+    assert!(!path.join("docs").exists());
+    assert!(!path.join("README.md").exists());
+    // Slimming twice does nothing
+    assert!(!cache.slim_source(&source).unwrap());
+    drop(source);
+
+    // The slimmed source still goes by the hash of the full one, so its builds are still found
+    let pkg = PackageId::new(
+        Name::from_str("compressed/pkg").unwrap(),
+        Resolution::Direct(loc.clone()),
+    );
+    let (_, source) = cache
+        .checkout_source(&pkg, &loc, false, true, || {})
+        .unwrap();
+    assert!(source.is_slim());
+    assert_eq!(source.hash(), hash);
+}
+
+#[test]
+fn cache_restore_slim_source() {
+    let root = TempDir::new("elba").unwrap();
+    let tarballs = TempDir::new("elba").unwrap();
+    let (cache, _, source) = checkout_bulky(&root, &tarballs);
+    let hash = source.hash().to_owned();
+
+    assert!(cache.slim_source(&source).unwrap());
+    cache.restore_source(&source).unwrap();
+    assert!(!source.is_slim());
+    assert!(source.path().join("README.md").exists());
+    assert!(source.path().join("docs/guide.md").exists());
+    assert!(source.path().join("src/Data/Pkg/Internal.md").exists());
+
+    // Without the tarball to restore it from, a slimmed source can't be built again offline
+    assert!(cache.slim_source(&source).unwrap());
+    fs::remove_dir_all(tarballs.path()).unwrap();
+    let mut offline = new_cache(&root);
+    offline.offline = true;
+    assert!(offline.restore_source(&source).is_err());
+    assert_eq!(source.hash(), hash);
+}
+
+#[test]
+fn cache_checkout_version_mismatch() {
+    let root = TempDir::new("elba").unwrap();
+    let tarballs = TempDir::new("elba").unwrap();
+    let body = pkg_tarball();
+    fs::write(tarballs.path().join("compressed_pkg-1.0.0.tar.gz"), &body).unwrap();
+    let cache = new_cache(&root).with_tarball_dirs(&[tarballs.path().to_owned()]);
+
+    let loc = tar_res(
+        Url::parse("https://public.invalid/pkg.tar.gz").unwrap(),
+        &body,
+    );
+    let pkg = PackageId::new(
+        Name::from_str("compressed/pkg").unwrap(),
+        Resolution::Direct(loc.clone()),
+    );
+
+    // The index says this tarball is version 2.0.0, but its manifest says it's 1.0.0
+    let sum = Summary::new(pkg.clone(), Version::parse("2.0.0").unwrap());
+    let err = cache
+        .checkout_summary(&sum, &loc, false, true, || {})
+        .unwrap_err();
+    assert!(err
+        .iter_chain()
+        .any(|c| c.downcast_ref::<Error>() == Some(&Error::VersionMismatch)));
+    let msg = err.to_string();
+    assert!(msg.contains("2.0.0") && msg.contains("1.0.0"), "{}", msg);
+
+    let sum = Summary::new(pkg, Version::parse("1.0.0").unwrap());
+    let (_, source) = cache
+        .checkout_summary(&sum, &loc, false, true, || {})
+        .unwrap();
+    assert_eq!(source.meta().version(), sum.version());
+}
+
+/// A lockfile of a root package in `project` and two dependencies, each served once as a tarball.
+/// The checksum of the second dependency is taken from `locked` instead of what's served if given.
+fn served_lock(
+    project: &Path,
+    locked: Option<&[u8]>,
+) -> (Graph<Summary>, Vec<thread::JoinHandle<String>>) {
+    write_package(project, "warm/root");
+    let summary = |name: &str, res: DirectRes| {
+        Summary::new(
+            PackageId::new(Name::from_str(name).unwrap(), res.into()),
+            Version::parse("1.0.0").unwrap(),
+        )
+    };
+
+    let mut inner = petgraph::Graph::new();
+    let root = inner.add_node(summary(
+        "warm/root",
+        DirectRes::Dir {
+            path: project.to_owned(),
+        },
+    ));
+    let mut servers = vec![];
+    for (i, name) in ["warm/one", "warm/two"].iter().enumerate() {
+        let manifest = format!(
+            "[package]\nname = \"{}\"\nversion = \"1.0.0\"\nauthors = []\n",
+            name
+        );
+        let body = GzEncoder::new(
+            tar_of("", &[("elba.toml", manifest.as_bytes())]),
+            Compression::default(),
+        )
+        .finish()
+        .unwrap();
+        let served = body.clone();
+        let (url, server) = serve_once(move |_| response("200 OK", "", &served));
+        servers.push(server);
+
+        let cksum = match locked {
+            Some(locked) if i == 1 => locked.to_vec(),
+            _ => body,
+        };
+        let dep = inner.add_node(summary(name, tar_res(url, &cksum)));
+        inner.add_edge(root, dep, ());
+    }
+
+    (Graph::new(inner), servers)
+}
+
+#[test]
+fn cache_warm_from_lock() {
+    let root = TempDir::new("elba").unwrap();
+    let project = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+    let (solve, servers) = served_lock(project.path(), None);
+    let lock: LockfileToml = solve.clone().into();
+
+    cache.warm_from_lock(&lock, 0).unwrap();
+    for server in servers {
+        server.join().unwrap();
+    }
+
+    // Both servers are gone now, so warming again only works if nothing's downloaded again
+    cache.warm_from_lock(&lock, 0).unwrap();
+
+    let mut retriever = Retriever::new(
+        &cache.logger,
+        &cache,
+        solve.inner[NodeIndex::new(0)].clone(),
+        vec![],
+        Left(vec![]),
+        solve.clone(),
+        &IXMAP,
+        shell(),
+        true,
+    );
+    let sources = retriever.retrieve_packages(&solve).unwrap();
+    assert_eq!(sources.inner.node_count(), 3);
+}
+
+#[test]
+fn cache_warm_from_lock_checksum() {
+    let root = TempDir::new("elba").unwrap();
+    let project = TempDir::new("elba").unwrap();
+    let cache = new_cache(&root);
+    let (solve, servers) = served_lock(project.path(), Some(b"not the tarball"));
+    let lock: LockfileToml = solve.clone().into();
+
+    let err = cache.warm_from_lock(&lock, 1).unwrap_err();
+    assert!(err.to_string().contains("warm/two"), "{}", err);
+    for server in servers {
+        server.join().unwrap();
+    }
+
+    // The dependency with the right checksum was still cached
+    let one = &solve.inner[NodeIndex::new(1)];
+    let two = &solve.inner[NodeIndex::new(2)];
+    assert!(cache
+        .check_source(one.resolution().direct().unwrap())
+        .is_some());
+    assert!(cache
+        .check_source(two.resolution().direct().unwrap())
+        .is_none());
+}
+
+/// Every file and directory under `dir`, with when it was last modified.
+fn snapshot(dir: &Path) -> Vec<(PathBuf, SystemTime)> {
+    walkdir::WalkDir::new(dir)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            let modified = entry.metadata().unwrap().modified().unwrap();
+            (entry.into_path(), modified)
+        })
+        .collect()
+}
+
+/// A cache in `root` on top of the read-only cache in `base`.
+fn overlay_cache(root: &TempDir, base: &TempDir) -> Cache {
+    let layout = new_cache(root).layout;
+    let base = layout.relocated(base.path());
+    let mut cache = Cache::from_disk(&LOGGER, layout, Some(base), shell()).unwrap();
+    cache.offline = true;
+    cache
+}
+
+#[test]
+fn cache_base_source() {
+    // Whoever manages the base cache fills it in
+    let base = TempDir::new("elba").unwrap();
+    let tarballs = TempDir::new("elba").unwrap();
+    let (_, loc, source) = checkout_bulky(&base, &tarballs);
+    let pkg = PackageId::new(
+        Name::from_str("compressed/pkg").unwrap(),
+        Resolution::Direct(loc.clone()),
+    );
+    let hash = source.hash().to_owned();
+    drop(source);
+    let before = snapshot(base.path());
+
+    // Without the tarball directory, and offline, the base cache is the only place to get it from
+    let root = TempDir::new("elba").unwrap();
+    let cache = overlay_cache(&root, &base);
+    let (_, source) = cache
+        .checkout_source(&pkg, &loc, false, true, || {})
+        .unwrap();
+    assert!(source.path().starts_with(base.path()));
+    assert_eq!(source.hash(), hash);
+    assert_eq!(snapshot(base.path()), before);
+    assert_eq!(fs::read_dir(&cache.layout.src).unwrap().count(), 0);
+}
+
+#[test]
+fn cache_base_build() {
+    let base = TempDir::new("elba").unwrap();
+    let hash = BuildHash("0123456789abcdef".to_string());
+    let profile = BuildProfile::new("debug");
+    let out = TempDir::new("elba").unwrap();
+    fs::write(out.path().join("lib.ibc"), b"shared").unwrap();
+    drop(
+        new_cache(&base)
+            .store_build(out.path(), &hash, &profile)
+            .unwrap(),
+    );
+    let before = snapshot(base.path());
+
+    let root = TempDir::new("elba").unwrap();
+    let cache = overlay_cache(&root, &base);
+    let binary = cache.checkout_build(&hash, &profile).unwrap().unwrap();
+    assert!(binary.target.path().starts_with(base.path()));
+    assert_eq!(snapshot(base.path()), before);
+
+    // New builds only ever go in the writable cache
+    let other = BuildHash("fedcba9876543210".to_string());
+    let stored = cache.store_build(out.path(), &other, &profile).unwrap();
+    assert!(stored.target.path().starts_with(root.path()));
+    drop((binary, stored));
+    assert_eq!(snapshot(base.path()), before);
+}
+
+#[test]
+fn cache_tarball_dir_miss() {
+    let root = TempDir::new("elba").unwrap();
+    let tarballs = TempDir::new("elba").unwrap();
+    // A tarball with the right name but the wrong checksum is never used
+    fs::write(
+        tarballs.path().join("compressed_pkg-1.0.0.tar.gz"),
+        pkg_tar(),
+    )
+    .unwrap();
+    let cache = new_cache(&root).with_tarball_dirs(&[tarballs.path().to_owned()]);
+
+    let (_, source) = checkout_body(&cache, pkg_tarball()).unwrap();
+    assert!(source.path().join("src/Data/Pkg.idr").exists());
+}
+
+#[test]
+fn cache_compressed_sources() {
+    let plain_root = TempDir::new("elba").unwrap();
+    let (plain, plain_loc, plain_src) = checkout_tarball(&plain_root, false);
+    let root = TempDir::new("elba").unwrap();
+    let (cache, loc, source) = checkout_tarball(&root, true);
+
+    let dir = cache.layout.src.join(Cache::get_source_dir(&loc, true));
+    assert!(!dir.exists());
+    let archive = cache.check_source(&loc).unwrap();
+    assert!(archive.is_file());
+    assert_eq!(
+        plain.check_source(&plain_loc),
+        Some(plain_src.path().to_owned())
+    );
+
+    // Both storage modes give the same package
+    assert_eq!(source.hash(), plain_src.hash());
+    assert_eq!(source.meta().name(), plain_src.meta().name());
+    assert_eq!(source.meta().version(), plain_src.meta().version());
+    assert!(source.path().join("src/Data/Pkg.idr").exists());
+    assert!(source.path().starts_with(&cache.layout.tmp));
+
+    let sum = Summary::new(
+        PackageId::new(source.meta().name().clone(), loc.clone().into()),
+        source.meta().version().clone(),
+    );
+    assert_eq!(cache.which(&sum, &loc), Some(archive));
+    assert!(cache
+        .cached_packages()
+        .contains(&Cache::get_source_dir(&loc, true)));
+
+    // The archive is used from then on without downloading anything, and working directories
+    // which nobody holds anymore are cleaned up along the way
+    let stale = cache.layout.tmp.join("src-stale");
+    fs::create_dir_all(&stale).unwrap();
+    let first = source.path().to_owned();
+    drop(source);
+    let (_, again) = cache
+        .checkout_source(sum.id(), &loc, false, true, || {})
+        .unwrap();
+    assert_eq!(again.hash(), plain_src.hash());
+    assert_eq!(again.path(), first.as_path());
+    assert!(!stale.exists());
+}
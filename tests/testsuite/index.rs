@@ -1,6 +1,35 @@
-use super::util::index;
-use elba::package::Name;
-use std::str::FromStr;
+use super::util::{cache, index, CACHE, INDEX_DIR};
+use elba::{
+    cli::index::{check_republish, package},
+    package::{manifest::Manifest, Name, PackageId},
+    remote::{
+        prioritize_index,
+        resolution::{DirectRes, IndexRes},
+        signature::PublicKey,
+        EntryMeta, Index, Indices, RawEntry,
+    },
+    util::{copy_dir, error::Error, lock::DirLock},
+};
+use filetime::{set_file_mtime, FileTime};
+use flate2::{write::GzEncoder, Compression};
+use indexmap::{indexmap, IndexMap};
+use openssl::{
+    pkey::{PKey, Private},
+    sign::Signer,
+};
+use semver::Version;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Read, Write},
+    net::TcpListener,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
+};
+use tempdir::TempDir;
 
 #[test]
 fn index_success() {
@@ -9,3 +38,901 @@ fn index_success() {
     let vs = i.entries(&Name::from_str("no_conflict/root").unwrap());
     vs.unwrap();
 }
+
+#[test]
+fn index_summaries_channel() {
+    let i = index();
+    let name = Name::from_str("prerelease/bar").unwrap();
+    let versions = |channel| {
+        i.summaries(&name, channel)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.version().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        versions(None),
+        vec!["1.0.0", "1.1.0-beta.1", "2.0.0-alpha.1"]
+    );
+    assert_eq!(versions(Some("stable")), vec!["1.0.0"]);
+    assert_eq!(versions(Some("beta")), vec!["1.1.0-beta.1"]);
+    // An explicit channel takes precedence over the prerelease tag
+    assert_eq!(versions(Some("nightly")), vec!["2.0.0-alpha.1"]);
+    assert!(versions(Some("alpha")).is_empty());
+}
+
+#[test]
+fn index_search_channel() {
+    let ixs = Indices::new(vec![index()]);
+    let search = |channel| {
+        ixs.search("prerelease/bar", channel)
+            .unwrap()
+            .into_iter()
+            .filter(|(name, _, _)| name.as_str() == "prerelease/bar")
+            .map(|(_, version, _)| version.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(search(None), vec!["2.0.0-alpha.1"]);
+    assert_eq!(search(Some("stable")), vec!["1.0.0"]);
+    assert!(search(Some("alpha")).is_empty());
+}
+
+fn ixmap() -> IndexMap<String, IndexRes> {
+    indexmap!("private".to_string() => IndexRes {
+        res: DirectRes::Dir {
+            path: INDEX_DIR.path().to_owned(),
+        },
+    })
+}
+
+#[test]
+fn index_by_name() {
+    let ixmap = ixmap();
+    let res = ixmap.values().map(|x| x.res.clone()).collect::<Vec<_>>();
+    let mut ixs = CACHE.get_indices(&res, false, false);
+    ixs.alias(&ixmap);
+
+    let ix = ixs.by_name("private").unwrap();
+    assert_eq!(ix.name(), Some("private"));
+}
+
+#[test]
+fn index_by_name_missing() {
+    let ixmap = ixmap();
+    let res = ixmap.values().map(|x| x.res.clone()).collect::<Vec<_>>();
+    let mut ixs = CACHE.get_indices(&res, false, false);
+    ixs.alias(&ixmap);
+
+    let err = ixs.by_name("public").unwrap_err().to_string();
+    assert!(err.contains("`public`"));
+    assert!(err.contains("private"));
+}
+
+#[test]
+fn index_prioritize_config() {
+    let mut ixmap = ixmap();
+    ixmap.insert(
+        "other".to_string(),
+        IndexRes {
+            res: DirectRes::from_str("dir+data/other").unwrap(),
+        },
+    );
+
+    prioritize_index(&mut ixmap, "other").unwrap();
+    assert_eq!(ixmap.keys().collect::<Vec<_>>(), vec!["other", "private"]);
+
+    let err = prioritize_index(&mut ixmap, "missing")
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("no index named `missing`"));
+    assert!(err.contains("other, private"));
+}
+
+const FULL_MANIFEST: &str = r#"
+[package]
+name = 'awesome/pkg'
+version = '1.2.3'
+authors = ['me']
+description = 'An awesome package'
+keywords = ['awesome', 'cool']
+categories = ['parsing']
+homepage = 'https://example.com'
+repository = 'https://github.com/awesome/pkg'
+license = 'MIT'
+
+[dependencies]
+'awesome/dep' = '>= 1.0.0 < 2.0.0'
+
+[badges]
+travis-ci = { repository = 'awesome/pkg' }
+"#;
+
+const MINIMAL_MANIFEST: &str = r#"
+[package]
+name = 'awesome/pkg'
+version = '1.2.3'
+authors = ['me']
+"#;
+
+#[test]
+fn index_entry_full_meta() {
+    let manifest = Manifest::from_str(FULL_MANIFEST).unwrap();
+    let entry = RawEntry::from_manifest(&manifest, None).unwrap();
+
+    assert_eq!(
+        entry.meta.description.as_deref(),
+        Some("An awesome package")
+    );
+    assert_eq!(entry.meta.categories, vec!["parsing".to_string()]);
+    assert_eq!(entry.meta.badges["travis-ci"]["repository"], "awesome/pkg");
+    assert_eq!(entry.dependencies.len(), 1);
+
+    let json = serde_json::to_value(&entry).unwrap();
+    assert_eq!(json["homepage"], "https://example.com");
+    assert_eq!(json["repository"], "https://github.com/awesome/pkg");
+    assert_eq!(json["license"], "MIT");
+    assert_eq!(json["keywords"][1], "cool");
+
+    // The metadata survives a round trip through the index format
+    let back: RawEntry = serde_json::from_value(json).unwrap();
+    assert_eq!(back, entry);
+}
+
+#[test]
+fn index_entry_minimal_meta() {
+    let manifest = Manifest::from_str(MINIMAL_MANIFEST).unwrap();
+    let entry = RawEntry::from_manifest(&manifest, None).unwrap();
+    let json = serde_json::to_value(&entry).unwrap();
+    let json = json.as_object().unwrap();
+
+    for key in &[
+        "description",
+        "homepage",
+        "repository",
+        "license",
+        "keywords",
+        "categories",
+        "badges",
+    ] {
+        assert!(!json.contains_key(*key), "{} shouldn't be serialized", key);
+    }
+    assert_eq!(entry.meta, EntryMeta::default());
+}
+
+#[test]
+fn index_entry_features() {
+    let manifest = Manifest::from_str(
+        r#"
+[package]
+name = "awesome/pkg"
+version = "1.0.0"
+authors = []
+
+[dependencies]
+"awesome/dep" = "1.0.0"
+"awesome/short" = { version = "1.0.0", package = "awesome/optional", optional = true, features = ["fast"] }
+
+[features]
+default = ["turbo"]
+turbo = ["awesome/short"]
+
+[targets.lib]
+mods = []
+"#,
+    )
+    .unwrap();
+    let entry = RawEntry::from_manifest(&manifest, None).unwrap();
+
+    let optional = &entry.dependencies[1];
+    assert!(optional.optional);
+    assert_eq!(optional.features, vec!["fast".to_string()]);
+    assert_eq!(
+        entry.features["turbo"],
+        vec!["awesome/optional".to_string()]
+    );
+    assert!(!serde_json::to_value(&entry.dependencies[0])
+        .unwrap()
+        .as_object()
+        .unwrap()
+        .contains_key("optional"));
+
+    // The default feature turns the optional dependency on
+    assert_eq!(entry.active_deps(&[]).unwrap().len(), 2);
+    let mut no_default = entry.clone();
+    no_default.features.remove("default");
+    assert_eq!(no_default.active_deps(&[]).unwrap().len(), 1);
+    assert_eq!(
+        no_default
+            .active_deps(&["turbo".to_string()])
+            .unwrap()
+            .len(),
+        2
+    );
+    assert!(no_default.active_deps(&["missing".to_string()]).is_err());
+}
+
+#[test]
+fn index_refresh() {
+    let dir = TempDir::new("elba").unwrap();
+    let write_index = |name: &str, versions: &[&str]| {
+        fs::write(
+            dir.path().join("index.toml"),
+            format!(
+                "[index]\nname = \"{}\"\nsecure = false\ndependencies = {{}}\n",
+                name
+            ),
+        )
+        .unwrap();
+        let entries = versions
+            .iter()
+            .map(|v| {
+                format!(
+                    r#"{{ "name": "refresh/pkg", "version": "{}", "dependencies": [], "yanked": false, "location": "dir+test" }}"#,
+                    v
+                )
+            })
+            .collect::<Vec<_>>();
+        fs::create_dir_all(dir.path().join("refresh")).unwrap();
+        fs::write(dir.path().join("refresh/pkg"), entries.join("\n")).unwrap();
+    };
+    write_index("before", &["1.0.0"]);
+
+    let res = DirectRes::Dir {
+        path: dir.path().to_owned(),
+    };
+    let mut ixs = CACHE.get_indices(std::slice::from_ref(&res), false, false);
+    let pkg = PackageId::new(
+        Name::from_str("refresh/pkg").unwrap(),
+        IndexRes { res }.into(),
+    );
+    assert_eq!(ixs.entries(&pkg).unwrap().len(), 1);
+
+    write_index("after", &["1.0.0", "1.1.0"]);
+    // Entries which were already loaded stick around until the indices are refreshed.
+    assert_eq!(ixs.entries(&pkg).unwrap().len(), 1);
+
+    ixs.refresh(CACHE.client(), &CACHE.credentials).unwrap();
+    let versions = ixs
+        .entries(&pkg)
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+    assert_eq!(
+        versions,
+        vec![
+            Version::parse("1.0.0").unwrap(),
+            Version::parse("1.1.0").unwrap()
+        ]
+    );
+    assert!(ixs.by_name("after").is_ok());
+}
+
+fn sign(key: &PKey<Private>, msg: &[u8]) -> String {
+    let mut signer = Signer::new_without_digest(key).unwrap();
+    hex::encode(signer.sign_oneshot_to_vec(msg).unwrap())
+}
+
+/// Writes out a signed index with a single entry, letting the entry be changed after it's
+/// signed. The index as a whole is signed after that, so only the entry's signature is off.
+fn write_signed_index(dir: &Path, key: &PKey<Private>, tamper: impl FnOnce(&mut RawEntry)) {
+    fs::write(
+        dir.join("index.toml"),
+        format!(
+            "[index]\nsecure = false\ndependencies = {{}}\npublic_key = \"{}\"\n",
+            public_key(key)
+        ),
+    )
+    .unwrap();
+
+    let mut entry: RawEntry = serde_json::from_str(
+        r#"{ "name": "signed/pkg", "version": "1.0.0", "dependencies": [], "yanked": false, "location": "tar+https://example.com/pkg.tar.gz#sha256=abcd" }"#,
+    )
+    .unwrap();
+    entry.signature = Some(sign(key, entry.signing_message().unwrap().as_bytes()));
+    tamper(&mut entry);
+    fs::create_dir_all(dir.join("signed")).unwrap();
+    fs::write(
+        dir.join("signed/pkg"),
+        serde_json::to_string(&entry).unwrap(),
+    )
+    .unwrap();
+
+    let digest = open_index(dir).digest().unwrap();
+    fs::write(dir.join("index.sig"), sign(key, digest.as_bytes())).unwrap();
+}
+
+fn public_key(key: &PKey<Private>) -> PublicKey {
+    let der = key.public_key_to_der().unwrap();
+    PublicKey::from_str(&hex::encode(&der[der.len() - 32..])).unwrap()
+}
+
+/// Looks `signed/pkg` up in the index at `dir` through a cache which trusts `key` for it.
+fn trusted_entries(dir: &Path, key: Option<&PKey<Private>>) -> Option<usize> {
+    let res = DirectRes::Dir {
+        path: dir.to_owned(),
+    };
+    let keys = key
+        .map(|key| indexmap!(res.clone() => public_key(key)))
+        .unwrap_or_default();
+    let mut ixs =
+        cache()
+            .with_index_keys(&keys)
+            .get_indices(std::slice::from_ref(&res), false, false);
+    let pkg = PackageId::new(signed_pkg(), IndexRes { res }.into());
+    ixs.entries(&pkg).ok().map(|es| es.len())
+}
+
+fn open_index(dir: &Path) -> Index {
+    let res = DirectRes::Dir {
+        path: dir.to_owned(),
+    };
+    Index::from_disk(res, DirLock::acquire(dir).unwrap()).unwrap()
+}
+
+fn signed_pkg() -> Name {
+    Name::from_str("signed/pkg").unwrap()
+}
+
+#[test]
+fn index_signed() {
+    let dir = TempDir::new("elba").unwrap();
+    let key = PKey::generate_ed25519().unwrap();
+    write_signed_index(dir.path(), &key, |_| {});
+
+    assert_eq!(trusted_entries(dir.path(), Some(&key)), Some(1));
+}
+
+#[test]
+fn index_signed_untrusted_key() {
+    let dir = TempDir::new("elba").unwrap();
+    let key = PKey::generate_ed25519().unwrap();
+    write_signed_index(dir.path(), &key, |_| {});
+
+    // The key an index declares isn't trusted unless it's configured too
+    assert_eq!(trusted_entries(dir.path(), None), None);
+    let other = PKey::generate_ed25519().unwrap();
+    assert_eq!(trusted_entries(dir.path(), Some(&other)), None);
+}
+
+#[test]
+fn index_signed_undeclared_key() {
+    let dir = TempDir::new("elba").unwrap();
+    let key = PKey::generate_ed25519().unwrap();
+    write_signed_index(dir.path(), &key, |_| {});
+    fs::write(
+        dir.path().join("index.toml"),
+        "[index]\nsecure = false\ndependencies = {}\n",
+    )
+    .unwrap();
+
+    // An index with a configured key has to be signed with it even if it stops saying so
+    assert_eq!(trusted_entries(dir.path(), Some(&key)), None);
+    fs::remove_file(dir.path().join("index.sig")).unwrap();
+    assert_eq!(trusted_entries(dir.path(), Some(&key)), None);
+}
+
+#[test]
+fn index_signed_tampered_entry() {
+    let dir = TempDir::new("elba").unwrap();
+    let key = PKey::generate_ed25519().unwrap();
+    write_signed_index(dir.path(), &key, |entry| {
+        entry.location =
+            Some(DirectRes::from_str("tar+https://example.com/evil.tar.gz#sha256=abcd").unwrap())
+    });
+
+    let err = open_index(dir.path()).entries(&signed_pkg()).unwrap_err();
+    assert!(err.to_string().contains("signed/pkg"), "{}", err);
+}
+
+#[test]
+fn index_signed_unsigned_entry() {
+    let dir = TempDir::new("elba").unwrap();
+    let key = PKey::generate_ed25519().unwrap();
+    write_signed_index(dir.path(), &key, |entry| entry.signature = None);
+
+    assert!(open_index(dir.path()).entries(&signed_pkg()).is_err());
+}
+
+#[test]
+fn index_signed_tampered_contents() {
+    let dir = TempDir::new("elba").unwrap();
+    let key = PKey::generate_ed25519().unwrap();
+    write_signed_index(dir.path(), &key, |_| {});
+
+    let ix = open_index(dir.path());
+    let public_key = ix.config.index.public_key.clone().unwrap();
+    assert!(ix.verify_signature(&public_key).is_ok());
+    drop(ix);
+
+    fs::write(dir.path().join("signed/other"), "").unwrap();
+    let ix = open_index(dir.path());
+    assert!(ix.verify_signature(&public_key).is_err());
+    drop(ix);
+
+    // An index which fails verification isn't used at all
+    assert_eq!(trusted_entries(dir.path(), Some(&key)), None);
+}
+
+/// Serves the files of a sparse index until the test ends, returning the index's resolution and
+/// a log of every path which was requested.
+pub fn serve_sparse(files: HashMap<&'static str, String>) -> (IndexRes, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let res = IndexRes::from_str(&format!(
+        "index+sparse+http://{}/",
+        listener.local_addr().unwrap()
+    ))
+    .unwrap();
+    let log = Arc::new(Mutex::new(vec![]));
+
+    let requests = log.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut req = vec![];
+            let mut buf = [0; 1024];
+            while !req.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                req.extend_from_slice(&buf[..n]);
+            }
+
+            let req = String::from_utf8(req).unwrap();
+            let path = req.split_whitespace().nth(1).unwrap().to_string();
+            let (status, body) = match files.get(path.as_str()) {
+                Some(body) => ("200 OK", body.as_str()),
+                None => ("404 Not Found", ""),
+            };
+            requests.lock().unwrap().push(path);
+            stream
+                .write_all(
+                    format!(
+                        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    )
+                    .as_bytes(),
+                )
+                .unwrap();
+        }
+    });
+
+    (res, log)
+}
+
+#[test]
+fn index_sparse() {
+    let mut files = HashMap::new();
+    files.insert(
+        "/index.toml",
+        "[index]\nname = \"sparse\"\nsecure = false\ndependencies = {}\n".to_string(),
+    );
+    files.insert(
+        "/sparse/pkg",
+        r#"{ "name": "sparse/pkg", "version": "1.0.0", "dependencies": [], "yanked": false, "location": "dir+test" }"#
+            .to_string(),
+    );
+    let (ir, log) = serve_sparse(files);
+    assert!(ir.to_string().starts_with("index+sparse+http://"));
+    assert_eq!(IndexRes::from_str(&ir.to_string()).unwrap(), ir);
+
+    let pkg = PackageId::new(Name::from_str("sparse/pkg").unwrap(), ir.clone().into());
+    let missing = PackageId::new(Name::from_str("sparse/missing").unwrap(), ir.clone().into());
+
+    // Only the config is fetched up front, and entries are only fetched when they're looked up
+    let mut ixs = CACHE.get_indices(std::slice::from_ref(&ir.res), false, false);
+    assert!(ixs.by_name("sparse").is_ok());
+    assert_eq!(*log.lock().unwrap(), vec!["/index.toml"]);
+    assert_eq!(ixs.entries(&pkg).unwrap().len(), 1);
+    assert!(ixs.entries(&missing).is_err());
+    assert_eq!(
+        *log.lock().unwrap(),
+        vec!["/index.toml", "/sparse/pkg", "/sparse/missing"]
+    );
+    drop(ixs);
+
+    // Entries which were fetched before are cached, even when we're offline
+    log.lock().unwrap().clear();
+    let mut ixs = CACHE.get_indices(std::slice::from_ref(&ir.res), false, true);
+    assert_eq!(ixs.entries(&pkg).unwrap().len(), 1);
+    assert!(ixs.entries(&missing).is_err());
+    assert!(log.lock().unwrap().is_empty());
+
+    // Refreshing the index drops the cached entries, and indices loaded offline can't fetch them
+    // again
+    ixs.refresh(CACHE.client(), &CACHE.credentials).unwrap();
+    ixs.cache.clear();
+    assert_eq!(*log.lock().unwrap(), vec!["/index.toml"]);
+    assert!(ixs.entries(&pkg).is_err());
+}
+
+/// Serves an index as a tarball until the test ends, answering a request which carries the
+/// tarball's ETag with `304 Not Modified`. Returns the index's resolution and every request the
+/// server got.
+fn serve_index_tarball() -> (DirectRes, Arc<Mutex<Vec<String>>>) {
+    let files: &[(&str, &str)] = &[
+        (
+            "index.toml",
+            "[index]\nname = \"tarball\"\nsecure = false\ndependencies = {}\n",
+        ),
+        (
+            "tarball/pkg",
+            r#"{ "name": "tarball/pkg", "version": "1.0.0", "dependencies": [], "yanked": false, "location": "dir+test" }"#,
+        ),
+    ];
+    let mut tar = tar::Builder::new(GzEncoder::new(vec![], Compression::default()));
+    for (path, contents) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, path, contents.as_bytes())
+            .unwrap();
+    }
+    let body = tar.into_inner().unwrap().finish().unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let res = DirectRes::from_str(&format!(
+        "tar+http://{}/index.tar.gz",
+        listener.local_addr().unwrap()
+    ))
+    .unwrap();
+    let log = Arc::new(Mutex::new(vec![]));
+
+    let requests = log.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut req = vec![];
+            let mut buf = [0; 1024];
+            while !req.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                req.extend_from_slice(&buf[..n]);
+            }
+
+            let req = String::from_utf8(req).unwrap();
+            let fresh = req
+                .lines()
+                .any(|l| l.eq_ignore_ascii_case("if-none-match: \"v1\""));
+            requests.lock().unwrap().push(req);
+            let mut res = if fresh {
+                b"HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n".to_vec()
+            } else {
+                format!(
+                    "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .into_bytes()
+            };
+            if !fresh {
+                res.extend_from_slice(&body);
+            }
+            stream.write_all(&res).unwrap();
+        }
+    });
+
+    (res, log)
+}
+
+#[test]
+fn index_tarball_not_modified() {
+    let (res, log) = serve_index_tarball();
+    let pkg = PackageId::new(
+        Name::from_str("tarball/pkg").unwrap(),
+        IndexRes { res: res.clone() }.into(),
+    );
+
+    let mut ixs = CACHE.get_indices(std::slice::from_ref(&res), false, false);
+    assert_eq!(ixs.entries(&pkg).unwrap().len(), 1);
+    let dir = ixs.by_name("tarball").unwrap().path.path().to_owned();
+    drop(ixs);
+    assert_eq!(log.lock().unwrap().len(), 1);
+    assert!(!log.lock().unwrap()[0]
+        .to_lowercase()
+        .contains("if-none-match"));
+
+    // Anything which is downloaded again is unpacked into a cleared directory, which would take
+    // this file with it
+    fs::write(dir.join("marker"), b"").unwrap();
+
+    let mut ixs = CACHE.get_indices(std::slice::from_ref(&res), false, false);
+    assert_eq!(ixs.entries(&pkg).unwrap().len(), 1);
+    ixs.refresh(CACHE.client(), &CACHE.credentials).unwrap();
+    assert_eq!(ixs.entries(&pkg).unwrap().len(), 1);
+
+    let log = log.lock().unwrap();
+    assert_eq!(log.len(), 3);
+    assert!(log[1..]
+        .iter()
+        .all(|req| req.to_lowercase().contains("if-none-match: \"v1\"")));
+    assert!(dir.join("marker").exists());
+}
+
+/// Loads two indices which define the same package differently, with the private one first.
+fn shadowing_indices(dir: &Path) -> Indices {
+    copy_dir(
+        &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/shadowing"),
+        dir,
+        false,
+    )
+    .unwrap();
+
+    let indices = ["private", "public"]
+        .iter()
+        .map(|name| {
+            let mut ix = open_index(&dir.join(name));
+            ix.name = Some(name.to_string());
+            ix
+        })
+        .collect();
+    Indices::new(indices)
+}
+
+#[test]
+fn indices_conflicts() {
+    let tmp = TempDir::new("elba").unwrap();
+    let mut ixs = shadowing_indices(tmp.path());
+    let private = ixs.indices.keys().next().unwrap().clone();
+    let pkg = PackageId::new(Name::from_str("shadow/pkg").unwrap(), private.into());
+
+    // The private index wins, but only 1.0.0 is actually defined differently
+    assert_eq!(ixs.entries(&pkg).unwrap().len(), 2);
+    assert_eq!(ixs.conflicts.len(), 1);
+    let conflict = &ixs.conflicts[0];
+    assert_eq!(conflict.version, Version::parse("1.0.0").unwrap());
+    assert_eq!(conflict.used.0, "private");
+    assert_eq!(conflict.other.0, "public");
+    assert_eq!(
+        conflict.to_string(),
+        "shadow/pkg 1.0.0 is defined differently by index `private` \
+         (tar+https://internal.example.com/pkg-1.0.0.tar.gz) and index `public` \
+         (tar+https://example.com/pkg-1.0.0.tar.gz)"
+    );
+
+    // In strict mode, the package can't be loaded at all
+    ixs.cache.clear();
+    ixs.strict = true;
+    let err = ixs.entries(&pkg).unwrap_err().to_string();
+    assert!(err.contains("indices disagree about shadow/pkg"), "{}", err);
+    assert!(err.contains("index `private`") && err.contains("index `public`"));
+}
+
+#[test]
+fn indices_versions() {
+    let tmp = TempDir::new("elba").unwrap();
+    let ixs = shadowing_indices(tmp.path());
+    let versions = |name| {
+        ixs.versions(&Name::from_str(name).unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    // 1.1.0 is only in the public index, and 1.2.0 is yanked
+    assert_eq!(versions("shadow/pkg"), vec!["1.1.0", "1.0.0", "0.9.0"]);
+    assert!(versions("shadow/missing").is_empty());
+}
+
+/// Loads the entries of a package from the index of broken entries, returning the error.
+fn broken_entry(pkg: &str) -> failure::Error {
+    let tmp = TempDir::new("elba").unwrap();
+    copy_dir(
+        &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/broken-index"),
+        tmp.path(),
+        false,
+    )
+    .unwrap();
+    let index = open_index(tmp.path());
+
+    index.entries(&Name::from_str(pkg).unwrap()).unwrap_err()
+}
+
+fn error_kind(e: &failure::Error) -> Option<Error> {
+    e.iter_chain()
+        .find_map(|c| c.downcast_ref::<Error>().cloned())
+}
+
+#[test]
+fn index_entry_malformed_json() {
+    let e = broken_entry("broken/json");
+    let lines = fs::read_to_string(
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/broken-index/broken/json"),
+    )
+    .unwrap()
+    .lines()
+    .map(|x| x.len())
+    .collect::<Vec<_>>();
+
+    assert_eq!(error_kind(&e), Some(Error::MalformedIndexEntry));
+    // The json is cut off, so the package's version can't be made out. The error points at the
+    // end of the second line, where the json ends without closing its object
+    assert_eq!(
+        e.to_string(),
+        format!(
+            "invalid index entry for broken/json at line 2 (byte {}): EOF while parsing an object",
+            lines[0] + 1 + lines[1] - 1
+        )
+    );
+}
+
+#[test]
+fn index_entry_unknown_field() {
+    let tmp = TempDir::new("elba").unwrap();
+    copy_dir(
+        &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/broken-index"),
+        tmp.path(),
+        false,
+    )
+    .unwrap();
+    let index = open_index(tmp.path());
+
+    // Fields from newer versions of elba are ignored, with a warning
+    let (entries, warnings) = index
+        .entries_with_warnings(&Name::from_str("broken/unknown").unwrap())
+        .unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(warnings.len(), 1);
+    assert!(
+        warnings[0].contains("`locaton` in index entry for broken/unknown 1.1.0 at line 2 "),
+        "{}",
+        warnings[0]
+    );
+}
+
+#[test]
+fn index_entry_missing_field() {
+    let e = broken_entry("broken/missing");
+
+    assert_eq!(error_kind(&e), Some(Error::MissingIndexField));
+    let msg = e.to_string();
+    assert!(
+        msg.starts_with("invalid index entry for broken/missing 1.1.0 at line 2 "),
+        "{}",
+        msg
+    );
+    assert!(msg.contains("`dependencies`"), "{}", msg);
+}
+
+#[test]
+fn index_entry_misfiled() {
+    let e = broken_entry("broken/misfiled");
+
+    assert_eq!(error_kind(&e), Some(Error::InvalidIndex));
+    let msg = e.to_string();
+    assert!(
+        msg.starts_with("invalid index entry for broken/other 1.1.0 at line 2 "),
+        "{}",
+        msg
+    );
+    assert!(
+        msg.ends_with("the entry is for broken/other, but it's filed under `broken/misfiled`"),
+        "{}",
+        msg
+    );
+}
+
+const REPUBLISH_MANIFEST: &str = r#"[package]
+name = "republish/pkg"
+version = "1.0.0"
+authors = []
+
+[targets.lib]
+mods = ["Pkg"]
+"#;
+
+#[test]
+fn package_reproducible() {
+    let project = TempDir::new("elba").unwrap();
+    fs::write(project.path().join("elba.toml"), REPUBLISH_MANIFEST).unwrap();
+    fs::create_dir_all(project.path().join("src")).unwrap();
+    fs::write(project.path().join("src/Pkg.idr"), "module Pkg\n").unwrap();
+    let (_, _, first) = package(project.path()).unwrap();
+
+    // Touching the files without changing them gives the same tarball
+    let later = SystemTime::now() + Duration::from_secs(3600);
+    for file in &["elba.toml", "src/Pkg.idr"] {
+        set_file_mtime(project.path().join(file), FileTime::from_system_time(later)).unwrap();
+    }
+    let (_, _, second) = package(project.path()).unwrap();
+    assert_eq!(first, second);
+
+    fs::write(project.path().join("src/Pkg.idr"), "module Pkg\n\n").unwrap();
+    let (_, _, third) = package(project.path()).unwrap();
+    assert_ne!(first, third);
+}
+
+/// Packages the same package in two different places, with its files written in a different
+/// order and at different times, as if it were packaged on two different machines.
+#[test]
+fn package_reproducible_across_machines() {
+    let files = [
+        ("elba.toml", REPUBLISH_MANIFEST),
+        ("src/Pkg.idr", "module Pkg\n"),
+        ("src/Pkg/Internal.idr", "module Pkg.Internal\n"),
+        ("README.md", "# pkg\n"),
+    ];
+    let machine = |order: &mut dyn Iterator<Item = &(&str, &str)>, at: SystemTime| {
+        let project = TempDir::new("elba").unwrap();
+        for (path, contents) in order {
+            let path = project.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, contents).unwrap();
+            set_file_mtime(&path, FileTime::from_system_time(at)).unwrap();
+        }
+        let (tarball, _, cksum) = package(project.path()).unwrap();
+        (fs::read(tarball).unwrap(), cksum)
+    };
+
+    let (first, first_cksum) = machine(&mut files.iter(), SystemTime::UNIX_EPOCH);
+    let (second, second_cksum) = machine(
+        &mut files.iter().rev(),
+        SystemTime::now() + Duration::from_secs(86400),
+    );
+    assert_eq!(first, second);
+    assert_eq!(first_cksum, second_cksum);
+}
+
+#[test]
+fn index_republish() {
+    let project = TempDir::new("elba").unwrap();
+    fs::write(project.path().join("elba.toml"), REPUBLISH_MANIFEST).unwrap();
+    fs::create_dir_all(project.path().join("src")).unwrap();
+    fs::write(project.path().join("src/Pkg.idr"), "module Pkg\n").unwrap();
+    let (_, manifest, cksum) = package(project.path()).unwrap();
+
+    let tmp = TempDir::new("elba").unwrap();
+    fs::write(
+        tmp.path().join("index.toml"),
+        "[index]\nsecure = false\ndependencies = {}\n",
+    )
+    .unwrap();
+    let location = DirectRes::Tar {
+        url: "https://example.com/pkg.tar.gz".parse().unwrap(),
+        cksum: Some(cksum.clone()),
+        subpath: None,
+    };
+    let entry = RawEntry::from_manifest(&manifest, Some(location)).unwrap();
+    fs::create_dir_all(tmp.path().join("republish")).unwrap();
+    fs::write(
+        tmp.path().join("republish/pkg"),
+        serde_json::to_string(&entry).unwrap(),
+    )
+    .unwrap();
+    let index = open_index(tmp.path());
+    assert_eq!(
+        index.checksums(manifest.name()).unwrap()[manifest.version()],
+        Some(cksum.clone())
+    );
+
+    // Publishing exactly the same tarball again doesn't change anything
+    let (_, manifest, again) = package(project.path()).unwrap();
+    assert_eq!(again, cksum);
+    check_republish(&index, &manifest, &again).unwrap();
+
+    // But the same version with different contents can't be published
+    fs::write(
+        project.path().join("src/Pkg.idr"),
+        "module Pkg\n\nx : Int\n",
+    )
+    .unwrap();
+    let (_, manifest, changed) = package(project.path()).unwrap();
+    let err = check_republish(&index, &manifest, &changed).unwrap_err();
+    assert_eq!(error_kind(&err), Some(Error::VersionAlreadyPublished));
+    assert!(err.to_string().contains(&cksum.to_string()), "{}", err);
+
+    // ...until the version is bumped
+    fs::write(
+        project.path().join("elba.toml"),
+        REPUBLISH_MANIFEST.replace("1.0.0", "1.0.1"),
+    )
+    .unwrap();
+    let (_, manifest, bumped) = package(project.path()).unwrap();
+    check_republish(&index, &manifest, &bumped).unwrap();
+    assert!(index
+        .checksums(&Name::from_str("republish/other").unwrap())
+        .unwrap()
+        .is_empty());
+}
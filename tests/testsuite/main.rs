@@ -1,4 +1,6 @@
 mod build;
+mod cache;
 mod index;
+mod resolution;
 mod resolver;
 mod util;
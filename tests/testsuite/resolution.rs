@@ -0,0 +1,644 @@
+use elba::{
+    package::Checksum,
+    remote::{
+        archive::ArchiveFormats,
+        auth::{is_unauthorized, Credential, Credentials},
+        resolution::DirectRes,
+    },
+    util::{git::UserPass, lock::DirLock},
+};
+use flate2::{write::GzEncoder, Compression};
+use indexmap::indexmap;
+use reqwest::blocking::Client;
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    io::{Read, Write},
+    net::TcpListener,
+    path::Path,
+    str::FromStr,
+    thread,
+};
+use tempdir::TempDir;
+use url::Url;
+
+/// Commits a single file to the master branch of a repository, returning the commit's id.
+fn commit(repo: &git2::Repository, contents: &str) -> String {
+    let root = repo.workdir().unwrap();
+    fs::write(root.join("elba.toml"), contents).unwrap();
+
+    let mut index = repo.index().unwrap();
+    index.add_path(Path::new("elba.toml")).unwrap();
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let sig = git2::Signature::now("elba", "elba@example.com").unwrap();
+    let parent = repo
+        .find_reference("refs/heads/master")
+        .and_then(|x| x.peel_to_commit())
+        .ok();
+    let parents = parent.iter().collect::<Vec<_>>();
+
+    let id = repo
+        .commit(
+            Some("refs/heads/master"),
+            &sig,
+            &sig,
+            contents,
+            &tree,
+            &parents,
+        )
+        .unwrap();
+    repo.set_head("refs/heads/master").unwrap();
+
+    id.to_string()
+}
+
+fn rev(res: &DirectRes) -> Option<&str> {
+    if let DirectRes::Git { rev, .. } = res {
+        rev.as_ref().map(|x| x.as_str())
+    } else {
+        None
+    }
+}
+
+#[test]
+fn git_res_rev_roundtrip() {
+    let res = DirectRes::from_str("git+https://github.com/elba/elba#master:abc123").unwrap();
+    assert_eq!(rev(&res), Some("abc123"));
+    assert_eq!(
+        res.to_string(),
+        "git+https://github.com/elba/elba#master:abc123"
+    );
+
+    let res = DirectRes::from_str("git+https://github.com/elba/elba#master").unwrap();
+    assert_eq!(rev(&res), None);
+    assert_eq!(res.to_string(), "git+https://github.com/elba/elba#master");
+}
+
+#[test]
+fn tar_res_subpath_roundtrip() {
+    let res =
+        DirectRes::from_str("tar+https://example.com/a.tar.gz#sha256=abcd&subpath=pkgs/a").unwrap();
+    match &res {
+        DirectRes::Tar { cksum, subpath, .. } => {
+            assert_eq!(cksum.as_ref().unwrap().hash, "abcd");
+            assert_eq!(subpath.as_ref().unwrap().0, Path::new("pkgs/a"));
+        }
+        _ => panic!("not a tarball"),
+    }
+    assert_eq!(
+        res.to_string(),
+        "tar+https://example.com/a.tar.gz#sha256=abcd&subpath=pkgs/a"
+    );
+
+    assert!(DirectRes::from_str("tar+https://example.com/a.tar.gz#subpath=../a").is_err());
+}
+
+#[test]
+fn git_res_subdir_roundtrip() {
+    let res =
+        DirectRes::from_str("git+https://github.com/elba/mono#dev:abc123&subdir=pkgs/a").unwrap();
+    match &res {
+        DirectRes::Git {
+            tag, rev, subdir, ..
+        } => {
+            assert_eq!(tag, "dev");
+            assert_eq!(rev.as_ref().unwrap(), "abc123");
+            assert_eq!(subdir.as_ref().unwrap().0, Path::new("pkgs/a"));
+        }
+        _ => panic!("not a git repository"),
+    }
+    assert_eq!(
+        res.to_string(),
+        "git+https://github.com/elba/mono#dev:abc123&subdir=pkgs/a"
+    );
+    assert_eq!(
+        res.cache_key(),
+        "git+https://github.com/elba/mono#dev:abc123&subdir=pkgs/a"
+    );
+
+    // Packages in different subdirs of the same repository are different packages
+    let other = DirectRes::from_str("git+https://github.com/elba/mono#dev&subdir=pkgs/b").unwrap();
+    assert!(!res.lowkey_eq(&other));
+    assert_ne!(res.lowkey_cache_key(), other.lowkey_cache_key());
+}
+
+fn manifest_res(dep: &str) -> Result<DirectRes, failure::Error> {
+    DirectRes::from_manifest_value(
+        &toml::from_str::<toml::Value>(&format!("dep = {}", dep))?["dep"],
+    )
+}
+
+#[test]
+fn manifest_value_shorthands() {
+    let res = manifest_res(r#"{ git = "https://github.com/elba/mono", tag = "dev", subdir = "pkgs/a", features = ["x"] }"#).unwrap();
+    assert_eq!(
+        res,
+        DirectRes::from_str("git+https://github.com/elba/mono#dev&subdir=pkgs/a").unwrap()
+    );
+    let res = manifest_res(r#"{ git = "https://github.com/elba/elba" }"#).unwrap();
+    assert_eq!(res.to_string(), "git+https://github.com/elba/elba#master");
+
+    let res = manifest_res(r#"{ path = "../here", optional = true }"#).unwrap();
+    assert_eq!(res, DirectRes::from_str("dir+../here").unwrap());
+
+    let res = manifest_res(r#"{ tar = "https://example.com/a.tar.gz" }"#).unwrap();
+    assert_eq!(res.to_string(), "tar+https://example.com/a.tar.gz");
+    let res = manifest_res(
+        r#"{ tar = { url = "https://example.com/a.tar.gz", cksum = "sha256=abcd", subpath = "pkgs/a" } }"#,
+    )
+    .unwrap();
+    assert_eq!(
+        res.to_string(),
+        "tar+https://example.com/a.tar.gz#sha256=abcd&subpath=pkgs/a"
+    );
+
+    // Resolution strings work too
+    let res = manifest_res(r#""tar+https://example.com/a.tar.gz#sha256=abcd""#).unwrap();
+    assert_eq!(
+        res,
+        DirectRes::from_str("tar+https://example.com/a.tar.gz#sha256=abcd").unwrap()
+    );
+}
+
+#[test]
+fn manifest_value_malformed() {
+    let err = |dep: &str| manifest_res(dep).unwrap_err().to_string();
+
+    assert!(err(r#"{ features = [] }"#).contains("`git`, `path` or `tar`"));
+    assert!(err(r#"{ git = "https://a.com/b", path = "c" }"#)
+        .contains("`git` and `path` can't be used together"));
+    assert!(err(r#"{ git = "not a url" }"#).contains("`git` isn't a valid url"));
+    assert!(err(r#"{ git = 1 }"#).contains("`git` should be a string"));
+    assert!(err(r#"{ path = "a", tag = "b" }"#).contains("`tag` only applies"));
+    assert!(err(r#"{ git = "https://a.com/b", subdir = "../c" }"#).contains("`subdir`"));
+    assert!(err(r#"{ tar = "ftp://a.com/b.tar.gz" }"#).contains("`tar` should be an http"));
+    assert!(err(r#"{ tar = { cksum = "sha256=abcd" } }"#).contains("`tar.url` is missing"));
+    assert!(
+        err(r#"{ tar = { url = "https://a.com/b.tar.gz", cksum = "md5=abcd" } }"#)
+            .contains("`tar.cksum`")
+    );
+    assert!(
+        err(r#"{ tar = { url = "https://a.com/b.tar.gz", checksum = "sha256=abcd" } }"#)
+            .contains("`tar.checksum` isn't a field")
+    );
+    assert!(err(r#""nope+https://a.com/b""#).contains("isn't a valid source"));
+}
+
+fn cache_key(res: &str) -> String {
+    DirectRes::from_str(res).unwrap().cache_key()
+}
+
+#[test]
+fn cache_key_format() {
+    // These keys name directories in existing caches, so they can't change by accident
+    assert_eq!(
+        cache_key("git+https://github.com/elba/elba#master:abc123"),
+        "git+https://github.com/elba/elba#master:abc123"
+    );
+    assert_eq!(
+        cache_key("git+https://github.com/elba/elba"),
+        "git+https://github.com/elba/elba#master"
+    );
+    assert_eq!(cache_key("dir+../pkgs/a"), "dir+../pkgs/a");
+    assert_eq!(
+        cache_key("tar+https://example.com/a.tar.gz#sha256=abcd"),
+        "tar+https://example.com/a.tar.gz#sha256=abcd"
+    );
+    assert_eq!(
+        cache_key("tar+https://example.com/a.tar.gz#sha256=abcd&subpath=pkgs/a"),
+        "tar+https://example.com/a.tar.gz#sha256=abcd&subpath=pkgs/a"
+    );
+    assert_eq!(
+        cache_key("tar+https://example.com/a.tar.gz#subpath=pkgs/a"),
+        "tar+https://example.com/a.tar.gz#subpath=pkgs/a"
+    );
+    assert_eq!(
+        DirectRes::Sparse {
+            url: Url::parse("https://example.com/index").unwrap()
+        }
+        .cache_key(),
+        "sparse+https://example.com/index"
+    );
+
+    let git = DirectRes::from_str("git+https://github.com/elba/elba#dev:abc123").unwrap();
+    assert_eq!(git.lowkey_cache_key(), "git+https://github.com/elba/elba");
+    let dir = DirectRes::from_str("dir+../pkgs/a").unwrap();
+    assert_eq!(dir.lowkey_cache_key(), dir.cache_key());
+}
+
+#[test]
+fn cache_key_normalized() {
+    // An empty query string is the same as none at all, but other queries are kept
+    assert_eq!(
+        cache_key("tar+https://example.com/a.tar.gz?"),
+        cache_key("tar+https://example.com/a.tar.gz")
+    );
+    assert_eq!(
+        cache_key("tar+https://example.com/a.tar.gz?v=2#sha256=abcd"),
+        "tar+https://example.com/a.tar.gz?v=2#sha256=abcd"
+    );
+    let url = Url::parse("https://example.com/index?#top").unwrap();
+    assert_eq!(
+        DirectRes::Sparse { url }.cache_key(),
+        "sparse+https://example.com/index"
+    );
+    assert_eq!(
+        cache_key("git+https://github.com/elba/elba?#master"),
+        "git+https://github.com/elba/elba#master"
+    );
+
+    // Trailing slashes and `.` components don't change which directory is meant
+    assert_eq!(cache_key("dir+pkgs/a/"), "dir+pkgs/a");
+    assert_eq!(cache_key("dir+./pkgs/./a//"), "dir+pkgs/a");
+    assert_ne!(cache_key("dir+pkgs/a"), cache_key("dir+pkgs/b"));
+}
+
+#[test]
+fn git_branch_records_rev() {
+    let origin = TempDir::new("elba").unwrap();
+    let repo = git2::Repository::init(origin.path()).unwrap();
+    let first = commit(&repo, "first");
+
+    let client = Client::new();
+    let branch = DirectRes::Git {
+        repo: Url::from_file_path(origin.path()).unwrap(),
+        tag: "master".to_string(),
+        rev: None,
+        subdir: None,
+    };
+
+    let before = TempDir::new("elba").unwrap();
+    let before = DirLock::acquire(before.path()).unwrap();
+    let locked = branch
+        .retrieve(
+            &client,
+            &Credentials::default(),
+            &ArchiveFormats::default(),
+            &before,
+            true,
+            |_| Ok(()),
+        )
+        .unwrap()
+        .unwrap();
+    assert_eq!(rev(&locked), Some(first.as_str()));
+
+    // The branch moves on, but the recorded rev still points to the first commit
+    let second = commit(&repo, "second");
+
+    let later = TempDir::new("elba").unwrap();
+    let later = DirLock::acquire(later.path()).unwrap();
+    let res = locked
+        .retrieve(
+            &client,
+            &Credentials::default(),
+            &ArchiveFormats::default(),
+            &later,
+            true,
+            |_| Ok(()),
+        )
+        .unwrap();
+    assert_eq!(res, None);
+    assert_eq!(
+        fs::read_to_string(later.path().join("elba.toml")).unwrap(),
+        "first"
+    );
+
+    // Re-resolving the branch from scratch picks up the new commit
+    let fresh = TempDir::new("elba").unwrap();
+    let fresh = DirLock::acquire(fresh.path()).unwrap();
+    let res = branch
+        .retrieve(
+            &client,
+            &Credentials::default(),
+            &ArchiveFormats::default(),
+            &fresh,
+            true,
+            |_| Ok(()),
+        )
+        .unwrap()
+        .unwrap();
+    assert_eq!(rev(&res), Some(second.as_str()));
+
+    // An existing checkout at the locked rev isn't moved when we aren't updating eagerly
+    let res = locked
+        .retrieve(
+            &client,
+            &Credentials::default(),
+            &ArchiveFormats::default(),
+            &before,
+            false,
+            |_| Ok(()),
+        )
+        .unwrap();
+    assert_eq!(res, None);
+}
+
+/// A gzipped tarball containing a single manifest.
+fn tarball() -> Vec<u8> {
+    let contents = (0..4096).map(|x| (x % 251) as u8).collect::<Vec<_>>();
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+
+    let mut tar = tar::Builder::new(GzEncoder::new(vec![], Compression::default()));
+    tar.append_data(&mut header, "elba.toml", &contents[..])
+        .unwrap();
+    tar.into_inner().unwrap().finish().unwrap()
+}
+
+/// Answers a single request with whatever `respond` makes of it. Returns the url to request and a
+/// handle which yields the request the server got.
+pub fn serve_once(
+    respond: impl FnOnce(&str) -> Vec<u8> + Send + 'static,
+) -> (Url, thread::JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = Url::parse(&format!(
+        "http://{}/pkg.tar.gz",
+        listener.local_addr().unwrap()
+    ))
+    .unwrap();
+
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut req = vec![];
+        let mut buf = [0; 1024];
+        while !req.ends_with(b"\r\n\r\n") {
+            let n = stream.read(&mut buf).unwrap();
+            req.extend_from_slice(&buf[..n]);
+        }
+
+        let req = String::from_utf8(req).unwrap();
+        stream.write_all(&respond(&req)).unwrap();
+
+        req
+    });
+
+    (url, handle)
+}
+
+/// The value of a header in a raw request.
+fn header(req: &str, name: &str) -> Option<String> {
+    req.lines().find_map(|l| {
+        let mut l = l.splitn(2, ':');
+        if l.next()?.eq_ignore_ascii_case(name) {
+            Some(l.next()?.trim().to_string())
+        } else {
+            None
+        }
+    })
+}
+
+pub fn response(status: &str, extra: &str, body: &[u8]) -> Vec<u8> {
+    let mut res = format!(
+        "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        extra,
+        body.len()
+    )
+    .into_bytes();
+    res.extend_from_slice(body);
+    res
+}
+
+/// Serves `body`, honoring a `Range` header if `ranges` is set.
+pub fn serve_tarball(body: Vec<u8>, ranges: bool) -> (Url, thread::JoinHandle<String>) {
+    serve_once(move |req| {
+        let start = header(req, "range")
+            .filter(|_| ranges)
+            .map(|r| r["bytes=".len()..r.len() - 1].parse::<usize>().unwrap());
+
+        match start {
+            Some(start) => response(
+                "206 Partial Content",
+                &format!(
+                    "Content-Range: bytes {}-{}/{}\r\n",
+                    start,
+                    body.len() - 1,
+                    body.len()
+                ),
+                &body[start..],
+            ),
+            None => response("200 OK", "", &body),
+        }
+    })
+}
+
+pub fn tar_res(url: Url, body: &[u8]) -> DirectRes {
+    let hash = hex::encode(Sha256::digest(body));
+    DirectRes::Tar {
+        url,
+        cksum: Some(Checksum::from_str(&format!("sha256={}", hash)).unwrap()),
+        subpath: None,
+    }
+}
+
+/// Retrieves a tarball after half of it has already been downloaded to the `.part` file.
+fn retrieve_resumed(ranges: bool) -> Option<String> {
+    let body = tarball();
+    let (url, server) = serve_tarball(body.clone(), ranges);
+
+    let root = TempDir::new("elba").unwrap();
+    let half = body.len() / 2;
+    fs::write(root.path().join("pkg.part"), &body[..half]).unwrap();
+
+    let res = tar_res(url, &body);
+    let client = Client::builder().no_proxy().build().unwrap();
+    let target = DirLock::acquire(&root.path().join("pkg")).unwrap();
+    // The checksum only matches if the resumed bytes were appended correctly
+    res.retrieve(
+        &client,
+        &Credentials::default(),
+        &ArchiveFormats::default(),
+        &target,
+        false,
+        |_| Ok(()),
+    )
+    .unwrap();
+
+    assert_eq!(
+        fs::read(target.path().join("elba.toml")).unwrap().len(),
+        4096
+    );
+    assert!(!root.path().join("pkg.part").exists());
+    assert!(!root.path().join("pkg.tar.gz").exists());
+
+    header(&server.join().unwrap(), "range")
+}
+
+#[test]
+fn tar_download_resume() {
+    let range = retrieve_resumed(true);
+    assert_eq!(range, Some(format!("bytes={}-", tarball().len() / 2)));
+}
+
+#[test]
+fn tar_download_resume_unsupported() {
+    // The server ignores the range and sends the whole file, so we start over
+    assert!(retrieve_resumed(false).is_some());
+}
+
+/// Serves a tarball only to requests which carry the bearer token `secret`.
+fn download_private(auth: &Credentials) -> (Result<(), failure::Error>, String) {
+    let body = tarball();
+    let served = body.clone();
+    let (url, server) = serve_once(move |req| {
+        if header(req, "authorization").as_deref() == Some("Bearer secret") {
+            response("200 OK", "", &served)
+        } else {
+            response("401 Unauthorized", "", b"")
+        }
+    });
+
+    let root = TempDir::new("elba").unwrap();
+    let client = Client::builder().no_proxy().build().unwrap();
+    let target = DirLock::acquire(&root.path().join("pkg")).unwrap();
+    let res = tar_res(url, &body)
+        .retrieve(
+            &client,
+            auth,
+            &ArchiveFormats::default(),
+            &target,
+            false,
+            |_| Ok(()),
+        )
+        .map(|_| ());
+
+    (res, server.join().unwrap())
+}
+
+#[test]
+fn tar_download_token() {
+    let auth = Credentials::default().with_hosts(&indexmap!(
+        "127.0.0.1".to_string() => Credential::Token { token: "secret".to_string() },
+    ));
+
+    let (res, _) = download_private(&auth);
+    res.unwrap();
+}
+
+#[test]
+fn tar_download_unauthorized() {
+    let (res, req) = download_private(&Credentials::default());
+    assert_eq!(header(&req, "authorization"), None);
+
+    let err = res.unwrap_err();
+    assert!(is_unauthorized(&err));
+    assert!(err.to_string().contains("127.0.0.1"), "{}", err);
+}
+
+#[test]
+fn credentials_env() {
+    let url = Url::parse("https://private.example.com/pkg.tar.gz").unwrap();
+    let config = indexmap!(
+        "Private.Example.com".to_string() => Credential::Basic {
+            username: "me".to_string(),
+            password: Some("hunter2".to_string()),
+        },
+    );
+
+    let auth = Credentials::default().with_hosts(&config);
+    assert_eq!(auth.get(&url), config.values().next().cloned());
+
+    let env = vec![(
+        "ELBA_TOKEN_PRIVATE_EXAMPLE_COM".to_string(),
+        "from-env".to_string(),
+    )];
+    let auth = Credentials::from_vars(env).with_hosts(&config);
+
+    // The environment takes precedence over the configuration
+    assert_eq!(
+        auth.get(&url),
+        Some(Credential::Token {
+            token: "from-env".to_string()
+        })
+    );
+
+    let debug = format!("{:?}", auth);
+    assert!(
+        !debug.contains("from-env") && !debug.contains("hunter2"),
+        "{}",
+        debug
+    );
+}
+
+/// Writes an executable shell script.
+#[cfg(unix)]
+fn script(path: &Path, body: &str) {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::write(path, format!("#!/bin/sh\n{}", body)).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn git_userpass_sources() {
+    let root = TempDir::new("elba").unwrap();
+    let url = "https://git.example.com/private/repo";
+    let lookup = |source: UserPass, cfg: &git2::Config, auth: &Credentials| {
+        source.lookup(cfg, auth, url, None)
+    };
+
+    // A stub credential helper, which notes down that it was asked
+    let helper = root.path().join("helper");
+    let asked = root.path().join("asked");
+    script(
+        &helper,
+        &format!(
+            "cat > {}\necho username=helped\necho password=from-helper\n",
+            asked.display()
+        ),
+    );
+    let config = root.path().join("gitconfig");
+    fs::write(
+        &config,
+        format!("[credential]\n\thelper = {}\n", helper.display()),
+    )
+    .unwrap();
+    let cfg = git2::Config::open(&config).unwrap();
+
+    assert_eq!(
+        lookup(UserPass::Helper, &cfg, &Credentials::default()),
+        Some(("helped".to_string(), "from-helper".to_string()))
+    );
+    // The helper is told which host the credentials are for
+    let request = fs::read_to_string(&asked).unwrap();
+    assert!(request.contains("host=git.example.com"), "{}", request);
+
+    // A token from elba's configuration is sent as the password
+    let auth = Credentials::default().with_hosts(&indexmap!(
+        "git.example.com".to_string() => Credential::Token { token: "s3cr3t".to_string() },
+    ));
+    assert_eq!(
+        lookup(UserPass::Configured, &cfg, &auth),
+        Some(("x-access-token".to_string(), "s3cr3t".to_string()))
+    );
+    assert_eq!(
+        lookup(UserPass::Configured, &cfg, &Credentials::default()),
+        None
+    );
+
+    // An askpass program is asked for the username, then the password
+    if std::env::var_os("GIT_ASKPASS").is_none() {
+        let askpass = root.path().join("askpass");
+        script(
+            &askpass,
+            "case \"$1\" in Username*) echo asked ;; *) echo \"$1\" ;; esac\n",
+        );
+        fs::write(
+            &config,
+            format!("[core]\n\taskPass = {}\n", askpass.display()),
+        )
+        .unwrap();
+        let cfg = git2::Config::open(&config).unwrap();
+        assert_eq!(
+            lookup(UserPass::Askpass, &cfg, &Credentials::default()),
+            Some((
+                "asked".to_string(),
+                "Password for 'https://asked@git.example.com': ".to_string()
+            ))
+        );
+    }
+}
@@ -11,7 +11,8 @@ use elba::{
 };
 use itertools::Either::Right;
 use semver::Version;
-use std::str::FromStr;
+use std::{fs, str::FromStr};
+use tempdir::TempDir;
 
 macro_rules! sum {
     ($a:tt, $b:tt) => {{
@@ -38,8 +39,9 @@ fn retriever(root: Summary) -> Retriever<'static> {
     let root_deps = ixs
         .select(&root)
         .unwrap()
-        .dependencies
-        .iter()
+        .active_deps(&[])
+        .unwrap()
+        .into_iter()
         .cloned()
         .map(|d| (PackageId::new(d.name, Resolution::Index(d.index)), d.req))
         .collect::<Vec<_>>();
@@ -104,3 +106,253 @@ fn resolve_conflict_complex_report() {
     let msg = resolver.solve();
     assert!(msg.is_err())
 }
+
+#[test]
+fn resolve_cycle_report() {
+    let mut retriever = retriever(sum!("cycle/root", "1.0.0"));
+    let resolver = resolver(&mut retriever);
+    let err = resolver.solve().unwrap_err().to_string();
+    assert!(err.contains("cycle/a -> cycle/b -> cycle/a"), "{}", err);
+}
+
+#[test]
+fn resolve_prerelease_default() {
+    // The only versions newer than 1.0.0 are prereleases, which `> 1.0.0` doesn't ask for
+    let mut retriever = retriever(sum!("prerelease/default", "1.0.0"));
+    let resolver = resolver(&mut retriever);
+    assert!(resolver.solve().is_err())
+}
+
+#[test]
+fn resolve_prerelease_opt_in() {
+    let mut retriever = retriever(sum!("prerelease/opt_in", "1.0.0"));
+    let solve = resolver(&mut retriever).solve().unwrap();
+
+    let bar = solve
+        .find_by(|s| s.name().name() == "bar")
+        .expect("bar wasn't resolved");
+    assert_eq!(bar.version(), &Version::parse("1.1.0-beta.1").unwrap());
+}
+
+#[test]
+fn resolve_constraint_syntax() {
+    let cases = [
+        ("syntax/exact", "1.2.0"),
+        ("syntax/wildcard", "1.2.5"),
+        ("syntax/compound", "1.2.5"),
+        ("syntax/caret", "1.3.0"),
+        ("syntax/tilde", "1.2.5"),
+        ("syntax/pre", "1.4.0-beta.1"),
+        ("syntax/star", "2.0.0"),
+    ];
+
+    for (root, expected) in cases.iter() {
+        let mut retriever = retriever(sum!(root, "1.0.0"));
+        let solve = resolver(&mut retriever).solve().unwrap();
+
+        let lib = solve
+            .find_by(|s| s.name().name() == "lib")
+            .expect("lib wasn't resolved");
+        assert_eq!(
+            lib.version(),
+            &Version::parse(expected).unwrap(),
+            "{}",
+            root
+        );
+    }
+}
+
+#[test]
+fn resolve_override_transitive() {
+    let dir = TempDir::new("elba").unwrap();
+    fs::write(
+        dir.path().join("elba.toml"),
+        r#"[package]
+name = "no_conflict/bar"
+version = "1.0.0"
+authors = []
+
+[targets.lib]
+mods = []
+"#,
+    )
+    .unwrap();
+
+    // root -> foo -> bar, where bar only comes in through foo's index entry.
+    let mut retriever = retriever(sum!("no_conflict/root", "1.0.0"));
+    retriever.overrides.insert(
+        Name::from_str("no_conflict/bar").unwrap(),
+        DirectRes::Dir {
+            path: dir.path().to_owned(),
+        },
+    );
+    let solve = resolver(&mut retriever).solve().unwrap();
+
+    let bar = solve
+        .find_by(|s| s.name().name() == "bar")
+        .expect("bar wasn't resolved");
+    assert_eq!(
+        bar.resolution(),
+        &Resolution::Direct(DirectRes::Dir {
+            path: dir.path().to_owned(),
+        })
+    );
+}
+
+#[test]
+fn resolve_fetch_plan() {
+    let ix = TempDir::new("elba").unwrap();
+    let local = TempDir::new("elba").unwrap();
+    fs::write(
+        ix.path().join("index.toml"),
+        "[index]\nsecure = false\ndependencies = {}\n",
+    )
+    .unwrap();
+    fs::create_dir_all(ix.path().join("plan")).unwrap();
+    let local_loc = format!("dir+{}", local.path().display());
+    let entries = [
+        (
+            "root",
+            r#"[{ "name": "plan/remote", "req": "1.0.0" }, { "name": "plan/local", "req": "1.0.0" }]"#,
+            local_loc.clone(),
+            "",
+        ),
+        (
+            "remote",
+            "[]",
+            "tar+https://example.invalid/remote.tar.gz".to_string(),
+            r#", "size": 2048"#,
+        ),
+        ("local", "[]", local_loc, ""),
+    ];
+    for (name, deps, loc, extra) in entries.iter() {
+        fs::write(
+            ix.path().join("plan").join(name),
+            format!(
+                r#"{{ "name": "plan/{}", "version": "1.0.0", "dependencies": {}, "yanked": false, "location": "{}"{} }}"#,
+                name, deps, loc, extra
+            ),
+        )
+        .unwrap();
+    }
+
+    let index = DirectRes::Dir {
+        path: ix.path().to_owned(),
+    };
+    let mut ixs = CACHE.get_indices(std::slice::from_ref(&index), false, false);
+    let root = Summary::new(
+        PackageId::new(
+            Name::from_str("plan/root").unwrap(),
+            IndexRes { res: index }.into(),
+        ),
+        Version::parse("1.0.0").unwrap(),
+    );
+    let root_deps = ixs
+        .select(&root)
+        .unwrap()
+        .active_deps(&[])
+        .unwrap()
+        .into_iter()
+        .cloned()
+        .map(|d| (PackageId::new(d.name, Resolution::Index(d.index)), d.req))
+        .collect::<Vec<_>>();
+    let mut retriever = Retriever::new(
+        &CACHE.logger.clone(),
+        &CACHE,
+        root,
+        root_deps,
+        Right(ixs),
+        Graph::default(),
+        &IXMAP,
+        shell(),
+        false,
+    );
+
+    let solve = resolver(&mut retriever).solve().unwrap();
+    let plan = retriever.plan_packages(&solve).unwrap();
+    assert_eq!(plan.packages.len(), 3);
+
+    // Only the remote package would be downloaded; the dir package is already there
+    let downloads = plan.downloads().collect::<Vec<_>>();
+    assert_eq!(downloads.len(), 1);
+    assert_eq!(downloads[0].summary.name().as_str(), "plan/remote");
+    assert_eq!(downloads[0].size, Some(2048));
+    assert!(downloads[0].location.is_tar());
+    assert_eq!(plan.download_size(), 2048);
+
+    let local = plan
+        .packages
+        .iter()
+        .find(|x| x.summary.name().as_str() == "plan/local")
+        .unwrap();
+    assert!(local.cached);
+    assert_eq!(local.size, None);
+}
+
+#[test]
+fn resolve_conflict_explanation() {
+    let mut retriever = retriever(sum!("conflict_chain/root", "1.0.0"));
+    let err = resolver(&mut retriever).solve().unwrap_err().to_string();
+    assert!(err.contains("these requirements conflict"), "{}", err);
+    assert!(
+        err.contains(
+            "conflict_chain/root 1.0.0 -> conflict_chain/a needs conflict_chain/c >=2.0.0"
+        ),
+        "{}",
+        err
+    );
+    assert!(
+        err.contains("conflict_chain/root 1.0.0 -> conflict_chain/b needs conflict_chain/c <2.0.0"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn resolve_conflict_explanation_unavailable() {
+    let mut retriever = retriever(sum!("conflict_chain/missing", "1.0.0"));
+    let err = resolver(&mut retriever).solve().unwrap_err().to_string();
+    assert!(
+        err.contains(
+            "conflict_chain/missing 1.0.0 needs conflict_chain/c >=3.0.0, which is unavailable"
+        ),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn resolve_conflict_simple_explanation() {
+    // The conflicting requirement is a few dependencies away from the root
+    let mut retriever = retriever(sum!("conflict_simple/root", "1.0.0"));
+    let err = resolver(&mut retriever).solve().unwrap_err().to_string();
+    assert!(
+        err.contains("conflict_simple/root 1.0.0 needs conflict_simple/baz"),
+        "{}",
+        err
+    );
+    assert!(
+        err.contains(
+            "conflict_simple/root 1.0.0 -> conflict_simple/foo -> conflict_simple/bar needs conflict_simple/baz"
+        ),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn resolve_index_features() {
+    // root -> left -> shared, and root -> right -> shared with the `extra` feature, which turns
+    // on shared's optional dependency on opt. left gets visited first, before anything has
+    // asked for `extra`.
+    let mut root = retriever(sum!("features/root", "1.0.0"));
+    let solve = resolver(&mut root).solve().unwrap();
+    assert!(solve.find_by(|s| s.name().name() == "opt").is_some());
+    // The retriever holds the lock on the index until it's gone
+    drop(root);
+
+    let mut plain = retriever(sum!("features/plain", "1.0.0"));
+    let solve = resolver(&mut plain).solve().unwrap();
+    assert!(solve.find_by(|s| s.name().name() == "shared").is_some());
+    assert!(solve.find_by(|s| s.name().name() == "opt").is_none());
+}
@@ -9,7 +9,7 @@ use elba::{
 use indexmap::{indexmap, IndexMap};
 use lazy_static::lazy_static;
 use slog::{self, o, Logger};
-use std::{path::PathBuf, str::FromStr};
+use std::{env, path::PathBuf, process::Command, str::FromStr};
 use tempdir::TempDir;
 
 lazy_static! {
@@ -46,7 +46,7 @@ pub fn cache() -> Cache {
         tmp: CACHE_DIR.path().join("tmp"),
     };
 
-    Cache::from_disk(&LOGGER, layout, shell()).unwrap()
+    Cache::from_disk(&LOGGER, layout, None, shell()).unwrap()
 }
 
 fn index_dir() -> TempDir {
@@ -74,3 +74,34 @@ fn cache_dir() -> TempDir {
 
     tmp
 }
+
+/// Set in the environment of a test which `rerun_with_env` runs in a process of its own.
+const RERUN_ENV: &str = "ELBA_TEST_RERUN";
+
+/// Runs the test `name` again in a process of its own with the environment variables `vars` set,
+/// since setting them in this process would change the environment of every other test running
+/// alongside it. Returns whether the test was rerun, in which case the caller should return
+/// straight away; in the rerun itself, this returns false and the test goes on as usual.
+pub fn rerun_with_env(name: &str, vars: &[(&str, &str)]) -> bool {
+    if env::var_os(RERUN_ENV).is_some() {
+        return false;
+    }
+
+    let out = Command::new(env::current_exe().unwrap())
+        .arg("--exact")
+        .arg(name)
+        .env(RERUN_ENV, "1")
+        .envs(vars.iter().cloned())
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(
+        out.status.success() && stdout.contains("1 passed"),
+        "{} failed when rerun:\n{}{}",
+        name,
+        stdout,
+        String::from_utf8_lossy(&out.stderr)
+    );
+
+    true
+}